@@ -62,6 +62,106 @@ pub trait Hasher: Sync + Send {
 
 	/// Compute the hash of the provided slice of bytes returning the `Out` type of the `Hasher`.
 	fn hash(x: &[u8]) -> Self::Out;
+
+	/// Compute the hash of the concatenation of `parts` without copying them to a single
+	/// slice, for hashers able to hash data incrementally (see `StreamingHasher`).
+	/// Returns `None` by default, callers then hash the concatenation with `hash`.
+	fn hash_parts(_parts: &[&[u8]]) -> Option<Self::Out> {
+		None
+	}
+}
+
+/// Hashing of values carrying layout metadata, eg a value format version, stored
+/// alongside the value without being part of its encoding.
+pub trait MetaHasher<H: Hasher, T>: Send + Sync {
+	/// Metadata of a value.
+	type Meta;
+
+	/// Hash a value with its metadata. The metadata may or may not be covered by the hash.
+	fn hash(value: &[u8], meta: &Self::Meta) -> H::Out;
+
+	/// Build the value to store for a value with its metadata.
+	fn stored_value(value: &[u8], meta: Self::Meta) -> T;
+
+	/// Split a stored value into the value and its metadata.
+	fn extract_value(stored: &[u8]) -> (&[u8], Self::Meta);
+}
+
+/// `MetaHasher` of values without metadata, storing values as is.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct NoMeta;
+
+impl<H: Hasher, T: for<'a> From<&'a [u8]>> MetaHasher<H, T> for NoMeta {
+	type Meta = ();
+
+	fn hash(value: &[u8], _meta: &()) -> H::Out {
+		H::hash(value)
+	}
+
+	fn stored_value(value: &[u8], _meta: ()) -> T {
+		value.into()
+	}
+
+	fn extract_value(stored: &[u8]) -> (&[u8], ()) {
+		(stored, ())
+	}
+}
+
+/// A `Hasher` able to hash data fed incrementally, so that large inputs do not need
+/// to be buffered in a single slice. Implementations should also implement
+/// `Hasher::hash_parts` with it.
+pub trait StreamingHasher: Hasher {
+	/// State of a hash computation.
+	type Stream: Clone;
+
+	/// Start a hash computation.
+	fn stream() -> Self::Stream;
+
+	/// Feed `data` to a hash computation.
+	fn update(stream: &mut Self::Stream, data: &[u8]);
+
+	/// Complete a hash computation.
+	fn finalize(stream: Self::Stream) -> Self::Out;
+
+	/// Start a hash computation fed through `HashWriter`, which implements
+	/// `std::io::Write` with the `std` feature.
+	fn writer() -> HashWriter<Self> where Self: Sized {
+		HashWriter(Self::stream())
+	}
+}
+
+/// Hash computation of a `StreamingHasher`, see `StreamingHasher::writer`.
+pub struct HashWriter<H: StreamingHasher>(H::Stream);
+
+impl<H: StreamingHasher> HashWriter<H> {
+	/// Feed `data` to the hash computation.
+	pub fn update(&mut self, data: &[u8]) {
+		H::update(&mut self.0, data);
+	}
+
+	/// Complete the hash computation.
+	pub fn finalize(self) -> H::Out {
+		H::finalize(self.0)
+	}
+}
+
+impl<H: StreamingHasher> Clone for HashWriter<H> {
+	fn clone(&self) -> Self {
+		HashWriter(self.0.clone())
+	}
+}
+
+#[cfg(feature = "std")]
+impl<H: StreamingHasher> std::io::Write for HashWriter<H> {
+	fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+		self.update(data);
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
 }
 
 /// Trait modelling a plain datastore whose key is a fixed type.
@@ -124,6 +224,18 @@ pub trait HashDB<H: Hasher, T>: Send + Sync + AsHashDB<H, T> {
 	/// Like `insert()`, except you provide the key and the data is all moved.
 	fn emplace(&mut self, key: H::Out, prefix: Prefix, value: T);
 
+	/// Like `insert()`, except the datum is written by `encode` into a default value,
+	/// which is then moved into the DB, saving the copy of the datum made by `insert()`.
+	fn emplace_with(&mut self, prefix: Prefix, encode: &mut dyn FnMut(&mut T)) -> H::Out
+		where T: Default + AsRef<[u8]>
+	{
+		let mut value = T::default();
+		encode(&mut value);
+		let key = H::hash(value.as_ref());
+		self.emplace(key, prefix, value);
+		key
+	}
+
 	/// Remove a datum previously inserted. Insertions can be "owed" such that the same number of
 	/// `insert()`s may happen without the data being eventually being inserted into the DB.
 	/// It can be "owed" more than once.
@@ -138,6 +250,19 @@ pub trait HashDBRef<H: Hasher, T> {
 
 	/// Check for the existance of a hash-key.
 	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool;
+
+	/// Borrow the value stored for a given hash, avoiding the copy made by `get`.
+	/// Returns None if the hash is not known or if the database cannot lend its values,
+	/// in which case `get` is to be used instead.
+	fn lend(&self, _key: &H::Out, _prefix: Prefix) -> Option<&T> { None }
+
+	/// Look up several hashes at once, setting `values[i]` to the value of `keys[i]`.
+	/// Databases with a per-read latency can override it to issue a single read.
+	fn get_batch(&self, keys: &[(H::Out, Prefix)], values: &mut [Option<T>]) {
+		for ((key, prefix), value) in keys.iter().zip(values.iter_mut()) {
+			*value = self.get(key, *prefix);
+		}
+	}
 }
 
 impl<'a, H: Hasher, T> HashDBRef<H, T> for &'a dyn HashDB<H, T> {
@@ -23,7 +23,7 @@ use core::hash;
 use crunchy::unroll;
 
 /// Hasher that just takes 8 bytes of the provided value.
-/// May only be used for keys which are 32 bytes.
+/// May only be used for keys of at least 32 bytes, only the first 32 are used.
 #[derive(Default)]
 pub struct Hash256StdHasher {
 	prefix: u64,
@@ -40,7 +40,7 @@ impl hash::Hasher for Hash256StdHasher {
 	fn write(&mut self, bytes: &[u8]) {
 		// we get a length written first as 8 bytes (possibly 4 on 32-bit platforms?). this
 		// keeps it safe.
-		debug_assert!(bytes.len() == 4 || bytes.len() == 8 || bytes.len() == 32);
+		debug_assert!(bytes.len() == 4 || bytes.len() == 8 || bytes.len() >= 32);
 		if bytes.len() < 32 { return }
 
 		let mut bytes_ptr = bytes.as_ptr();
@@ -71,4 +71,13 @@ mod tests {
 		hasher.write(&bytes);
 		assert_eq!(hasher.prefix, 47);
 	}
+
+	#[test]
+	fn longer_keys_use_first_32_bytes() {
+		let mut bytes = [32u8; 64];
+		bytes[0] = 15;
+		let mut hasher = Hash256StdHasher::default();
+		hasher.write(&bytes);
+		assert_eq!(hasher.prefix, 47);
+	}
 }
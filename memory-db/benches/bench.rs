@@ -21,7 +21,11 @@ criterion_group!(
 	compare_to_null_embedded_in_struct,
 	compare_to_null_in_const,
 	contains_with_non_null_key,
-	contains_with_null_key
+	contains_with_null_key,
+	insert_and_get_hash_keys,
+	insert_and_get_prefixed_keys,
+	map_hash_keys,
+	map_prefixed_keys
 );
 criterion_main!(benches);
 
@@ -31,7 +35,7 @@ extern crate memory_db;
 
 use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
 use keccak_hasher::KeccakHasher;
-use memory_db::HashKey;
+use memory_db::{HashKey, PrefixedKey, NodeMap, prefixed_key};
 use memory_db::MemoryDB;
 
 fn instantiation(b: &mut Criterion) {
@@ -89,3 +93,77 @@ fn contains_with_null_key(b: &mut Criterion) {
 		})
 	});
 }
+
+fn insert_and_get_hash_keys(b: &mut Criterion) {
+	let values: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_le_bytes().repeat(10)).collect();
+	b.bench_function("insert_and_get_hash_keys", move |b| {
+		b.iter(|| {
+			let mut m = MemoryDB::<KeccakHasher, HashKey<KeccakHasher>, Vec<u8>>::default();
+			let keys: Vec<_> = values.iter().map(|value| m.insert(EMPTY_PREFIX, value)).collect();
+			for key in keys.iter() {
+				black_box(m.get(key, EMPTY_PREFIX));
+			}
+		})
+	});
+}
+
+fn insert_and_get_prefixed_keys(b: &mut Criterion) {
+	let values: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_le_bytes().repeat(10)).collect();
+	b.bench_function("insert_and_get_prefixed_keys", move |b| {
+		b.iter(|| {
+			let mut m = MemoryDB::<KeccakHasher, PrefixedKey<KeccakHasher>, Vec<u8>>::default();
+			let keys: Vec<_> = values.iter()
+				.map(|value| (m.insert((&value[..2], None), value), &value[..2]))
+				.collect();
+			for (key, prefix) in keys.iter() {
+				black_box(m.get(key, (prefix, None)));
+			}
+		})
+	});
+}
+
+/// Insert then get every key in a map with the default hasher, as used by `MemoryDB`
+/// before, and in a `NodeMap`, leaving out the hashing of the node values.
+fn map_insert_and_get<K>(b: &mut Criterion, name: &str, keys: Vec<K>)
+	where K: Clone + Eq + std::hash::Hash + 'static
+{
+	let default_keys = keys.clone();
+	b.bench_function(&format!("{}_default_hasher", name), move |b| {
+		b.iter(|| {
+			let mut map = std::collections::HashMap::<K, u32>::default();
+			for (i, key) in default_keys.iter().enumerate() {
+				map.insert(key.clone(), i as u32);
+			}
+			for key in default_keys.iter() {
+				black_box(map.get(key));
+			}
+		})
+	});
+	b.bench_function(&format!("{}_pass_through_hasher", name), move |b| {
+		b.iter(|| {
+			let mut map = NodeMap::<K, u32>::default();
+			for (i, key) in keys.iter().enumerate() {
+				map.insert(key.clone(), i as u32);
+			}
+			for key in keys.iter() {
+				black_box(map.get(key));
+			}
+		})
+	});
+}
+
+fn map_hash_keys(b: &mut Criterion) {
+	let keys = (0..1000u32).map(|i| KeccakHasher::hash(&i.to_le_bytes())).collect();
+	map_insert_and_get(b, "map_hash_keys", keys);
+}
+
+fn map_prefixed_keys(b: &mut Criterion) {
+	// few distinct nodes under many prefixes, as for repeated subtrees.
+	let keys = (0..1000u32)
+		.map(|i| {
+			let hash = KeccakHasher::hash(&(i % 10).to_le_bytes());
+			prefixed_key::<KeccakHasher>(&hash, (&i.to_be_bytes()[2..], None))
+		})
+		.collect();
+	map_insert_and_get(b, "map_prefixed_keys", keys);
+}
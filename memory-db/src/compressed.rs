@@ -0,0 +1,165 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `MemoryDB` storing its values compressed.
+
+use hash_db::{HashDB, HashDBRef, Hasher as KeyHasher, AsHashDB, Prefix};
+use crate::{KeyFunction, MemoryDB};
+
+/// Values at least this long are stored compressed.
+pub const COMPRESSION_THRESHOLD: usize = 128;
+
+/// Value of a `CompressedMemoryDB`, compressed with snappy when at least
+/// `COMPRESSION_THRESHOLD` bytes long and compressing well.
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub struct CompressedValue {
+	compressed: bool,
+	data: Vec<u8>,
+}
+
+impl CompressedValue {
+	/// Get the original value.
+	pub fn decompress(&self) -> Vec<u8> {
+		if self.compressed {
+			snap::raw::Decoder::new().decompress_vec(&self.data)
+				.expect("data was compressed by `CompressedValue::from`; qed")
+		} else {
+			self.data.clone()
+		}
+	}
+
+	/// Length of the value as stored.
+	pub fn stored_len(&self) -> usize {
+		self.data.len()
+	}
+}
+
+impl<'a> From<&'a [u8]> for CompressedValue {
+	fn from(value: &'a [u8]) -> Self {
+		if value.len() >= COMPRESSION_THRESHOLD {
+			if let Ok(data) = snap::raw::Encoder::new().compress_vec(value) {
+				if data.len() < value.len() {
+					return CompressedValue { compressed: true, data };
+				}
+			}
+		}
+		CompressedValue { compressed: false, data: value.to_vec() }
+	}
+}
+
+/// `HashDB` of `Vec<u8>` values keeping them compressed in a `MemoryDB`, to reduce
+/// the memory used by large in-memory overlays. Values are decompressed when read.
+pub struct CompressedMemoryDB<H, KF>
+	where
+	H: KeyHasher,
+	KF: KeyFunction<H>,
+{
+	db: MemoryDB<H, KF, CompressedValue>,
+}
+
+impl<H, KF> Default for CompressedMemoryDB<H, KF>
+where
+	H: KeyHasher,
+	KF: KeyFunction<H>,
+{
+	fn default() -> Self {
+		CompressedMemoryDB { db: MemoryDB::default() }
+	}
+}
+
+impl<H, KF> CompressedMemoryDB<H, KF>
+where
+	H: KeyHasher,
+	KF: KeyFunction<H>,
+{
+	/// Get the underlying database, holding the compressed values.
+	pub fn inner(&self) -> &MemoryDB<H, KF, CompressedValue> {
+		&self.db
+	}
+
+	/// Get the underlying database mutably.
+	pub fn inner_mut(&mut self) -> &mut MemoryDB<H, KF, CompressedValue> {
+		&mut self.db
+	}
+}
+
+impl<H, KF> HashDB<H, Vec<u8>> for CompressedMemoryDB<H, KF>
+where
+	H: KeyHasher,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<Vec<u8>> {
+		HashDBRef::lend(&self.db, key, prefix).map(CompressedValue::decompress)
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		HashDB::contains(&self.db, key, prefix)
+	}
+
+	fn emplace(&mut self, key: H::Out, prefix: Prefix, value: Vec<u8>) {
+		self.db.emplace(key, prefix, CompressedValue::from(&value[..]))
+	}
+
+	fn insert(&mut self, prefix: Prefix, value: &[u8]) -> H::Out {
+		self.db.insert(prefix, value)
+	}
+
+	fn remove(&mut self, key: &H::Out, prefix: Prefix) {
+		self.db.remove(key, prefix)
+	}
+}
+
+impl<H, KF> HashDBRef<H, Vec<u8>> for CompressedMemoryDB<H, KF>
+where
+	H: KeyHasher,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<Vec<u8>> { HashDB::get(self, key, prefix) }
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool { HashDB::contains(self, key, prefix) }
+}
+
+impl<H, KF> AsHashDB<H, Vec<u8>> for CompressedMemoryDB<H, KF>
+where
+	H: KeyHasher,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	fn as_hash_db(&self) -> &dyn HashDB<H, Vec<u8>> { self }
+	fn as_hash_db_mut(&mut self) -> &mut dyn HashDB<H, Vec<u8>> { self }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CompressedMemoryDB, COMPRESSION_THRESHOLD};
+	use crate::HashKey;
+	use hash_db::{HashDB, Hasher as KeyHasher, EMPTY_PREFIX};
+	use keccak_hasher::KeccakHasher;
+
+	#[test]
+	fn values_round_trip() {
+		let mut db = CompressedMemoryDB::<KeccakHasher, HashKey<_>>::default();
+		let small = b"small".to_vec();
+		let large = vec![7u8; COMPRESSION_THRESHOLD * 4];
+		let small_key = db.insert(EMPTY_PREFIX, &small);
+		let large_key = db.insert(EMPTY_PREFIX, &large);
+		assert_eq!(small_key, KeccakHasher::hash(&small));
+		assert_eq!(db.get(&small_key, EMPTY_PREFIX), Some(small));
+		assert_eq!(db.get(&large_key, EMPTY_PREFIX), Some(large.clone()));
+
+		let stored = db.inner().raw(&large_key, EMPTY_PREFIX).unwrap().0;
+		assert!(stored.stored_len() < large.len());
+
+		db.remove(&large_key, EMPTY_PREFIX);
+		assert!(!db.contains(&large_key, EMPTY_PREFIX));
+	}
+}
@@ -20,7 +20,7 @@
 extern crate alloc;
 
 use hash_db::{HashDB, HashDBRef, PlainDB, PlainDBRef, Hasher as KeyHasher,
-	AsHashDB, AsPlainDB, Prefix};
+	AsHashDB, AsPlainDB, MetaHasher, Prefix, EMPTY_PREFIX};
 use parity_util_mem::{MallocSizeOf, MallocSizeOfOps};
 #[cfg(feature = "deprecated")]
 #[cfg(feature = "std")]
@@ -34,6 +34,7 @@ use std::{
 	marker::PhantomData,
 	cmp::Eq,
 	borrow::Borrow,
+	convert::{TryFrom, TryInto},
 };
 
 #[cfg(not(feature = "std"))]
@@ -49,11 +50,21 @@ use core::{
 	marker::PhantomData,
 	cmp::Eq,
 	borrow::Borrow,
+	convert::{TryFrom, TryInto},
 };
 
 #[cfg(not(feature = "std"))]
 use alloc::vec::Vec;
 
+#[cfg(feature = "compression")]
+mod compressed;
+#[cfg(feature = "compression")]
+pub use compressed::{CompressedMemoryDB, CompressedValue, COMPRESSION_THRESHOLD};
+#[cfg(feature = "std")]
+mod sync;
+#[cfg(feature = "std")]
+pub use sync::SyncMemoryDB;
+
 #[cfg(feature = "std")]
 pub trait MaybeDebug: std::fmt::Debug {}
 #[cfg(feature = "std")]
@@ -63,6 +74,49 @@ pub trait MaybeDebug {}
 #[cfg(not(feature = "std"))]
 impl<T> MaybeDebug for T {}
 
+/// Hasher for the keys of a `MemoryDB`, which all end with a node hash: the last 8 bytes
+/// of the key are already uniformly distributed and used as is instead of being hashed
+/// again. The bytes before, holding the prefix of prefixed keys, are folded in so that
+/// a node stored under many prefixes does not put all its keys in one probe chain.
+#[derive(Default, Clone, Copy)]
+pub struct PassThroughHasher(u64);
+
+/// Odd multiplier spreading the folded bytes over the 64 bits of the state.
+const FOLD_MULTIPLIER: u64 = 0x9e37_79b9_7f4a_7c15;
+
+impl hash::Hasher for PassThroughHasher {
+	#[inline]
+	fn finish(&self) -> u64 {
+		self.0
+	}
+
+	#[inline]
+	fn write(&mut self, bytes: &[u8]) {
+		// keys are hashed as a length followed by the key bytes: the last write, holding
+		// the end of the key, replaces the state unless shorter than 8 bytes.
+		let (head, tail) = bytes.split_at(bytes.len().saturating_sub(8));
+		let mut fold = 0u64;
+		let mut chunks = head.chunks_exact(8);
+		for chunk in &mut chunks {
+			let word = u64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes; qed"));
+			fold = (fold ^ word).wrapping_mul(FOLD_MULTIPLIER);
+		}
+		for byte in chunks.remainder() {
+			fold = (fold ^ *byte as u64).wrapping_mul(FOLD_MULTIPLIER);
+		}
+		match <[u8; 8]>::try_from(tail) {
+			Ok(tail) => self.0 = u64::from_be_bytes(tail),
+			Err(_) => for byte in tail {
+				self.0 = (self.0 << 8) | *byte as u64;
+			},
+		}
+		self.0 ^= fold;
+	}
+}
+
+/// Map from `MemoryDB` keys, using `PassThroughHasher`.
+pub type NodeMap<K, V> = HashMap<K, V, hash::BuildHasherDefault<PassThroughHasher>>;
+
 /// Reference-counted memory-based `HashDB` implementation.
 ///
 /// Use `new()` to create a new database. Insert items with `insert()`, remove items
@@ -112,18 +166,45 @@ pub struct MemoryDB<H, KF, T>
 	H: KeyHasher,
 	KF: KeyFunction<H>,
 {
-	data: HashMap<KF::Key, (T, i32)>,
+	data: NodeMap<KF::Key, (T, i32)>,
 	hashed_null_node: H::Out,
 	null_node_data: T,
+	policy: RemovalPolicy,
+	journal: Vec<KF::Key>,
+	violations: Vec<KF::Key>,
 	_kf: PhantomData<KF>,
 }
 
+/// What a `MemoryDB` does on removal of a key which is not referenced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalPolicy {
+	/// Let the reference count go negative, so that a later insertion cancels the
+	/// removal. This is the default, suited to overlays consolidated into another database.
+	Negative,
+	/// Ignore the removal: reference counts never go below zero.
+	Saturating,
+	/// Ignore the removal and record it as a violation, for databases in which such
+	/// a removal is a bug, see `MemoryDB::take_violations`.
+	Strict,
+	/// Ignore the removal and record the key, see `MemoryDB::drain_journal`.
+	Journal,
+}
+
+impl Default for RemovalPolicy {
+	fn default() -> Self {
+		RemovalPolicy::Negative
+	}
+}
+
 impl<H: KeyHasher, KF: KeyFunction<H>, T: Clone> Clone for MemoryDB<H, KF, T> {
 	fn clone(&self) -> Self {
 		Self {
 			data: self.data.clone(),
 			hashed_null_node: self.hashed_null_node.clone(),
 			null_node_data: self.null_node_data.clone(),
+			policy: self.policy,
+			journal: self.journal.clone(),
+			violations: self.violations.clone(),
 			_kf: Default::default(),
 		}
 	}
@@ -283,18 +364,37 @@ where
 			return None;
 		}
 		let key = KF::key(key, prefix);
-		match self.data.entry(key) {
-			Entry::Occupied(mut entry) =>
-				if entry.get().1 == 1 {
-					Some(entry.remove().0)
-				} else {
+		if self.data.get(&key).map_or(false, |entry| entry.1 == 1) {
+			return self.data.remove(&key).map(|(value, _)| value);
+		}
+		self.dereference(key);
+		None
+	}
+
+	/// Decrement the reference count of `key`, following the removal policy if it is
+	/// not referenced.
+	fn dereference(&mut self, key: KF::Key) {
+		let policy = self.policy;
+		let key = match self.data.entry(key) {
+			Entry::Occupied(mut entry) => {
+				if entry.get().1 > 0 || policy == RemovalPolicy::Negative {
 					entry.get_mut().1 -= 1;
-					None
-				},
+					return;
+				}
+				entry.key().clone()
+			},
 			Entry::Vacant(entry) => {
-				entry.insert((T::default(), -1)); // FIXME: shouldn't it be purged?
-				None
-			}
+				if policy == RemovalPolicy::Negative {
+					entry.insert((T::default(), -1));
+					return;
+				}
+				entry.into_key()
+			},
+		};
+		match policy {
+			RemovalPolicy::Negative | RemovalPolicy::Saturating => (),
+			RemovalPolicy::Strict => self.violations.push(key),
+			RemovalPolicy::Journal => self.journal.push(key),
 		}
 	}
 }
@@ -311,10 +411,34 @@ where
 			data: HashMap::default(),
 			hashed_null_node: H::hash(null_key),
 			null_node_data,
+			policy: RemovalPolicy::default(),
+			journal: Vec::new(),
+			violations: Vec::new(),
 			_kf: Default::default(),
 		}
 	}
 
+	/// Set what to do on removal of a key which is not referenced.
+	pub fn with_removal_policy(mut self, policy: RemovalPolicy) -> Self {
+		self.policy = policy;
+		self
+	}
+
+	/// Get the removal policy.
+	pub fn removal_policy(&self) -> RemovalPolicy { self.policy }
+
+	/// Return the keys removed while not referenced with `RemovalPolicy::Journal`,
+	/// in removal order, clearing the journal.
+	pub fn drain_journal(&mut self) -> Vec<KF::Key> {
+		mem::replace(&mut self.journal, Vec::new())
+	}
+
+	/// Return the keys removed while not referenced with `RemovalPolicy::Strict`,
+	/// in removal order, clearing them.
+	pub fn take_violations(&mut self) -> Vec<KF::Key> {
+		mem::replace(&mut self.violations, Vec::new())
+	}
+
 	/// Create a new instance of `Self`.
 	pub fn new(data: &'a [u8]) -> Self {
 		Self::from_null_node(data, data.into())
@@ -359,7 +483,7 @@ where
 	}
 
 	/// Return the internal map of hashes to data, clearing the current state.
-	pub fn drain(&mut self) -> HashMap<KF::Key, (T, i32)> {
+	pub fn drain(&mut self) -> NodeMap<KF::Key, (T, i32)> {
 		mem::replace(&mut self.data, Default::default())
 	}
 
@@ -393,6 +517,15 @@ where
 		}
 	}
 
+	/// Get the keys whose number of references is not 1, together with that number:
+	/// shared nodes, removed but not purged nodes, and pending removals.
+	pub fn irregular_keys(&self) -> HashMap<KF::Key, i32> {
+		self.data.iter()
+			.filter(|(_, v)| v.1 != 1)
+			.map(|(k, v)| (k.clone(), v.1))
+			.collect()
+	}
+
 	/// Get the keys in the database together with number of underlying references.
 	pub fn keys(&self) -> HashMap<KF::Key, i32> {
 		self.data.iter()
@@ -405,6 +538,54 @@ where
 	}
 }
 
+impl<H, KF, T> MemoryDB<H, KF, T>
+where
+	H: KeyHasher,
+	T: Default + PartialEq<T> + for<'a> From<&'a [u8]> + Clone + Send + Sync,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	/// Insert a value with its metadata, stored and hashed as defined by `M`.
+	pub fn insert_with_meta<M: MetaHasher<H, T>>(
+		&mut self,
+		prefix: Prefix,
+		value: &[u8],
+		meta: M::Meta,
+	) -> H::Out {
+		let key = M::hash(value, &meta);
+		HashDB::emplace(self, key, prefix, M::stored_value(value, meta));
+		key
+	}
+
+	/// Get a value inserted with `insert_with_meta`, along with its metadata.
+	pub fn get_with_meta<M: MetaHasher<H, T>>(
+		&self,
+		key: &H::Out,
+		prefix: Prefix,
+	) -> Option<(Vec<u8>, M::Meta)>
+		where T: AsRef<[u8]>
+	{
+		let stored = HashDBRef::lend(self, key, prefix)?;
+		let (value, meta) = M::extract_value(stored.as_ref());
+		Some((value.to_vec(), meta))
+	}
+
+	/// Create a new `MemoryDB` holding the given encoded nodes, keyed by their hash.
+	///
+	/// Proofs carry no prefix information, so nodes are inserted with an empty
+	/// prefix: this is only meaningful with a key function ignoring the prefix,
+	/// such as `HashKey`.
+	pub fn from_proof<I>(nodes: I) -> Self
+	where
+		I: IntoIterator<Item = Vec<u8>>,
+	{
+		let mut db = Self::default();
+		for node in nodes {
+			HashDB::insert(&mut db, EMPTY_PREFIX, &node[..]);
+		}
+		db
+	}
+}
+
 #[cfg(feature = "deprecated")]
 #[cfg(feature = "std")]
 impl<H, KF, T> MemoryDB<H, KF, T>
@@ -499,15 +680,7 @@ where
 	}
 
 	fn remove(&mut self, key: &H::Out) {
-		match self.data.entry(key.as_ref().into()) {
-			Entry::Occupied(mut entry) => {
-				let &mut (_, ref mut rc) = entry.get_mut();
-				*rc -= 1;
-			},
-			Entry::Vacant(entry) => {
-				entry.insert((T::default(), -1));
-			},
-		}
+		self.dereference(key.as_ref().into());
 	}
 }
 
@@ -587,16 +760,7 @@ where
 			return;
 		}
 
-		let key = KF::key(key, prefix);
-		match self.data.entry(key) {
-			Entry::Occupied(mut entry) => {
-				let &mut (_, ref mut rc) = entry.get_mut();
-				*rc -= 1;
-			},
-			Entry::Vacant(entry) => {
-				entry.insert((T::default(), -1));
-			},
-		}
+		self.dereference(KF::key(key, prefix));
 	}
 }
 
@@ -608,6 +772,18 @@ where
 {
 	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<T> { HashDB::get(self, key, prefix) }
 	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool { HashDB::contains(self, key, prefix) }
+
+	fn lend(&self, key: &H::Out, prefix: Prefix) -> Option<&T> {
+		if key == &self.hashed_null_node {
+			return Some(&self.null_node_data);
+		}
+
+		let key = KF::key(key, prefix);
+		match self.data.get(&key) {
+			Some(&(ref d, rc)) if rc > 0 => Some(d),
+			_ => None
+		}
+	}
 }
 
 impl<H, KF, T> AsPlainDB<H::Out, T> for MemoryDB<H, KF, T>
@@ -637,6 +813,48 @@ mod tests {
 	use hash_db::EMPTY_PREFIX;
 	use keccak_hasher::KeccakHasher;
 
+	#[test]
+	fn emplace_with_matches_insert() {
+		let hello_bytes = b"Hello world!";
+		let mut m = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+		let key = m.emplace_with(EMPTY_PREFIX, &mut |value| value.extend_from_slice(hello_bytes));
+		assert_eq!(key, KeccakHasher::hash(hello_bytes));
+		assert_eq!(m.insert(EMPTY_PREFIX, hello_bytes), key);
+		assert_eq!(m.raw(&key, EMPTY_PREFIX).unwrap(), (&hello_bytes.to_vec(), 2));
+	}
+
+	#[test]
+	fn values_with_meta() {
+		use hash_db::{MetaHasher, NoMeta};
+
+		/// Stores a version byte after the value, not covered by the hash.
+		struct Versioned;
+		impl MetaHasher<KeccakHasher, Vec<u8>> for Versioned {
+			type Meta = u8;
+			fn hash(value: &[u8], _meta: &u8) -> <KeccakHasher as KeyHasher>::Out {
+				KeccakHasher::hash(value)
+			}
+			fn stored_value(value: &[u8], meta: u8) -> Vec<u8> {
+				let mut stored = value.to_vec();
+				stored.push(meta);
+				stored
+			}
+			fn extract_value(stored: &[u8]) -> (&[u8], u8) {
+				let (meta, value) = stored.split_last().expect("version byte was pushed; qed");
+				(value, *meta)
+			}
+		}
+
+		let mut m = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+		let key = m.insert_with_meta::<Versioned>(EMPTY_PREFIX, b"value", 1);
+		assert_eq!(key, KeccakHasher::hash(b"value"));
+		assert_eq!(m.get(&key, EMPTY_PREFIX), Some(b"value\x01".to_vec()));
+		assert_eq!(m.get_with_meta::<Versioned>(&key, EMPTY_PREFIX), Some((b"value".to_vec(), 1)));
+
+		let key = m.insert_with_meta::<NoMeta>(EMPTY_PREFIX, b"plain", ());
+		assert_eq!(m.get_with_meta::<NoMeta>(&key, EMPTY_PREFIX), Some((b"plain".to_vec(), ())));
+	}
+
 	#[test]
 	fn memorydb_remove_and_purge() {
 		let hello_bytes = b"Hello world!";
@@ -663,6 +881,93 @@ mod tests {
 		assert!(m.remove_and_purge(&hello_key, EMPTY_PREFIX).is_none());
 	}
 
+	#[test]
+	fn removal_policies() {
+		use super::RemovalPolicy;
+
+		let hello_bytes = b"Hello world!";
+		let hello_key = KeccakHasher::hash(hello_bytes);
+
+		let mut m = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default()
+			.with_removal_policy(RemovalPolicy::Saturating);
+		m.remove(&hello_key, EMPTY_PREFIX);
+		assert_eq!(m.raw(&hello_key, EMPTY_PREFIX), None);
+		m.insert(EMPTY_PREFIX, hello_bytes);
+		m.remove(&hello_key, EMPTY_PREFIX);
+		m.remove(&hello_key, EMPTY_PREFIX);
+		assert_eq!(m.raw(&hello_key, EMPTY_PREFIX).unwrap().1, 0);
+		m.insert(EMPTY_PREFIX, hello_bytes);
+		assert!(m.contains(&hello_key, EMPTY_PREFIX));
+
+		let mut m = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default()
+			.with_removal_policy(RemovalPolicy::Journal);
+		m.insert(EMPTY_PREFIX, hello_bytes);
+		m.remove(&hello_key, EMPTY_PREFIX);
+		m.remove(&hello_key, EMPTY_PREFIX);
+		assert!(m.remove_and_purge(&hello_key, EMPTY_PREFIX).is_none());
+		assert_eq!(m.drain_journal(), vec![hello_key, hello_key]);
+		assert!(m.drain_journal().is_empty());
+	}
+
+	#[test]
+	fn strict_removal_policy() {
+		use super::RemovalPolicy;
+
+		let mut m = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default()
+			.with_removal_policy(RemovalPolicy::Strict);
+		let hello_key = m.insert(EMPTY_PREFIX, b"Hello world!");
+		let missing_key = KeccakHasher::hash(b"missing");
+		m.remove(&hello_key, EMPTY_PREFIX);
+		m.remove(&hello_key, EMPTY_PREFIX);
+		m.remove(&missing_key, EMPTY_PREFIX);
+		assert!(!m.contains(&hello_key, EMPTY_PREFIX));
+		assert_eq!(m.take_violations(), vec![hello_key, missing_key]);
+		assert!(m.take_violations().is_empty());
+
+		// removals were ignored: a single insertion makes the value available.
+		m.insert(EMPTY_PREFIX, b"Hello world!");
+		assert!(m.contains(&hello_key, EMPTY_PREFIX));
+	}
+
+	#[test]
+	fn irregular_keys() {
+		let mut m = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+		let shared = m.insert(EMPTY_PREFIX, b"shared");
+		m.insert(EMPTY_PREFIX, b"shared");
+		m.insert(EMPTY_PREFIX, b"single");
+		let removed = m.insert(EMPTY_PREFIX, b"removed");
+		m.remove(&removed, EMPTY_PREFIX);
+		let pending = KeccakHasher::hash(b"pending");
+		m.remove(&pending, EMPTY_PREFIX);
+
+		let irregular = m.irregular_keys();
+		assert_eq!(irregular.len(), 3);
+		assert_eq!(irregular[&shared], 2);
+		assert_eq!(irregular[&removed], 0);
+		assert_eq!(irregular[&pending], -1);
+	}
+
+	#[test]
+	fn pass_through_hasher_mixes_prefix() {
+		use std::collections::HashSet;
+		use std::hash::{Hash, Hasher};
+		use super::{PassThroughHasher, prefixed_key};
+
+		let hash_of = |key: &[u8]| {
+			let mut hasher = PassThroughHasher::default();
+			key.hash(&mut hasher);
+			hasher.finish()
+		};
+		// the same node under many prefixes, as for repeated subtrees.
+		let key = KeccakHasher::hash(b"doggo");
+		let hashes: HashSet<_> = (0..=255u8)
+			.flat_map(|a| (0..16u8).map(move |b| (a, b)))
+			.map(|(a, b)| prefixed_key::<KeccakHasher>(&key, (&[a][..], Some(b << 4))))
+			.map(|prefixed| hash_of(&prefixed[..]))
+			.collect();
+		assert_eq!(hashes.len(), 256 * 16);
+	}
+
 	#[test]
 	fn consolidate() {
 		let mut main = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
@@ -688,6 +993,17 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn from_proof_works() {
+		let nodes = vec![b"doggo".to_vec(), b"arf".to_vec(), b"doggo".to_vec()];
+		let db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::from_proof(nodes);
+		let doggo_key = KeccakHasher::hash(b"doggo");
+		let arf_key = KeccakHasher::hash(b"arf");
+		assert_eq!(db.raw(&doggo_key, EMPTY_PREFIX).unwrap(), (&b"doggo".to_vec(), 2));
+		assert_eq!(db.get(&arf_key, EMPTY_PREFIX).unwrap(), b"arf".to_vec());
+		assert!(!db.contains(&KeccakHasher::hash(b"miaow"), EMPTY_PREFIX));
+	}
+
 	#[test]
 	fn default_works() {
 		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
@@ -0,0 +1,249 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reference-counted memory-based `HashDB` shared between threads.
+
+use hash_db::{HashDB, HashDBRef, Hasher as KeyHasher, AsHashDB, Prefix};
+use std::collections::hash_map::Entry;
+use std::marker::PhantomData;
+use std::sync::RwLock;
+use crate::{KeyFunction, MemoryDB, NodeMap};
+
+/// Default number of shards of a `SyncMemoryDB`.
+const DEFAULT_SHARDS: usize = 16;
+
+/// Reference-counted memory-based `HashDB` whose methods take `&self`, to be shared
+/// between threads. Entries are spread over several maps, each behind its own lock,
+/// depending on the first byte of their hash, so that concurrent accesses to different
+/// shards do not wait on each other.
+///
+/// Reference counts behave as in `MemoryDB`, with the default `RemovalPolicy`.
+pub struct SyncMemoryDB<H, KF, T>
+	where
+	H: KeyHasher,
+	KF: KeyFunction<H>,
+{
+	shards: Vec<RwLock<NodeMap<KF::Key, (T, i32)>>>,
+	hashed_null_node: H::Out,
+	null_node_data: T,
+	_kf: PhantomData<KF>,
+}
+
+impl<'a, H, KF, T> Default for SyncMemoryDB<H, KF, T>
+where
+	H: KeyHasher,
+	T: From<&'a [u8]>,
+	KF: KeyFunction<H>,
+{
+	fn default() -> Self {
+		Self::with_shards(DEFAULT_SHARDS)
+	}
+}
+
+impl<'a, H, KF, T> SyncMemoryDB<H, KF, T>
+where
+	H: KeyHasher,
+	T: From<&'a [u8]>,
+	KF: KeyFunction<H>,
+{
+	/// Create an empty database with `shards` shards, at least one.
+	pub fn with_shards(shards: usize) -> Self {
+		SyncMemoryDB {
+			shards: (0..shards.max(1)).map(|_| RwLock::new(NodeMap::default())).collect(),
+			hashed_null_node: H::hash(&[0u8][..]),
+			null_node_data: [0u8][..].into(),
+			_kf: PhantomData,
+		}
+	}
+}
+
+impl<H, KF, T> SyncMemoryDB<H, KF, T>
+where
+	H: KeyHasher,
+	T: Default + PartialEq<T> + for<'a> From<&'a [u8]> + Clone + Send + Sync,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	fn shard(&self, key: &H::Out) -> &RwLock<NodeMap<KF::Key, (T, i32)>> {
+		let first = key.as_ref().first().cloned().unwrap_or(0) as usize;
+		&self.shards[first % self.shards.len()]
+	}
+
+	/// Look up the value stored under `key`, see `HashDB::get`.
+	pub fn get(&self, key: &H::Out, prefix: Prefix) -> Option<T> {
+		if key == &self.hashed_null_node {
+			return Some(self.null_node_data.clone());
+		}
+		let shard = self.shard(key).read().expect("lock is not poisoned; qed");
+		match shard.get(&KF::key(key, prefix)) {
+			Some(&(ref d, rc)) if rc > 0 => Some(d.clone()),
+			_ => None,
+		}
+	}
+
+	/// Check for the existence of `key`, see `HashDB::contains`.
+	pub fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		if key == &self.hashed_null_node {
+			return true;
+		}
+		let shard = self.shard(key).read().expect("lock is not poisoned; qed");
+		match shard.get(&KF::key(key, prefix)) {
+			Some(&(_, rc)) => rc > 0,
+			None => false,
+		}
+	}
+
+	/// Store `value` under `key`, see `HashDB::emplace`.
+	pub fn emplace(&self, key: H::Out, prefix: Prefix, value: T) {
+		if value == self.null_node_data {
+			return;
+		}
+		let mut shard = self.shard(&key).write().expect("lock is not poisoned; qed");
+		match shard.entry(KF::key(&key, prefix)) {
+			Entry::Occupied(mut entry) => {
+				let &mut (ref mut old_value, ref mut rc) = entry.get_mut();
+				if *rc <= 0 {
+					*old_value = value;
+				}
+				*rc += 1;
+			},
+			Entry::Vacant(entry) => {
+				entry.insert((value, 1));
+			},
+		}
+	}
+
+	/// Store `value` under its hash, see `HashDB::insert`.
+	pub fn insert(&self, prefix: Prefix, value: &[u8]) -> H::Out {
+		if T::from(value) == self.null_node_data {
+			return self.hashed_null_node.clone();
+		}
+		let key = H::hash(value);
+		self.emplace(key, prefix, value.into());
+		key
+	}
+
+	/// Remove a reference to `key`, see `HashDB::remove`.
+	pub fn remove(&self, key: &H::Out, prefix: Prefix) {
+		if key == &self.hashed_null_node {
+			return;
+		}
+		let mut shard = self.shard(key).write().expect("lock is not poisoned; qed");
+		match shard.entry(KF::key(key, prefix)) {
+			Entry::Occupied(mut entry) => {
+				entry.get_mut().1 -= 1;
+			},
+			Entry::Vacant(entry) => {
+				entry.insert((T::default(), -1));
+			},
+		}
+	}
+
+	/// Merge the shards into a `MemoryDB`.
+	pub fn into_memory_db(self) -> MemoryDB<H, KF, T> {
+		let mut db = MemoryDB::from_null_node(&[0u8][..], self.null_node_data);
+		for shard in self.shards {
+			db.data.extend(shard.into_inner().expect("lock is not poisoned; qed"));
+		}
+		db
+	}
+}
+
+impl<H, KF, T> HashDB<H, T> for SyncMemoryDB<H, KF, T>
+where
+	H: KeyHasher,
+	T: Default + PartialEq<T> + for<'a> From<&'a [u8]> + Clone + Send + Sync,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<T> { SyncMemoryDB::get(self, key, prefix) }
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		SyncMemoryDB::contains(self, key, prefix)
+	}
+
+	fn emplace(&mut self, key: H::Out, prefix: Prefix, value: T) {
+		SyncMemoryDB::emplace(self, key, prefix, value)
+	}
+
+	fn insert(&mut self, prefix: Prefix, value: &[u8]) -> H::Out {
+		SyncMemoryDB::insert(self, prefix, value)
+	}
+
+	fn remove(&mut self, key: &H::Out, prefix: Prefix) { SyncMemoryDB::remove(self, key, prefix) }
+}
+
+impl<H, KF, T> HashDBRef<H, T> for SyncMemoryDB<H, KF, T>
+where
+	H: KeyHasher,
+	T: Default + PartialEq<T> + for<'a> From<&'a [u8]> + Clone + Send + Sync,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<T> { SyncMemoryDB::get(self, key, prefix) }
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		SyncMemoryDB::contains(self, key, prefix)
+	}
+}
+
+impl<H, KF, T> AsHashDB<H, T> for SyncMemoryDB<H, KF, T>
+where
+	H: KeyHasher,
+	T: Default + PartialEq<T> + for<'a> From<&'a [u8]> + Clone + Send + Sync,
+	KF: Send + Sync + KeyFunction<H>,
+{
+	fn as_hash_db(&self) -> &dyn HashDB<H, T> { self }
+	fn as_hash_db_mut(&mut self) -> &mut dyn HashDB<H, T> { self }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::SyncMemoryDB;
+	use crate::{HashKey, MemoryDB};
+	use hash_db::{HashDB, Hasher as KeyHasher, EMPTY_PREFIX};
+	use keccak_hasher::KeccakHasher;
+	use std::sync::Arc;
+	use std::thread;
+
+	#[test]
+	fn concurrent_inserts() {
+		let db = Arc::new(SyncMemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default());
+		let threads: Vec<_> = (0..4u8).map(|t| {
+			let db = db.clone();
+			thread::spawn(move || {
+				for i in 0..100u8 {
+					db.insert(EMPTY_PREFIX, &[t, i]);
+					// every thread also inserts the shared values.
+					db.insert(EMPTY_PREFIX, &[i]);
+				}
+			})
+		}).collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		let db = Arc::try_unwrap(db).ok().unwrap();
+		assert!(db.contains(&KeccakHasher::hash(&[3, 99]), EMPTY_PREFIX));
+		db.remove(&KeccakHasher::hash(&[3, 99]), EMPTY_PREFIX);
+		assert!(!db.contains(&KeccakHasher::hash(&[3, 99]), EMPTY_PREFIX));
+
+		let mut expected = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+		for t in 0..4u8 {
+			for i in 0..100u8 {
+				expected.insert(EMPTY_PREFIX, &[t, i]);
+				expected.insert(EMPTY_PREFIX, &[i]);
+			}
+		}
+		expected.remove(&KeccakHasher::hash(&[3, 99]), EMPTY_PREFIX);
+		assert_eq!(db.into_memory_db().keys(), expected.keys());
+	}
+}
@@ -0,0 +1,92 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hasher implementation for the Blake3 hash
+
+use hash_db::{Hasher, StreamingHasher};
+use hash256_std_hasher::Hash256StdHasher;
+
+/// Concrete `Hasher` impl for the Blake3 hash
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Blake3Hasher;
+impl Hasher for Blake3Hasher {
+	type Out = [u8; 32];
+
+	type StdHasher = Hash256StdHasher;
+
+	const LENGTH: usize = 32;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		*blake3::hash(x).as_bytes()
+	}
+
+	fn hash_parts(parts: &[&[u8]]) -> Option<Self::Out> {
+		let mut writer = Self::writer();
+		for part in parts {
+			writer.update(part);
+		}
+		Some(writer.finalize())
+	}
+}
+
+impl StreamingHasher for Blake3Hasher {
+	type Stream = blake3::Hasher;
+
+	fn stream() -> blake3::Hasher {
+		blake3::Hasher::new()
+	}
+
+	fn update(stream: &mut blake3::Hasher, data: &[u8]) {
+		stream.update(data);
+	}
+
+	fn finalize(stream: blake3::Hasher) -> Self::Out {
+		*stream.finalize().as_bytes()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+	use hex_literal::hex;
+
+	#[test]
+	fn test_vectors() {
+		assert_eq!(
+			Blake3Hasher::hash(b""),
+			hex!("af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+		);
+		assert_eq!(
+			Blake3Hasher::hash(b"abc"),
+			hex!("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+		);
+	}
+
+	#[test]
+	fn streaming_hash_matches_hash() {
+		let data = vec![7u8; 10_000];
+		let mut stream = Blake3Hasher::stream();
+		for chunk in data.chunks(999) {
+			Blake3Hasher::update(&mut stream, chunk);
+		}
+		assert_eq!(Blake3Hasher::finalize(stream), Blake3Hasher::hash(&data));
+		assert_eq!(Blake3Hasher::hash_parts(&[&data[..3], &data[3..]]), Some(Blake3Hasher::hash(&data)));
+
+		let mut writer = Blake3Hasher::writer();
+		std::io::copy(&mut &data[..], &mut writer).unwrap();
+		write!(writer, "{}", 42).unwrap();
+		assert_eq!(writer.finalize(), Blake3Hasher::hash(&[&data[..], b"42"].concat()));
+	}
+}
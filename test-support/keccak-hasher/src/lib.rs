@@ -14,7 +14,7 @@
 
 //! Hasher implementation for the Keccak-256 hash
 
-use hash_db::Hasher;
+use hash_db::{Hasher, StreamingHasher};
 use tiny_keccak::Keccak;
 use hash256_std_hasher::Hash256StdHasher;
 
@@ -33,11 +33,78 @@ impl Hasher for KeccakHasher {
 		Keccak::keccak256(x, &mut out);
 		out
 	}
+
+	fn hash_parts(parts: &[&[u8]]) -> Option<Self::Out> {
+		let mut writer = Self::writer();
+		for part in parts {
+			writer.update(part);
+		}
+		Some(writer.finalize())
+	}
+}
+
+impl StreamingHasher for KeccakHasher {
+	type Stream = Keccak;
+
+	fn stream() -> Keccak {
+		Keccak::new_keccak256()
+	}
+
+	fn update(stream: &mut Keccak, data: &[u8]) {
+		stream.update(data);
+	}
+
+	fn finalize(stream: Keccak) -> Self::Out {
+		let mut out = [0u8; 32];
+		stream.finalize(&mut out);
+		out
+	}
+}
+
+/// 64 bytes hash, output of `Keccak512Hasher`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct H512(pub [u8; 64]);
+
+impl Default for H512 {
+	fn default() -> Self {
+		H512([0u8; 64])
+	}
+}
+
+impl AsRef<[u8]> for H512 {
+	fn as_ref(&self) -> &[u8] {
+		&self.0[..]
+	}
+}
+
+impl AsMut<[u8]> for H512 {
+	fn as_mut(&mut self) -> &mut [u8] {
+		&mut self.0[..]
+	}
+}
+
+/// Concrete `Hasher` impl for the Keccak-512 hash, with outputs longer than
+/// the usual 32 bytes.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Keccak512Hasher;
+impl Hasher for Keccak512Hasher {
+	type Out = H512;
+
+	type StdHasher = Hash256StdHasher;
+
+	const LENGTH: usize = 64;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		let mut out = H512::default();
+		Keccak::keccak512(x, &mut out.0);
+		out
+	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::io::Write;
 	use std::collections::HashMap;
 
 	#[test]
@@ -53,4 +120,33 @@ mod tests {
 		h.insert(hello_key, hello_bytes.to_vec());
 		h.remove(&hello_key);
 	}
+
+	#[test]
+	fn streaming_hash_matches_hash() {
+		let data = vec![7u8; 10_000];
+		let mut stream = KeccakHasher::stream();
+		for chunk in data.chunks(999) {
+			KeccakHasher::update(&mut stream, chunk);
+		}
+		assert_eq!(KeccakHasher::finalize(stream), KeccakHasher::hash(&data));
+		assert_eq!(KeccakHasher::hash_parts(&[&data[..3], &data[3..]]), Some(KeccakHasher::hash(&data)));
+
+		let mut writer = KeccakHasher::writer();
+		std::io::copy(&mut &data[..], &mut writer).unwrap();
+		write!(writer, "{}", 42).unwrap();
+		assert_eq!(writer.finalize(), KeccakHasher::hash(&[&data[..], b"42"].concat()));
+		assert_eq!(KeccakHasher::hash_parts(&[]), Some(KeccakHasher::hash(&[])));
+	}
+
+	#[test]
+	fn keccak512_hasher_works() {
+		let hello_key = Keccak512Hasher::hash(b"Hello world!");
+		assert_ne!(hello_key, Keccak512Hasher::hash(b"Hello world?"));
+		assert_eq!(&KeccakHasher::hash(b"")[..4], &[0xc5, 0xd2, 0x46, 0x01]);
+		assert_eq!(&Keccak512Hasher::hash(b"").0[..4], &[0x0e, 0xab, 0x42, 0xde]);
+
+		let mut h: HashMap<H512, Vec<u8>, std::hash::BuildHasherDefault<Hash256StdHasher>> = Default::default();
+		h.insert(hello_key, b"Hello world!".to_vec());
+		assert_eq!(h.remove(&hello_key), Some(b"Hello world!".to_vec()));
+	}
 }
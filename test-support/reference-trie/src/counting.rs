@@ -0,0 +1,347 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Trie layout whose node hashes count the values below the node.
+//!
+//! `CountingHasher` appends to the Keccak hash of a node the number of values in the
+//! subtrie it roots, summing the counts held by the hashes of its children. Branch nodes
+//! thus commit to the number of values below each of their children without any codec
+//! change, `TrieDBMut` and `trie_visit` maintaining the counts as they hash nodes. The root
+//! hash gives the number of values of the trie, and `nth_key` and `rank` only read the
+//! nodes on a single path.
+
+use std::fmt;
+use std::convert::TryInto;
+use hash_db::{HashDBRef, Hasher};
+use hash256_std_hasher::Hash256StdHasher;
+use keccak_hasher::KeccakHasher;
+use trie_db::{
+	node::{Node, NodeHandle},
+	DBValue, NibbleSlice, NibbleVec, NodeCodec, TrieError,
+};
+use super::{ReferenceError, ReferenceNodeCodec};
+
+const KECCAK_LENGTH: usize = 32;
+const COUNTED_HASH_LENGTH: usize = KECCAK_LENGTH + 8;
+
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes, whose node hashes count the values below them.
+	pub struct CountingLayout {
+		hash: CountingHasher,
+		codec: ReferenceNodeCodec<CountingHasher>,
+		use_extension: true,
+		value_count: |root: &CountedHash| Some(root.count() as usize),
+	}
+	aliases { trie_db: CountingTrieDB, trie_db_mut: CountingTrieDBMut, lookup: CountingLookup }
+}
+
+/// Keccak hash of a node followed by the number of values below it, big endian.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CountedHash([u8; COUNTED_HASH_LENGTH]);
+
+impl CountedHash {
+	/// Number of values in the subtrie rooted by the hashed node.
+	pub fn count(&self) -> u64 {
+		count_from_hash(&self.0)
+	}
+}
+
+impl Default for CountedHash {
+	fn default() -> Self {
+		CountedHash([0; COUNTED_HASH_LENGTH])
+	}
+}
+
+impl fmt::Debug for CountedHash {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "CountedHash({:?}, {})", &self.0[..KECCAK_LENGTH], self.count())
+	}
+}
+
+impl AsRef<[u8]> for CountedHash {
+	fn as_ref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl AsMut<[u8]> for CountedHash {
+	fn as_mut(&mut self) -> &mut [u8] {
+		&mut self.0
+	}
+}
+
+/// Keccak hasher appending to the hash of a trie node the number of values below it.
+/// Data that is not a `CountingLayout` node counts no values.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CountingHasher;
+
+impl Hasher for CountingHasher {
+	type Out = CountedHash;
+
+	type StdHasher = Hash256StdHasher;
+
+	const LENGTH: usize = COUNTED_HASH_LENGTH;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		let mut out = CountedHash::default();
+		out.0[..KECCAK_LENGTH].copy_from_slice(&KeccakHasher::hash(x));
+		out.0[KECCAK_LENGTH..].copy_from_slice(&node_count(x).to_be_bytes());
+		out
+	}
+}
+
+type CountingCodec = ReferenceNodeCodec<CountingHasher>;
+
+const NO_NIBBLED_BRANCH: &str = "`ReferenceNodeCodec` decodes no nibbled branch; qed";
+
+fn count_from_hash(hash: &[u8]) -> u64 {
+	hash.get(KECCAK_LENGTH..)
+		.and_then(|count| count.try_into().ok())
+		.map_or(0, u64::from_be_bytes)
+}
+
+fn node_count(data: &[u8]) -> u64 {
+	match CountingCodec::decode(data) {
+		Ok(Node::Empty) | Err(_) => 0,
+		Ok(Node::Leaf(..)) => 1,
+		Ok(Node::Extension(_, child)) => child_count(&child),
+		Ok(Node::Branch(children, value)) | Ok(Node::NibbledBranch(_, children, value)) =>
+			value.map_or(0, |_| 1) + children.iter().flatten().map(child_count).sum::<u64>(),
+	}
+}
+
+fn child_count(child: &NodeHandle) -> u64 {
+	match child {
+		NodeHandle::Hash(hash) => count_from_hash(hash),
+		NodeHandle::Inline(data) => node_count(data),
+	}
+}
+
+fn child_hash(child: &[u8]) -> CountedHash {
+	let mut hash = CountedHash::default();
+	hash.0.copy_from_slice(child);
+	hash
+}
+
+fn decode_node(hash: CountedHash, data: &[u8]) -> trie_db::Result<Node<'_>, CountedHash, ReferenceError> {
+	CountingCodec::decode(data).map_err(|e| Box::new(TrieError::DecoderError(hash, e)))
+}
+
+fn fetch_node(
+	db: &dyn HashDBRef<CountingHasher, DBValue>,
+	hash: CountedHash,
+	prefix: hash_db::Prefix,
+	root: &CountedHash,
+) -> trie_db::Result<DBValue, CountedHash, ReferenceError> {
+	db.get(&hash, prefix).ok_or_else(|| Box::new(match hash == *root {
+		true => TrieError::InvalidStateRoot(hash),
+		false => TrieError::IncompleteDatabase(hash),
+	}))
+}
+
+/// Get the `n`th key, in key order, of the trie with the given root, reading only the
+/// nodes on its path. Returns `None` if the trie has `n` values or fewer.
+pub fn nth_key(
+	db: &dyn HashDBRef<CountingHasher, DBValue>,
+	root: &CountedHash,
+	mut n: u64,
+) -> trie_db::Result<Option<Vec<u8>>, CountedHash, ReferenceError> {
+	if n >= root.count() {
+		return Ok(None);
+	}
+	let mut path = NibbleVec::new();
+	let mut hash = *root;
+	loop {
+		let data = fetch_node(db, hash, path.as_prefix(), root)?;
+		let mut node = decode_node(hash, &data)?;
+		// walk down the node and its inline children, up to a hashed child.
+		loop {
+			let child = match node {
+				Node::Empty => return Ok(None),
+				Node::Leaf(partial, _) => {
+					path.append_partial(partial.right());
+					return Ok(Some(path.inner().to_vec()));
+				},
+				Node::Extension(partial, child) => {
+					path.append_partial(partial.right());
+					child
+				},
+				Node::Branch(children, value) => {
+					if value.is_some() {
+						if n == 0 {
+							return Ok(Some(path.inner().to_vec()));
+						}
+						n -= 1;
+					}
+					let mut next = None;
+					for (index, child) in children.iter().enumerate() {
+						if let Some(child) = child {
+							let count = child_count(child);
+							if n < count {
+								path.push(index as u8);
+								next = Some(*child);
+								break;
+							}
+							n -= count;
+						}
+					}
+					match next {
+						Some(child) => child,
+						None => return Ok(None),
+					}
+				},
+				Node::NibbledBranch(..) => unreachable!("{}", NO_NIBBLED_BRANCH),
+			};
+			match child {
+				NodeHandle::Hash(child) => {
+					hash = child_hash(child);
+					break;
+				},
+				NodeHandle::Inline(child) => node = decode_node(hash, child)?,
+			}
+		}
+	}
+}
+
+/// Get the number of keys lower than `key` in the trie with the given root, reading only
+/// the nodes on the path of `key`.
+pub fn rank(
+	db: &dyn HashDBRef<CountingHasher, DBValue>,
+	root: &CountedHash,
+	key: &[u8],
+) -> trie_db::Result<u64, CountedHash, ReferenceError> {
+	let mut key = NibbleSlice::new(key);
+	let mut rank = 0;
+	let mut hash = *root;
+	loop {
+		let data = fetch_node(db, hash, key.left(), root)?;
+		let mut node = decode_node(hash, &data)?;
+		loop {
+			let child = match node {
+				Node::Empty => return Ok(rank),
+				Node::Leaf(partial, _) => {
+					return Ok(rank + if partial < key { 1 } else { 0 });
+				},
+				Node::Extension(partial, child) => {
+					if !key.starts_with(&partial) {
+						return Ok(rank + if partial < key { child_count(&child) } else { 0 });
+					}
+					key.advance(partial.len());
+					child
+				},
+				Node::Branch(children, value) => {
+					if key.is_empty() {
+						return Ok(rank);
+					}
+					if value.is_some() {
+						rank += 1;
+					}
+					let index = key.at(0) as usize;
+					rank += children[..index].iter().flatten().map(child_count).sum::<u64>();
+					key.advance(1);
+					match children[index] {
+						Some(child) => child,
+						None => return Ok(rank),
+					}
+				},
+				Node::NibbledBranch(..) => unreachable!("{}", NO_NIBBLED_BRANCH),
+			};
+			match child {
+				NodeHandle::Hash(child) => {
+					hash = child_hash(child);
+					break;
+				},
+				NodeHandle::Inline(child) => node = decode_node(hash, child)?,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory_db::{MemoryDB, PrefixedKey};
+	use trie_db::{Trie, TrieConfiguration, TrieMut};
+
+	fn dataset() -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut entries: Vec<_> = (0..150u16)
+			.map(|i| ((i * 7).to_be_bytes().to_vec(), vec![i as u8; i as usize % 50 + 1]))
+			.collect();
+		// values at branches, and inline nodes.
+		entries.push((vec![0], vec![1]));
+		entries.push((vec![0, 7, 1], vec![2]));
+		entries.sort();
+		entries
+	}
+
+	#[test]
+	fn root_counts_values() {
+		let entries = dataset();
+		let mut db = MemoryDB::<CountingHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = CountingTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in &entries {
+				t.insert(key, value).unwrap();
+			}
+		}
+		assert_eq!(root.count(), entries.len() as u64);
+		assert_eq!(CountingLayout::trie_root(entries.clone()).unwrap(), root);
+
+		let mut t = CountingTrieDBMut::from_existing(&mut db, &mut root).unwrap();
+		for (key, _) in entries.iter().step_by(3) {
+			t.remove(key).unwrap();
+		}
+		let remaining = entries.len() - entries.iter().step_by(3).count();
+		assert_eq!(t.root().count(), remaining as u64);
+	}
+
+	#[test]
+	fn len_reads_count_from_root() {
+		let entries = dataset();
+		let mut db = MemoryDB::<CountingHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = CountingTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in &entries {
+				t.insert(key, value).unwrap();
+			}
+		}
+		assert_eq!(CountingTrieDB::new(&db, &root).unwrap().len().unwrap(), entries.len());
+		// no node is read: the count comes from the root hash.
+		let empty = MemoryDB::<CountingHasher, PrefixedKey<_>, DBValue>::default();
+		assert_eq!(CountingTrieDB::new_unchecked(&empty, &root).len().unwrap(), entries.len());
+	}
+
+	#[test]
+	fn nth_key_and_rank() {
+		let entries = dataset();
+		let mut db = MemoryDB::<CountingHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = CountingTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in &entries {
+				t.insert(key, value).unwrap();
+			}
+		}
+		for (i, (key, _)) in entries.iter().enumerate() {
+			assert_eq!(nth_key(&db, &root, i as u64).unwrap(), Some(key.clone()));
+			assert_eq!(rank(&db, &root, key).unwrap(), i as u64);
+		}
+		assert_eq!(nth_key(&db, &root, entries.len() as u64).unwrap(), None);
+		assert_eq!(rank(&db, &root, &[]).unwrap(), 0);
+		assert_eq!(rank(&db, &root, &[0, 8]).unwrap(), 4);
+		assert_eq!(rank(&db, &root, &[0xff; 3]).unwrap(), entries.len() as u64);
+	}
+}
@@ -0,0 +1,227 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hasher whose hash function is chosen at runtime.
+//!
+//! `Hasher::hash` takes no `self`, so the hash function of `DynHasher` is the one selected
+//! for the current thread with `DynHasher::with`, see `DynHasher`.
+
+use std::cell::Cell;
+use hash_db::Hasher;
+use hash256_std_hasher::Hash256StdHasher;
+use keccak_hasher::KeccakHasher;
+#[cfg(feature = "sha2")]
+use sha2_hasher::Sha256Hasher;
+#[cfg(feature = "blake3")]
+use blake3_hasher::Blake3Hasher;
+use super::{GenericNoExtensionLayout, ReferenceNodeCodec};
+
+thread_local! {
+	static SELECTED: Cell<Option<DynHasher>> = Cell::new(None);
+}
+
+/// Hash functions with a 32 byte output, selectable at runtime.
+///
+/// As a `Hasher`, `DynHasher` hashes with the function selected for the current thread by
+/// `DynHasher::with`, and panics if none is. The selection is per thread and is not
+/// recorded in the trie or the database: a trie must be read on every thread with the
+/// hash function it was built with, or its nodes are not found and roots do not match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynHasher {
+	/// Keccak-256.
+	Keccak,
+	/// Blake2b with a 32 byte output.
+	#[cfg(feature = "blake2")]
+	Blake2,
+	/// SHA2-256.
+	#[cfg(feature = "sha2")]
+	Sha2,
+	/// Blake3.
+	#[cfg(feature = "blake3")]
+	Blake3,
+}
+
+impl DynHasher {
+	/// All available hash functions.
+	pub const ALL: &'static [DynHasher] = &[
+		DynHasher::Keccak,
+		#[cfg(feature = "blake2")]
+		DynHasher::Blake2,
+		#[cfg(feature = "sha2")]
+		DynHasher::Sha2,
+		#[cfg(feature = "blake3")]
+		DynHasher::Blake3,
+	];
+
+	/// Name of the hash function, as parsed by `from_str`.
+	pub fn name(&self) -> &'static str {
+		match self {
+			DynHasher::Keccak => "keccak",
+			#[cfg(feature = "blake2")]
+			DynHasher::Blake2 => "blake2",
+			#[cfg(feature = "sha2")]
+			DynHasher::Sha2 => "sha2",
+			#[cfg(feature = "blake3")]
+			DynHasher::Blake3 => "blake3",
+		}
+	}
+
+	/// Hash `x` with this hash function, whatever the selected one.
+	pub fn hash_with(self, x: &[u8]) -> [u8; 32] {
+		match self {
+			DynHasher::Keccak => KeccakHasher::hash(x),
+			#[cfg(feature = "blake2")]
+			DynHasher::Blake2 => {
+				let mut out = [0u8; 32];
+				out.copy_from_slice(blake2_rfc::blake2b::blake2b(32, &[], x).as_bytes());
+				out
+			},
+			#[cfg(feature = "sha2")]
+			DynHasher::Sha2 => Sha256Hasher::hash(x),
+			#[cfg(feature = "blake3")]
+			DynHasher::Blake3 => Blake3Hasher::hash(x),
+		}
+	}
+
+	/// Hash function currently selected for this thread, `None` outside of `with`.
+	pub fn selected() -> Option<DynHasher> {
+		SELECTED.with(|selected| selected.get())
+	}
+
+	/// Run `f` with this hash function selected for the current thread.
+	/// The previous selection is restored afterwards, even if `f` panics.
+	pub fn with<R>(self, f: impl FnOnce() -> R) -> R {
+		struct Restore(Option<DynHasher>);
+
+		impl Drop for Restore {
+			fn drop(&mut self) {
+				SELECTED.with(|selected| selected.set(self.0));
+			}
+		}
+
+		let _restore = Restore(SELECTED.with(|selected| selected.replace(Some(self))));
+		f()
+	}
+}
+
+impl std::str::FromStr for DynHasher {
+	type Err = &'static str;
+
+	fn from_str(name: &str) -> Result<Self, Self::Err> {
+		DynHasher::ALL.iter()
+			.find(|hasher| hasher.name() == name)
+			.cloned()
+			.ok_or("Unknown hasher")
+	}
+}
+
+impl Hasher for DynHasher {
+	type Out = [u8; 32];
+
+	type StdHasher = Hash256StdHasher;
+
+	const LENGTH: usize = 32;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		DynHasher::selected()
+			.expect("DynHasher used outside of DynHasher::with on this thread")
+			.hash_with(x)
+	}
+}
+
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes and the selected `DynHasher`.
+	pub struct DynHasherExtensionLayout {
+		hash: DynHasher,
+		codec: ReferenceNodeCodec<DynHasher>,
+		use_extension: true,
+	}
+}
+
+/// Trie layout without extension nodes using the selected `DynHasher`.
+pub type DynHasherNoExtensionLayout = GenericNoExtensionLayout<DynHasher>;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ExtensionLayout;
+	use trie_db::TrieConfiguration;
+
+	fn dataset() -> Vec<(Vec<u8>, Vec<u8>)> {
+		(0..20u8).map(|i| (vec![i; 3], vec![i; i as usize * 3])).collect()
+	}
+
+	#[test]
+	fn names_round_trip() {
+		for hasher in DynHasher::ALL {
+			assert_eq!(hasher.name().parse::<DynHasher>(), Ok(*hasher));
+		}
+		assert!("unknown".parse::<DynHasher>().is_err());
+	}
+
+	#[test]
+	#[cfg(all(feature = "sha2", feature = "blake3"))]
+	fn selected_hasher_is_used() {
+		use crate::{Sha2ExtensionLayout, Blake3NoExtensionLayout};
+		use trie_db::TrieLayout;
+
+		let data = dataset();
+		assert_eq!(DynHasher::selected(), None);
+		DynHasher::Keccak.with(|| assert_eq!(
+			DynHasherExtensionLayout::trie_root(data.clone()).unwrap(),
+			ExtensionLayout::trie_root(data.clone()).unwrap(),
+		));
+		DynHasher::Sha2.with(|| {
+			assert_eq!(
+				DynHasherExtensionLayout::trie_root(data.clone()).unwrap(),
+				Sha2ExtensionLayout::trie_root(data.clone()).unwrap(),
+			);
+			DynHasher::Blake3.with(|| assert_eq!(
+				DynHasherNoExtensionLayout::trie_root(data.clone()).unwrap(),
+				Blake3NoExtensionLayout::trie_root(data.clone()).unwrap(),
+			));
+			assert_eq!(DynHasher::selected(), Some(DynHasher::Sha2));
+			assert_eq!(
+				<DynHasherExtensionLayout as TrieLayout>::Hash::hash(b"abc"),
+				Sha256Hasher::hash(b"abc"),
+			);
+		});
+		assert_eq!(DynHasher::selected(), None);
+	}
+
+	#[test]
+	#[should_panic(expected = "outside of DynHasher::with")]
+	fn unselected_hasher_panics() {
+		DynHasherExtensionLayout::trie_root(dataset()).unwrap();
+	}
+
+	#[test]
+	fn selection_is_per_thread() {
+		let data = dataset();
+		let root = DynHasher::Keccak.with(|| DynHasherExtensionLayout::trie_root(data.clone()).unwrap());
+		assert_eq!(root, ExtensionLayout::trie_root(data.clone()).unwrap());
+
+		// the selection of this thread does not carry over to another thread.
+		let unselected = DynHasher::Keccak.with(|| {
+			let data = data.clone();
+			std::thread::spawn(move || DynHasherExtensionLayout::trie_root(data)).join()
+		});
+		assert!(unselected.is_err());
+
+		let selected = std::thread::spawn(move || {
+			DynHasher::Keccak.with(|| DynHasherExtensionLayout::trie_root(data).unwrap())
+		}).join().unwrap();
+		assert_eq!(selected, root);
+	}
+}
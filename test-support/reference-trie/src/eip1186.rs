@@ -0,0 +1,517 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ethereum trie nodes and `eth_getProof` (EIP-1186) proofs.
+//!
+//! `RlpNodeCodec` encodes nodes as the Ethereum Merkle Patricia trie does: RLP lists with
+//! hex prefix encoded partial keys. `EthereumLayout` hashes keys with Keccak like the state
+//! and storage tries, so an account or storage proof returned by `eth_getProof` can be
+//! checked against its root, or converted to a compact proof for `proof::verify_proof`.
+
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::ops::Range;
+use hash_db::{HashDBRef, Hasher};
+use keccak_hasher::KeccakHasher;
+use memory_db::{HashKey, MemoryDB};
+use trie_db::{
+	node::{NibbleSlicePlan, NodeHandlePlan, NodePlan},
+	nibble_ops, ChildReference, DBValue, NodeCodec, Partial, Trie, TrieDB, TrieError,
+};
+use crate::ReferenceError;
+
+trie_db::impl_trie_layout! {
+	/// Layout of the Ethereum state and storage tries: RLP encoded nodes with extension
+	/// nodes, values stored under the Keccak hash of their key.
+	pub struct EthereumLayout {
+		hash: KeccakHasher,
+		codec: RlpNodeCodec<KeccakHasher>,
+		use_extension: true,
+		hash_keys: true,
+	}
+}
+
+/// Hash of an Ethereum trie node.
+pub type EthereumHash = <KeccakHasher as Hasher>::Out;
+
+/// Result of an operation on an Ethereum trie.
+pub type EthereumResult<T> = trie_db::Result<T, EthereumHash, ReferenceError>;
+
+/// Codec of the Ethereum Merkle Patricia trie nodes.
+#[derive(Default, Clone)]
+pub struct RlpNodeCodec<H>(PhantomData<H>);
+
+const RLP_EMPTY_STRING: u8 = 0x80;
+const RLP_EMPTY_LIST: u8 = 0xc0;
+/// Longest payload with its length in the first byte of an item.
+const RLP_SHORT_LENGTH: usize = 55;
+/// Hex prefix flag of leaf partial keys, extension ones having no flag.
+const HEX_PREFIX_LEAF: u8 = 2;
+/// Hex prefix flag of partial keys of odd length.
+const HEX_PREFIX_ODD: u8 = 1;
+/// Number of items of a branch node list: a child per nibble then the value.
+const BRANCH_ITEMS: usize = nibble_ops::NIBBLE_LENGTH + 1;
+
+/// Append the RLP header of an item with a `len` bytes payload, `offset` being
+/// `RLP_EMPTY_STRING` for strings and `RLP_EMPTY_LIST` for lists.
+fn rlp_header(output: &mut Vec<u8>, len: usize, offset: u8) {
+	if len <= RLP_SHORT_LENGTH {
+		output.push(offset + len as u8);
+	} else {
+		let len_bytes = len.to_be_bytes();
+		let skip = len_bytes.iter().take_while(|b| **b == 0).count();
+		output.push(offset + RLP_SHORT_LENGTH as u8 + (len_bytes.len() - skip) as u8);
+		output.extend_from_slice(&len_bytes[skip..]);
+	}
+}
+
+fn rlp_string(output: &mut Vec<u8>, data: &[u8]) {
+	if data.len() == 1 && data[0] < RLP_EMPTY_STRING {
+		output.push(data[0]);
+	} else {
+		rlp_header(output, data.len(), RLP_EMPTY_STRING);
+		output.extend_from_slice(data);
+	}
+}
+
+/// Wrap an encoded list payload with its header.
+fn rlp_list(payload: Vec<u8>) -> Vec<u8> {
+	let mut output = Vec::with_capacity(payload.len() + 9);
+	rlp_header(&mut output, payload.len(), RLP_EMPTY_LIST);
+	output.extend_from_slice(&payload);
+	output
+}
+
+/// RLP item read from an encoded node.
+struct RlpItem {
+	is_list: bool,
+	/// Range of the whole item, header included.
+	range: Range<usize>,
+	/// Range of the item payload.
+	payload: Range<usize>,
+}
+
+/// Read the RLP item at `offset` in `data`, rejecting non canonical lengths.
+fn rlp_item(data: &[u8], offset: usize) -> Result<RlpItem, ReferenceError> {
+	let truncated = ReferenceError::TruncatedInput { offset };
+	let invalid = ReferenceError::InvalidHeader { offset };
+	let first = *data.get(offset).ok_or(truncated.clone())?;
+	let (is_list, start, len) = match first {
+		0..=0x7f => return Ok(RlpItem { is_list: false, range: offset..offset + 1, payload: offset..offset + 1 }),
+		0x80..=0xb7 => (false, offset + 1, (first - RLP_EMPTY_STRING) as usize),
+		0xc0..=0xf7 => (true, offset + 1, (first - RLP_EMPTY_LIST) as usize),
+		_ => {
+			let (is_list, len_of_len) = match first {
+				0xb8..=0xbf => (false, (first - 0xb7) as usize),
+				_ => (true, (first - 0xf7) as usize),
+			};
+			let len_bytes = data.get(offset + 1..offset + 1 + len_of_len).ok_or(truncated.clone())?;
+			if len_bytes[0] == 0 || len_of_len > std::mem::size_of::<usize>() {
+				return Err(invalid);
+			}
+			let len = len_bytes.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+			if len <= RLP_SHORT_LENGTH {
+				return Err(invalid);
+			}
+			(is_list, offset + 1 + len_of_len, len)
+		},
+	};
+	let end = start.checked_add(len).filter(|end| *end <= data.len()).ok_or(truncated)?;
+	if !is_list && len == 1 && data[start] < RLP_EMPTY_STRING {
+		return Err(invalid);
+	}
+	Ok(RlpItem { is_list, range: offset..end, payload: start..end })
+}
+
+/// Append a hex prefix encoded partial key, given as in `NodeCodec::extension_node`.
+fn hex_prefix(
+	output: &mut Vec<u8>,
+	partial: impl Iterator<Item = u8>,
+	number_nibble: usize,
+	flag: u8,
+) {
+	let mut encoded = Vec::with_capacity(number_nibble / nibble_ops::NIBBLE_PER_BYTE + 1);
+	let odd = number_nibble % nibble_ops::NIBBLE_PER_BYTE == 1;
+	if !odd {
+		encoded.push(flag << nibble_ops::BIT_PER_NIBBLE);
+	}
+	encoded.extend(partial);
+	if odd {
+		encoded[0] |= (flag | HEX_PREFIX_ODD) << nibble_ops::BIT_PER_NIBBLE;
+	}
+	rlp_string(output, &encoded);
+}
+
+/// Plan of a hex prefix encoded partial key, and whether it is the one of a leaf.
+fn hex_prefix_plan(data: &[u8], item: &RlpItem) -> Result<(NibbleSlicePlan, bool), ReferenceError> {
+	if item.is_list || item.payload.is_empty() {
+		return Err(ReferenceError::InvalidHeader { offset: item.range.start });
+	}
+	let first = data[item.payload.start];
+	let flag = first >> nibble_ops::BIT_PER_NIBBLE;
+	if flag > (HEX_PREFIX_LEAF | HEX_PREFIX_ODD) {
+		return Err(ReferenceError::InvalidHeader { offset: item.payload.start });
+	}
+	let partial = if flag & HEX_PREFIX_ODD == HEX_PREFIX_ODD {
+		NibbleSlicePlan::new(item.payload.clone(), 1)
+	} else if nibble_ops::pad_right(first) == 0 {
+		NibbleSlicePlan::new(item.payload.start + 1..item.payload.end, 0)
+	} else {
+		return Err(ReferenceError::BadPadding { offset: item.payload.start });
+	};
+	Ok((partial, flag & HEX_PREFIX_LEAF == HEX_PREFIX_LEAF))
+}
+
+fn encode_child<HO: AsRef<[u8]>>(output: &mut Vec<u8>, child: Option<&ChildReference<HO>>) {
+	match child {
+		Some(ChildReference::Hash(hash)) => rlp_string(output, hash.as_ref()),
+		// children omitted from compact proofs are empty inline nodes, not a valid node.
+		Some(ChildReference::Inline(_, 0)) => output.push(RLP_EMPTY_LIST),
+		// inline nodes are embedded as they are encoded.
+		Some(ChildReference::Inline(data, len)) => output.extend_from_slice(&data.as_ref()[..*len]),
+		None => output.push(RLP_EMPTY_STRING),
+	}
+}
+
+impl<H: Hasher> RlpNodeCodec<H> {
+	fn child_plan(item: RlpItem) -> Result<Option<NodeHandlePlan>, ReferenceError> {
+		let offset = item.range.start;
+		Ok(match (item.is_list, item.payload.len()) {
+			(true, 0) => Some(NodeHandlePlan::Inline(item.payload)),
+			(true, _) => Some(NodeHandlePlan::Inline(item.range)),
+			(false, 0) => None,
+			(false, len) if len == H::LENGTH => Some(NodeHandlePlan::Hash(item.payload)),
+			_ => return Err(ReferenceError::InvalidChild { offset }),
+		})
+	}
+
+	/// Decode a node plan, also returning the number of bytes read.
+	fn decode_plan_and_offset(data: &[u8]) -> Result<(NodePlan, usize), ReferenceError> {
+		let node = rlp_item(data, 0)?;
+		if !node.is_list {
+			return match node.payload.is_empty() {
+				true => Ok((NodePlan::Empty, node.range.end)),
+				false => Err(ReferenceError::InvalidHeader { offset: 0 }),
+			};
+		}
+		let mut items = Vec::with_capacity(BRANCH_ITEMS);
+		let mut offset = node.payload.start;
+		while offset < node.payload.end {
+			let item = rlp_item(&data[..node.payload.end], offset)?;
+			offset = item.range.end;
+			items.push(item);
+		}
+		let plan = match items.len() {
+			2 => {
+				let child = items.pop().expect("two items; qed");
+				let (partial, is_leaf) = hex_prefix_plan(data, &items[0])?;
+				if is_leaf {
+					if child.is_list {
+						return Err(ReferenceError::InvalidHeader { offset: child.range.start });
+					}
+					NodePlan::Leaf { partial, value: child.payload }
+				} else {
+					let offset = child.range.start;
+					let child = Self::child_plan(child)?.ok_or(ReferenceError::InvalidChild { offset })?;
+					NodePlan::Extension { partial, child }
+				}
+			},
+			BRANCH_ITEMS => {
+				let value = items.pop().expect("branch items; qed");
+				if value.is_list {
+					return Err(ReferenceError::InvalidHeader { offset: value.range.start });
+				}
+				let value = Some(value.payload).filter(|value| !value.is_empty());
+				let mut children = [
+					None, None, None, None, None, None, None, None,
+					None, None, None, None, None, None, None, None,
+				];
+				for (child, item) in children.iter_mut().zip(items) {
+					*child = Self::child_plan(item)?;
+				}
+				NodePlan::Branch { value, children }
+			},
+			_ => return Err(ReferenceError::InvalidHeader { offset: 0 }),
+		};
+		Ok((plan, node.range.end))
+	}
+}
+
+impl<H: Hasher> NodeCodec for RlpNodeCodec<H> {
+	type Error = ReferenceError;
+	type HashOut = H::Out;
+
+	fn hashed_null_node() -> <H as Hasher>::Out {
+		H::hash(<Self as NodeCodec>::empty_node())
+	}
+
+	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+		Ok(Self::decode_plan_and_offset(data)?.0)
+	}
+
+	fn decode_plan_prefix(data: &[u8]) -> ::std::result::Result<(NodePlan, usize), Self::Error> {
+		Self::decode_plan_and_offset(data)
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		data == <Self as NodeCodec>::empty_node()
+	}
+
+	fn empty_node() -> &'static[u8] {
+		&[RLP_EMPTY_STRING]
+	}
+
+	fn leaf_node(partial: Partial, value: &[u8]) -> Result<Vec<u8>, Self::Error> {
+		let ((number_nibble_encoded, first), rest) = partial;
+		let number_nibble = rest.len() * nibble_ops::NIBBLE_PER_BYTE + number_nibble_encoded as usize;
+		let partial = Some(first).filter(|_| number_nibble_encoded > 0).into_iter()
+			.chain(rest.iter().cloned());
+		Self::leaf_node_iter(partial, number_nibble, value)
+	}
+
+	fn leaf_node_iter_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		let mut payload = Vec::with_capacity(value.len() + number_nibble + 8);
+		hex_prefix(&mut payload, partial, number_nibble, HEX_PREFIX_LEAF);
+		rlp_string(&mut payload, value);
+		output.extend_from_slice(&rlp_list(payload));
+		Ok(())
+	}
+
+	fn leaf_node_head_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value_len: usize,
+	) -> Result<bool, Self::Error> {
+		// a single byte value below `RLP_EMPTY_STRING` is encoded without header.
+		if value_len == 1 {
+			return Ok(false);
+		}
+		let mut head = Vec::with_capacity(number_nibble / nibble_ops::NIBBLE_PER_BYTE + 20);
+		hex_prefix(&mut head, partial, number_nibble, HEX_PREFIX_LEAF);
+		rlp_header(&mut head, value_len, RLP_EMPTY_STRING);
+		rlp_header(output, head.len() + value_len, RLP_EMPTY_LIST);
+		output.extend_from_slice(&head);
+		Ok(true)
+	}
+
+	fn extension_node(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child: ChildReference<Self::HashOut>,
+	) -> Result<Vec<u8>, Self::Error> {
+		let mut payload = Vec::with_capacity(number_nibble + H::LENGTH + 2);
+		hex_prefix(&mut payload, partial, number_nibble, 0);
+		encode_child(&mut payload, Some(&child));
+		Ok(rlp_list(payload))
+	}
+
+	fn branch_node(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Result<Vec<u8>, Self::Error> {
+		let mut payload = Vec::with_capacity(BRANCH_ITEMS * (H::LENGTH + 1));
+		for child in children {
+			encode_child(&mut payload, child.borrow().as_ref());
+		}
+		rlp_string(&mut payload, maybe_value.unwrap_or(&[]));
+		Ok(rlp_list(payload))
+	}
+
+	fn branch_node_nibbled(
+		_partial:	impl Iterator<Item = u8>,
+		_number_nibble: usize,
+		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		_maybe_value: Option<&[u8]>) -> Result<Vec<u8>, Self::Error> {
+		Err(ReferenceError::UnsupportedNode("nibbled branch"))
+	}
+}
+
+/// Error checking an `eth_getProof` proof.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Eip1186Error {
+	/// The proof misses the root or a node on the path to the key, or holds a node
+	/// that cannot be decoded.
+	Trie(Box<TrieError<EthereumHash, ReferenceError>>),
+	/// The key is not mapped to the expected value. Holds the value found, if any.
+	ValueMismatch(Option<Vec<u8>>),
+}
+
+impl From<Box<TrieError<EthereumHash, ReferenceError>>> for Eip1186Error {
+	fn from(error: Box<TrieError<EthereumHash, ReferenceError>>) -> Self {
+		Eip1186Error::Trie(error)
+	}
+}
+
+/// Get the value at `key` together with its `eth_getProof` proof: the encoded nodes on the
+/// path from the root to the key, inline nodes being part of their parent.
+/// `key` is an account address or a storage slot, the path being its hash.
+pub fn generate_eip1186_proof(
+	db: &dyn HashDBRef<KeccakHasher, DBValue>,
+	root: &EthereumHash,
+	key: &[u8],
+) -> EthereumResult<(Option<DBValue>, Vec<Vec<u8>>)> {
+	TrieDB::<EthereumLayout>::new(db, root)?.get_with_proof(key)
+}
+
+/// In memory database for Ethereum tries, whose null node is the empty RLP string.
+pub fn ethereum_memory_db() -> MemoryDB<KeccakHasher, HashKey<KeccakHasher>, DBValue> {
+	let empty = RlpNodeCodec::<KeccakHasher>::empty_node();
+	MemoryDB::from_null_node(empty, empty.into())
+}
+
+/// Database holding the nodes of an `eth_getProof` proof.
+fn proof_db(proof: &[Vec<u8>]) -> MemoryDB<KeccakHasher, HashKey<KeccakHasher>, DBValue> {
+	let mut db = ethereum_memory_db();
+	for node in proof {
+		hash_db::HashDB::insert(&mut db, hash_db::EMPTY_PREFIX, node);
+	}
+	db
+}
+
+/// Check an `eth_getProof` proof that `key` is mapped to `value` in the trie with the
+/// given `root`, or is absent from it if `value` is `None`.
+pub fn verify_eip1186_proof(
+	root: &EthereumHash,
+	proof: &[Vec<u8>],
+	key: &[u8],
+	value: Option<&[u8]>,
+) -> Result<(), Eip1186Error> {
+	let db = proof_db(proof);
+	let found = TrieDB::<EthereumLayout>::new(&db, root)?.get(key)?;
+	if found.as_deref() != value {
+		return Err(Eip1186Error::ValueMismatch(found.map(Into::into)));
+	}
+	Ok(())
+}
+
+/// Convert an `eth_getProof` proof of the value at `key` to a compact proof, to check
+/// with `proof::verify_proof::<EthereumLayout, _, _, _>`.
+pub fn eip1186_to_compact_proof(
+	root: &EthereumHash,
+	proof: &[Vec<u8>],
+	key: &[u8],
+) -> Result<Vec<Vec<u8>>, Eip1186Error> {
+	let db = proof_db(proof);
+	let trie = TrieDB::<EthereumLayout>::new(&db, root)?;
+	Ok(trie_db::proof::generate_proof(&trie, &[key])?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use hex_literal::hex;
+	use trie_db::{proof::verify_proof, TrieDBMut, TrieMut};
+
+	trie_db::impl_trie_layout! {
+		/// Ethereum trie with unhashed keys, as in the Ethereum trie test vectors.
+		struct PlainEthereumLayout {
+			hash: KeccakHasher,
+			codec: RlpNodeCodec<KeccakHasher>,
+			use_extension: true,
+		}
+	}
+
+	fn root<L: trie_db::TrieLayout<Hash = KeccakHasher>>(entries: &[(&[u8], &[u8])]) -> EthereumHash {
+		let mut db = ethereum_memory_db();
+		let mut root = Default::default();
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in entries {
+			t.insert(key, value).unwrap();
+		}
+		*t.root()
+	}
+
+	#[test]
+	fn ethereum_test_vectors() {
+		assert_eq!(
+			root::<PlainEthereumLayout>(&[]),
+			hex!("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"),
+		);
+		assert_eq!(
+			root::<PlainEthereumLayout>(&[
+				(b"do", b"verb"),
+				(b"horse", b"stallion"),
+				(b"doge", b"coin"),
+				(b"dog", b"puppy"),
+			]),
+			hex!("5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"),
+		);
+		assert_eq!(
+			root::<PlainEthereumLayout>(&[
+				(b"doe", b"reindeer"),
+				(b"dog", b"puppy"),
+				(b"dogglesworth", b"cat"),
+			]),
+			hex!("8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"),
+		);
+	}
+
+	#[test]
+	fn nodes_are_canonical() {
+		let mut db = ethereum_memory_db();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<EthereumLayout>::new(&mut db, &mut root);
+			for i in 0..200u32 {
+				t.insert(&i.to_be_bytes(), &vec![i as u8; i as usize % 70]).unwrap();
+			}
+		}
+		for (_, (node, _)) in db.drain() {
+			assert!(RlpNodeCodec::<KeccakHasher>::is_canonical(&node));
+		}
+		assert!(!RlpNodeCodec::<KeccakHasher>::is_canonical(&[0x81, 0x01]));
+		assert!(RlpNodeCodec::<KeccakHasher>::decode(&[0xc2, 0x80]).is_err());
+	}
+
+	#[test]
+	fn eip1186_proofs() {
+		let mut db = ethereum_memory_db();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<EthereumLayout>::new(&mut db, &mut root);
+			for i in 0..100u8 {
+				t.insert(&[i; 20], &[i; 40]).unwrap();
+			}
+		}
+
+		let (value, proof) = generate_eip1186_proof(&db, &root, &[7; 20]).unwrap();
+		assert_eq!(value, Some(vec![7; 40].into()));
+		assert_eq!(verify_eip1186_proof(&root, &proof, &[7; 20], Some(&[7; 40])), Ok(()));
+		assert_eq!(
+			verify_eip1186_proof(&root, &proof, &[7; 20], Some(&[8; 40])),
+			Err(Eip1186Error::ValueMismatch(Some(vec![7; 40]))),
+		);
+		assert!(matches!(
+			verify_eip1186_proof(&root, &proof[..proof.len() - 1], &[7; 20], Some(&[7; 40])),
+			Err(Eip1186Error::Trie(_)),
+		));
+
+		let compact = eip1186_to_compact_proof(&root, &proof, &[7; 20]).unwrap();
+		let items = vec![(vec![7; 20], Some(vec![7; 40]))];
+		verify_proof::<EthereumLayout, _, _, _>(&root, &compact, &items).unwrap();
+
+		let (value, proof) = generate_eip1186_proof(&db, &root, &[200; 20]).unwrap();
+		assert_eq!(value, None);
+		assert_eq!(verify_eip1186_proof(&root, &proof, &[200; 20], None), Ok(()));
+
+		let empty_root = RlpNodeCodec::<KeccakHasher>::hashed_null_node();
+		assert_eq!(verify_eip1186_proof(&empty_root, &[], &[1; 20], None), Ok(()));
+	}
+}
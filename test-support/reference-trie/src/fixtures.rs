@@ -0,0 +1,181 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Golden vectors of the trie formats of this crate: the root and the stored nodes
+//! of the tries of a few datasets, to detect accidental changes of the encoding.
+//!
+//! Fixtures are kept in `fixtures/golden.json` and checked by the tests of this module;
+//! `trie-cli fixtures` prints them for the current code.
+
+use keccak_hasher::KeccakHasher;
+use memory_db::{HashKey, MemoryDB};
+use serde::{Deserialize, Serialize};
+use trie_db::{DBValue, TrieDBMut, TrieLayout, TrieMut};
+use super::{
+	DynLayout, ExtensionLayout, HashedKeyExtensionLayout, NoExtensionLayout, NoExtensionLayoutV1,
+};
+
+/// A dataset, and the root and stored nodes of its trie with a layout, all hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fixture {
+	/// Name of the dataset.
+	pub name: String,
+	/// Name of the layout, see `DynLayout::name`.
+	pub layout: String,
+	/// Key-value pairs.
+	pub dataset: Vec<(String, String)>,
+	/// Root of the trie.
+	pub root: String,
+	/// Stored nodes of the trie, sorted.
+	pub nodes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Fixtures {
+	fixtures: Vec<Fixture>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+	if hex.len() % 2 != 0 {
+		return Err(format!("Odd length hex `{}`", hex));
+	}
+	(0..hex.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid hex `{}`", hex)))
+		.collect()
+}
+
+/// Datasets of the fixtures, covering inline and hashed nodes, values in branches
+/// and odd length partial keys.
+pub fn datasets() -> Vec<(&'static str, Vec<(Vec<u8>, Vec<u8>)>)> {
+	vec![
+		("single", vec![(b"key".to_vec(), b"value".to_vec())]),
+		("small", vec![
+			(b"alfa".to_vec(), vec![1; 40]),
+			(b"alfabet".to_vec(), vec![2; 40]),
+			(b"al".to_vec(), b"v".to_vec()),
+			(b"beta".to_vec(), b"short".to_vec()),
+			(b"delta".to_vec(), vec![4; 40]),
+		]),
+		("nested", vec![
+			(b"a".to_vec(), b"1".to_vec()),
+			(b"ab".to_vec(), b"2".to_vec()),
+			(b"abc".to_vec(), vec![3; 33]),
+			(b"abcd".to_vec(), b"4".to_vec()),
+		]),
+		("sequential", (0..64u32)
+			.map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; (i % 40) as usize + 1]))
+			.collect()),
+	]
+}
+
+/// Build the trie of `dataset` with `L`, returning its root and its stored nodes.
+fn build<L: TrieLayout<Hash = KeccakHasher>>(
+	dataset: &[(Vec<u8>, Vec<u8>)],
+) -> (Vec<u8>, Vec<Vec<u8>>) {
+	let mut db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in dataset {
+			t.insert(key, value).expect("In memory trie; qed");
+		}
+	}
+	let nodes: Vec<_> = db.drain().into_iter()
+		.filter(|(_, (_, rc))| *rc > 0)
+		.map(|(_, (node, _))| node.to_vec())
+		.collect();
+	(root.to_vec(), nodes)
+}
+
+/// Fixture of `dataset` with `layout`, for the current code.
+pub fn fixture(layout: DynLayout, name: &str, dataset: &[(Vec<u8>, Vec<u8>)]) -> Fixture {
+	let (root, nodes) = match layout {
+		DynLayout::Extension => build::<ExtensionLayout>(dataset),
+		DynLayout::HashedKeyExtension => build::<HashedKeyExtensionLayout>(dataset),
+		DynLayout::NoExtension => build::<NoExtensionLayout>(dataset),
+		DynLayout::NoExtensionV1 => build::<NoExtensionLayoutV1>(dataset),
+	};
+	let mut nodes: Vec<_> = nodes.iter().map(|node| to_hex(node)).collect();
+	nodes.sort();
+	Fixture {
+		name: name.to_string(),
+		layout: layout.name().to_string(),
+		dataset: dataset.iter().map(|(key, value)| (to_hex(key), to_hex(value))).collect(),
+		root: to_hex(&root),
+		nodes,
+	}
+}
+
+/// Fixtures of all the datasets with all the layouts, for the current code.
+pub fn generate_fixtures() -> Vec<Fixture> {
+	let datasets = datasets();
+	DynLayout::ALL.iter()
+		.flat_map(|layout| datasets.iter().map(move |(name, dataset)| fixture(*layout, name, dataset)))
+		.collect()
+}
+
+/// Check that the current code reproduces `expected`.
+pub fn check_fixture(expected: &Fixture) -> Result<(), String> {
+	let layout: DynLayout = expected.layout.parse()?;
+	let dataset = expected.dataset.iter()
+		.map(|(key, value)| Ok((from_hex(key)?, from_hex(value)?)))
+		.collect::<Result<Vec<_>, String>>()?;
+	let actual = fixture(layout, &expected.name, &dataset);
+	if actual.root != expected.root {
+		return Err(format!(
+			"Fixture `{}` with layout `{}`: root {} instead of {}",
+			expected.name, expected.layout, actual.root, expected.root,
+		));
+	}
+	if actual.nodes != expected.nodes {
+		return Err(format!(
+			"Fixture `{}` with layout `{}`: nodes {:?} instead of {:?}",
+			expected.name, expected.layout, actual.nodes, expected.nodes,
+		));
+	}
+	Ok(())
+}
+
+/// Serialize fixtures to JSON, as stored in `fixtures/golden.json`.
+pub fn fixtures_to_json(fixtures: Vec<Fixture>) -> String {
+	serde_json::to_string_pretty(&Fixtures { fixtures }).expect("Fixtures only hold strings; qed")
+}
+
+/// Deserialize fixtures from JSON.
+pub fn fixtures_from_json(json: &str) -> Result<Vec<Fixture>, String> {
+	serde_json::from_str::<Fixtures>(json)
+		.map(|fixtures| fixtures.fixtures)
+		.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const GOLDEN: &str = include_str!("../fixtures/golden.json");
+
+	#[test]
+	fn golden_fixtures_are_reproduced() {
+		let fixtures = fixtures_from_json(GOLDEN).unwrap();
+		assert_eq!(fixtures.len(), DynLayout::ALL.len() * datasets().len());
+		for fixture in &fixtures {
+			check_fixture(fixture).unwrap();
+		}
+		assert_eq!(fixtures_from_json(&fixtures_to_json(fixtures.clone())).unwrap(), fixtures);
+	}
+}
@@ -23,53 +23,216 @@ use trie_root::Hasher;
 
 use trie_db::{
 	node::{NibbleSlicePlan, NodePlan, NodeHandlePlan},
-	triedbmut::ChildReference,
-	DBValue,
-	trie_visit,
 	TrieBuilder,
-	TrieRoot,
 	Partial,
+	TrieItem,
 };
+use hash_db::HashDBRef;
 use std::borrow::Borrow;
-use keccak_hasher::KeccakHasher;
+use keccak_hasher::{KeccakHasher, Keccak512Hasher};
+#[cfg(feature = "sha2")]
+use sha2_hasher::Sha256Hasher;
+#[cfg(feature = "blake3")]
+use blake3_hasher::Blake3Hasher;
 
 pub use trie_db::{
-	decode_compact, encode_compact,
-	nibble_ops, NibbleSlice, NibbleVec, NodeCodec, proof, Record, Recorder,
-	Trie, TrieConfiguration, TrieDB, TrieDBIterator, TrieDBMut, TrieDBNodeIterator, TrieError,
-	TrieIterator, TrieLayout, TrieMut,
+	BranchCallback, CheckError, DBValue, CheckReport, ChildReference, check_trie, tries_equal, trie_difference, trie_intersection, trie_visit, TrieRoot, copy_trie, extract_subtrie, merge_tries,
+	nodes_removed, nodes_removed_by_hash,
+	InvariantError, LoggingTrieMut, OpLog, TrieOp, FetchingHashDB, CountingMeter, NoMeter, TrieMeter, TrieCache,
+	decode_compact, decode_compact_with_max_depth, encode_compact, FaultTolerantIterator,
+	nibble_ops, NibbleSlice, NibbleVec, NodeCodec, proof, Query, Record, Recorder, Recording,
+	Trie, TrieConfiguration, TrieDB, TrieDBIterator, TrieDBMut, TrieDBNodeIterator, TrieDBOwned, TrieError,
+	TrieIterator, TrieLayout, TrieMut, TrieVisitor, transcode_trie, TranscodeError,
 };
 pub use trie_root::TrieStream;
 pub mod node {
-	pub use trie_db::node::Node;
+	pub use trie_db::node::{ChildHandle, Node, NodeHandle};
 }
 
-/// Trie layout using extension nodes.
-pub struct ExtensionLayout;
+pub mod fixtures;
+mod counting;
+mod dyn_hasher;
+mod eip1186;
 
-impl TrieLayout for ExtensionLayout {
-	const USE_EXTENSION: bool = true;
-	type Hash = KeccakHasher;
-	type Codec = ReferenceNodeCodec<KeccakHasher>;
+pub use crate::counting::{
+	CountedHash, CountingHasher, CountingLayout, CountingLookup, CountingTrieDB, CountingTrieDBMut,
+	nth_key, rank,
+};
+pub use crate::dyn_hasher::{DynHasher, DynHasherExtensionLayout, DynHasherNoExtensionLayout};
+pub use crate::eip1186::{
+	EthereumHash, EthereumLayout, EthereumResult, RlpNodeCodec, Eip1186Error, generate_eip1186_proof,
+	verify_eip1186_proof, eip1186_to_compact_proof, ethereum_memory_db,
+};
+
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes.
+	pub struct ExtensionLayout {
+		hash: KeccakHasher,
+		codec: ReferenceNodeCodec<KeccakHasher>,
+		use_extension: true,
+	}
+	aliases { trie_db: RefTrieDB, trie_db_mut: RefTrieDBMut, lookup: RefLookup }
 }
 
-impl TrieConfiguration for ExtensionLayout { }
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes and storing values under hashed keys.
+	pub struct HashedKeyExtensionLayout {
+		hash: KeccakHasher,
+		codec: ReferenceNodeCodec<KeccakHasher>,
+		use_extension: true,
+		hash_keys: true,
+	}
+}
 
 /// Trie layout without extension nodes, allowing
-/// generic hasher.
-pub struct GenericNoExtensionLayout<H>(PhantomData<H>);
+/// generic hasher and node header version.
+pub struct GenericNoExtensionLayout<H, V = NoExtV0>(PhantomData<(H, V)>);
 
-impl<H: Hasher> TrieLayout for GenericNoExtensionLayout<H> {
+impl<H: Hasher, V: NoExtVersion> TrieLayout for GenericNoExtensionLayout<H, V> {
 	const USE_EXTENSION: bool = false;
 	type Hash = H;
-	type Codec = ReferenceNodeCodecNoExt<H>;
+	type Codec = ReferenceNodeCodecNoExt<H, V>;
 }
 
-impl<H: Hasher> TrieConfiguration for GenericNoExtensionLayout<H> { }
+impl<H: Hasher, V: NoExtVersion> TrieConfiguration for GenericNoExtensionLayout<H, V> { }
 
 /// Trie layout without extension nodes.
 pub type NoExtensionLayout = GenericNoExtensionLayout<keccak_hasher::KeccakHasher>;
 
+/// Trie layout without extension nodes, writing version 1 node headers.
+/// Nodes written with version 0 headers are still readable.
+pub type NoExtensionLayoutV1 = GenericNoExtensionLayout<keccak_hasher::KeccakHasher, NoExtV1>;
+
+/// Fast non-cryptographic `Hasher` with an 8 byte output, for fuzzing and randomized
+/// tests: hashing is cheap, and nodes straddle the inline threshold far more often.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct FastHasher;
+
+impl Hasher for FastHasher {
+	type Out = [u8; 8];
+
+	type StdHasher = FastStdHasher;
+
+	const LENGTH: usize = 8;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		// FNV-1a, followed by a finalizer mixing every input bit into every output bit.
+		let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+		for byte in x {
+			hash ^= u64::from(*byte);
+			hash = hash.wrapping_mul(0x0100_0000_01b3);
+		}
+		hash ^= hash >> 33;
+		hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+		hash ^= hash >> 33;
+		hash.to_le_bytes()
+	}
+}
+
+/// `std::hash::Hasher` for the already hashed `FastHasher` outputs.
+#[derive(Default)]
+pub struct FastStdHasher(u64);
+
+impl std::hash::Hasher for FastStdHasher {
+	fn finish(&self) -> u64 {
+		self.0
+	}
+
+	fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.0 = self.0.rotate_left(8) ^ u64::from(*byte);
+		}
+	}
+}
+
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes and `FastHasher`.
+	pub struct FastExtensionLayout {
+		hash: FastHasher,
+		codec: ReferenceNodeCodec<FastHasher>,
+		use_extension: true,
+	}
+}
+
+/// Trie layout without extension nodes using `FastHasher`.
+pub type FastNoExtensionLayout = GenericNoExtensionLayout<FastHasher>;
+
+#[cfg(feature = "sha2")]
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes and `Sha256Hasher`.
+	pub struct Sha2ExtensionLayout {
+		hash: Sha256Hasher,
+		codec: ReferenceNodeCodec<Sha256Hasher>,
+		use_extension: true,
+	}
+}
+
+/// Trie layout without extension nodes using `Sha256Hasher`.
+#[cfg(feature = "sha2")]
+pub type Sha2NoExtensionLayout = GenericNoExtensionLayout<Sha256Hasher>;
+
+#[cfg(feature = "blake3")]
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes and `Blake3Hasher`.
+	pub struct Blake3ExtensionLayout {
+		hash: Blake3Hasher,
+		codec: ReferenceNodeCodec<Blake3Hasher>,
+		use_extension: true,
+	}
+}
+
+/// Trie layout without extension nodes using `Blake3Hasher`.
+#[cfg(feature = "blake3")]
+pub type Blake3NoExtensionLayout = GenericNoExtensionLayout<Blake3Hasher>;
+
+trie_db::impl_trie_layout! {
+	/// Trie layout using extension nodes and `Keccak512Hasher`, whose 64 byte
+	/// hashes let nodes of up to 63 bytes be inlined.
+	pub struct Keccak512ExtensionLayout {
+		hash: Keccak512Hasher,
+		codec: ReferenceNodeCodec<Keccak512Hasher>,
+		use_extension: true,
+	}
+}
+
+/// Trie layout without extension nodes using `Keccak512Hasher`.
+pub type Keccak512NoExtensionLayout = GenericNoExtensionLayout<Keccak512Hasher>;
+
+/// Trie layout of the production Substrate trie format (`sp-trie` layout,
+/// without hashed values), allowing generic hasher.
+pub struct SubstrateLayout<H>(PhantomData<H>);
+
+impl<H: Hasher> TrieLayout for SubstrateLayout<H> {
+	const USE_EXTENSION: bool = false;
+	type Hash = H;
+	type Codec = SubstrateNodeCodec<H>;
+}
+
+impl<H: Hasher> TrieConfiguration for SubstrateLayout<H> { }
+
+/// Version of the node header encoding used by `ReferenceNodeCodecNoExt`.
+/// Nodes are encoded with `VERSION`, and decoding accepts any version up to it.
+pub trait NoExtVersion {
+	/// Version of the encoded nodes, from 0 to `MAX_VERSION_NO_EXT`.
+	const VERSION: u8;
+}
+
+/// Original node header encoding, without version discriminant.
+#[derive(Default, Clone)]
+pub struct NoExtV0;
+
+impl NoExtVersion for NoExtV0 {
+	const VERSION: u8 = 0;
+}
+
+/// Node header encoding prefixed with a version discriminant byte.
+#[derive(Default, Clone)]
+pub struct NoExtV1;
+
+impl NoExtVersion for NoExtV1 {
+	const VERSION: u8 = 1;
+}
+
 /// Children bitmap codec for radix 16 trie.
 pub struct Bitmap(u16);
 
@@ -77,9 +240,8 @@ const BITMAP_LENGTH: usize = 2;
 
 impl Bitmap {
 
-	fn decode(data: &[u8]) -> Result<Self, CodecError> {
-		Ok(u16::decode(&mut &data[..])
-			.map(|v| Bitmap(v))?)
+	fn decode(data: &[u8]) -> Self {
+		Bitmap(u16::from_le_bytes([data[0], data[1]]))
 	}
 
 	fn value_at(&self, i: usize) -> bool {
@@ -98,23 +260,179 @@ impl Bitmap {
 	}
 }
 
-pub type RefTrieDB<'a> = trie_db::TrieDB<'a, ExtensionLayout>;
 pub type RefTrieDBNoExt<'a> = trie_db::TrieDB<'a, NoExtensionLayout>;
-pub type RefTrieDBMut<'a> = trie_db::TrieDBMut<'a, ExtensionLayout>;
 pub type RefTrieDBMutNoExt<'a> = trie_db::TrieDBMut<'a, NoExtensionLayout>;
 pub type RefFatDB<'a> = trie_db::FatDB<'a, ExtensionLayout>;
 pub type RefFatDBMut<'a> = trie_db::FatDBMut<'a, ExtensionLayout>;
+pub type RefBlobDB<'a> = trie_db::BlobDB<'a, ExtensionLayout>;
+pub type RefBlobDBMut<'a> = trie_db::BlobDBMut<'a, ExtensionLayout>;
 pub type RefSecTrieDB<'a> = trie_db::SecTrieDB<'a, ExtensionLayout>;
 pub type RefSecTrieDBMut<'a> = trie_db::SecTrieDBMut<'a, ExtensionLayout>;
-pub type RefLookup<'a, Q> = trie_db::Lookup<'a, ExtensionLayout, Q>;
 pub type RefLookupNoExt<'a, Q> = trie_db::Lookup<'a, NoExtensionLayout, Q>;
 
+/// Layouts of this crate, for tries whose layout is only known at runtime.
+/// All of them use `KeccakHasher` and codec errors are `ReferenceError`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynLayout {
+	/// `ExtensionLayout`.
+	Extension,
+	/// `HashedKeyExtensionLayout`.
+	HashedKeyExtension,
+	/// `NoExtensionLayout`.
+	NoExtension,
+	/// `NoExtensionLayoutV1`.
+	NoExtensionV1,
+}
+
+impl DynLayout {
+	/// All layouts.
+	pub const ALL: [DynLayout; 4] = [
+		DynLayout::Extension,
+		DynLayout::HashedKeyExtension,
+		DynLayout::NoExtension,
+		DynLayout::NoExtensionV1,
+	];
+
+	/// Name of the layout, as parsed by `from_str`.
+	pub fn name(&self) -> &'static str {
+		match self {
+			DynLayout::Extension => "extension",
+			DynLayout::HashedKeyExtension => "hashed-key-extension",
+			DynLayout::NoExtension => "no-extension",
+			DynLayout::NoExtensionV1 => "no-extension-v1",
+		}
+	}
+
+	/// Decode a node encoded with this layout codec.
+	pub fn decode_node(self, data: &[u8]) -> Result<trie_db::node::Node<'_>, ReferenceError> {
+		match self {
+			DynLayout::Extension | DynLayout::HashedKeyExtension =>
+				<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::decode(data),
+			DynLayout::NoExtension =>
+				<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(data),
+			DynLayout::NoExtensionV1 =>
+				<ReferenceNodeCodecNoExt<KeccakHasher, NoExtV1> as NodeCodec>::decode(data),
+		}
+	}
+
+	/// Open the trie at `root` with this layout.
+	pub fn open<'db>(
+		self,
+		db: &'db dyn HashDBRef<KeccakHasher, DBValue>,
+		root: &'db <KeccakHasher as Hasher>::Out,
+	) -> trie_db::Result<DynTrieDB<'db>, <KeccakHasher as Hasher>::Out, ReferenceError> {
+		Ok(match self {
+			DynLayout::Extension => DynTrieDB::Extension(TrieDB::new(db, root)?),
+			DynLayout::HashedKeyExtension => DynTrieDB::HashedKeyExtension(TrieDB::new(db, root)?),
+			DynLayout::NoExtension => DynTrieDB::NoExtension(TrieDB::new(db, root)?),
+			DynLayout::NoExtensionV1 => DynTrieDB::NoExtensionV1(TrieDB::new(db, root)?),
+		})
+	}
+}
+
+impl std::str::FromStr for DynLayout {
+	type Err = &'static str;
+
+	fn from_str(name: &str) -> Result<Self, Self::Err> {
+		DynLayout::ALL.iter()
+			.find(|layout| layout.name() == name)
+			.cloned()
+			.ok_or("Unknown layout")
+	}
+}
+
+/// A `TrieDB` with a layout chosen at runtime, see `DynLayout`.
+pub enum DynTrieDB<'db> {
+	/// Trie using `ExtensionLayout`.
+	Extension(TrieDB<'db, ExtensionLayout>),
+	/// Trie using `HashedKeyExtensionLayout`.
+	HashedKeyExtension(TrieDB<'db, HashedKeyExtensionLayout>),
+	/// Trie using `NoExtensionLayout`.
+	NoExtension(TrieDB<'db, NoExtensionLayout>),
+	/// Trie using `NoExtensionLayoutV1`.
+	NoExtensionV1(TrieDB<'db, NoExtensionLayoutV1>),
+}
+
+// wrapper macro for making the match easier to deal with.
+macro_rules! dyn_wrapper {
+	($me: ident, $f_name: ident, $($param: ident),*) => {
+		match *$me {
+			DynTrieDB::Extension(ref t) => t.$f_name($($param),*),
+			DynTrieDB::HashedKeyExtension(ref t) => t.$f_name($($param),*),
+			DynTrieDB::NoExtension(ref t) => t.$f_name($($param),*),
+			DynTrieDB::NoExtensionV1(ref t) => t.$f_name($($param),*),
+		}
+	}
+}
+
+impl<'db> DynTrieDB<'db> {
+	/// Layout of the trie.
+	pub fn layout(&self) -> DynLayout {
+		match self {
+			DynTrieDB::Extension(_) => DynLayout::Extension,
+			DynTrieDB::HashedKeyExtension(_) => DynLayout::HashedKeyExtension,
+			DynTrieDB::NoExtension(_) => DynLayout::NoExtension,
+			DynTrieDB::NoExtensionV1(_) => DynLayout::NoExtensionV1,
+		}
+	}
+
+	/// Return the root of the trie.
+	pub fn root(&self) -> &<KeccakHasher as Hasher>::Out {
+		dyn_wrapper!(self, root,)
+	}
+
+	/// Is the trie empty?
+	pub fn is_empty(&self) -> bool {
+		dyn_wrapper!(self, is_empty,)
+	}
+
+	/// Does the trie contain a given key?
+	pub fn contains(
+		&self,
+		key: &[u8],
+	) -> trie_db::Result<bool, <KeccakHasher as Hasher>::Out, ReferenceError> {
+		dyn_wrapper!(self, contains, key)
+	}
+
+	/// What is the value of the given key in this trie?
+	pub fn get(
+		&self,
+		key: &[u8],
+	) -> trie_db::Result<Option<DBValue>, <KeccakHasher as Hasher>::Out, ReferenceError> {
+		dyn_wrapper!(self, get, key)
+	}
+
+	/// Search for the key with the given query parameter.
+	pub fn get_with<Q: Query<KeccakHasher>>(
+		&self,
+		key: &[u8],
+		query: Q,
+	) -> trie_db::Result<Option<Q::Item>, <KeccakHasher as Hasher>::Out, ReferenceError> {
+		dyn_wrapper!(self, get_with, key, query)
+	}
+
+	/// Returns a depth-first iterator over the elements of trie.
+	pub fn iter<'a>(&'a self) -> trie_db::Result<
+		Box<dyn Iterator<Item = TrieItem<'a, <KeccakHasher as Hasher>::Out, ReferenceError>> + 'a>,
+		<KeccakHasher as Hasher>::Out,
+		ReferenceError,
+	> {
+		Ok(match self {
+			DynTrieDB::Extension(t) => Box::new(t.iter()?),
+			DynTrieDB::HashedKeyExtension(t) => Box::new(t.iter()?),
+			DynTrieDB::NoExtension(t) => Box::new(t.iter()?),
+			DynTrieDB::NoExtensionV1(t) => Box::new(t.iter()?),
+		})
+	}
+}
+
 pub fn reference_trie_root<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord + fmt::Debug,
 	B: AsRef<[u8]> + fmt::Debug,
 {
 	trie_root::trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(input)
+		.expect("keys are too long for the reference encoding")
 }
 
 fn reference_trie_root_unhashed<I, A, B>(input: I) -> Vec<u8> where
@@ -123,6 +441,7 @@ fn reference_trie_root_unhashed<I, A, B>(input: I) -> Vec<u8> where
 	B: AsRef<[u8]> + fmt::Debug,
 {
 	trie_root::unhashed_trie::<KeccakHasher, ReferenceTrieStream, _, _, _>(input)
+		.expect("keys are too long for the reference encoding")
 }
 
 pub fn reference_trie_root_no_extension<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out where
@@ -131,6 +450,7 @@ pub fn reference_trie_root_no_extension<I, A, B>(input: I) -> <KeccakHasher as H
 	B: AsRef<[u8]> + fmt::Debug,
 {
 	trie_root::trie_root_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input)
+		.expect("keys are too long for the reference encoding")
 }
 
 fn reference_trie_root_unhashed_no_extension<I, A, B>(input: I) -> Vec<u8> where
@@ -139,6 +459,7 @@ fn reference_trie_root_unhashed_no_extension<I, A, B>(input: I) -> Vec<u8> where
 	B: AsRef<[u8]> + fmt::Debug,
 {
 	trie_root::unhashed_trie_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input)
+		.expect("keys are too long for the reference encoding")
 }
 
 const EMPTY_TRIE: u8 = 0;
@@ -157,24 +478,77 @@ const NIBBLE_SIZE_BOUND_NO_EXT: usize = u16::max_value() as usize;
 const LEAF_PREFIX_MASK_NO_EXT: u8 = 0b_01 << 6;
 const BRANCH_WITHOUT_MASK_NO_EXT: u8 = 0b_10 << 6;
 const BRANCH_WITH_MASK_NO_EXT: u8 = 0b_11 << 6;
+// Version discriminant bytes share the empty node prefix, 0 being the empty node itself.
+const VERSION_MASK_NO_EXT: u8 = 0b_00 << 6;
+// Substrate headers of nodes with hashed values, not supported by `SubstrateNodeCodec`.
+const ALT_HASHING_LEAF_PREFIX_MASK: u8 = 0b_001 << 5;
+const ALT_HASHING_BRANCH_WITH_MASK: u8 = 0b_0001 << 4;
+/// Highest node header version of the no extension codec.
+pub const MAX_VERSION_NO_EXT: u8 = 0b_11_1111;
+
+/// Error of the codecs and trie streams of this crate. Decoding errors carry the
+/// offset in the encoded node of the item that could not be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReferenceError {
+	/// The node ends before the item starting at `offset`.
+	TruncatedInput { offset: usize },
+	/// The node header or item length at `offset` is invalid, or of a node kind
+	/// the codec does not decode.
+	InvalidHeader { offset: usize },
+	/// The padding nibble of the partial key starting at `offset` is not zero.
+	BadPadding { offset: usize },
+	/// The children bitmap at `offset` is not valid for the codec, eg without any child.
+	BadBitmap { offset: usize },
+	/// The child reference at `offset` is neither a hash nor an inline node.
+	InvalidChild { offset: usize },
+	/// A partial key of this number of nibbles does not fit in the node header.
+	PartialKeyTooLong(usize),
+	/// The codec does not encode this kind of node.
+	UnsupportedNode(&'static str),
+}
 
-/// Create a leaf/extension node, encoding a number of nibbles. Note that this
-/// cannot handle a number of nibbles that is zero or greater than 125 and if
-/// you attempt to do so *IT WILL PANIC*.
-fn fuse_nibbles_node<'a>(nibbles: &'a [u8], leaf: bool) -> impl Iterator<Item = u8> + 'a {
-	debug_assert!(
-		nibbles.len() < LEAF_NODE_OVER.min(EXTENSION_NODE_OVER) as usize,
-		"nibbles length too long. what kind of size of key are you trying to include in the trie!?!"
-	);
-	let first_byte = if leaf {
-		LEAF_NODE_OFFSET
+impl fmt::Display for ReferenceError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ReferenceError::TruncatedInput { offset } =>
+				write!(f, "Node truncated at offset {}", offset),
+			ReferenceError::InvalidHeader { offset } =>
+				write!(f, "Invalid header at offset {}", offset),
+			ReferenceError::BadPadding { offset } =>
+				write!(f, "Non-zero partial key padding at offset {}", offset),
+			ReferenceError::BadBitmap { offset } =>
+				write!(f, "Invalid children bitmap at offset {}", offset),
+			ReferenceError::InvalidChild { offset } =>
+				write!(f, "Invalid child reference at offset {}", offset),
+			ReferenceError::PartialKeyTooLong(nibble_count) =>
+				write!(f, "Partial key of {} nibbles too long", nibble_count),
+			ReferenceError::UnsupportedNode(kind) =>
+				write!(f, "Node kind not supported by the codec: {}", kind),
+		}
+	}
+}
+
+impl std::error::Error for ReferenceError {}
+
+/// Create a leaf/extension node, encoding a number of nibbles.
+/// Fails if the number of nibbles does not fit in the node header.
+fn fuse_nibbles_node<'a>(
+	nibbles: &'a [u8],
+	leaf: bool,
+) -> Result<impl Iterator<Item = u8> + 'a, ReferenceError> {
+	let (offset, over) = if leaf {
+		(LEAF_NODE_OFFSET, LEAF_NODE_OVER)
 	} else {
-		EXTENSION_NODE_OFFSET
-	} + nibbles.len() as u8;
+		(EXTENSION_NODE_OFFSET, EXTENSION_NODE_OVER)
+	};
+	if nibbles.len() >= over as usize {
+		return Err(ReferenceError::PartialKeyTooLong(nibbles.len()));
+	}
+	let first_byte = offset + nibbles.len() as u8;
 
-	once(first_byte)
+	Ok(once(first_byte)
 		.chain(if nibbles.len() % 2 == 1 { Some(nibbles[0]) } else { None })
-		.chain(nibbles[nibbles.len() % 2..].chunks(2).map(|ch| ch[0] << 4 | ch[1]))
+		.chain(nibbles[nibbles.len() % 2..].chunks(2).map(|ch| ch[0] << 4 | ch[1])))
 }
 
 enum NodeKindNoExt {
@@ -184,20 +558,29 @@ enum NodeKindNoExt {
 }
 
 /// Create a leaf or branch node header followed by its encoded partial nibbles.
+/// Fails if the number of nibbles does not fit in the node header.
 fn fuse_nibbles_node_no_extension<'a>(
 	nibbles: &'a [u8],
 	kind: NodeKindNoExt,
-) -> impl Iterator<Item = u8> + 'a {
-	let size = ::std::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibbles.len());
+) -> Result<impl Iterator<Item = u8> + 'a, ReferenceError> {
+	let size = check_nibble_count_no_ext(nibbles.len())?;
 
 	let iter_start = match kind {
 		NodeKindNoExt::Leaf => size_and_prefix_iterator(size, LEAF_PREFIX_MASK_NO_EXT),
 		NodeKindNoExt::BranchNoValue => size_and_prefix_iterator(size, BRANCH_WITHOUT_MASK_NO_EXT),
 		NodeKindNoExt::BranchWithValue => size_and_prefix_iterator(size, BRANCH_WITH_MASK_NO_EXT),
 	};
-	iter_start
+	Ok(iter_start
 		.chain(if nibbles.len() % 2 == 1 { Some(nibbles[0]) } else { None })
-		.chain(nibbles[nibbles.len() % 2..].chunks(2).map(|ch| ch[0] << 4 | ch[1]))
+		.chain(nibbles[nibbles.len() % 2..].chunks(2).map(|ch| ch[0] << 4 | ch[1])))
+}
+
+/// Check that a number of nibbles fits in a node header without extension.
+fn check_nibble_count_no_ext(nibble_count: usize) -> Result<usize, ReferenceError> {
+	if nibble_count > NIBBLE_SIZE_BOUND_NO_EXT {
+		return Err(ReferenceError::PartialKeyTooLong(nibble_count));
+	}
+	Ok(nibble_count)
 }
 
 /// Encoding of branch header and children bitmap (for trie stream radix 16).
@@ -243,6 +626,8 @@ pub struct ReferenceTrieStream {
 }
 
 impl TrieStream for ReferenceTrieStream {
+	type Error = ReferenceError;
+
 	fn new() -> Self {
 		ReferenceTrieStream {
 			buffer: Vec::new()
@@ -253,9 +638,10 @@ impl TrieStream for ReferenceTrieStream {
 		self.buffer.push(EMPTY_TRIE);
 	}
 
-	fn append_leaf(&mut self, key: &[u8], value: &[u8]) {
-		self.buffer.extend(fuse_nibbles_node(key, true));
+	fn append_leaf(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+		self.buffer.extend(fuse_nibbles_node(key, true)?);
 		value.encode_to(&mut self.buffer);
+		Ok(())
 	}
 
 	fn begin_branch(
@@ -263,19 +649,21 @@ impl TrieStream for ReferenceTrieStream {
 		maybe_key: Option<&[u8]>,
 		maybe_value: Option<&[u8]>,
 		has_children: impl Iterator<Item = bool>,
-	) {
+	) -> Result<(), Self::Error> {
 		self.buffer.extend(&branch_node(maybe_value.is_some(), has_children));
 		if let Some(partial) = maybe_key {
 			// should not happen
-			self.buffer.extend(fuse_nibbles_node(partial, false));
+			self.buffer.extend(fuse_nibbles_node(partial, false)?);
 		}
 		if let Some(value) = maybe_value {
 			value.encode_to(&mut self.buffer);
 		}
+		Ok(())
 	}
 
-	fn append_extension(&mut self, key: &[u8]) {
-		self.buffer.extend(fuse_nibbles_node(key, false));
+	fn append_extension(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+		self.buffer.extend(fuse_nibbles_node(key, false)?);
+		Ok(())
 	}
 
 	fn append_substream<H: Hasher>(&mut self, other: Self) {
@@ -296,6 +684,8 @@ pub struct ReferenceTrieStreamNoExt {
 }
 
 impl TrieStream for ReferenceTrieStreamNoExt {
+	type Error = ReferenceError;
+
 	fn new() -> Self {
 		ReferenceTrieStreamNoExt {
 			buffer: Vec::new()
@@ -306,9 +696,10 @@ impl TrieStream for ReferenceTrieStreamNoExt {
 		self.buffer.push(EMPTY_TRIE_NO_EXT);
 	}
 
-	fn append_leaf(&mut self, key: &[u8], value: &[u8]) {
-		self.buffer.extend(fuse_nibbles_node_no_extension(key, NodeKindNoExt::Leaf));
+	fn append_leaf(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+		self.buffer.extend(fuse_nibbles_node_no_extension(key, NodeKindNoExt::Leaf)?);
 		value.encode_to(&mut self.buffer);
+		Ok(())
 	}
 
 	fn begin_branch(
@@ -316,15 +707,15 @@ impl TrieStream for ReferenceTrieStreamNoExt {
 		maybe_key: Option<&[u8]>,
 		maybe_value: Option<&[u8]>,
 		has_children: impl Iterator<Item = bool>
-	) {
+	) -> Result<(), Self::Error> {
 		if let Some(partial) = maybe_key {
 			if maybe_value.is_some() {
 				self.buffer.extend(
-					fuse_nibbles_node_no_extension(partial, NodeKindNoExt::BranchWithValue)
+					fuse_nibbles_node_no_extension(partial, NodeKindNoExt::BranchWithValue)?
 				);
 			} else {
 				self.buffer.extend(
-					fuse_nibbles_node_no_extension(partial, NodeKindNoExt::BranchNoValue)
+					fuse_nibbles_node_no_extension(partial, NodeKindNoExt::BranchNoValue)?
 				);
 			}
 			let bitmap = branch_node_bit_mask(has_children);
@@ -336,10 +727,12 @@ impl TrieStream for ReferenceTrieStreamNoExt {
 		if let Some(value) = maybe_value {
 			value.encode_to(&mut self.buffer);
 		}
+		Ok(())
 	}
 
-	fn append_extension(&mut self, _key: &[u8]) {
+	fn append_extension(&mut self, _key: &[u8]) -> Result<(), Self::Error> {
 		// should not happen
+		Ok(())
 	}
 
 	fn append_substream<H: Hasher>(&mut self, other: Self) {
@@ -418,20 +811,22 @@ fn encode_size_and_prefix(size: usize, prefix: u8, out: &mut impl Output) {
 	}
 }
 
-fn decode_size<I: Input>(first: u8, input: &mut I) -> Result<usize, CodecError> {
+/// Decode the size of a header whose first byte `first` was already read from `input`.
+fn decode_size(first: u8, input: &mut ByteSliceInput) -> Result<usize, ReferenceError> {
+	let header_offset = input.offset - 1;
 	let mut result = (first & 255u8 >> 2) as usize;
 	if result < 63 {
 		return Ok(result);
 	}
 	result -= 1;
 	while result <= NIBBLE_SIZE_BOUND_NO_EXT {
-		let n = input.read_byte()? as usize;
+		let n = input.next_byte()? as usize;
 		if n < 255 {
 			return Ok(result + n + 1);
 		}
 		result += 255;
 	}
-	Err("Size limit reached for a nibble slice".into())
+	Err(ReferenceError::InvalidHeader { offset: header_offset })
 }
 
 impl Encode for NodeHeaderNoExt {
@@ -448,9 +843,9 @@ impl Encode for NodeHeaderNoExt {
 	}
 }
 
-impl Decode for NodeHeader {
-	fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
-		Ok(match input.read_byte()? {
+impl NodeHeader {
+	fn decode(input: &mut ByteSliceInput) -> Result<Self, ReferenceError> {
+		Ok(match input.next_byte()? {
 			EMPTY_TRIE => NodeHeader::Null,
 			BRANCH_NODE_NO_VALUE => NodeHeader::Branch(false),
 			BRANCH_NODE_WITH_VALUE => NodeHeader::Branch(true),
@@ -462,9 +857,14 @@ impl Decode for NodeHeader {
 	}
 }
 
-impl Decode for NodeHeaderNoExt {
-	fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
-		let i = input.read_byte()?;
+impl NodeHeaderNoExt {
+	fn decode(input: &mut ByteSliceInput) -> Result<Self, ReferenceError> {
+		let i = input.next_byte()?;
+		NodeHeaderNoExt::decode_from_first_byte(i, input)
+	}
+
+	/// Decode a header whose first byte `i` was already read from `input`.
+	fn decode_from_first_byte(i: u8, input: &mut ByteSliceInput) -> Result<Self, ReferenceError> {
 		if i == EMPTY_TRIE_NO_EXT {
 			return Ok(NodeHeaderNoExt::Null);
 		}
@@ -476,11 +876,39 @@ impl Decode for NodeHeaderNoExt {
 			BRANCH_WITH_MASK_NO_EXT =>
 				Ok(NodeHeaderNoExt::Branch(true, decode_size(i, input)?)),
 			// do not allow any special encoding
-			_ => Err("Unknown type of node".into()),
+			_ => Err(ReferenceError::InvalidHeader { offset: input.offset - 1 }),
 		}
 	}
 }
 
+/// Encode the version discriminant of a node, version 0 having none.
+fn encode_version_no_ext(version: u8, output: &mut impl Output) {
+	debug_assert!(version <= MAX_VERSION_NO_EXT);
+	if version > 0 {
+		output.push_byte(VERSION_MASK_NO_EXT | version);
+	}
+}
+
+/// Decode a node header with its version, accepting any version up to `max_version`.
+fn decode_versioned_header_no_ext(
+	input: &mut ByteSliceInput,
+	max_version: u8,
+) -> Result<(u8, NodeHeaderNoExt), ReferenceError> {
+	let first = input.next_byte()?;
+	if first == EMPTY_TRIE_NO_EXT || first & (0b11 << 6) != VERSION_MASK_NO_EXT {
+		return Ok((0, NodeHeaderNoExt::decode_from_first_byte(first, input)?));
+	}
+	let version = first & !(0b11 << 6);
+	if version > max_version {
+		return Err(ReferenceError::InvalidHeader { offset: input.offset - 1 });
+	}
+	match NodeHeaderNoExt::decode(input)? {
+		// the empty node is never versioned
+		NodeHeaderNoExt::Null => Err(ReferenceError::InvalidHeader { offset: input.offset - 1 }),
+		header => Ok((version, header)),
+	}
+}
+
 /// Simple reference implementation of a `NodeCodec`.
 #[derive(Default, Clone)]
 pub struct ReferenceNodeCodec<H>(PhantomData<H>);
@@ -489,74 +917,94 @@ pub struct ReferenceNodeCodec<H>(PhantomData<H>);
 /// Even if implementation follows initial specification of
 /// https://github.com/w3f/polkadot-re-spec/issues/8, this may
 /// not follow it in the future, it is mainly the testing codec without extension node.
+/// `V` selects the node header version, see `NoExtVersion`.
 #[derive(Default, Clone)]
-pub struct ReferenceNodeCodecNoExt<H>(PhantomData<H>);
+pub struct ReferenceNodeCodecNoExt<H, V = NoExtV0>(PhantomData<(H, V)>);
 
-fn partial_to_key(partial: Partial, offset: u8, over: u8) -> Vec<u8> {
+fn partial_to_key(
+	output: &mut Vec<u8>,
+	partial: Partial,
+	offset: u8,
+	over: u8,
+) -> Result<(), ReferenceError> {
 	let number_nibble_encoded = (partial.0).0 as usize;
 	let nibble_count = partial.1.len() * nibble_ops::NIBBLE_PER_BYTE + number_nibble_encoded;
-	assert!(nibble_count < over as usize);
-	let mut output = vec![offset + nibble_count as u8];
+	if nibble_count >= over as usize {
+		return Err(ReferenceError::PartialKeyTooLong(nibble_count));
+	}
+	output.push(offset + nibble_count as u8);
 	if number_nibble_encoded > 0 {
 		output.push(nibble_ops::pad_right((partial.0).1));
 	}
 	output.extend_from_slice(&partial.1[..]);
-	output
+	Ok(())
 }
 
 fn partial_from_iterator_to_key<I: Iterator<Item = u8>>(
+	output: &mut Vec<u8>,
 	partial: I,
 	nibble_count: usize,
 	offset: u8,
 	over: u8,
-) -> Vec<u8> {
-	assert!(nibble_count < over as usize);
-	let mut output = Vec::with_capacity(1 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
+) -> Result<(), ReferenceError> {
+	if nibble_count >= over as usize {
+		return Err(ReferenceError::PartialKeyTooLong(nibble_count));
+	}
+	output.reserve(1 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
 	output.push(offset + nibble_count as u8);
 	output.extend(partial);
-	output
+	Ok(())
 }
 
 fn partial_from_iterator_encode<I: Iterator<Item = u8>>(
+	output: &mut Vec<u8>,
 	partial: I,
 	nibble_count: usize,
 	node_kind: NodeKindNoExt,
-) -> Vec<u8> {
-	let nibble_count = ::std::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibble_count);
+	version: u8,
+) -> Result<(), ReferenceError> {
+	let nibble_count = check_nibble_count_no_ext(nibble_count)?;
 
-	let mut output = Vec::with_capacity(3 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
+	output.reserve(4 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
+	encode_version_no_ext(version, output);
 	match node_kind {
 		NodeKindNoExt::Leaf =>
-			NodeHeaderNoExt::Leaf(nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Leaf(nibble_count).encode_to(output),
 		NodeKindNoExt::BranchWithValue =>
-			NodeHeaderNoExt::Branch(true, nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Branch(true, nibble_count).encode_to(output),
 		NodeKindNoExt::BranchNoValue =>
-			NodeHeaderNoExt::Branch(false, nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Branch(false, nibble_count).encode_to(output),
 	};
 	output.extend(partial);
-	output
+	Ok(())
 }
 
-fn partial_encode(partial: Partial, node_kind: NodeKindNoExt) -> Vec<u8> {
+fn partial_encode(
+	output: &mut Vec<u8>,
+	partial: Partial,
+	node_kind: NodeKindNoExt,
+	version: u8,
+) -> Result<(), ReferenceError> {
 	let number_nibble_encoded = (partial.0).0 as usize;
 	let nibble_count = partial.1.len() * nibble_ops::NIBBLE_PER_BYTE + number_nibble_encoded;
 
-	let nibble_count = ::std::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibble_count);
+	let nibble_count = check_nibble_count_no_ext(nibble_count)?;
 
-	let mut output = Vec::with_capacity(3 + partial.1.len());
+	output.reserve(4 + partial.1.len());
+	encode_version_no_ext(version, output);
 	match node_kind {
 		NodeKindNoExt::Leaf =>
-			NodeHeaderNoExt::Leaf(nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Leaf(nibble_count).encode_to(output),
 		NodeKindNoExt::BranchWithValue =>
-			NodeHeaderNoExt::Branch(true, nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Branch(true, nibble_count).encode_to(output),
 		NodeKindNoExt::BranchNoValue =>
-			NodeHeaderNoExt::Branch(false, nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Branch(false, nibble_count).encode_to(output),
 	};
 	if number_nibble_encoded > 0 {
 		output.push(nibble_ops::pad_right((partial.0).1));
 	}
 	output.extend_from_slice(&partial.1[..]);
-	output
+	Ok(())
 }
 
 struct ByteSliceInput<'a> {
@@ -572,15 +1020,41 @@ impl<'a> ByteSliceInput<'a> {
 		}
 	}
 
-	fn take(&mut self, count: usize) -> Result<Range<usize>, CodecError> {
+	fn take(&mut self, count: usize) -> Result<Range<usize>, ReferenceError> {
 		if self.offset + count > self.data.len() {
-			return Err("out of data".into());
+			return Err(ReferenceError::TruncatedInput { offset: self.offset });
 		}
 
 		let range = self.offset..(self.offset + count);
 		self.offset += count;
 		Ok(range)
 	}
+
+	fn next_byte(&mut self) -> Result<u8, ReferenceError> {
+		let range = self.take(1)?;
+		Ok(self.data[range.start])
+	}
+
+	/// Read a compact encoded length.
+	fn compact_len(&mut self) -> Result<usize, ReferenceError> {
+		let offset = self.offset;
+		match <Compact<u32>>::decode(self) {
+			Ok(len) => Ok(len.0 as usize),
+			Err(_) => {
+				let encoded_len = self.data.get(offset).map_or(1, |first| match first & 0b11 {
+					0 => 1,
+					1 => 2,
+					2 => 4,
+					_ => (first >> 2) as usize + 5,
+				});
+				if offset + encoded_len > self.data.len() {
+					Err(ReferenceError::TruncatedInput { offset })
+				} else {
+					Err(ReferenceError::InvalidHeader { offset })
+				}
+			},
+		}
+	}
 }
 
 impl<'a> Input for ByteSliceInput<'a> {
@@ -594,7 +1068,7 @@ impl<'a> Input for ByteSliceInput<'a> {
 	}
 
 	fn read(&mut self, into: &mut [u8]) -> Result<(), CodecError> {
-		let range = self.take(into.len())?;
+		let range = self.take(into.len()).map_err(|_| "out of data")?;
 		into.copy_from_slice(&self.data[range]);
 		Ok(())
 	}
@@ -615,24 +1089,20 @@ impl<'a> Input for ByteSliceInput<'a> {
 // but due to the current limitations of Rust const evaluation we can't do
 // `const HASHED_NULL_NODE: <KeccakHasher as Hasher>::Out = <KeccakHasher as Hasher>::Out( … … )`.
 // Perhaps one day soon?
-impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
-	type Error = CodecError;
-	type HashOut = H::Out;
-
-	fn hashed_null_node() -> <H as Hasher>::Out {
-		H::hash(<Self as NodeCodec>::empty_node())
-	}
-
-	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+impl<H: Hasher> ReferenceNodeCodec<H> {
+	/// Decode a node plan, also returning the number of bytes read.
+	fn decode_plan_and_offset(
+		data: &[u8],
+	) -> ::std::result::Result<(NodePlan, usize), ReferenceError> {
 		let mut input = ByteSliceInput::new(data);
-		match NodeHeader::decode(&mut input)? {
+		let plan: ::std::result::Result<_, ReferenceError> = match NodeHeader::decode(&mut input)? {
 			NodeHeader::Null => Ok(NodePlan::Empty),
 			NodeHeader::Branch(has_value) => {
 				let bitmap_range = input.take(BITMAP_LENGTH)?;
-				let bitmap = Bitmap::decode(&data[bitmap_range])?;
+				let bitmap = Bitmap::decode(&data[bitmap_range.clone()]);
 
 				let value = if has_value {
-					let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+					let count = input.compact_len()?;
 					Some(input.take(count)?)
 				} else {
 					None
@@ -643,7 +1113,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 				];
 				for i in 0..nibble_ops::NIBBLE_LENGTH {
 					if bitmap.value_at(i) {
-						let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+						let count = input.compact_len()?;
 						let range = input.take(count)?;
 						children[i] = Some(if count == H::LENGTH {
 							NodeHandlePlan::Hash(range)
@@ -659,7 +1129,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 					(nibble_count + (nibble_ops::NIBBLE_PER_BYTE - 1)) / nibble_ops::NIBBLE_PER_BYTE
 				)?;
 				let partial_padding = nibble_ops::number_padding(nibble_count);
-				let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+				let count = input.compact_len()?;
 				let range = input.take(count)?;
 				let child = if count == H::LENGTH {
 					NodeHandlePlan::Hash(range)
@@ -676,14 +1146,32 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 					(nibble_count + (nibble_ops::NIBBLE_PER_BYTE - 1)) / nibble_ops::NIBBLE_PER_BYTE
 				)?;
 				let partial_padding = nibble_ops::number_padding(nibble_count);
-				let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+				let count = input.compact_len()?;
 				let value = input.take(count)?;
 				Ok(NodePlan::Leaf {
 					partial: NibbleSlicePlan::new(partial, partial_padding),
 					value,
 				})
 			}
-		}
+		};
+		Ok((plan?, input.offset))
+	}
+}
+
+impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
+	type Error = ReferenceError;
+	type HashOut = H::Out;
+
+	fn hashed_null_node() -> <H as Hasher>::Out {
+		H::hash(<Self as NodeCodec>::empty_node())
+	}
+
+	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+		Ok(Self::decode_plan_and_offset(data)?.0)
+	}
+
+	fn decode_plan_prefix(data: &[u8]) -> ::std::result::Result<(NodePlan, usize), Self::Error> {
+		Self::decode_plan_and_offset(data)
 	}
 
 	fn is_empty_node(data: &[u8]) -> bool {
@@ -694,95 +1182,154 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 		&[EMPTY_TRIE]
 	}
 
-	fn leaf_node(partial: Partial, value: &[u8]) -> Vec<u8> {
-		let mut output = partial_to_key(partial, LEAF_NODE_OFFSET, LEAF_NODE_OVER);
-		value.encode_to(&mut output);
-		output
+	fn leaf_node(partial: Partial, value: &[u8]) -> Result<Vec<u8>, Self::Error> {
+		let mut output = Vec::new();
+		Self::leaf_node_into(&mut output, partial, value)?;
+		Ok(output)
 	}
 
 	fn extension_node(
 		partial: impl Iterator<Item = u8>,
 		number_nibble: usize,
 		child: ChildReference<Self::HashOut>,
-	) -> Vec<u8> {
-		let mut output = partial_from_iterator_to_key(
+	) -> Result<Vec<u8>, Self::Error> {
+		let mut output = Vec::new();
+		Self::extension_node_into(&mut output, partial, number_nibble, child)?;
+		Ok(output)
+	}
+
+	fn branch_node(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Result<Vec<u8>, Self::Error> {
+		let mut output = Vec::new();
+		Self::branch_node_into(&mut output, children, maybe_value)?;
+		Ok(output)
+	}
+
+	fn branch_node_nibbled(
+		_partial:	impl Iterator<Item = u8>,
+		_number_nibble: usize,
+		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		_maybe_value: Option<&[u8]>) -> Result<Vec<u8>, Self::Error> {
+		Err(ReferenceError::UnsupportedNode("nibbled branch"))
+	}
+
+	fn leaf_node_into(
+		output: &mut Vec<u8>,
+		partial: Partial,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		partial_to_key(output, partial, LEAF_NODE_OFFSET, LEAF_NODE_OVER)?;
+		value.encode_to(output);
+		Ok(())
+	}
+
+	fn leaf_node_iter_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		partial_from_iterator_to_key(output, partial, number_nibble, LEAF_NODE_OFFSET, LEAF_NODE_OVER)?;
+		value.encode_to(output);
+		Ok(())
+	}
+
+	fn leaf_node_head_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value_len: usize,
+	) -> Result<bool, Self::Error> {
+		partial_from_iterator_to_key(output, partial, number_nibble, LEAF_NODE_OFFSET, LEAF_NODE_OVER)?;
+		Compact(value_len as u32).encode_to(output);
+		Ok(true)
+	}
+
+	fn extension_node_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child: ChildReference<Self::HashOut>,
+	) -> Result<(), Self::Error> {
+		partial_from_iterator_to_key(
+			output,
 			partial,
 			number_nibble,
 			EXTENSION_NODE_OFFSET,
 			EXTENSION_NODE_OVER,
-		);
+		)?;
 		match child {
-			ChildReference::Hash(h) => h.as_ref().encode_to(&mut output),
+			ChildReference::Hash(h) => h.as_ref().encode_to(output),
 			ChildReference::Inline(inline_data, len) =>
-				(&AsRef::<[u8]>::as_ref(&inline_data)[..len]).encode_to(&mut output),
+				(&AsRef::<[u8]>::as_ref(&inline_data)[..len]).encode_to(output),
 		};
-		output
+		Ok(())
 	}
 
-	fn branch_node(
+	fn branch_node_into(
+		output: &mut Vec<u8>,
 		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
 		maybe_value: Option<&[u8]>,
-	) -> Vec<u8> {
-		let mut output = vec![0; BITMAP_LENGTH + 1];
+	) -> Result<(), Self::Error> {
+		let start = output.len();
+		output.resize(start + BITMAP_LENGTH + 1, 0);
 		let mut prefix: [u8; 3] = [0; 3];
 		let have_value = if let Some(value) = maybe_value {
-			value.encode_to(&mut output);
+			value.encode_to(output);
 			true
 		} else {
 			false
 		};
 		let has_children = children.map(|maybe_child| match maybe_child.borrow() {
 			Some(ChildReference::Hash(h)) => {
-				h.as_ref().encode_to(&mut output);
+				h.as_ref().encode_to(output);
 				true
 			}
 			&Some(ChildReference::Inline(inline_data, len)) => {
-				inline_data.as_ref()[..len].encode_to(&mut output);
+				inline_data.as_ref()[..len].encode_to(output);
 				true
 			}
 			None => false,
 		});
 		branch_node_buffered(have_value, has_children, prefix.as_mut());
-		output[0..BITMAP_LENGTH + 1].copy_from_slice(prefix.as_ref());
-		output
-	}
-
-	fn branch_node_nibbled(
-		_partial:	impl Iterator<Item = u8>,
-		_number_nibble: usize,
-		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
-		_maybe_value: Option<&[u8]>) -> Vec<u8> {
-		unreachable!()
+		output[start..start + BITMAP_LENGTH + 1].copy_from_slice(prefix.as_ref());
+		Ok(())
 	}
 
 }
 
-impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
-	type Error = CodecError;
-	type HashOut = <H as Hasher>::Out;
-
-	fn hashed_null_node() -> <H as Hasher>::Out {
-		H::hash(<Self as NodeCodec>::empty_node())
-	}
-
-	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+impl<H: Hasher, V: NoExtVersion> ReferenceNodeCodecNoExt<H, V> {
+	/// Decode a node plan, also returning the number of bytes read and the node version.
+	/// Nodes of any version up to `V::VERSION` are accepted, versions 0 and 1
+	/// only differing by the version discriminant. Branches without children are
+	/// rejected unless `allow_childless`.
+	fn decode_plan_and_offset(
+		data: &[u8],
+		allow_childless: bool,
+	) -> ::std::result::Result<(NodePlan, usize, u8), ReferenceError> {
 		let mut input = ByteSliceInput::new(data);
-		match NodeHeaderNoExt::decode(&mut input)? {
+		let (version, header) = decode_versioned_header_no_ext(&mut input, V::VERSION)?;
+		let plan: ::std::result::Result<_, ReferenceError> = match header {
 			NodeHeaderNoExt::Null => Ok(NodePlan::Empty),
 			NodeHeaderNoExt::Branch(has_value, nibble_count) => {
 				let padding = nibble_count % nibble_ops::NIBBLE_PER_BYTE != 0;
-				// check that the padding is valid (if any)
-				if padding && nibble_ops::pad_left(data[input.offset]) != 0 {
-					return Err(CodecError::from("Bad format"));
-				}
 				let partial = input.take(
 					(nibble_count + (nibble_ops::NIBBLE_PER_BYTE - 1)) / nibble_ops::NIBBLE_PER_BYTE
 				)?;
+				// check that the padding is valid (if any)
+				if padding && nibble_ops::pad_left(data[partial.start]) != 0 {
+					return Err(ReferenceError::BadPadding { offset: partial.start });
+				}
 				let partial_padding = nibble_ops::number_padding(nibble_count);
 				let bitmap_range = input.take(BITMAP_LENGTH)?;
-				let bitmap = Bitmap::decode(&data[bitmap_range])?;
+				let bitmap = Bitmap::decode(&data[bitmap_range.clone()]);
+				if !allow_childless && bitmap.0 == 0 {
+					return Err(ReferenceError::BadBitmap { offset: bitmap_range.start });
+				}
 				let value = if has_value {
-					let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+					let count = input.compact_len()?;
 					Some(input.take(count)?)
 				} else {
 					None
@@ -793,7 +1340,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 				];
 				for i in 0..nibble_ops::NIBBLE_LENGTH {
 					if bitmap.value_at(i) {
-						let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+						let count = input.compact_len()?;
 						let range = input.take(count)?;
 						children[i] = Some(if count == H::LENGTH {
 							NodeHandlePlan::Hash(range)
@@ -810,22 +1357,46 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 			}
 			NodeHeaderNoExt::Leaf(nibble_count) => {
 				let padding = nibble_count % nibble_ops::NIBBLE_PER_BYTE != 0;
-				// check that the padding is valid (if any)
-				if padding && nibble_ops::pad_left(data[input.offset]) != 0 {
-					return Err(CodecError::from("Bad format"));
-				}
 				let partial = input.take(
 					(nibble_count + (nibble_ops::NIBBLE_PER_BYTE - 1)) / nibble_ops::NIBBLE_PER_BYTE
 				)?;
+				// check that the padding is valid (if any)
+				if padding && nibble_ops::pad_left(data[partial.start]) != 0 {
+					return Err(ReferenceError::BadPadding { offset: partial.start });
+				}
 				let partial_padding = nibble_ops::number_padding(nibble_count);
-				let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+				let count = input.compact_len()?;
 				let value = input.take(count)?;
 				Ok(NodePlan::Leaf {
 					partial: NibbleSlicePlan::new(partial, partial_padding),
 					value,
 				})
 			}
-		}
+		};
+		Ok((plan?, input.offset, version))
+	}
+}
+
+impl<H: Hasher, V: NoExtVersion> NodeCodec for ReferenceNodeCodecNoExt<H, V> {
+	type Error = ReferenceError;
+	type HashOut = <H as Hasher>::Out;
+
+	fn hashed_null_node() -> <H as Hasher>::Out {
+		H::hash(<Self as NodeCodec>::empty_node())
+	}
+
+	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+		Ok(Self::decode_plan_and_offset(data, true)?.0)
+	}
+
+	fn decode_plan_prefix(data: &[u8]) -> ::std::result::Result<(NodePlan, usize), Self::Error> {
+		let (plan, offset, _) = Self::decode_plan_and_offset(data, true)?;
+		Ok((plan, offset))
+	}
+
+	fn decode_plan_versioned(data: &[u8]) -> ::std::result::Result<(NodePlan, u8), Self::Error> {
+		let (plan, _, version) = Self::decode_plan_and_offset(data, true)?;
+		Ok((plan, version))
 	}
 
 	fn is_empty_node(data: &[u8]) -> bool {
@@ -836,25 +1407,25 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 		&[EMPTY_TRIE_NO_EXT]
 	}
 
-	fn leaf_node(partial: Partial, value: &[u8]) -> Vec<u8> {
-		let mut output = partial_encode(partial, NodeKindNoExt::Leaf);
-		value.encode_to(&mut output);
-		output
+	fn leaf_node(partial: Partial, value: &[u8]) -> Result<Vec<u8>, Self::Error> {
+		let mut output = Vec::new();
+		Self::leaf_node_into(&mut output, partial, value)?;
+		Ok(output)
 	}
 
 	fn extension_node(
 		_partial: impl Iterator<Item = u8>,
 		_nbnibble: usize,
 		_child: ChildReference<<H as Hasher>::Out>,
-	) -> Vec<u8> {
-		unreachable!()
+	) -> Result<Vec<u8>, Self::Error> {
+		Err(ReferenceError::UnsupportedNode("extension"))
 	}
 
 	fn branch_node(
 		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<<H as Hasher>::Out>>>>,
 		_maybe_value: Option<&[u8]>,
-	) -> Vec<u8> {
-		unreachable!()
+	) -> Result<Vec<u8>, Self::Error> {
+		Err(ReferenceError::UnsupportedNode("branch without partial key"))
 	}
 
 	fn branch_node_nibbled(
@@ -862,42 +1433,206 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 		number_nibble: usize,
 		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
 		maybe_value: Option<&[u8]>,
-	) -> Vec<u8> {
-		let mut output = if maybe_value.is_some() {
-			partial_from_iterator_encode(
-				partial,
-				number_nibble,
-				NodeKindNoExt::BranchWithValue,
-			)
+	) -> Result<Vec<u8>, Self::Error> {
+		let mut output = Vec::new();
+		Self::branch_node_nibbled_into(&mut output, partial, number_nibble, children, maybe_value)?;
+		Ok(output)
+	}
+
+	fn leaf_node_into(
+		output: &mut Vec<u8>,
+		partial: Partial,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		partial_encode(output, partial, NodeKindNoExt::Leaf, V::VERSION)?;
+		value.encode_to(output);
+		Ok(())
+	}
+
+	fn leaf_node_iter_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		partial_from_iterator_encode(output, partial, number_nibble, NodeKindNoExt::Leaf, V::VERSION)?;
+		value.encode_to(output);
+		Ok(())
+	}
+
+	fn leaf_node_head_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value_len: usize,
+	) -> Result<bool, Self::Error> {
+		partial_from_iterator_encode(output, partial, number_nibble, NodeKindNoExt::Leaf, V::VERSION)?;
+		Compact(value_len as u32).encode_to(output);
+		Ok(true)
+	}
+
+	fn branch_node_nibbled_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Result<(), Self::Error> {
+		let node_kind = if maybe_value.is_some() {
+			NodeKindNoExt::BranchWithValue
 		} else {
-			partial_from_iterator_encode(
-				partial,
-				number_nibble,
-				NodeKindNoExt::BranchNoValue,
-			)
+			NodeKindNoExt::BranchNoValue
 		};
+		partial_from_iterator_encode(output, partial, number_nibble, node_kind, V::VERSION)?;
 		let bitmap_index = output.len();
 		let mut bitmap: [u8; BITMAP_LENGTH] = [0; BITMAP_LENGTH];
 		(0..BITMAP_LENGTH).for_each(|_| output.push(0));
 		if let Some(value) = maybe_value {
-			value.encode_to(&mut output);
+			value.encode_to(output);
 		};
 		Bitmap::encode(children.map(|maybe_child| match maybe_child.borrow() {
 			Some(ChildReference::Hash(h)) => {
-				h.as_ref().encode_to(&mut output);
+				h.as_ref().encode_to(output);
 				true
 			}
 			&Some(ChildReference::Inline(inline_data, len)) => {
-				inline_data.as_ref()[..len].encode_to(&mut output);
+				inline_data.as_ref()[..len].encode_to(output);
 				true
 			}
 			None => false,
 		}), bitmap.as_mut());
 		output[bitmap_index..bitmap_index + BITMAP_LENGTH]
 			.copy_from_slice(&bitmap.as_ref()[..BITMAP_LENGTH]);
-		output
+		Ok(())
+	}
+
+}
+
+/// Node codec of the production Substrate trie format, as implemented by `sp-trie`.
+/// Nodes are encoded as with `ReferenceNodeCodecNoExt` (version 0 headers), but decoding
+/// also rejects branches without children, as `sp-trie` does. Nodes with hashed values
+/// are not supported.
+#[derive(Default, Clone)]
+pub struct SubstrateNodeCodec<H>(PhantomData<H>);
+
+impl<H: Hasher> SubstrateNodeCodec<H> {
+	/// Reject the node kinds this codec does not support.
+	fn check_header(data: &[u8]) -> ::std::result::Result<(), ReferenceError> {
+		match data.first() {
+			Some(&i) if i & (0b111 << 5) == ALT_HASHING_LEAF_PREFIX_MASK
+				|| i & (0b1111 << 4) == ALT_HASHING_BRANCH_WITH_MASK =>
+				Err(ReferenceError::InvalidHeader { offset: 0 }),
+			_ => Ok(()),
+		}
+	}
+
+	/// Decode a node plan, also returning the number of bytes read. Branches without
+	/// children, which the substrate format does not allow, are rejected.
+	fn decode_plan_and_offset(data: &[u8]) -> ::std::result::Result<(NodePlan, usize), ReferenceError> {
+		Self::check_header(data)?;
+		let (plan, offset, _) = ReferenceNodeCodecNoExt::<H>::decode_plan_and_offset(data, false)?;
+		Ok((plan, offset))
+	}
+}
+
+impl<H: Hasher> NodeCodec for SubstrateNodeCodec<H> {
+	type Error = ReferenceError;
+	type HashOut = <H as Hasher>::Out;
+
+	fn hashed_null_node() -> <H as Hasher>::Out {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::hashed_null_node()
+	}
+
+	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+		Ok(Self::decode_plan_and_offset(data)?.0)
+	}
+
+	fn decode_plan_prefix(data: &[u8]) -> ::std::result::Result<(NodePlan, usize), Self::Error> {
+		Self::decode_plan_and_offset(data)
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::is_empty_node(data)
+	}
+
+	fn empty_node() -> &'static [u8] {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::empty_node()
+	}
+
+	fn leaf_node(partial: Partial, value: &[u8]) -> Result<Vec<u8>, Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::leaf_node(partial, value)
+	}
+
+	fn extension_node(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child: ChildReference<<H as Hasher>::Out>,
+	) -> Result<Vec<u8>, Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::extension_node(partial, number_nibble, child)
+	}
+
+	fn branch_node(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<<H as Hasher>::Out>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Result<Vec<u8>, Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::branch_node(children, maybe_value)
+	}
+
+	fn branch_node_nibbled(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Result<Vec<u8>, Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::branch_node_nibbled(
+			partial,
+			number_nibble,
+			children,
+			maybe_value,
+		)
+	}
+
+	fn leaf_node_into(
+		output: &mut Vec<u8>,
+		partial: Partial,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::leaf_node_into(output, partial, value)
+	}
+
+	fn leaf_node_iter_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::leaf_node_iter_into(output, partial, number_nibble, value)
+	}
+
+	fn leaf_node_head_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value_len: usize,
+	) -> Result<bool, Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::leaf_node_head_into(output, partial, number_nibble, value_len)
 	}
 
+	fn branch_node_nibbled_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Result<(), Self::Error> {
+		<ReferenceNodeCodecNoExt<H> as NodeCodec>::branch_node_nibbled_into(
+			output,
+			partial,
+			number_nibble,
+			children,
+			maybe_value,
+		)
+	}
 }
 
 /// Compare trie builder and in memory trie.
@@ -908,7 +1643,7 @@ pub fn compare_implementations<X : hash_db::HashDB<KeccakHasher, DBValue> + Eq>
 ) {
 	let root_new = {
 		let mut cb = TrieBuilder::new(&mut hashdb);
-		trie_visit::<ExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb);
+		trie_visit::<ExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb).unwrap();
 		cb.root.unwrap_or(Default::default())
 	};
 	let root = {
@@ -951,7 +1686,7 @@ pub fn compare_root(
 ) {
 	let root_new = {
 		let mut cb = TrieRoot::<KeccakHasher, _>::default();
-		trie_visit::<ExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb);
+		trie_visit::<ExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb).unwrap();
 		cb.root.unwrap_or(Default::default())
 	};
 	let root = {
@@ -972,7 +1707,7 @@ pub fn compare_unhashed(
 ) {
 	let root_new = {
 		let mut cb = trie_db::TrieRootUnhashed::<KeccakHasher>::default();
-		trie_visit::<ExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb);
+		trie_visit::<ExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb).unwrap();
 		cb.root.unwrap_or(Default::default())
 	};
 	let root = reference_trie_root_unhashed(data);
@@ -987,7 +1722,7 @@ pub fn compare_unhashed_no_extension(
 ) {
 	let root_new = {
 		let mut cb = trie_db::TrieRootUnhashed::<KeccakHasher>::default();
-		trie_visit::<NoExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb);
+		trie_visit::<NoExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb).unwrap();
 		cb.root.unwrap_or(Default::default())
 	};
 	let root = reference_trie_root_unhashed_no_extension(data);
@@ -1005,7 +1740,7 @@ pub fn calc_root<I, A, B>(
 		B: AsRef<[u8]> + fmt::Debug,
 {
 	let mut cb = TrieRoot::<KeccakHasher, _>::default();
-	trie_visit::<ExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb);
+	trie_visit::<ExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb).unwrap();
 	cb.root.unwrap_or(Default::default())
 }
 
@@ -1020,7 +1755,7 @@ pub fn calc_root_no_extension<I, A, B>(
 		B: AsRef<[u8]> + fmt::Debug,
 {
 	let mut cb = TrieRoot::<KeccakHasher, _>::default();
-	trie_db::trie_visit::<NoExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb);
+	trie_db::trie_visit::<NoExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb).unwrap();
 	cb.root.unwrap_or(Default::default())
 }
 
@@ -1036,7 +1771,7 @@ pub fn calc_root_build<I, A, B, DB>(
 		DB: hash_db::HashDB<KeccakHasher, DBValue>
 {
 	let mut cb = TrieBuilder::new(hashdb);
-	trie_visit::<ExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb);
+	trie_visit::<ExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb).unwrap();
 	cb.root.unwrap_or(Default::default())
 }
 
@@ -1053,7 +1788,7 @@ pub fn calc_root_build_no_extension<I, A, B, DB>(
 		DB: hash_db::HashDB<KeccakHasher, DBValue>
 {
 	let mut cb = TrieBuilder::new(hashdb);
-	trie_db::trie_visit::<NoExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb);
+	trie_db::trie_visit::<NoExtensionLayout, _, _, _, _>(data.into_iter(), &mut cb).unwrap();
 	cb.root.unwrap_or(Default::default())
 }
 
@@ -1066,7 +1801,7 @@ pub fn compare_implementations_no_extension(
 ) {
 	let root_new = {
 		let mut cb = TrieBuilder::new(&mut hashdb);
-		trie_visit::<NoExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb);
+		trie_visit::<NoExtensionLayout, _, _, _, _>(data.clone().into_iter(), &mut cb).unwrap();
 		cb.root.unwrap_or(Default::default())
 	};
 	let root = {
@@ -1119,7 +1854,7 @@ pub fn compare_implementations_no_extension_unordered(
 	};
 	let root_new = {
 		let mut cb = TrieBuilder::new(&mut hashdb);
-		trie_visit::<NoExtensionLayout, _, _, _, _>(b_map.into_iter(), &mut cb);
+		trie_visit::<NoExtensionLayout, _, _, _, _>(b_map.into_iter(), &mut cb).unwrap();
 		cb.root.unwrap_or(Default::default())
 	};
 
@@ -1188,10 +1923,77 @@ pub fn compare_no_extension_insert_remove(
 	assert_eq!(*t.root(), calc_root_no_extension(data2));
 }
 
+/// Operation applied by `check_trie_operations`.
+#[derive(Clone, Debug)]
+pub enum TrieOperation {
+	/// Insert a value.
+	Insert(Vec<u8>, Vec<u8>),
+	/// Remove a key.
+	Remove(Vec<u8>),
+	/// Iterate over the whole trie.
+	Iterate,
+	/// Prove the values of the keys, present or not.
+	Prove(Vec<Vec<u8>>),
+}
+
+/// Applies `operations` to a trie of layout `L` stored in `memdb` and to a `BTreeMap`
+/// model, checking after each of them that the trie matches the model: values, iteration,
+/// proofs, and the root computed by `trie_visit`. Panics on mismatch.
+pub fn check_trie_operations<L: TrieConfiguration>(
+	operations: &[TrieOperation],
+	mut memdb: impl hash_db::HashDB<L::Hash, DBValue>,
+) {
+	let mut model = std::collections::BTreeMap::<Vec<u8>, Vec<u8>>::new();
+	let mut root = Default::default();
+	TrieDBMut::<L>::new(&mut memdb, &mut root).commit();
+	for operation in operations {
+		match operation {
+			TrieOperation::Insert(key, value) => {
+				let mut t = TrieDBMut::<L>::from_existing(&mut memdb, &mut root).unwrap();
+				let old = t.insert(key, value).unwrap();
+				assert_eq!(old.map(Vec::from), model.insert(key.clone(), value.clone()), "{:?}", operation);
+			},
+			TrieOperation::Remove(key) => {
+				let mut t = TrieDBMut::<L>::from_existing(&mut memdb, &mut root).unwrap();
+				let old = t.remove(key).unwrap();
+				assert_eq!(old.map(Vec::from), model.remove(key), "{:?}", operation);
+			},
+			TrieOperation::Iterate => {
+				let db: &dyn hash_db::HashDB<L::Hash, DBValue> = &memdb;
+				let t = TrieDB::<L>::new(&db, &root).unwrap();
+				let items: Vec<_> = t.iter().unwrap()
+					.map(|item| item.map(|(key, value)| (key, value.to_vec())).unwrap())
+					.collect();
+				let mut expected: Vec<_> = model.iter()
+					.map(|(key, value)| if L::HASH_KEYS {
+						(L::Hash::hash(key).as_ref().to_vec(), value.clone())
+					} else {
+						(key.clone(), value.clone())
+					})
+					.collect();
+				expected.sort();
+				assert_eq!(items, expected);
+			},
+			TrieOperation::Prove(keys) => {
+				let db: &dyn hash_db::HashDB<L::Hash, DBValue> = &memdb;
+				let t = TrieDB::<L>::new(&db, &root).unwrap();
+				let proof = proof::generate_proof::<_, L, _, _>(&t, keys).unwrap();
+				let items: Vec<_> = keys.iter()
+					.map(|key| (key.clone(), model.get(key).cloned()))
+					.collect();
+				proof::verify_proof::<L, _, _, _>(&root, &proof, items.iter()).unwrap();
+			},
+		}
+		assert_eq!(root, L::trie_root(model.iter()).unwrap(), "{:?}", operation);
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use trie_db::node::Node;
+	use trie_standardmap::{Alphabet, StandardMap, ValueMode};
+	use proptest::{collection, prelude::*};
 
 	#[test]
 	fn test_encoding_simple_trie() {
@@ -1203,8 +2005,8 @@ mod tests {
 			for i in (0..1000).chain(NIBBLE_SIZE_BOUND_NO_EXT - 2..NIBBLE_SIZE_BOUND_NO_EXT + 2) {
 				let mut output = Vec::new();
 				encode_size_and_prefix(i, *prefix, &mut output);
-				let input = &mut &output[..];
-				let first = input.read_byte().unwrap();
+				let input = &mut ByteSliceInput::new(&output[..]);
+				let first = input.next_byte().unwrap();
 				assert_eq!(first & (0b11 << 6), *prefix);
 				let v = decode_size(first, input);
 				assert_eq!(Ok(std::cmp::min(i, NIBBLE_SIZE_BOUND_NO_EXT)), v);
@@ -1216,8 +2018,14 @@ mod tests {
 	fn too_big_nibble_length() {
 		// + 1 for 0 added byte of nibble encode
 		let input = vec![0u8; (NIBBLE_SIZE_BOUND_NO_EXT as usize + 1) / 2 + 1];
+		assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
+			::leaf_node(((0, 0), &input), &[1]).is_err());
+		assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>
+			::leaf_node(((0, 0), &[0u8; 64]), &[1]).is_err());
+
+		let input = vec![0u8; NIBBLE_SIZE_BOUND_NO_EXT as usize / 2];
 		let enc = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
-		::leaf_node(((0, 0), &input), &[1]);
+		::leaf_node(((0, 0), &input), &[1]).unwrap();
 		let dec = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
 		::decode(&enc).unwrap();
 		let o_sl = if let Node::Leaf(sl, _) = dec {
@@ -1226,6 +2034,17 @@ mod tests {
 		assert!(o_sl.is_some());
 	}
 
+	#[test]
+	fn too_big_nibble_length_in_stream() {
+		let key = vec![0u8; 64];
+		assert!(trie_root::trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(
+			vec![(&key, &[1u8])],
+		).is_err());
+		assert!(trie_root::trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(
+			vec![(&key[..63], &[1u8])],
+		).is_ok());
+	}
+
 	#[test]
 	fn size_encode_limit_values() {
 		let sizes = [0, 1, 62, 63, 64, 317, 318, 319, 572, 573, 574];
@@ -1246,8 +2065,659 @@ mod tests {
 			let mut enc = Vec::new();
 			encode_size_and_prefix(sizes[i], 0, &mut enc);
 			assert_eq!(enc, encs[i]);
-			let s_dec = decode_size(encs[i][0], &mut &encs[i][1..]);
+			let input = &mut ByteSliceInput::new(&encs[i][..]);
+			let first = input.next_byte().unwrap();
+			let s_dec = decode_size(first, input);
 			assert_eq!(s_dec, Ok(sizes[i]));
 		}
 	}
+
+	#[test]
+	fn decode_plan_prefix_excludes_trailing_bytes() {
+		let mut enc = <ReferenceNodeCodec<KeccakHasher> as NodeCodec>
+		::leaf_node(((0, 0), &[1, 2]), &[3; 4]).unwrap();
+		let len = enc.len();
+		enc.push(0);
+		assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::decode(&enc).is_ok());
+		assert_eq!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>
+			::decode_plan_prefix(&enc).unwrap().1, len);
+
+		let mut enc = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
+		::leaf_node(((0, 0), &[1, 2]), &[3; 4]).unwrap();
+		let len = enc.len();
+		enc.push(0);
+		assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&enc).is_ok());
+		assert_eq!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
+			::decode_plan_prefix(&enc).unwrap().1, len);
+	}
+
+	#[test]
+	fn non_canonical_leaves_are_detected() {
+		let partial = ((1, 0x01), &[0x23][..]);
+		let enc = <ReferenceNodeCodec<KeccakHasher> as NodeCodec>
+		::leaf_node(partial, &[7; 4]).unwrap();
+		assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::is_canonical(&enc));
+		let mut padded = enc.clone();
+		padded[1] |= 0xf0;
+		assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::decode(&padded).is_ok());
+		assert!(!<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::is_canonical(&padded));
+		let mut trailing = enc.clone();
+		trailing.push(0);
+		assert!(!<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::is_canonical(&trailing));
+
+		let enc = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
+		::leaf_node(partial, &[7; 4]).unwrap();
+		assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::is_canonical(&enc));
+		// this codec already rejects non-zero padding when decoding.
+		let mut padded = enc.clone();
+		padded[1] |= 0xf0;
+		assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&padded).is_err());
+		assert!(!<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::is_canonical(&padded));
+		let mut trailing = enc.clone();
+		trailing.push(0);
+		assert!(!<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::is_canonical(&trailing));
+	}
+
+	#[test]
+	fn decode_errors_report_offset() {
+		let partial = ((1, 0x01), &[0x23][..]);
+		let enc = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
+		::leaf_node(partial, &[7; 4]).unwrap();
+		assert_eq!(
+			<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&enc[..enc.len() - 1]),
+			Err(ReferenceError::TruncatedInput { offset: 4 }),
+		);
+		let mut padded = enc.clone();
+		padded[1] |= 0xf0;
+		assert_eq!(
+			<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&padded),
+			Err(ReferenceError::BadPadding { offset: 1 }),
+		);
+
+		let childless = <SubstrateNodeCodec<KeccakHasher> as NodeCodec>::branch_node_nibbled(
+			(&[][..]).iter().cloned(),
+			0,
+			std::iter::repeat(None::<ChildReference<_>>).take(16),
+			Some(&[7; 4]),
+		).unwrap();
+		assert!(matches!(
+			<SubstrateNodeCodec<KeccakHasher> as NodeCodec>::decode(&childless),
+			Err(ReferenceError::BadBitmap { .. }),
+		));
+	}
+
+	#[test]
+	fn versioned_no_ext_headers() {
+		type V0 = ReferenceNodeCodecNoExt<KeccakHasher>;
+		type V1 = ReferenceNodeCodecNoExt<KeccakHasher, NoExtV1>;
+		let partial = ((1, 0x01), &[0x23][..]);
+		let enc_v0 = <V0 as NodeCodec>::leaf_node(partial, &[7; 4]).unwrap();
+		let enc_v1 = <V1 as NodeCodec>::leaf_node(partial, &[7; 4]).unwrap();
+		assert_eq!(enc_v1[0], 1);
+		assert_eq!(&enc_v1[1..], &enc_v0[..]);
+
+		let node = <V0 as NodeCodec>::decode(&enc_v0).unwrap();
+		assert_eq!(<V1 as NodeCodec>::decode(&enc_v0).unwrap(), node);
+		assert_eq!(<V1 as NodeCodec>::decode(&enc_v1).unwrap(), node);
+		assert!(<V0 as NodeCodec>::decode(&enc_v1).is_err());
+		assert_eq!(<V1 as NodeCodec>::decode_plan_versioned(&enc_v0).unwrap().1, 0);
+		assert_eq!(<V1 as NodeCodec>::decode_plan_versioned(&enc_v1).unwrap().1, 1);
+		assert_eq!(<V0 as NodeCodec>::decode_plan_versioned(&enc_v0).unwrap().1, 0);
+
+		assert_eq!(<V1 as NodeCodec>::empty_node(), <V0 as NodeCodec>::empty_node());
+		assert!(<V1 as NodeCodec>::decode(&[1, EMPTY_TRIE_NO_EXT]).is_err());
+	}
+
+	#[test]
+	fn encode_into_appends_to_buffer() {
+		type Ext = ReferenceNodeCodec<KeccakHasher>;
+		type NoExt = ReferenceNodeCodecNoExt<KeccakHasher>;
+		let partial = ((1, 0x01), &[0x23][..]);
+		let children = [Some(ChildReference::Inline(Default::default(), 2)), None];
+
+		let mut buffer = vec![0xff];
+		<Ext as NodeCodec>::leaf_node_into(&mut buffer, partial, &[7; 4]).unwrap();
+		let leaf = <Ext as NodeCodec>::leaf_node(partial, &[7; 4]).unwrap();
+		assert_eq!(buffer[0], 0xff);
+		assert_eq!(&buffer[1..], &leaf[..]);
+
+		buffer.truncate(1);
+		<Ext as NodeCodec>::branch_node_into(&mut buffer, children.iter(), Some(&[1])).unwrap();
+		let branch = <Ext as NodeCodec>::branch_node(children.iter(), Some(&[1])).unwrap();
+		assert_eq!(&buffer[1..], &branch[..]);
+
+		buffer.truncate(1);
+		<NoExt as NodeCodec>::branch_node_nibbled_into(
+			&mut buffer,
+			[0x01, 0x23].iter().cloned(),
+			3,
+			children.iter(),
+			None,
+		).unwrap();
+		let branch = <NoExt as NodeCodec>::branch_node_nibbled(
+			[0x01, 0x23].iter().cloned(),
+			3,
+			children.iter(),
+			None,
+		).unwrap();
+		assert_eq!(&buffer[1..], &branch[..]);
+	}
+
+	fn check_leaf_node_iter<C: NodeCodec>() {
+		for key in [&b""[..], &b"a"[..], &b"abc"[..]].iter() {
+			for offset in 0..2 {
+				let partial = NibbleSlice::new_offset(key, offset.min(key.len()));
+				let leaf = C::leaf_node(partial.right(), &[7; 4]).unwrap();
+				let leaf_iter = C::leaf_node_iter(partial.right_iter(), partial.len(), &[7; 4]).unwrap();
+				assert_eq!(leaf_iter, leaf);
+			}
+		}
+	}
+
+	#[test]
+	fn leaf_node_iter_matches_leaf_node() {
+		check_leaf_node_iter::<ReferenceNodeCodec<KeccakHasher>>();
+		check_leaf_node_iter::<ReferenceNodeCodecNoExt<KeccakHasher>>();
+		check_leaf_node_iter::<ReferenceNodeCodecNoExt<KeccakHasher, NoExtV1>>();
+		check_leaf_node_iter::<SubstrateNodeCodec<KeccakHasher>>();
+		assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::leaf_node_iter(
+			[0u8; 64].iter().cloned(),
+			128,
+			&[1],
+		).is_err());
+	}
+
+	fn check_leaf_node_head<C: NodeCodec>() {
+		let key = &b"abc"[..];
+		for value in [vec![7u8; 4], vec![7u8; 100_000]].iter() {
+			let partial = NibbleSlice::new_offset(key, 1);
+			let mut head = Vec::new();
+			assert!(C::leaf_node_head_into(&mut head, partial.right_iter(), partial.len(), value.len()).unwrap());
+			head.extend_from_slice(value);
+			assert_eq!(head, C::leaf_node(partial.right(), value).unwrap());
+		}
+	}
+
+	#[test]
+	fn leaf_node_head_is_leaf_node_before_value() {
+		check_leaf_node_head::<ReferenceNodeCodec<KeccakHasher>>();
+		check_leaf_node_head::<ReferenceNodeCodecNoExt<KeccakHasher>>();
+		check_leaf_node_head::<ReferenceNodeCodecNoExt<KeccakHasher, NoExtV1>>();
+		check_leaf_node_head::<SubstrateNodeCodec<KeccakHasher>>();
+	}
+
+	fn check_leaf_node_chunked<C: NodeCodec>() {
+		let key = &b"abc"[..];
+		let values = [vec![], vec![7u8], vec![0x80u8], vec![7u8; 4], (0..100_000).map(|i| i as u8).collect()];
+		for value in values.iter() {
+			for offset in 0..2 {
+				let partial = NibbleSlice::new_offset(key, offset);
+				let chunked = C::leaf_node_chunked(
+					partial.right_iter(),
+					partial.len(),
+					value.len(),
+					value.chunks(3),
+				).unwrap();
+				assert_eq!(chunked, C::leaf_node(partial.right(), value).unwrap());
+			}
+		}
+
+		let partial = NibbleSlice::new(key);
+		let mut output = vec![1, 2];
+		assert!(matches!(
+			C::leaf_node_chunked_into(&mut output, partial.right_iter(), partial.len(), 5, vec![&[7u8; 4][..]]),
+			Err(trie_db::EncodeNodeError::ValueLength { expected: 5, actual: 4 }),
+		));
+		assert_eq!(output, vec![1, 2]);
+	}
+
+	#[test]
+	fn leaf_node_head_is_leaf_node_before_value_rlp() {
+		check_leaf_node_head::<RlpNodeCodec<KeccakHasher>>();
+	}
+
+	#[test]
+	fn leaf_node_chunked_matches_leaf_node() {
+		check_leaf_node_chunked::<ReferenceNodeCodec<KeccakHasher>>();
+		check_leaf_node_chunked::<ReferenceNodeCodecNoExt<KeccakHasher>>();
+		check_leaf_node_chunked::<SubstrateNodeCodec<KeccakHasher>>();
+		check_leaf_node_chunked::<RlpNodeCodec<KeccakHasher>>();
+	}
+
+	#[test]
+	fn decode_prefix_of_node_stream() {
+		type NoExt = ReferenceNodeCodecNoExt<KeccakHasher>;
+		let leaf = <NoExt as NodeCodec>::leaf_node(((1, 0x01), &[0x23][..]), &[7; 4]).unwrap();
+		let branch = <NoExt as NodeCodec>::branch_node_nibbled(
+			[0x01].iter().cloned(),
+			2,
+			[Some(ChildReference::Inline(Default::default(), 2))].iter(),
+			Some(&[1]),
+		).unwrap();
+		let mut stream = leaf.clone();
+		stream.extend_from_slice(&branch);
+
+		let (node, len) = <NoExt as NodeCodec>::decode_prefix(&stream).unwrap();
+		assert_eq!(len, leaf.len());
+		assert_eq!(node, <NoExt as NodeCodec>::decode(&leaf).unwrap());
+		let (node, len) = <NoExt as NodeCodec>::decode_prefix(&stream[len..]).unwrap();
+		assert_eq!(len, branch.len());
+		assert_eq!(node, <NoExt as NodeCodec>::decode(&branch).unwrap());
+		assert!(<NoExt as NodeCodec>::decode_prefix(&leaf[..leaf.len() - 1]).is_err());
+	}
+
+	/// Short keys over a small alphabet, so that keys are often reused and share prefixes.
+	fn key_strategy() -> impl Strategy<Value = Vec<u8>> {
+		collection::vec((0..4u8).prop_map(|nibble| nibble * 0x11), 0..5)
+	}
+
+	fn operation_strategy() -> impl Strategy<Value = TrieOperation> {
+		prop_oneof![
+			6 => (key_strategy(), any::<u8>(), 1..40usize)
+				.prop_map(|(key, byte, len)| TrieOperation::Insert(key, vec![byte; len])),
+			2 => key_strategy().prop_map(TrieOperation::Remove),
+			1 => Just(TrieOperation::Iterate),
+			1 => collection::btree_set(key_strategy(), 1..4)
+				.prop_map(|keys| TrieOperation::Prove(keys.into_iter().collect())),
+		]
+	}
+
+	macro_rules! check_layout_operations {
+		($($name:ident: $layout:ty,)*) => {
+			proptest! {
+				#![proptest_config(ProptestConfig::with_cases(32))]
+				$(
+					#[test]
+					fn $name(operations in collection::vec(operation_strategy(), 0..200)) {
+						let memdb = memory_db::MemoryDB::<
+							<$layout as TrieLayout>::Hash,
+							memory_db::PrefixedKey<_>,
+							DBValue,
+						>::default();
+						check_trie_operations::<$layout>(&operations, memdb);
+					}
+				)*
+			}
+		};
+	}
+
+	check_layout_operations! {
+		operations_extension_layout: ExtensionLayout,
+		operations_hashed_key_extension_layout: HashedKeyExtensionLayout,
+		operations_no_extension_layout: NoExtensionLayout,
+		operations_no_extension_layout_v1: NoExtensionLayoutV1,
+		operations_substrate_layout: SubstrateLayout<KeccakHasher>,
+		operations_fast_extension_layout: FastExtensionLayout,
+		operations_fast_no_extension_layout: FastNoExtensionLayout,
+		operations_keccak512_extension_layout: Keccak512ExtensionLayout,
+		operations_keccak512_no_extension_layout: Keccak512NoExtensionLayout,
+	}
+
+	#[cfg(feature = "sha2")]
+	check_layout_operations! {
+		operations_sha2_extension_layout: Sha2ExtensionLayout,
+		operations_sha2_no_extension_layout: Sha2NoExtensionLayout,
+	}
+
+	#[cfg(feature = "blake3")]
+	check_layout_operations! {
+		operations_blake3_extension_layout: Blake3ExtensionLayout,
+		operations_blake3_no_extension_layout: Blake3NoExtensionLayout,
+	}
+
+	fn trie_nodes<L: TrieLayout>(
+		dataset: &[(&[u8], &[u8])],
+	) -> (<L::Hash as Hasher>::Out, Vec<Vec<u8>>) {
+		let mut db = memory_db::MemoryDB::<L::Hash, memory_db::HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+			for (key, value) in dataset {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let mut nodes: Vec<_> = db.drain().into_iter()
+			.filter(|(_, (_, rc))| *rc > 0)
+			.map(|(_, (node, _))| node.to_vec())
+			.collect();
+		nodes.sort();
+		(root, nodes)
+	}
+
+	fn check_encode_node_round_trip<L: TrieLayout>(dataset: &[(Vec<u8>, Vec<u8>)]) {
+		let dataset: Vec<_> = dataset.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+		let (_, nodes) = trie_nodes::<L>(&dataset);
+		assert!(nodes.len() > 10);
+		for node in &nodes {
+			let decoded = L::Codec::decode(node).unwrap();
+			assert_eq!(&L::Codec::encode_node(&decoded).unwrap(), node);
+		}
+
+		// modify a decoded leaf and encode it back.
+		let leaf = L::Codec::leaf_node(NibbleSlice::new(b"ab").right(), b"value").unwrap();
+		let modified = match L::Codec::decode(&leaf).unwrap() {
+			Node::Leaf(partial, _) => Node::Leaf(partial, b"other"),
+			node => panic!("Unexpected node {:?}", node),
+		};
+		let encoded = L::Codec::encode_node(&modified).unwrap();
+		assert_eq!(encoded, L::Codec::leaf_node(NibbleSlice::new(b"ab").right(), b"other").unwrap());
+		assert_eq!(L::Codec::decode(&encoded).unwrap(), modified);
+
+		// an inline child too large to be referenced.
+		let large = vec![0u8; L::Hash::LENGTH + 8];
+		let mut children = [None; nibble_ops::NIBBLE_LENGTH];
+		children[0] = Some(trie_db::node::NodeHandle::Inline(&large[..]));
+		let branch = match L::USE_EXTENSION {
+			true => Node::Branch(children, None),
+			false => Node::NibbledBranch(NibbleSlice::new(&[]), children, None),
+		};
+		match L::Codec::encode_node(&branch) {
+			Err(trie_db::EncodeNodeError::InvalidChild(data)) => assert_eq!(data, large.to_vec()),
+			_ => panic!("Inline child of {} bytes must be rejected", large.len()),
+		}
+	}
+
+	#[test]
+	fn encode_node_round_trip() {
+		let dataset: Vec<(Vec<u8>, Vec<u8>)> = (0..40u8)
+			.map(|i| (vec![i / 4, i], vec![i; i as usize]))
+			.collect();
+		check_encode_node_round_trip::<ExtensionLayout>(&dataset);
+		check_encode_node_round_trip::<NoExtensionLayout>(&dataset);
+		check_encode_node_round_trip::<NoExtensionLayoutV1>(&dataset);
+
+		// values long enough for leaves not to be inlined with 64 byte hashes.
+		let dataset: Vec<(Vec<u8>, Vec<u8>)> = (0..40u8)
+			.map(|i| (vec![i / 4, i], vec![i; i as usize + 64]))
+			.collect();
+		check_encode_node_round_trip::<Keccak512ExtensionLayout>(&dataset);
+		check_encode_node_round_trip::<Keccak512NoExtensionLayout>(&dataset);
+	}
+
+	#[test]
+	fn inline_children_longer_than_32_bytes() {
+		// leaves of about 45 bytes: hashed with 32 byte hashes, inlined with 64 byte ones.
+		let dataset: &[(&[u8], &[u8])] = &[(b"alfa", &[1; 40]), (b"beta", &[2; 40])];
+		let (_, nodes) = trie_nodes::<NoExtensionLayout>(dataset);
+		assert_eq!(nodes.len(), 3);
+		let (root, nodes) = trie_nodes::<Keccak512NoExtensionLayout>(dataset);
+		assert_eq!(nodes.len(), 1);
+		assert_eq!(root, Keccak512Hasher::hash(&nodes[0]));
+		assert_eq!(root, Keccak512NoExtensionLayout::trie_root(dataset.iter().cloned()).unwrap());
+		match <Keccak512NoExtensionLayout as TrieLayout>::Codec::decode(&nodes[0]).unwrap() {
+			Node::NibbledBranch(_, children, None) => {
+				let inline: Vec<_> = children.iter().flatten().map(|child| match child {
+					trie_db::node::NodeHandle::Inline(data) => data.len(),
+					trie_db::node::NodeHandle::Hash(_) => panic!("Child must be inline"),
+				}).collect();
+				assert_eq!(inline.len(), 2);
+				assert!(inline.iter().all(|len| *len > 32 && *len < 64));
+			},
+			node => panic!("Unexpected root {:?}", node),
+		}
+
+		let mut memdb = memory_db::MemoryDB::<Keccak512Hasher, memory_db::HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<Keccak512ExtensionLayout>::new(&mut memdb, &mut root);
+			for (key, value) in dataset {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let t = TrieDB::<Keccak512ExtensionLayout>::new(&memdb, &root).unwrap();
+		assert_eq!(t.get(b"alfa").unwrap(), Some(vec![1; 40].into()));
+		assert_eq!(t.get(b"beta").unwrap(), Some(vec![2; 40].into()));
+		let proof = proof::generate_proof::<_, Keccak512ExtensionLayout, _, _>(&t, &[b"alfa"]).unwrap();
+		proof::verify_proof::<Keccak512ExtensionLayout, _, _, _>(
+			&root,
+			&proof,
+			&[(b"alfa", Some(vec![1; 40]))],
+		).unwrap();
+	}
+
+	#[test]
+	#[cfg(all(feature = "sha2", feature = "blake3"))]
+	fn cross_hash_vectors() {
+		// The empty trie is the single `0` byte node with every layout.
+		assert_eq!(
+			<Sha2ExtensionLayout as TrieLayout>::Codec::hashed_null_node(),
+			hex_literal::hex!("6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d"),
+		);
+		assert_eq!(
+			<Blake3NoExtensionLayout as TrieLayout>::Codec::hashed_null_node(),
+			Blake3Hasher::hash(&[0]),
+		);
+
+		// Values too short to be hashed: a single inline root node, the same for every hasher.
+		let inline: &[(&[u8], &[u8])] = &[(b"a", b"1"), (b"b", b"2")];
+		let (keccak_root, keccak_nodes) = trie_nodes::<ExtensionLayout>(inline);
+		let (sha2_root, sha2_nodes) = trie_nodes::<Sha2ExtensionLayout>(inline);
+		let (blake3_root, blake3_nodes) = trie_nodes::<Blake3ExtensionLayout>(inline);
+		assert_eq!(keccak_nodes.len(), 1);
+		assert_eq!(keccak_nodes, sha2_nodes);
+		assert_eq!(keccak_nodes, blake3_nodes);
+		assert_eq!(keccak_root, KeccakHasher::hash(&keccak_nodes[0]));
+		assert_eq!(sha2_root, Sha256Hasher::hash(&sha2_nodes[0]));
+		assert_eq!(blake3_root, Blake3Hasher::hash(&blake3_nodes[0]));
+
+		// Hashed children: same node count and sizes, different content and roots.
+		let hashed: &[(&[u8], &[u8])] = &[(b"alfa", &[1; 40]), (b"beta", &[2; 40])];
+		let (keccak_root, keccak_nodes) = trie_nodes::<NoExtensionLayout>(hashed);
+		let (sha2_root, sha2_nodes) = trie_nodes::<Sha2NoExtensionLayout>(hashed);
+		let (blake3_root, blake3_nodes) = trie_nodes::<Blake3NoExtensionLayout>(hashed);
+		let sizes = |nodes: &[Vec<u8>]| {
+			let mut sizes: Vec<_> = nodes.iter().map(Vec::len).collect();
+			sizes.sort();
+			sizes
+		};
+		assert_eq!(keccak_nodes.len(), 3);
+		assert_eq!(sizes(&keccak_nodes), sizes(&sha2_nodes));
+		assert_eq!(sizes(&keccak_nodes), sizes(&blake3_nodes));
+		assert_ne!(keccak_nodes, sha2_nodes);
+		assert_ne!(keccak_root, sha2_root);
+		assert_ne!(keccak_root, blake3_root);
+		assert_ne!(sha2_root, blake3_root);
+	}
+
+	fn substrate_trie_root_unhashed(input: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+		let mut cb = trie_db::TrieRootUnhashed::<KeccakHasher>::default();
+		trie_visit::<SubstrateLayout<KeccakHasher>, _, _, _, _>(input.into_iter(), &mut cb).unwrap();
+		cb.root.unwrap_or(Default::default())
+	}
+
+	fn to_compact(n: u8) -> u8 {
+		Compact(n).encode()[0]
+	}
+
+	// Test vectors from `sp-trie`.
+	#[test]
+	fn substrate_codec_trie_single_tuple() {
+		let input = vec![(vec![0xaa], vec![0xbb])];
+		assert_eq!(substrate_trie_root_unhashed(input), vec![
+			0x42, // leaf 0x40 (2^6) with (+) key of 2 nibbles (0x02)
+			0xaa, // key data
+			to_compact(1), // length of value in bytes as Compact
+			0xbb, // value data
+		]);
+	}
+
+	#[test]
+	fn substrate_codec_trie_two_tuples_disjoint_keys() {
+		let input = vec![(vec![0x48, 0x19], vec![0xfe]), (vec![0x13, 0x14], vec![0xff])];
+		let mut ex = Vec::<u8>::new();
+		ex.push(0x80); // branch, no value (0b_10..) no nibble
+		ex.push(0x12); // slots 1 & 4 are taken from 0-7
+		ex.push(0x00); // no slots from 8-15
+		ex.push(to_compact(0x05)); // first slot: LEAF, 5 bytes long.
+		ex.push(0x43); // leaf 0x40 with 3 nibbles
+		ex.push(0x03); // first nibble
+		ex.push(0x14); // second & third nibble
+		ex.push(to_compact(0x01)); // 1 byte data
+		ex.push(0xff); // value data
+		ex.push(to_compact(0x05)); // second slot: LEAF, 5 bytes long.
+		ex.push(0x43); // leaf with 3 nibbles
+		ex.push(0x08); // first nibble
+		ex.push(0x19); // second & third nibble
+		ex.push(to_compact(0x01)); // 1 byte data
+		ex.push(0xfe); // value data
+		assert_eq!(substrate_trie_root_unhashed(input), ex);
+	}
+
+	#[test]
+	fn substrate_codec_rejects_unsupported_nodes() {
+		type Codec = SubstrateNodeCodec<KeccakHasher>;
+		// branch without value nor children
+		let enc = <Codec as NodeCodec>::branch_node_nibbled(
+			std::iter::empty(),
+			0,
+			std::iter::repeat(None::<ChildReference<<KeccakHasher as Hasher>::Out>>)
+				.take(nibble_ops::NIBBLE_LENGTH),
+			None,
+		).unwrap();
+		assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&enc).is_ok());
+		assert!(<Codec as NodeCodec>::decode(&enc).is_err());
+		// leaf with hashed value
+		assert!(<Codec as NodeCodec>::decode(&[ALT_HASHING_LEAF_PREFIX_MASK | 1, 0x01]).is_err());
+	}
+
+	fn sorted_input(seed: u8, count: u32) -> std::collections::BTreeMap<Vec<u8>, Vec<u8>> {
+		let mut seed = [seed; 32];
+		StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 1,
+			journal_key: 4,
+			value_mode: ValueMode::Random,
+			count,
+		}.make_with(&mut seed).into_iter().collect()
+	}
+
+	#[test]
+	fn sorted_input_matches_unsorted() {
+		for (seed, count) in [(0, 0), (1, 1), (2, 2), (3, 17), (4, 300)].iter() {
+			let input = sorted_input(*seed, *count);
+			assert_eq!(
+				trie_root::trie_root_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(&input).unwrap(),
+				trie_root::trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(input.iter().rev()).unwrap(),
+			);
+			assert_eq!(
+				trie_root::trie_root_sorted_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(&input).unwrap(),
+				trie_root::trie_root_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input.iter().rev()).unwrap(),
+			);
+		}
+	}
+
+	#[test]
+	fn sorted_input_with_shared_prefix() {
+		let input = vec![
+			(vec![0x01, 0x23], vec![1; 40]),
+			(vec![0x01, 0x23, 0x45], vec![2; 40]),
+			(vec![0x01, 0x2f], vec![3; 40]),
+		];
+		assert_eq!(
+			trie_root::trie_root_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(input.clone()).unwrap(),
+			ExtensionLayout::trie_root(input.clone()).unwrap(),
+		);
+		assert_eq!(
+			trie_root::trie_root_sorted_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input.clone()).unwrap(),
+			NoExtensionLayout::trie_root(input).unwrap(),
+		);
+	}
+
+	#[test]
+	fn sorted_input_with_prefix_keys() {
+		let input = vec![
+			(vec![0x01], vec![1]),
+			(vec![0x01, 0x23], vec![2]),
+			(vec![0x01, 0x23, 0x45], vec![3]),
+			(vec![0x01, 0x24], vec![4]),
+			(vec![0xf0], vec![5]),
+		];
+		assert_eq!(
+			trie_root::unhashed_trie_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(input.clone()).unwrap(),
+			trie_root::unhashed_trie::<KeccakHasher, ReferenceTrieStream, _, _, _>(input.clone()).unwrap(),
+		);
+		assert_eq!(
+			trie_root::unhashed_trie_sorted_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input.clone()).unwrap(),
+			trie_root::unhashed_trie_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input).unwrap(),
+		);
+	}
+
+	#[test]
+	fn sorted_input_rejects_unsorted_keys() {
+		let unsorted = vec![(vec![0x02], vec![1]), (vec![0x01], vec![2])];
+		assert_eq!(
+			trie_root::trie_root_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(unsorted),
+			Err(trie_root::SortedInputError::Unsorted(1)),
+		);
+		let duplicate = vec![(vec![0x01], vec![1]), (vec![0x02], vec![2]), (vec![0x02], vec![3])];
+		assert_eq!(
+			trie_root::unhashed_trie_sorted_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(duplicate),
+			Err(trie_root::SortedInputError::Unsorted(2)),
+		);
+	}
+
+	#[test]
+	fn unsorted_input_keeps_last_duplicate() {
+		let input = vec![
+			(vec![0x02], vec![1]),
+			(vec![0x01], vec![2]),
+			(vec![0x02], vec![3]),
+			(vec![0x01, 0x23], vec![4]),
+		];
+		let expected = vec![
+			(vec![0x01], vec![2]),
+			(vec![0x01, 0x23], vec![4]),
+			(vec![0x02], vec![3]),
+		];
+		assert_eq!(
+			trie_root::trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(input).unwrap(),
+			trie_root::trie_root_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(expected).unwrap(),
+		);
+	}
+
+	#[test]
+	fn dyn_layout_opens_tries() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+
+		let pairs = vec![
+			(b"A".to_vec(), vec![1; 40]),
+			(b"AB".to_vec(), vec![2; 40]),
+			(b"B".to_vec(), vec![3; 40]),
+		];
+		let mut memdb = memory_db::MemoryDB::<KeccakHasher, memory_db::PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (key, value) in &pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let mut memdb_no_ext = memory_db::MemoryDB::<KeccakHasher, memory_db::PrefixedKey<_>, DBValue>::default();
+		let mut root_no_ext = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut memdb_no_ext, &mut root_no_ext);
+			for (key, value) in &pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+
+		for (name, db, root) in vec![
+			("extension", &memdb, &root),
+			("no-extension", &memdb_no_ext, &root_no_ext),
+		] {
+			let layout: DynLayout = name.parse().unwrap();
+			assert_eq!(layout.name(), name);
+			assert!(layout.decode_node(&HashDB::get(db, root, EMPTY_PREFIX).unwrap()).is_ok());
+
+			let t = layout.open(db, root).unwrap();
+			assert_eq!(t.layout(), layout);
+			assert_eq!(t.root(), root);
+			assert_eq!(t.get(b"AB").unwrap(), Some(vec![2; 40].into()));
+			let items = t.iter().unwrap()
+				.map(|item| item.map(|(key, value)| (key, value.to_vec())).unwrap())
+				.collect::<Vec<_>>();
+			assert_eq!(items, pairs);
+		}
+		assert!("unknown".parse::<DynLayout>().is_err());
+	}
 }
@@ -0,0 +1,97 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hasher implementation for the SHA2-256 hash
+
+use hash_db::{Hasher, StreamingHasher};
+use sha2::{Digest, Sha256};
+use hash256_std_hasher::Hash256StdHasher;
+
+/// Concrete `Hasher` impl for the SHA2-256 hash
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Sha256Hasher;
+impl Hasher for Sha256Hasher {
+	type Out = [u8; 32];
+
+	type StdHasher = Hash256StdHasher;
+
+	const LENGTH: usize = 32;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		let mut out = [0u8; 32];
+		out.copy_from_slice(&Sha256::digest(x));
+		out
+	}
+
+	fn hash_parts(parts: &[&[u8]]) -> Option<Self::Out> {
+		let mut writer = Self::writer();
+		for part in parts {
+			writer.update(part);
+		}
+		Some(writer.finalize())
+	}
+}
+
+impl StreamingHasher for Sha256Hasher {
+	type Stream = Sha256;
+
+	fn stream() -> Sha256 {
+		Sha256::new()
+	}
+
+	fn update(stream: &mut Sha256, data: &[u8]) {
+		stream.input(data);
+	}
+
+	fn finalize(stream: Sha256) -> Self::Out {
+		let mut out = [0u8; 32];
+		out.copy_from_slice(&stream.result());
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+	use hex_literal::hex;
+
+	#[test]
+	fn test_vectors() {
+		assert_eq!(
+			Sha256Hasher::hash(b""),
+			hex!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+		);
+		assert_eq!(
+			Sha256Hasher::hash(b"abc"),
+			hex!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+		);
+	}
+
+	#[test]
+	fn streaming_hash_matches_hash() {
+		let data = vec![7u8; 10_000];
+		let mut stream = Sha256Hasher::stream();
+		for chunk in data.chunks(999) {
+			Sha256Hasher::update(&mut stream, chunk);
+		}
+		assert_eq!(Sha256Hasher::finalize(stream), Sha256Hasher::hash(&data));
+		assert_eq!(Sha256Hasher::hash_parts(&[&data[..3], &data[3..]]), Some(Sha256Hasher::hash(&data)));
+
+		let mut writer = Sha256Hasher::writer();
+		std::io::copy(&mut &data[..], &mut writer).unwrap();
+		write!(writer, "{}", 42).unwrap();
+		assert_eq!(writer.finalize(), Sha256Hasher::hash(&[&data[..], b"42"].concat()));
+	}
+}
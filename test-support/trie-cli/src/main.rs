@@ -0,0 +1,367 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command line tool to inspect tries of the reference layouts.
+//!
+//! Tries are read from node dumps: text files holding one hex encoded node per line,
+//! blank lines and lines starting with `#` being ignored. Nodes are looked up by hash
+//! only, so a dump can hold the nodes of several tries.
+
+use std::io::{self, Write};
+use std::{env, fs, process};
+use hash_db::{HashDB, EMPTY_PREFIX};
+use keccak_hasher::KeccakHasher;
+use memory_db::{HashKey, MemoryDB};
+use reference_trie::fixtures::{fixtures_to_json, generate_fixtures};
+use reference_trie::node::Node;
+use reference_trie::{
+	DBValue, ExtensionLayout, NibbleVec, NoExtensionLayout, SubstrateLayout, Trie, TrieDB, TrieLayout,
+	TrieVisitor, proof, tries_equal, trie_difference, trie_intersection,
+};
+
+type TrieHash<L> = <<L as TrieLayout>::Hash as hash_db::Hasher>::Out;
+type DumpDB<L> = MemoryDB<<L as TrieLayout>::Hash, HashKey<<L as TrieLayout>::Hash>, DBValue>;
+
+const USAGE: &str = "\
+Usage: trie-cli [--layout <extension|no-extension|substrate>] <command>
+
+Commands:
+	summary <dump> <root>                  Print counts of keys and nodes of a trie
+	get <dump> <root> <key>                Print the value of a key
+	proof <dump> <root> <key>...           Print the nodes of a proof of some keys
+	dot <dump> <root>                      Print the nodes of a trie as a DOT graph
+	diff <dump-a> <root-a> <dump-b> <root-b>  Print the keys differing between two tries
+	fixtures                               Print the golden fixtures of all layouts
+
+Roots, keys and values are hex encoded. The default layout is `extension`.";
+
+fn main() {
+	let mut args: Vec<String> = env::args().skip(1).collect();
+	let layout = if args.first().map(String::as_str) == Some("--layout") && args.len() > 1 {
+		args.remove(0);
+		args.remove(0)
+	} else {
+		"extension".to_string()
+	};
+	let stdout = io::stdout();
+	let out = &mut stdout.lock();
+	let result = match layout.as_str() {
+		"extension" => run::<ExtensionLayout>(&args, out),
+		"no-extension" => run::<NoExtensionLayout>(&args, out),
+		"substrate" => run::<SubstrateLayout<KeccakHasher>>(&args, out),
+		_ => Err(format!("Unknown layout `{}`", layout)),
+	};
+	if let Err(e) = result {
+		eprintln!("{}\n\n{}", e, USAGE);
+		process::exit(1);
+	}
+}
+
+/// Run the command in `args` with the layout `L`.
+fn run<L: TrieLayout>(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+	let args: Vec<&str> = args.iter().map(String::as_str).collect();
+	match args.as_slice() {
+		["summary", dump, root] => summary::<L>(&load_dump::<L>(dump)?, &parse_root::<L>(root)?, out),
+		["get", dump, root, key] => {
+			get::<L>(&load_dump::<L>(dump)?, &parse_root::<L>(root)?, &from_hex(key)?, out)
+		},
+		["proof", dump, root, keys @ ..] if !keys.is_empty() => {
+			let keys = keys.iter().map(|key| from_hex(key)).collect::<Result<Vec<_>, _>>()?;
+			prove::<L>(&load_dump::<L>(dump)?, &parse_root::<L>(root)?, &keys, out)
+		},
+		["dot", dump, root] => dot::<L>(&load_dump::<L>(dump)?, &parse_root::<L>(root)?, out),
+		["diff", dump_a, root_a, dump_b, root_b] => diff::<L>(
+			&load_dump::<L>(dump_a)?,
+			&parse_root::<L>(root_a)?,
+			&load_dump::<L>(dump_b)?,
+			&parse_root::<L>(root_b)?,
+			out,
+		),
+		["fixtures"] => {
+			writeln!(out, "{}", fixtures_to_json(generate_fixtures())).map_err(|e| e.to_string())
+		},
+		_ => Err("Invalid command".to_string()),
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+	let hex = hex.trim_start_matches("0x");
+	if hex.len() % 2 != 0 {
+		return Err(format!("Odd length hex `{}`", hex));
+	}
+	(0..hex.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid hex `{}`", hex)))
+		.collect()
+}
+
+/// Hex digits of the nibbles of a path.
+fn path_to_hex(path: &NibbleVec) -> String {
+	(0..path.len()).map(|i| format!("{:x}", path.at(i))).collect()
+}
+
+fn parse_root<L: TrieLayout>(hex: &str) -> Result<TrieHash<L>, String> {
+	let bytes = from_hex(hex)?;
+	let mut root = TrieHash::<L>::default();
+	if bytes.len() != root.as_ref().len() {
+		return Err(format!("Root `{}` is not {} bytes long", hex, root.as_ref().len()));
+	}
+	root.as_mut().copy_from_slice(&bytes);
+	Ok(root)
+}
+
+/// Read a node dump into a database.
+fn load_dump<L: TrieLayout>(path: &str) -> Result<DumpDB<L>, String> {
+	let dump = fs::read_to_string(path).map_err(|e| format!("Cannot read `{}`: {}", path, e))?;
+	parse_dump::<L>(&dump)
+}
+
+fn parse_dump<L: TrieLayout>(dump: &str) -> Result<DumpDB<L>, String> {
+	let mut db = DumpDB::<L>::default();
+	for line in dump.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+		db.insert(EMPTY_PREFIX, &from_hex(line)?);
+	}
+	Ok(db)
+}
+
+/// Counts of the nodes of a trie.
+#[derive(Default)]
+struct Summary {
+	stored_nodes: usize,
+	inline_nodes: usize,
+	branches: usize,
+	leaves: usize,
+	values: usize,
+	max_path_len: usize,
+}
+
+impl Summary {
+	fn count<H>(&mut self, path: &NibbleVec, hash: Option<&H>, node: &Node) {
+		match hash {
+			Some(_) => self.stored_nodes += 1,
+			None => self.inline_nodes += 1,
+		}
+		match node {
+			Node::Leaf(..) => self.leaves += 1,
+			Node::Branch(..) | Node::NibbledBranch(..) => self.branches += 1,
+			Node::Extension(..) | Node::Empty => (),
+		}
+		match node {
+			Node::Leaf(..) | Node::Branch(_, Some(_)) | Node::NibbledBranch(_, _, Some(_)) => self.values += 1,
+			_ => (),
+		}
+		self.max_path_len = self.max_path_len.max(path.len());
+	}
+}
+
+impl<L: TrieLayout> TrieVisitor<L> for Summary {
+	fn enter_branch(&mut self, path: &NibbleVec, hash: Option<&TrieHash<L>>, node: &Node) -> bool {
+		self.count(path, hash, node);
+		true
+	}
+
+	fn leaf(&mut self, path: &NibbleVec, hash: Option<&TrieHash<L>>, node: &Node) {
+		self.count(path, hash, node);
+	}
+}
+
+fn summary<L: TrieLayout>(db: &DumpDB<L>, root: &TrieHash<L>, out: &mut dyn Write) -> Result<(), String> {
+	let trie = TrieDB::<L>::new(db, root).map_err(|e| e.to_string())?;
+	let mut summary = Summary::default();
+	trie.visit(&mut summary).map_err(|e| e.to_string())?;
+	writeln!(
+		out,
+		"values: {}\nstored nodes: {}\ninline nodes: {}\nbranches: {}\nleaves: {}\nmax depth: {} nibbles",
+		summary.values,
+		summary.stored_nodes,
+		summary.inline_nodes,
+		summary.branches,
+		summary.leaves,
+		summary.max_path_len,
+	).map_err(|e| e.to_string())
+}
+
+fn get<L: TrieLayout>(
+	db: &DumpDB<L>,
+	root: &TrieHash<L>,
+	key: &[u8],
+	out: &mut dyn Write,
+) -> Result<(), String> {
+	let trie = TrieDB::<L>::new(db, root).map_err(|e| e.to_string())?;
+	let written = match trie.get(key).map_err(|e| e.to_string())? {
+		Some(value) => writeln!(out, "{}", to_hex(&value)),
+		None => writeln!(out, "not found"),
+	};
+	written.map_err(|e| e.to_string())
+}
+
+fn prove<L: TrieLayout>(
+	db: &DumpDB<L>,
+	root: &TrieHash<L>,
+	keys: &[Vec<u8>],
+	out: &mut dyn Write,
+) -> Result<(), String> {
+	let trie = TrieDB::<L>::new(db, root).map_err(|e| e.to_string())?;
+	let nodes = proof::generate_proof::<_, L, _, _>(&trie, keys).map_err(|e| e.to_string())?;
+	for node in nodes {
+		writeln!(out, "{}", to_hex(&node)).map_err(|e| e.to_string())?;
+	}
+	Ok(())
+}
+
+/// Writes the nodes of a trie as DOT graph vertices and edges.
+struct DotWriter<'a> {
+	out: &'a mut dyn Write,
+	next_id: usize,
+	parents: Vec<usize>,
+	result: io::Result<()>,
+}
+
+impl<'a> DotWriter<'a> {
+	fn node<H: AsRef<[u8]>>(&mut self, path: &NibbleVec, hash: Option<&H>, node: &Node) -> usize {
+		let id = self.next_id;
+		self.next_id += 1;
+		let kind = match node {
+			Node::Empty => "empty",
+			Node::Leaf(..) => "leaf",
+			Node::Extension(..) => "extension",
+			Node::Branch(..) | Node::NibbledBranch(..) => "branch",
+		};
+		let hash = hash.map_or_else(|| "inline".to_string(), |hash| to_hex(&hash.as_ref()[..4]));
+		let result = writeln!(self.out, "\tn{} [label=\"{} {}\\n{}\"];", id, kind, path_to_hex(path), hash)
+			.and_then(|_| match self.parents.last() {
+				Some(parent) => writeln!(self.out, "\tn{} -> n{};", parent, id),
+				None => Ok(()),
+			});
+		if self.result.is_ok() {
+			self.result = result;
+		}
+		id
+	}
+}
+
+impl<'a, L: TrieLayout> TrieVisitor<L> for DotWriter<'a> {
+	fn enter_branch(&mut self, path: &NibbleVec, hash: Option<&TrieHash<L>>, node: &Node) -> bool {
+		let id = self.node(path, hash, node);
+		self.parents.push(id);
+		true
+	}
+
+	fn leaf(&mut self, path: &NibbleVec, hash: Option<&TrieHash<L>>, node: &Node) {
+		self.node(path, hash, node);
+	}
+
+	fn exit_branch(&mut self, _path: &NibbleVec, _hash: Option<&TrieHash<L>>, _node: &Node) {
+		self.parents.pop();
+	}
+}
+
+fn dot<L: TrieLayout>(db: &DumpDB<L>, root: &TrieHash<L>, out: &mut dyn Write) -> Result<(), String> {
+	let trie = TrieDB::<L>::new(db, root).map_err(|e| e.to_string())?;
+	writeln!(out, "digraph trie {{").map_err(|e| e.to_string())?;
+	let mut writer = DotWriter { out: &mut *out, next_id: 0, parents: Vec::new(), result: Ok(()) };
+	trie.visit(&mut writer).map_err(|e| e.to_string())?;
+	writer.result.map_err(|e| e.to_string())?;
+	writeln!(out, "}}").map_err(|e| e.to_string())
+}
+
+/// Print the keys only in the first trie prefixed with `-`, the keys only in the second one
+/// prefixed with `+` and the keys with different values prefixed with `~`.
+fn diff<L: TrieLayout>(
+	db_a: &DumpDB<L>,
+	root_a: &TrieHash<L>,
+	db_b: &DumpDB<L>,
+	root_b: &TrieHash<L>,
+	out: &mut dyn Write,
+) -> Result<(), String> {
+	if tries_equal::<L>(db_a, root_a, db_b, root_b).map_err(|e| e.to_string())?.is_none() {
+		return writeln!(out, "equal").map_err(|e| e.to_string());
+	}
+	for key in trie_difference::<L>(db_a, root_a, db_b, root_b) {
+		writeln!(out, "-{}", to_hex(&key.map_err(|e| e.to_string())?)).map_err(|e| e.to_string())?;
+	}
+	for key in trie_difference::<L>(db_b, root_b, db_a, root_a) {
+		writeln!(out, "+{}", to_hex(&key.map_err(|e| e.to_string())?)).map_err(|e| e.to_string())?;
+	}
+	let trie_a = TrieDB::<L>::new(db_a, root_a).map_err(|e| e.to_string())?;
+	let trie_b = TrieDB::<L>::new(db_b, root_b).map_err(|e| e.to_string())?;
+	for key in trie_intersection::<L>(db_a, root_a, db_b, root_b) {
+		let key = key.map_err(|e| e.to_string())?;
+		if trie_a.get(&key).map_err(|e| e.to_string())? != trie_b.get(&key).map_err(|e| e.to_string())? {
+			writeln!(out, "~{}", to_hex(&key)).map_err(|e| e.to_string())?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use reference_trie::{ExtensionLayout, RefTrieDBMut, TrieMut};
+	use super::*;
+
+	fn dump(pairs: &[(&[u8], &[u8])]) -> (String, String) {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let nodes: Vec<String> = db.drain().into_iter()
+			.map(|(_, (node, _))| to_hex(&node))
+			.collect();
+		(nodes.join("\n"), to_hex(&root))
+	}
+
+	fn run_on(args: &[&str], dump_a: &str, dump_b: &str) -> String {
+		let dir = env::temp_dir();
+		let path_a = dir.join(format!("trie-cli-test-a-{}", process::id()));
+		let path_b = dir.join(format!("trie-cli-test-b-{}", process::id()));
+		fs::write(&path_a, dump_a).unwrap();
+		fs::write(&path_b, dump_b).unwrap();
+		let args: Vec<String> = args.iter()
+			.map(|arg| arg
+				.replace("DUMP_A", path_a.to_str().unwrap())
+				.replace("DUMP_B", path_b.to_str().unwrap()))
+			.collect();
+		let mut out = Vec::new();
+		run::<ExtensionLayout>(&args, &mut out).unwrap();
+		String::from_utf8(out).unwrap()
+	}
+
+	#[test]
+	fn commands_work_on_dumps() {
+		let large = [1u8; 40];
+		let (dump_a, root_a) = dump(&[(b"alfa", &large), (b"beta", b"value"), (b"delta", &large)]);
+		let (dump_b, root_b) = dump(&[(b"alfa", &large), (b"beta", b"other"), (b"gamma", &large)]);
+
+		let get = run_on(&["get", "DUMP_A", &root_a, &to_hex(b"beta")], &dump_a, &dump_b);
+		assert_eq!(get, format!("{}\n", to_hex(b"value")));
+		let summary = run_on(&["summary", "DUMP_A", &root_a], &dump_a, &dump_b);
+		assert!(summary.starts_with("values: 3\n"));
+		let graph = run_on(&["dot", "DUMP_A", &root_a], &dump_a, &dump_b);
+		assert!(graph.starts_with("digraph trie {\n") && graph.ends_with("}\n"));
+		let diff = run_on(&["diff", "DUMP_A", &root_a, "DUMP_B", &root_b], &dump_a, &dump_b);
+		assert_eq!(
+			diff,
+			format!("-{}\n+{}\n~{}\n", to_hex(b"delta"), to_hex(b"gamma"), to_hex(b"beta")),
+		);
+		assert_eq!(run_on(&["diff", "DUMP_A", &root_a, "DUMP_A", &root_a], &dump_a, &dump_b), "equal\n");
+		assert!(run::<ExtensionLayout>(&["get".to_string()], &mut Vec::new()).is_err());
+	}
+}
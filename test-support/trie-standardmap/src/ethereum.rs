@@ -0,0 +1,97 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Loader for the `TrieTests` fixtures of the Ethereum tests repository, giving
+//! the roots of Ethereum (RLP encoded, keccak-256 hashed) tries.
+
+use std::collections::BTreeMap;
+use serde_json::Value;
+
+/// Cases of `trieanyorder.json`, whose input is a map from keys to values.
+pub const TRIE_ANY_ORDER: &str = include_str!("../fixtures/trieanyorder.json");
+
+/// Cases of `trietest.json`, whose input is a list of insertions applied in order,
+/// a `null` value removing the key.
+pub const TRIE_TEST: &str = include_str!("../fixtures/trietest.json");
+
+/// A `TrieTests` case.
+pub struct TrieTest {
+	/// Name of the case.
+	pub name: String,
+	/// Insertions in the order of the fixture, `None` removing the key.
+	pub input: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+	/// Expected trie root.
+	pub root: Vec<u8>,
+}
+
+impl TrieTest {
+	/// Entries of the trie after applying the insertions, sorted by key.
+	pub fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut entries = BTreeMap::new();
+		for (key, value) in &self.input {
+			match value {
+				Some(value) => entries.insert(key.clone(), value.clone()),
+				None => entries.remove(key),
+			};
+		}
+		entries.into_iter().collect()
+	}
+}
+
+/// Parse the cases of a `TrieTests` JSON file. Strings starting with `0x` are hex
+/// encoded bytes, other strings stand for their UTF-8 bytes.
+pub fn load(json: &str) -> Result<Vec<TrieTest>, String> {
+	let cases: BTreeMap<String, Value> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+	cases.into_iter().map(|(name, case)| {
+		let input = match &case["in"] {
+			Value::Object(map) => map.iter()
+				.map(|(key, value)| Ok((parse_bytes(key)?, parse_value(value)?)))
+				.collect::<Result<_, String>>()?,
+			Value::Array(list) => list.iter()
+				.map(|pair| match pair.as_array().map(|pair| &pair[..]) {
+					Some([Value::String(key), value]) => Ok((parse_bytes(key)?, parse_value(value)?)),
+					_ => Err(format!("{}: invalid insertion {}", name, pair)),
+				})
+				.collect::<Result<_, String>>()?,
+			input => return Err(format!("{}: invalid input {}", name, input)),
+		};
+		let root = match &case["root"] {
+			Value::String(root) => parse_bytes(root)?,
+			root => return Err(format!("{}: invalid root {}", name, root)),
+		};
+		Ok(TrieTest { name, input, root })
+	}).collect()
+}
+
+fn parse_value(value: &Value) -> Result<Option<Vec<u8>>, String> {
+	match value {
+		Value::String(value) => parse_bytes(value).map(Some),
+		Value::Null => Ok(None),
+		value => Err(format!("invalid value {}", value)),
+	}
+}
+
+fn parse_bytes(data: &str) -> Result<Vec<u8>, String> {
+	if !data.starts_with("0x") {
+		return Ok(data.as_bytes().to_vec());
+	}
+	let hex = &data[2..];
+	if hex.len() % 2 != 0 || !hex.is_ascii() {
+		return Err(format!("invalid hex string {}", data));
+	}
+	(0..hex.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16)
+			.map_err(|_| format!("invalid hex string {}", data)))
+		.collect()
+}
@@ -17,6 +17,9 @@
 use hash_db::Hasher;
 use keccak_hasher::KeccakHasher;
 
+pub mod ethereum;
+pub mod shapes;
+
 type H256 = <KeccakHasher as hash_db::Hasher>::Out;
 
 /// Alphabet to use when creating words for insertion into tries.
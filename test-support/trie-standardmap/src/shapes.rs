@@ -0,0 +1,66 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Datasets producing worst-case trie shapes, rarely hit by random data.
+//! Entries are returned sorted by key.
+
+use hash_db::Hasher;
+
+/// Entries building a chain of `depth` branches, each holding a leaf and the next
+/// branch, ending with a leaf at `2 * depth` nibbles.
+pub fn deep_chain(depth: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+	let mut entries: Vec<_> = (0..depth)
+		.map(|i| {
+			let mut key = vec![0; i];
+			key.push(1);
+			(key, vec![i as u8])
+		})
+		.collect();
+	entries.push((vec![0; depth], vec![depth as u8]));
+	entries.sort();
+	entries
+}
+
+/// Entries whose keys are each a prefix of the next, building `depth` branches with a
+/// value and a single child.
+pub fn single_child_branches(depth: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+	(1..=depth).map(|i| (vec![0x11; i], vec![i as u8])).collect()
+}
+
+/// Entries building `branches` branches with sixteen children, all small enough to be
+/// inlined in the branch with hasher `H`.
+pub fn inline_children<H: Hasher>(branches: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+	// a leaf with a one nibble partial key is encoded in a few bytes more than its value.
+	let value_len = H::LENGTH / 4;
+	let mut entries = Vec::with_capacity(branches * 16);
+	for branch in 0..branches {
+		for nibble in 0..16u8 {
+			entries.push((vec![branch as u8, nibble << 4], vec![nibble; value_len]));
+		}
+	}
+	entries.sort();
+	entries
+}
+
+/// Two entries whose leaves have a partial key of exactly `partial_nibbles` nibbles, to
+/// hit the partial length bounds of a codec.
+pub fn long_partial_keys(partial_nibbles: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+	// the keys differ at nibble `split` and the leaves hold the nibbles after it.
+	let split = if partial_nibbles % 2 == 1 { 0 } else { 1 };
+	let len = (partial_nibbles + split + 1) / 2;
+	let first = vec![0; len];
+	let mut second = vec![0; len];
+	second[0] = if split == 0 { 0x10 } else { 0x01 };
+	vec![(first, vec![1]), (second, vec![2])]
+}
@@ -0,0 +1,143 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Smoke test of `hash-db`, `memory-db`, `trie-db` and `trie-root` without `std`.
+//!
+//! This crate is meant to be built for `wasm32-unknown-unknown`:
+//!
+//! ```text
+//! cd test-support/wasm-smoke && cargo build --target wasm32-unknown-unknown
+//! ```
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
+use hash256_std_hasher::Hash256StdHasher;
+use memory_db::{HashKey, MemoryDB};
+use trie_root::TrieStream;
+
+// Referenced so that `trie-db` is built without `std` along with the other crates.
+pub use trie_db::{DBValue, NibbleVec};
+
+/// Non-cryptographic FNV-1a based hasher with a 32 byte output. It uses no randomness,
+/// which is not available in every wasm environment.
+pub struct FnvHasher;
+
+impl Hasher for FnvHasher {
+	type Out = [u8; 32];
+	type StdHasher = Hash256StdHasher;
+	const LENGTH: usize = 32;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		let mut out = [0u8; 32];
+		for (lane, chunk) in out.chunks_mut(8).enumerate() {
+			let mut hash = 0xcbf29ce484222325u64 ^ lane as u64;
+			for byte in x {
+				hash ^= *byte as u64;
+				hash = hash.wrapping_mul(0x100000001b3);
+			}
+			chunk.copy_from_slice(&hash.to_le_bytes());
+		}
+		out
+	}
+}
+
+/// `TrieStream` writing node kinds, lengths and data one after the other.
+pub struct SmokeStream(Vec<u8>);
+
+impl SmokeStream {
+	fn append_slice(&mut self, data: &[u8]) {
+		self.0.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		self.0.extend_from_slice(data);
+	}
+}
+
+impl TrieStream for SmokeStream {
+	type Error = ();
+
+	fn new() -> Self { SmokeStream(Vec::new()) }
+
+	fn append_empty_data(&mut self) {
+		self.0.push(0);
+	}
+
+	fn begin_branch(
+		&mut self,
+		maybe_key: Option<&[u8]>,
+		maybe_value: Option<&[u8]>,
+		has_children: impl Iterator<Item = bool>,
+	) -> Result<(), Self::Error> {
+		self.0.push(1);
+		self.append_slice(maybe_key.unwrap_or(&[]));
+		let bitmap = has_children.enumerate().fold(0u16, |bitmap, (i, has_child)| {
+			bitmap | ((has_child as u16) << i)
+		});
+		self.0.extend_from_slice(&bitmap.to_le_bytes());
+		self.append_slice(maybe_value.unwrap_or(&[]));
+		Ok(())
+	}
+
+	fn append_leaf(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+		self.0.push(2);
+		self.append_slice(key);
+		self.append_slice(value);
+		Ok(())
+	}
+
+	fn append_extension(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+		self.0.push(3);
+		self.append_slice(key);
+		Ok(())
+	}
+
+	fn append_substream<H: Hasher>(&mut self, other: Self) {
+		let data = other.out();
+		if data.len() < H::LENGTH {
+			self.append_slice(&data);
+		} else {
+			self.append_slice(H::hash(&data).as_ref());
+		}
+	}
+
+	fn out(self) -> Vec<u8> { self.0 }
+}
+
+/// Store and read back values in a `MemoryDB`, and compute the root of a trie holding them.
+/// Returns `None` if a value cannot be read back.
+pub fn smoke(values: &[&[u8]]) -> Option<[u8; 32]> {
+	let mut db = MemoryDB::<FnvHasher, HashKey<_>, DBValue>::default();
+	for value in values {
+		let hash = db.insert(EMPTY_PREFIX, value);
+		if db.get(&hash, EMPTY_PREFIX).as_ref().map(|stored| &stored[..]) != Some(*value) {
+			return None;
+		}
+	}
+	let pairs = values.iter().map(|value| (FnvHasher::hash(value), *value));
+	trie_root::trie_root::<FnvHasher, SmokeStream, _, _, _>(pairs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::smoke;
+
+	#[test]
+	fn smoke_works() {
+		let root = smoke(&[b"alfa", b"beta", &[7; 40]]).unwrap();
+		assert_eq!(smoke(&[&[7; 40], b"beta", b"alfa"]), Some(root));
+		assert_ne!(smoke(&[b"alfa", b"beta"]), Some(root));
+	}
+}
@@ -0,0 +1,352 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of decoded nodes and value hashes shared between tries and threads.
+//!
+//! A `SharedCache` holds decoded nodes by hash and value hashes by trie root and key,
+//! up to a total size. Each trie reads through its own `LocalCache`, set with
+//! `TrieDB::with_cache`, which records the nodes the trie decodes and the value hashes
+//! it computes and adds them to the shared cache when flushed or dropped, so that
+//! readers do not contend on the shared cache for every node.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasherDefault;
+use std::sync::{Arc, Mutex};
+
+use hash_db::Hasher;
+use trie_db::{DBValue, TrieCache};
+use trie_db::node::OwnedNode;
+
+/// Decoded node, shared by the caches holding it.
+pub type CachedNode = Arc<OwnedNode<DBValue>>;
+
+type NodeMap<H> = HashMap<<H as Hasher>::Out, CachedNode, BuildHasherDefault<<H as Hasher>::StdHasher>>;
+
+/// Value hashes by key, by trie root.
+type ValueHashMap<H> = HashMap<
+	<H as Hasher>::Out,
+	HashMap<Vec<u8>, Option<<H as Hasher>::Out>>,
+	BuildHasherDefault<<H as Hasher>::StdHasher>,
+>;
+
+/// Key of an entry of the shared cache.
+enum Entry<HO> {
+	Node(HO),
+	ValueHash(HO, Vec<u8>),
+}
+
+struct Inner<H: Hasher> {
+	nodes: NodeMap<H>,
+	value_hashes: ValueHashMap<H>,
+	/// Insertion order of the cached entries, oldest first.
+	order: VecDeque<Entry<H::Out>>,
+	size: usize,
+	max_size: usize,
+}
+
+/// Size accounted for a cached value hash.
+fn value_hash_size<HO: AsRef<[u8]>>(root: &HO, key: &[u8]) -> usize {
+	2 * root.as_ref().len() + key.len()
+}
+
+impl<H: Hasher> Inner<H> {
+	/// Evict the oldest entries until `size` more bytes fit in the cache. Returns `false`
+	/// if they never fit.
+	fn make_room(&mut self, size: usize) -> bool {
+		if size > self.max_size {
+			return false;
+		}
+		while self.size + size > self.max_size {
+			let removed = match self.order.pop_front()
+				.expect("the size of the cached entries is not zero; qed")
+			{
+				Entry::Node(hash) => {
+					let node = self.nodes.remove(&hash)
+						.expect("`order` holds the keys of the cached entries; qed");
+					hash.as_ref().len() + node.data().len()
+				},
+				Entry::ValueHash(root, key) => {
+					let hashes = self.value_hashes.get_mut(&root)
+						.expect("`order` holds the keys of the cached entries; qed");
+					hashes.remove(&key);
+					if hashes.is_empty() {
+						self.value_hashes.remove(&root);
+					}
+					value_hash_size(&root, &key)
+				},
+			};
+			self.size -= removed;
+		}
+		true
+	}
+
+	fn insert_node(&mut self, hash: H::Out, node: CachedNode) {
+		let size = hash.as_ref().len() + node.data().len();
+		if self.nodes.contains_key(&hash) || !self.make_room(size) {
+			return;
+		}
+		self.size += size;
+		self.order.push_back(Entry::Node(hash));
+		self.nodes.insert(hash, node);
+	}
+
+	fn insert_value_hash(&mut self, root: H::Out, key: Vec<u8>, hash: Option<H::Out>) {
+		let size = value_hash_size(&root, &key);
+		let cached = self.value_hashes.get(&root).is_some_and(|hashes| hashes.contains_key(&key));
+		if cached || !self.make_room(size) {
+			return;
+		}
+		self.size += size;
+		self.value_hashes.entry(root).or_default().insert(key.clone(), hash);
+		self.order.push_back(Entry::ValueHash(root, key));
+	}
+}
+
+/// Cache of decoded nodes and value hashes bounded by their total size, evicting the
+/// oldest entries first. Clones share the same cache, and can be sent to other threads.
+pub struct SharedCache<H: Hasher> {
+	inner: Arc<Mutex<Inner<H>>>,
+}
+
+impl<H: Hasher> Clone for SharedCache<H> {
+	fn clone(&self) -> Self {
+		SharedCache { inner: self.inner.clone() }
+	}
+}
+
+impl<H: Hasher> SharedCache<H> {
+	/// Create a cache holding up to `max_size` bytes of encoded nodes, keys and hashes.
+	pub fn new(max_size: usize) -> Self {
+		SharedCache {
+			inner: Arc::new(Mutex::new(Inner {
+				nodes: NodeMap::<H>::default(),
+				value_hashes: ValueHashMap::<H>::default(),
+				order: VecDeque::new(),
+				size: 0,
+				max_size,
+			})),
+		}
+	}
+
+	/// Create a cache local to a trie, feeding this one.
+	pub fn local_cache(&self) -> LocalCache<H> {
+		LocalCache {
+			shared: self.clone(),
+			nodes: RefCell::new(NodeMap::<H>::default()),
+			value_hashes: RefCell::new(ValueHashMap::<H>::default()),
+		}
+	}
+
+	/// Get the node cached for `hash`.
+	pub fn node(&self, hash: &H::Out) -> Option<CachedNode> {
+		self.lock().nodes.get(hash).cloned()
+	}
+
+	/// Add a node to the cache, evicting older entries if needed. Nodes larger than the
+	/// cache are not cached.
+	pub fn insert_node(&self, hash: H::Out, node: CachedNode) {
+		self.lock().insert_node(hash, node)
+	}
+
+	/// Get the hash of the value at `key` in the trie with root `root`, `Some(None)` if
+	/// the key is known not to be in the trie.
+	pub fn value_hash(&self, root: &H::Out, key: &[u8]) -> Option<Option<H::Out>> {
+		self.lock().value_hashes.get(root).and_then(|hashes| hashes.get(key)).cloned()
+	}
+
+	/// Add the hash of the value at `key` in the trie with root `root` to the cache,
+	/// evicting older entries if needed.
+	pub fn insert_value_hash(&self, root: H::Out, key: &[u8], hash: Option<H::Out>) {
+		self.lock().insert_value_hash(root, key.to_vec(), hash)
+	}
+
+	/// Number of cached nodes and value hashes.
+	pub fn len(&self) -> usize {
+		self.lock().order.len()
+	}
+
+	/// Whether nothing is cached.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Total size of the cached entries.
+	pub fn size(&self) -> usize {
+		self.lock().size
+	}
+
+	/// Remove every entry from the cache.
+	pub fn clear(&self) {
+		let mut inner = self.lock();
+		inner.nodes.clear();
+		inner.value_hashes.clear();
+		inner.order.clear();
+		inner.size = 0;
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, Inner<H>> {
+		self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+	}
+}
+
+/// Cache of a single trie, recording the nodes decoded and the value hashes computed by
+/// the trie until they are flushed into the shared cache. Flushed on drop.
+pub struct LocalCache<H: Hasher> {
+	shared: SharedCache<H>,
+	nodes: RefCell<NodeMap<H>>,
+	value_hashes: RefCell<ValueHashMap<H>>,
+}
+
+impl<H: Hasher> LocalCache<H> {
+	/// Add the entries recorded so far to the shared cache.
+	pub fn flush(&self) {
+		let nodes = std::mem::take(&mut *self.nodes.borrow_mut());
+		let value_hashes = std::mem::take(&mut *self.value_hashes.borrow_mut());
+		if nodes.is_empty() && value_hashes.is_empty() {
+			return;
+		}
+		let mut shared = self.shared.lock();
+		for (hash, node) in nodes {
+			shared.insert_node(hash, node);
+		}
+		for (root, hashes) in value_hashes {
+			for (key, hash) in hashes {
+				shared.insert_value_hash(root, key, hash);
+			}
+		}
+	}
+}
+
+impl<H: Hasher> TrieCache<H::Out> for LocalCache<H> {
+	fn node(&self, hash: &H::Out) -> Option<CachedNode> {
+		if let Some(node) = self.nodes.borrow().get(hash) {
+			return Some(node.clone());
+		}
+		self.shared.node(hash)
+	}
+
+	fn insert_node(&self, hash: H::Out, node: CachedNode) {
+		self.nodes.borrow_mut().insert(hash, node);
+	}
+
+	fn value_hash(&self, root: &H::Out, key: &[u8]) -> Option<Option<H::Out>> {
+		if let Some(hash) = self.value_hashes.borrow().get(root).and_then(|hashes| hashes.get(key)) {
+			return Some(*hash);
+		}
+		self.shared.value_hash(root, key)
+	}
+
+	fn insert_value_hash(&self, root: H::Out, key: &[u8], hash: Option<H::Out>) {
+		self.value_hashes.borrow_mut().entry(root).or_default().insert(key.to_vec(), hash);
+	}
+}
+
+impl<H: Hasher> Drop for LocalCache<H> {
+	fn drop(&mut self) {
+		self.flush();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::SharedCache;
+	use hash_db::Hasher;
+	use keccak_hasher::KeccakHasher;
+	use memory_db::{MemoryDB, HashKey};
+	use reference_trie::{CountingMeter, RefTrieDB, RefTrieDBMut, Trie, TrieMut};
+	use trie_db::DBValue;
+
+	#[test]
+	fn nodes_are_shared_between_tries() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for i in 0..20u8 {
+				t.insert(&[i, i], &[i; 40]).unwrap();
+			}
+		}
+
+		let cache = SharedCache::<KeccakHasher>::new(1 << 20);
+		{
+			let local = cache.local_cache();
+			let t = RefTrieDB::new(&db, &root).unwrap().with_cache(&local);
+			assert_eq!(t.get(&[3, 3]).unwrap(), Some(vec![3; 40].into()));
+			assert!(cache.is_empty());
+		}
+		assert!(!cache.is_empty());
+
+		// the nodes decoded by the first trie are neither read nor decoded again.
+		let local = cache.local_cache();
+		let meter = CountingMeter::default();
+		let t = RefTrieDB::new(&db, &root).unwrap().with_meter(&meter).with_cache(&local);
+		assert_eq!(t.get(&[3, 3]).unwrap(), Some(vec![3; 40].into()));
+		assert_eq!(meter.nodes_read(), 0);
+		assert_eq!(meter.bytes_decoded(), 0);
+
+		let thread_cache = cache.clone();
+		std::thread::spawn(move || {
+			let empty = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+			let local = thread_cache.local_cache();
+			let t = RefTrieDB::new_unchecked(&empty, &root).with_cache(&local);
+			assert_eq!(t.get(&[3, 3]).unwrap(), Some(vec![3; 40].into()));
+			assert!(t.get(&[4, 4]).is_err());
+		}).join().unwrap();
+	}
+
+	#[test]
+	fn value_hashes_are_shared_between_tries() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			t.insert(b"foo", b"bar").unwrap();
+		}
+
+		let cache = SharedCache::<KeccakHasher>::new(1 << 20);
+		{
+			let local = cache.local_cache();
+			let t = RefTrieDB::new(&db, &root).unwrap().with_cache(&local);
+			assert_eq!(t.get_hash(b"foo").unwrap(), Some(KeccakHasher::hash(b"bar")));
+			assert_eq!(t.get_hash(b"baz").unwrap(), None);
+		}
+		assert_eq!(cache.value_hash(&root, b"foo"), Some(Some(KeccakHasher::hash(b"bar"))));
+		assert_eq!(cache.value_hash(&root, b"baz"), Some(None));
+
+		let empty = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let local = cache.local_cache();
+		let t = RefTrieDB::new_unchecked(&empty, &root).with_cache(&local);
+		assert_eq!(t.get_hash(b"foo").unwrap(), Some(KeccakHasher::hash(b"bar")));
+	}
+
+	#[test]
+	fn oldest_entries_are_evicted() {
+		let root = KeccakHasher::hash(&[]);
+		let entry_size = 32 + 1 + 32;
+		let cache = SharedCache::<KeccakHasher>::new(entry_size * 2);
+		for i in 0..3u8 {
+			cache.insert_value_hash(root, &[i], Some(KeccakHasher::hash(&[i])));
+		}
+		assert_eq!(cache.len(), 2);
+		assert_eq!(cache.size(), entry_size * 2);
+		assert_eq!(cache.value_hash(&root, &[0]), None);
+		assert_eq!(cache.value_hash(&root, &[2]), Some(Some(KeccakHasher::hash(&[2]))));
+
+		// larger than the cache.
+		cache.insert_value_hash(root, &[0; 100], None);
+		assert_eq!(cache.value_hash(&root, &[0; 100]), None);
+		assert_eq!(cache.len(), 2);
+	}
+}
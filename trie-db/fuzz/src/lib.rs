@@ -328,7 +328,7 @@ pub fn fuzz_that_verify_rejects_invalid_proofs(input: &[u8]) {
 	match &mut items[items_idx] {
 		(_, Some(value)) if random_int % 2 == 0 => value.push(0),
 		(_, value) if value.is_some() => *value = None,
-		(_, value) => *value = Some(DBValue::new()),
+		(_, value) => *value = Some(Vec::new()),
 	}
 	assert!(verify_proof::<ExtensionLayout, _, _, _>(&root, &proof, items.iter()).is_err());
 }
@@ -336,7 +336,7 @@ pub fn fuzz_that_verify_rejects_invalid_proofs(input: &[u8]) {
 fn test_generate_proof<L: TrieLayout>(
 	entries: Vec<(Vec<u8>, Vec<u8>)>,
 	keys: Vec<Vec<u8>>,
-) -> (<L::Hash as Hasher>::Out, Vec<Vec<u8>>, Vec<(Vec<u8>, Option<DBValue>)>)
+) -> (<L::Hash as Hasher>::Out, Vec<Vec<u8>>, Vec<(Vec<u8>, Option<Vec<u8>>)>)
 {
 	// Populate DB with full trie from entries.
 	let (db, root) = {
@@ -356,7 +356,7 @@ fn test_generate_proof<L: TrieLayout>(
 	let proof = generate_proof::<_, L, _, _>(&trie, keys.iter()).unwrap();
 	let items = keys.into_iter()
 		.map(|key| {
-			let value = trie.get(&key).unwrap();
+			let value = trie.get(&key).unwrap().map(Vec::from);
 			(key,value)
 		})
 		.collect();
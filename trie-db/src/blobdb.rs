@@ -0,0 +1,259 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use hash_db::{HashDBRef, Hasher, EMPTY_PREFIX};
+use super::{Result, DBValue, TrieDB, Trie, TrieDBIterator, TrieItem, TrieIterator, Query,
+	TrieLayout, TrieError, CError, TrieHash};
+
+use crate::rstd::{boxed::Box, convert::TryInto, vec::Vec};
+
+/// Marker of a value stored in the trie leaf, followed by the value.
+const INLINE_VALUE: u8 = 0;
+
+/// Marker of a value stored in the blob database, followed by the value hash
+/// and its length as a little endian `u32`.
+const BLOB_VALUE: u8 = 1;
+
+/// Value as written in the trie by `BlobDBMut`.
+pub(crate) enum StoredValue<'a, H: Hasher> {
+	/// Value stored in the trie.
+	Inline(&'a [u8]),
+	/// Hash and length of a value stored in the blob database.
+	Blob(H::Out, usize),
+}
+
+impl<'a, H: Hasher> StoredValue<'a, H> {
+	/// Decode a value written by `BlobDBMut`, `None` if it is malformed.
+	pub(crate) fn decode(data: &'a [u8]) -> Option<Self> {
+		match data.split_first() {
+			Some((&INLINE_VALUE, value)) => Some(StoredValue::Inline(value)),
+			Some((&BLOB_VALUE, reference)) if reference.len() == H::LENGTH + 4 => {
+				let mut hash = H::Out::default();
+				hash.as_mut().copy_from_slice(&reference[..H::LENGTH]);
+				let len = u32::from_le_bytes(reference[H::LENGTH..].try_into()
+					.expect("Slice of 4 bytes; qed"));
+				Some(StoredValue::Blob(hash, len as usize))
+			},
+			_ => None,
+		}
+	}
+
+	/// Length of the value.
+	pub(crate) fn len(&self) -> usize {
+		match self {
+			StoredValue::Inline(value) => value.len(),
+			StoredValue::Blob(_, len) => *len,
+		}
+	}
+
+	/// Encode a value stored in the trie.
+	pub(crate) fn encode_inline(value: &[u8]) -> Vec<u8> {
+		let mut stored = Vec::with_capacity(value.len() + 1);
+		stored.push(INLINE_VALUE);
+		stored.extend_from_slice(value);
+		stored
+	}
+
+	/// Encode a reference to a value stored in the blob database.
+	pub(crate) fn encode_blob(hash: &H::Out, len: usize) -> Vec<u8> {
+		let mut stored = Vec::with_capacity(H::LENGTH + 5);
+		stored.push(BLOB_VALUE);
+		stored.extend_from_slice(hash.as_ref());
+		stored.extend_from_slice(&(len as u32).to_le_bytes());
+		stored
+	}
+}
+
+/// Resolve a value written by `BlobDBMut`, fetching it from `blobs` when needed.
+pub(crate) fn resolve_value<L: TrieLayout>(
+	blobs: &dyn HashDBRef<L::Hash, DBValue>,
+	stored: &[u8],
+) -> Result<DBValue, TrieHash<L>, CError<L>> {
+	match StoredValue::<L::Hash>::decode(stored) {
+		Some(StoredValue::Inline(value)) => Ok(DBValue::from(value)),
+		Some(StoredValue::Blob(hash, _)) => blobs.get(&hash, EMPTY_PREFIX)
+			.ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash))),
+		None => Err(Box::new(TrieError::InvalidHash(Default::default(), stored.to_vec()))),
+	}
+}
+
+/// Result of a `BlobQuery`.
+enum BlobQueryItem<H: Hasher, Q: Query<H>> {
+	/// Inline value, decoded by the query.
+	Value(Q::Item),
+	/// Value in the blob database, to be decoded by the query once fetched.
+	Blob(Q, H::Out),
+	/// Malformed stored value.
+	Invalid(Vec<u8>),
+}
+
+/// Query decoding inline values with the wrapped query, and returning
+/// the query back for values stored in the blob database.
+struct BlobQuery<Q>(Q);
+
+impl<H: Hasher, Q: Query<H>> Query<H> for BlobQuery<Q> {
+	type Item = BlobQueryItem<H, Q>;
+
+	fn decode(self, data: &[u8]) -> Self::Item {
+		match StoredValue::<H>::decode(data) {
+			Some(StoredValue::Inline(value)) => BlobQueryItem::Value(self.0.decode(value)),
+			Some(StoredValue::Blob(hash, _)) => BlobQueryItem::Blob(self.0, hash),
+			None => BlobQueryItem::Invalid(data.to_vec()),
+		}
+	}
+
+	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
+		self.0.record(hash, data, depth)
+	}
+
+	fn record_key(&mut self, key: &[u8]) {
+		self.0.record_key(key)
+	}
+
+	fn record_node(&mut self, depth: u32, encoded: &[u8]) {
+		self.0.record_node(depth, encoded)
+	}
+}
+
+/// A `Trie` implementation storing values of at least a threshold size in a separate blob
+/// database, keyed by their hash, the trie only holding their hash and length.
+/// Values are resolved transparently; the trie must be written with `BlobDBMut`.
+///
+/// Use it as a `Trie` trait object.
+pub struct BlobDB<'db, L>
+where
+	L: TrieLayout,
+{
+	raw: TrieDB<'db, L>,
+	blobs: &'db dyn HashDBRef<L::Hash, DBValue>,
+}
+
+impl<'db, L> BlobDB<'db, L>
+where
+	L: TrieLayout,
+{
+	/// Create a new trie with the backing database `db`, blob database `blobs` and `root`.
+	pub fn new(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		blobs: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(BlobDB { raw: TrieDB::new(db, root)?, blobs })
+	}
+
+	/// Get the backing database.
+	pub fn db(&self) -> &dyn HashDBRef<L::Hash, DBValue> { self.raw.db() }
+
+	/// Get the blob database.
+	pub fn blobs(&self) -> &dyn HashDBRef<L::Hash, DBValue> { self.blobs }
+
+	/// Get the length of the value at `key`. Values stored in the blob database are not
+	/// fetched, their length being kept in the trie.
+	pub fn value_length(&self, key: &[u8]) -> Result<Option<usize>, TrieHash<L>, CError<L>> {
+		let len = |stored: &[u8]| StoredValue::<L::Hash>::decode(stored)
+			.map(|value| value.len())
+			.ok_or_else(|| stored.to_vec());
+		match self.raw.get_with(key, len)? {
+			Some(Ok(len)) => Ok(Some(len)),
+			Some(Err(stored)) => Err(Box::new(TrieError::InvalidHash(Default::default(), stored))),
+			None => Ok(None),
+		}
+	}
+}
+
+impl<'db, L> Trie<L> for BlobDB<'db, L>
+where
+	L: TrieLayout,
+{
+	fn root(&self) -> &TrieHash<L> { self.raw.root() }
+
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.raw.contains(key)
+	}
+
+	fn get_with<'a, 'key, Q: Query<L::Hash>>(&'a self, key: &'key [u8], query: Q)
+		-> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
+		where 'a: 'key
+	{
+		match self.raw.get_with(key, BlobQuery(query))? {
+			None => Ok(None),
+			Some(BlobQueryItem::Value(item)) => Ok(Some(item)),
+			Some(BlobQueryItem::Blob(query, hash)) => {
+				let value = self.blobs.get(&hash, EMPTY_PREFIX)
+					.ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash)))?;
+				Ok(Some(query.decode(&value)))
+			},
+			Some(BlobQueryItem::Invalid(stored)) =>
+				Err(Box::new(TrieError::InvalidHash(Default::default(), stored))),
+		}
+	}
+
+	fn iter<'a>(&'a self) -> Result<
+		Box<dyn TrieIterator<L, Item = TrieItem<'a, TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
+	> {
+		BlobDBIterator::<L>::new(self).map(|iter| Box::new(iter) as Box<_>)
+	}
+
+	fn len(&self) -> Result<usize, TrieHash<L>, CError<L>> {
+		self.raw.len()
+	}
+}
+
+/// Iterator over the pairs of a `BlobDB`, fetching the values stored in the blob database.
+pub struct BlobDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	trie_iterator: TrieDBIterator<'a, L>,
+	blobs: &'a dyn HashDBRef<L::Hash, DBValue>,
+}
+
+impl<'a, L> BlobDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	/// Creates new iterator.
+	pub fn new(trie: &'a BlobDB<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(BlobDBIterator {
+			trie_iterator: TrieDBIterator::new(&trie.raw)?,
+			blobs: trie.blobs,
+		})
+	}
+}
+
+impl<'a, L> TrieIterator<L> for BlobDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.trie_iterator.seek(key)
+	}
+}
+
+impl<'a, L> Iterator for BlobDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	type Item = TrieItem<'a, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let blobs = self.blobs;
+		self.trie_iterator.next().map(|item| {
+			let (key, stored) = item?;
+			Ok((key, resolve_value::<L>(blobs, &stored)?))
+		})
+	}
+}
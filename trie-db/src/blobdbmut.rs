@@ -0,0 +1,187 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use hash_db::{HashDB, EMPTY_PREFIX};
+use super::{Result, DBValue, TrieDBMut, TrieMut, TrieLayout, TrieHash, CError};
+use crate::blobdb::{StoredValue, resolve_value};
+
+/// A mutable `Trie` implementation storing values of at least `threshold` bytes in a separate
+/// blob database, keyed by their hash, the trie only holding their hash and length.
+/// This keeps nodes and proofs small when values are large; read the trie with `BlobDB`.
+///
+/// Use it as a `TrieMut` trait object.
+pub struct BlobDBMut<'db, L>
+where
+	L: TrieLayout,
+{
+	raw: TrieDBMut<'db, L>,
+	blobs: &'db mut dyn HashDB<L::Hash, DBValue>,
+	threshold: usize,
+}
+
+impl<'db, L> BlobDBMut<'db, L>
+where
+	L: TrieLayout,
+{
+	/// Create a new trie with the backing database `db`, blob database `blobs` and empty `root`.
+	pub fn new(
+		db: &'db mut dyn HashDB<L::Hash, DBValue>,
+		blobs: &'db mut dyn HashDB<L::Hash, DBValue>,
+		root: &'db mut TrieHash<L>,
+		threshold: usize,
+	) -> Self {
+		BlobDBMut { raw: TrieDBMut::new(db, root), blobs, threshold }
+	}
+
+	/// Create a new trie with the backing database `db`, blob database `blobs` and `root`.
+	///
+	/// Returns an error if root does not exist.
+	pub fn from_existing(
+		db: &'db mut dyn HashDB<L::Hash, DBValue>,
+		blobs: &'db mut dyn HashDB<L::Hash, DBValue>,
+		root: &'db mut TrieHash<L>,
+		threshold: usize,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(BlobDBMut { raw: TrieDBMut::from_existing(db, root)?, blobs, threshold })
+	}
+
+	/// Get the backing database.
+	pub fn db(&self) -> &dyn HashDB<L::Hash, DBValue> {
+		self.raw.db()
+	}
+
+	/// Get the blob database.
+	pub fn blobs(&self) -> &dyn HashDB<L::Hash, DBValue> {
+		&*self.blobs
+	}
+
+	/// Resolve a value removed from the trie, releasing it from the blob database.
+	fn release(&mut self, stored: DBValue) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		let blobs: &dyn HashDB<L::Hash, DBValue> = &*self.blobs;
+		let value = resolve_value::<L>(&blobs, &stored)?;
+		if let Some(StoredValue::Blob(hash, _)) = StoredValue::<L::Hash>::decode(&stored) {
+			self.blobs.remove(&hash, EMPTY_PREFIX);
+		}
+		Ok(value)
+	}
+}
+
+impl<'db, L> TrieMut<L> for BlobDBMut<'db, L>
+where
+	L: TrieLayout,
+{
+	fn root(&mut self) -> &TrieHash<L> { self.raw.root() }
+
+	fn is_empty(&self) -> bool { self.raw.is_empty() }
+
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.raw.contains(key)
+	}
+
+	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
+		where 'a: 'key
+	{
+		let blobs: &dyn HashDB<L::Hash, DBValue> = &*self.blobs;
+		match self.raw.get(key)? {
+			Some(stored) => resolve_value::<L>(&blobs, &stored).map(Some),
+			None => Ok(None),
+		}
+	}
+
+	fn insert(
+		&mut self,
+		key: &[u8],
+		value: &[u8],
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let stored = if value.len() >= self.threshold {
+			let hash = self.blobs.insert(EMPTY_PREFIX, value);
+			StoredValue::<L::Hash>::encode_blob(&hash, value.len())
+		} else {
+			StoredValue::<L::Hash>::encode_inline(value)
+		};
+		match self.raw.insert(key, &stored)? {
+			Some(old) => self.release(old).map(Some),
+			None => Ok(None),
+		}
+	}
+
+	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		match self.raw.remove(key)? {
+			Some(old) => self.release(old).map(Some),
+			None => Ok(None),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use memory_db::{MemoryDB, HashKey};
+	use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::DBValue;
+	use reference_trie::{RefBlobDB, RefBlobDBMut, RefTrieDB, Trie, TrieMut};
+
+	#[test]
+	fn large_values_go_to_blob_db() {
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut blobs = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let large = vec![7u8; 100];
+		let large_hash = KeccakHasher::hash(&large);
+		{
+			let mut t = RefBlobDBMut::new(&mut memdb, &mut blobs, &mut root, 64);
+			t.insert(b"small", b"value").unwrap();
+			t.insert(b"large", &large).unwrap();
+			assert_eq!(t.get(b"large").unwrap(), Some(large.clone().into()));
+		}
+		assert!(blobs.contains(&large_hash, EMPTY_PREFIX));
+
+		let t = RefBlobDB::new(&memdb, &blobs, &root).unwrap();
+		assert_eq!(t.get(b"small").unwrap(), Some(b"value".to_vec().into()));
+		assert_eq!(t.get(b"large").unwrap(), Some(large.clone().into()));
+		assert_eq!(
+			t.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(),
+			vec![(b"large".to_vec(), large.clone().into()), (b"small".to_vec(), b"value".to_vec().into())],
+		);
+
+		// The trie itself only holds the hash and length of the large value.
+		let raw = RefTrieDB::new(&memdb, &root).unwrap();
+		assert_eq!(raw.get(b"large").unwrap().unwrap().len(), 1 + 32 + 4);
+
+		// Lengths are known without the blobs.
+		let no_blobs = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let t = RefBlobDB::new(&memdb, &no_blobs, &root).unwrap();
+		assert_eq!(t.value_length(b"small").unwrap(), Some(5));
+		assert_eq!(t.value_length(b"large").unwrap(), Some(100));
+		assert!(t.get(b"large").is_err());
+	}
+
+	#[test]
+	fn replaced_blobs_are_released() {
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut blobs = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let first = vec![1u8; 100];
+		let second = vec![2u8; 100];
+		{
+			let mut t = RefBlobDBMut::new(&mut memdb, &mut blobs, &mut root, 64);
+			t.insert(b"key", &first).unwrap();
+			assert_eq!(t.insert(b"key", &second).unwrap(), Some(first.clone().into()));
+			assert!(!t.blobs().contains(&KeccakHasher::hash(&first), EMPTY_PREFIX));
+			assert_eq!(t.remove(b"key").unwrap(), Some(second.clone().into()));
+		}
+		blobs.purge();
+		assert_eq!(blobs.keys().len(), 0);
+	}
+}
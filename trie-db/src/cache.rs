@@ -0,0 +1,40 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Caching of decoded trie nodes and value hashes.
+
+use crate::node::OwnedNode;
+use crate::rstd::sync::Arc;
+use crate::DBValue;
+
+/// Cache of decoded nodes and value hashes read through by `TrieDB` lookups, see
+/// `TrieDB::with_cache`. `HO` is the hash type of the trie.
+///
+/// Methods take `&self` so that a cache can be shared by several tries; implementations
+/// use interior mutability to record insertions.
+pub trait TrieCache<HO> {
+	/// Get the decoded node stored under `hash`.
+	fn node(&self, hash: &HO) -> Option<Arc<OwnedNode<DBValue>>>;
+
+	/// Cache the node stored under `hash`, decoded after being read from the database.
+	fn insert_node(&self, hash: HO, node: Arc<OwnedNode<DBValue>>);
+
+	/// Get the hash of the value at `key` in the trie with root `root`, `Some(None)` if
+	/// the key is known not to be in the trie.
+	fn value_hash(&self, root: &HO, key: &[u8]) -> Option<Option<HO>>;
+
+	/// Cache the hash of the value at `key` in the trie with root `root`, `None` if the
+	/// key is not in the trie.
+	fn insert_value_hash(&self, root: HO, key: &[u8], hash: Option<HO>);
+}
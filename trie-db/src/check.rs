@@ -0,0 +1,238 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integrity check of a trie stored in a database.
+
+use hash_db::{HashDBRef, Hasher};
+use crate::nibble::NibbleVec;
+use crate::node::{ChildHandle, Node};
+use crate::node_codec::DecodeError;
+use crate::rstd::{vec, vec::Vec};
+use super::{DBValue, TrieLayout, CError, TrieHash, decode_options};
+
+/// Problem found by `check_trie`.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum CheckError<HO, CE> {
+	/// The node stored under `key` hashes to `actual`.
+	HashMismatch {
+		/// Database key of the node.
+		key: HO,
+		/// Hash of the stored node.
+		actual: HO,
+	},
+	/// The node stored under the hash cannot be decoded.
+	Undecodable(HO, CE),
+	/// The node stored under the hash is longer than the layout `MAX_NODE_SIZE`.
+	/// The second parameter is its length.
+	NodeTooLarge(HO, usize),
+	/// The node stored under the hash is followed by unconsumed bytes, with the layout
+	/// `STRICT_DECODING`. The second parameter is their number.
+	TrailingBytes(HO, usize),
+	/// The node referenced by `parent` (`None` for the root) is not in the database.
+	Missing {
+		/// Hash of the missing node.
+		hash: HO,
+		/// Hash of the node referencing it.
+		parent: Option<HO>,
+	},
+	/// The node under the hash holds a child reference of invalid length.
+	InvalidHash(HO, Vec<u8>),
+}
+
+/// Result of `check_trie`.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct CheckReport<HO, CE> {
+	/// Number of nodes found in the database, inline nodes excluded.
+	pub nodes: usize,
+	/// Problems found, in traversal order.
+	pub errors: Vec<CheckError<HO, CE>>,
+}
+
+impl<HO, CE> CheckReport<HO, CE> {
+	/// Whether no problem was found.
+	pub fn is_ok(&self) -> bool { self.errors.is_empty() }
+}
+
+/// Checks every node reachable from `root` in `db`: the node must be stored under its
+/// hash and decode, and the nodes it references must be in `db`. The check goes on after
+/// a problem, skipping the nodes under an undecodable or missing one, so that the report
+/// lists every problem found.
+pub fn check_trie<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+) -> CheckReport<TrieHash<L>, CError<L>> {
+	check_trie_to_depth::<L>(db, root, None)
+}
+
+/// Like `check_trie`, but only checks the nodes referenced by hash at most `max_depth`
+/// times below `root`, inline nodes being checked with their parent.
+pub(crate) fn check_trie_to_depth<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	max_depth: Option<usize>,
+) -> CheckReport<TrieHash<L>, CError<L>> {
+	let mut report = CheckReport { nodes: 0, errors: Vec::new() };
+	let mut stack = vec![(*root, NibbleVec::new(), None, 0)];
+	while let Some((hash, path, parent, depth)) = stack.pop() {
+		if max_depth.map_or(false, |max_depth| depth > max_depth) {
+			continue;
+		}
+		let data = match db.get(&hash, path.as_prefix()) {
+			Some(data) => data,
+			None => {
+				report.errors.push(CheckError::Missing { hash, parent });
+				continue;
+			},
+		};
+		report.nodes += 1;
+		let actual = L::Hash::hash(&data);
+		if actual != hash {
+			report.errors.push(CheckError::HashMismatch { key: hash, actual });
+		}
+		check_node::<L>(hash, &data, &path, depth, &mut stack, &mut report.errors);
+	}
+	report
+}
+
+/// Decodes the node at `path` and `depth`, looking into its inline children, and pushes
+/// its hash-referenced children.
+fn check_node<L: TrieLayout>(
+	hash: TrieHash<L>,
+	data: &[u8],
+	path: &NibbleVec,
+	depth: usize,
+	stack: &mut Vec<(TrieHash<L>, NibbleVec, Option<TrieHash<L>>, usize)>,
+	errors: &mut Vec<CheckError<TrieHash<L>, CError<L>>>,
+) {
+	let node = match decode_options::<L>().decode::<L::Codec>(data) {
+		Ok(node) => node,
+		Err(e) => {
+			errors.push(match e {
+				DecodeError::Codec(e) => CheckError::Undecodable(hash, e),
+				DecodeError::TooLarge(len) => CheckError::NodeTooLarge(hash, len),
+				DecodeError::TrailingBytes(len) => CheckError::TrailingBytes(hash, len),
+			});
+			return;
+		},
+	};
+	let mut child_path = path.clone();
+	match node {
+		Node::Extension(ref partial, _) | Node::NibbledBranch(ref partial, _, _) =>
+			child_path.append_partial(partial.right()),
+		Node::Empty | Node::Leaf(..) | Node::Branch(..) => (),
+	}
+	for (index, child) in node.children() {
+		let mut path = child_path.clone();
+		if let Some(index) = index {
+			path.push(index);
+		}
+		match child.decode::<L::Codec>() {
+			Ok(ChildHandle::Hash(child_hash)) =>
+				stack.push((child_hash, path, Some(hash), depth + 1)),
+			Ok(ChildHandle::Inline(data)) =>
+				check_node::<L>(hash, data, &path, depth, stack, errors),
+			Err(data) => errors.push(CheckError::InvalidHash(hash, data.to_vec())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, HashKey};
+	use keccak_hasher::KeccakHasher;
+	use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
+	use reference_trie::{RefTrieDB, RefTrieDBMut, TrieMut, ExtensionLayout, check_trie, CheckError};
+	use reference_trie::DBValue;
+
+	#[test]
+	fn check_trie_reports_problems() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for i in 0..50u8 {
+				t.insert(&[i, i / 3], &[i; 40]).unwrap();
+			}
+		}
+		let report = check_trie::<ExtensionLayout>(&db, &root);
+		assert!(report.is_ok());
+		assert_eq!(report.nodes, db.keys().len());
+
+		let mut children: Vec<_> = db.keys().into_iter()
+			.map(|(key, _)| key)
+			.filter(|key| key != &root)
+			.collect();
+		children.sort();
+		let (missing, corrupted) = (children[0], children[1]);
+
+		let mut missing_db = db.clone();
+		HashDB::remove(&mut missing_db, &missing, EMPTY_PREFIX);
+		let report = check_trie::<ExtensionLayout>(&missing_db, &root);
+		assert!(report.errors.iter().any(|error| match error {
+			CheckError::Missing { hash, parent: Some(_) } => hash == &missing,
+			_ => false,
+		}));
+
+		let mut corrupted_db = db.clone();
+		HashDB::remove(&mut corrupted_db, &corrupted, EMPTY_PREFIX);
+		corrupted_db.emplace(corrupted, EMPTY_PREFIX, vec![0xff; 40].into());
+		let report = check_trie::<ExtensionLayout>(&corrupted_db, &root);
+		assert!(report.errors.iter().any(|error| match error {
+			CheckError::HashMismatch { key, .. } => key == &corrupted,
+			_ => false,
+		}));
+	}
+
+	#[test]
+	fn verify_root_checks_top_nodes_only() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for i in 0..50u8 {
+				t.insert(&[i, i / 3], &[i; 40]).unwrap();
+			}
+		}
+		assert!(RefTrieDB::new_verified(&db, &root, true).is_ok());
+
+		let mut corrupted_db = db.clone();
+		for (key, _) in db.keys() {
+			if key != root {
+				HashDB::remove(&mut corrupted_db, &key, EMPTY_PREFIX);
+				corrupted_db.emplace(key, EMPTY_PREFIX, vec![0xff; 40].into());
+			}
+		}
+		assert!(RefTrieDB::new_verified(&corrupted_db, &root, false).is_ok());
+		let report = RefTrieDB::new_verified(&corrupted_db, &root, true).err().unwrap();
+		assert!(report.nodes > 1 && report.nodes < db.keys().len());
+		assert!(report.errors.iter().all(|error| match error {
+			CheckError::HashMismatch { key, .. } | CheckError::Undecodable(key, _) => key != &root,
+			_ => false,
+		}));
+
+		let mut corrupted_db = db.clone();
+		HashDB::remove(&mut corrupted_db, &root, EMPTY_PREFIX);
+		corrupted_db.emplace(root, EMPTY_PREFIX, vec![0xff; 40].into());
+		let t = RefTrieDB::new(&corrupted_db, &root).unwrap();
+		let report = t.verify_root(false);
+		assert_eq!(report.nodes, 1);
+		let garbage_hash = KeccakHasher::hash(&[0xff; 40]);
+		assert!(report.errors.iter().any(|error| match error {
+			CheckError::HashMismatch { key, actual } => key == &root && actual == &garbage_hash,
+			_ => false,
+		}));
+	}
+}
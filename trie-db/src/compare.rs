@@ -0,0 +1,455 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Comparison of two tries, walking them together and skipping identical subtrees.
+
+use hash_db::HashDBRef;
+use crate::nibble::NibbleVec;
+use crate::merge::{NodeView, joined, load_view};
+use crate::node_codec::NodeCodec;
+use crate::triedbmut::ChildReference;
+use crate::rstd::{boxed::Box, vec, vec::Vec};
+use super::{DBValue, Result, TrieError, TrieLayout, CError, TrieHash};
+
+/// A subtree, either referenced by its parent node or already decoded.
+enum Subtree<HO> {
+	Reference(ChildReference<HO>),
+	Loaded(NodeView<HO>),
+}
+
+/// The value and children of a node seen at a given partial key.
+struct Level<HO> {
+	value: Option<DBValue>,
+	children: [Option<Subtree<HO>>; 16],
+}
+
+impl<HO: Copy> Level<HO> {
+	/// View `view` at its first `len` partial key nibbles. If its partial key is longer,
+	/// it becomes the only child of an empty level.
+	fn split(view: NodeView<HO>, len: usize) -> Self {
+		let mut children: [Option<Subtree<HO>>; 16] = Default::default();
+		if view.partial.len() == len {
+			for (child, reference) in children.iter_mut().zip(view.children.iter().copied()) {
+				*child = reference.map(Subtree::Reference);
+			}
+			return Level { value: view.value, children };
+		}
+		let index = view.partial.at(len);
+		children[index as usize] = Some(Subtree::Loaded(view.drop_partial(len + 1)));
+		Level { value: None, children }
+	}
+}
+
+/// Length of the common prefix of two nibble vectors.
+fn common_prefix(a: &NibbleVec, b: &NibbleVec) -> usize {
+	(0..a.len().min(b.len()))
+		.take_while(|i| a.at(*i) == b.at(*i))
+		.count()
+}
+
+fn load<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	subtree: Subtree<TrieHash<L>>,
+	path: &NibbleVec,
+) -> Result<NodeView<TrieHash<L>>, TrieHash<L>, CError<L>> {
+	match subtree {
+		Subtree::Reference(reference) => load_view::<L>(db, &reference, path),
+		Subtree::Loaded(view) => Ok(view),
+	}
+}
+
+/// Returns `None` if the tries at `root_a` in `db_a` and at `root_b` in `db_b` hold the same
+/// pairs, and otherwise the path of the first key, in key order, that is in only one of them
+/// or has different values in both.
+///
+/// Both tries are walked together, and subtrees with the same hash at the same path are
+/// skipped without being fetched, so only the nodes along the paths where the tries differ
+/// are read. Key paths are trie paths: with a layout using `HASH_KEYS` they are key hashes.
+pub fn tries_equal<L: TrieLayout>(
+	db_a: &dyn HashDBRef<L::Hash, DBValue>,
+	root_a: &TrieHash<L>,
+	db_b: &dyn HashDBRef<L::Hash, DBValue>,
+	root_b: &TrieHash<L>,
+) -> Result<Option<NibbleVec>, TrieHash<L>, CError<L>> {
+	if root_a == root_b {
+		return Ok(None);
+	}
+	let a = load_root::<L>(db_a, root_a)?;
+	let b = load_root::<L>(db_b, root_b)?;
+	first_difference::<L>(db_a, db_b, a, b, &NibbleVec::new())
+}
+
+/// Fetch and decode the root node, the empty node not needing to be in the database.
+fn load_root<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+) -> Result<NodeView<TrieHash<L>>, TrieHash<L>, CError<L>> {
+	if *root == L::Codec::hashed_null_node() {
+		return Ok(NodeView::new(NibbleVec::new(), None));
+	}
+	load_view::<L>(db, &ChildReference::Hash(*root), &NibbleVec::new())
+}
+
+/// First key path at which the nodes `a` and `b`, both starting at `path`, differ.
+fn first_difference<L: TrieLayout>(
+	db_a: &dyn HashDBRef<L::Hash, DBValue>,
+	db_b: &dyn HashDBRef<L::Hash, DBValue>,
+	a: NodeView<TrieHash<L>>,
+	b: NodeView<TrieHash<L>>,
+	path: &NibbleVec,
+) -> Result<Option<NibbleVec>, TrieHash<L>, CError<L>> {
+	let common = common_prefix(&a.partial, &b.partial);
+	if common < a.partial.len() && common < b.partial.len() {
+		// the keys diverge: no key is in both nodes
+		return if a.partial.at(common) < b.partial.at(common) {
+			first_key::<L>(db_a, a, path).map(Some)
+		} else {
+			first_key::<L>(db_b, b, path).map(Some)
+		};
+	}
+
+	let mut level_path = path.clone();
+	for i in 0..common {
+		level_path.push(a.partial.at(i));
+	}
+	let mut a = Level::split(a, common);
+	let mut b = Level::split(b, common);
+	if a.value != b.value {
+		return Ok(Some(level_path));
+	}
+	for (i, (child_a, child_b)) in a.children.iter_mut().zip(b.children.iter_mut()).enumerate() {
+		let mut child_path = level_path.clone();
+		child_path.push(i as u8);
+		let difference = match (child_a.take(), child_b.take()) {
+			(None, None) => None,
+			(Some(Subtree::Reference(reference_a)), Some(Subtree::Reference(reference_b)))
+				if reference_a == reference_b => None,
+			(Some(child_a), None) => {
+				let child_a = load::<L>(db_a, child_a, &child_path)?;
+				Some(first_key::<L>(db_a, child_a, &child_path)?)
+			},
+			(None, Some(child_b)) => {
+				let child_b = load::<L>(db_b, child_b, &child_path)?;
+				Some(first_key::<L>(db_b, child_b, &child_path)?)
+			},
+			(Some(child_a), Some(child_b)) => {
+				let child_a = load::<L>(db_a, child_a, &child_path)?;
+				let child_b = load::<L>(db_b, child_b, &child_path)?;
+				first_difference::<L>(db_a, db_b, child_a, child_b, &child_path)?
+			},
+		};
+		if difference.is_some() {
+			return Ok(difference);
+		}
+	}
+	Ok(None)
+}
+
+/// Path of the first key of the node `view` starting at `path`.
+fn first_key<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	view: NodeView<TrieHash<L>>,
+	path: &NibbleVec,
+) -> Result<NibbleVec, TrieHash<L>, CError<L>> {
+	if view.value.is_some() {
+		return Ok(joined(path, &view.partial, None));
+	}
+	match view.children.iter().position(Option::is_some) {
+		Some(index) => {
+			let child_path = joined(path, &view.partial, Some(index as u8));
+			let reference = view.children[index].expect("Position of a child; qed");
+			let child = load_view::<L>(db, &reference, &child_path)?;
+			first_key::<L>(db, child, &child_path)
+		},
+		// Only the empty node has neither value nor children.
+		None => Ok(joined(path, &view.partial, None)),
+	}
+}
+
+/// Pending step of a `KeySetIterator`.
+enum Step<HO> {
+	/// Compare the subtrees of both tries starting at a path.
+	Compare(NibbleVec, Option<Subtree<HO>>, Option<Subtree<HO>>),
+	/// Yield all the keys of a subtree of the first trie starting at a path.
+	All(NibbleVec, Subtree<HO>),
+}
+
+/// Iterator over the keys of the first of two tries that are also in the second one, or that
+/// are not, see `trie_intersection` and `trie_difference`. Keys are yielded in order.
+pub struct KeySetIterator<'a, L: TrieLayout> {
+	db_a: &'a dyn HashDBRef<L::Hash, DBValue>,
+	db_b: &'a dyn HashDBRef<L::Hash, DBValue>,
+	/// Whether keys of the first trie that are in the second one are yielded, or the others.
+	common: bool,
+	stack: Vec<Step<TrieHash<L>>>,
+}
+
+/// Iterate over the keys present in both the trie at `root_a` in `db_a` and the trie at
+/// `root_b` in `db_b`, whatever their values.
+///
+/// Both tries are walked together: subtrees with the same hash at the same path are
+/// enumerated from the first trie only, and subtrees only found in one trie are skipped.
+/// Keys are trie paths: with a layout using `HASH_KEYS` they are key hashes.
+pub fn trie_intersection<'a, L: TrieLayout>(
+	db_a: &'a dyn HashDBRef<L::Hash, DBValue>,
+	root_a: &TrieHash<L>,
+	db_b: &'a dyn HashDBRef<L::Hash, DBValue>,
+	root_b: &TrieHash<L>,
+) -> KeySetIterator<'a, L> {
+	KeySetIterator::new(db_a, root_a, db_b, root_b, true)
+}
+
+/// Iterate over the keys present in the trie at `root_a` in `db_a` but not in the trie
+/// at `root_b` in `db_b`.
+///
+/// Both tries are walked together: subtrees with the same hash at the same path are
+/// skipped without being fetched, and subtrees only found in the first trie are enumerated.
+/// Keys are trie paths: with a layout using `HASH_KEYS` they are key hashes.
+pub fn trie_difference<'a, L: TrieLayout>(
+	db_a: &'a dyn HashDBRef<L::Hash, DBValue>,
+	root_a: &TrieHash<L>,
+	db_b: &'a dyn HashDBRef<L::Hash, DBValue>,
+	root_b: &TrieHash<L>,
+) -> KeySetIterator<'a, L> {
+	KeySetIterator::new(db_a, root_a, db_b, root_b, false)
+}
+
+impl<'a, L: TrieLayout> KeySetIterator<'a, L> {
+	fn new(
+		db_a: &'a dyn HashDBRef<L::Hash, DBValue>,
+		root_a: &TrieHash<L>,
+		db_b: &'a dyn HashDBRef<L::Hash, DBValue>,
+		root_b: &TrieHash<L>,
+		common: bool,
+	) -> Self {
+		let null_root = L::Codec::hashed_null_node();
+		let root = |root: &TrieHash<L>| if *root == null_root {
+			None
+		} else {
+			Some(Subtree::Reference(ChildReference::Hash(*root)))
+		};
+		let stack = vec![Step::Compare(NibbleVec::new(), root(root_a), root(root_b))];
+		KeySetIterator { db_a, db_b, common, stack }
+	}
+
+	/// Process a step, returning the path of the key to yield if any.
+	fn step(&mut self, step: Step<TrieHash<L>>) -> Result<Option<NibbleVec>, TrieHash<L>, CError<L>> {
+		let (path, a, b) = match step {
+			Step::All(path, subtree) => {
+				let view = load::<L>(self.db_a, subtree, &path)?;
+				let level_path = joined(&path, &view.partial, None);
+				let partial_len = view.partial.len();
+				let level = Level::split(view, partial_len);
+				let key = level.value.as_ref().map(|_| level_path.clone());
+				self.push_children(&level_path, level, None);
+				return Ok(key);
+			},
+			Step::Compare(path, Some(Subtree::Reference(reference_a)), Some(Subtree::Reference(reference_b)))
+				if reference_a == reference_b => {
+				if self.common {
+					self.stack.push(Step::All(path, Subtree::Reference(reference_a)));
+				}
+				return Ok(None);
+			},
+			Step::Compare(path, Some(a), None) => {
+				if !self.common {
+					self.stack.push(Step::All(path, a));
+				}
+				return Ok(None);
+			},
+			Step::Compare(_, None, _) => return Ok(None),
+			Step::Compare(path, Some(a), Some(b)) => (path, a, b),
+		};
+
+		let a = load::<L>(self.db_a, a, &path)?;
+		let b = load::<L>(self.db_b, b, &path)?;
+		let common = common_prefix(&a.partial, &b.partial);
+		if common < a.partial.len() && common < b.partial.len() {
+			// the keys diverge: no key is in both nodes
+			if !self.common {
+				self.stack.push(Step::All(path, Subtree::Loaded(a)));
+			}
+			return Ok(None);
+		}
+
+		let mut level_path = path;
+		for i in 0..common {
+			level_path.push(a.partial.at(i));
+		}
+		let a = Level::split(a, common);
+		let b = Level::split(b, common);
+		let key = match (a.value.is_some(), b.value.is_some()) {
+			(true, in_b) if in_b == self.common => Some(level_path.clone()),
+			_ => None,
+		};
+		self.push_children(&level_path, a, Some(b));
+		Ok(key)
+	}
+
+	/// Push the steps for the children of the first trie `a` and of the second trie `b`,
+	/// if comparing, so that they are processed in order.
+	fn push_children(
+		&mut self,
+		path: &NibbleVec,
+		mut a: Level<TrieHash<L>>,
+		mut b: Option<Level<TrieHash<L>>>,
+	) {
+		for i in (0..16).rev() {
+			let mut child_path = path.clone();
+			child_path.push(i as u8);
+			let child_a = a.children[i].take();
+			match b.as_mut() {
+				Some(b) => self.stack.push(Step::Compare(child_path, child_a, b.children[i].take())),
+				None => if let Some(child_a) = child_a {
+					self.stack.push(Step::All(child_path, child_a));
+				},
+			}
+		}
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for KeySetIterator<'a, L> {
+	type Item = Result<Vec<u8>, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while let Some(step) = self.stack.pop() {
+			match self.step(step) {
+				Ok(Some(path)) => {
+					let (key_slice, maybe_extra_nibble) = path.as_prefix();
+					let key = key_slice.to_vec();
+					if let Some(extra_nibble) = maybe_extra_nibble {
+						return Some(Err(Box::new(TrieError::ValueAtIncompleteKey(key, extra_nibble))));
+					}
+					return Some(Ok(key));
+				},
+				Ok(None) => (),
+				Err(e) => return Some(Err(e)),
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{
+		ExtensionLayout, NibbleVec, NoExtensionLayout, TrieLayout, TrieDBMut, TrieMut, tries_equal,
+		trie_difference, trie_intersection,
+	};
+	use reference_trie::DBValue;
+
+	type MemDB = MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>;
+
+	fn build<L: TrieLayout<Hash = KeccakHasher>>(
+		data: &[(Vec<u8>, Vec<u8>)],
+	) -> (MemDB, <KeccakHasher as hash_db::Hasher>::Out) {
+		let mut db = MemDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+			for (k, v) in data {
+				t.insert(k, v).unwrap();
+			}
+		}
+		(db, root)
+	}
+
+	fn key_path(key: &[u8]) -> NibbleVec {
+		let mut path = NibbleVec::new();
+		for byte in key {
+			path.push(byte >> 4);
+			path.push(byte & 0x0f);
+		}
+		path
+	}
+
+	fn check_tries_equal<L: TrieLayout<Hash = KeccakHasher>>() {
+		let base: Vec<_> = (0..100u32).map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 40])).collect();
+		let (db_a, root_a) = build::<L>(&base);
+		let (db_b, root_b) = build::<L>(&base);
+		assert_eq!(tries_equal::<L>(&db_a, &root_a, &db_b, &root_b).unwrap(), None);
+
+		let mut changed = base.clone();
+		changed[42].1 = b"changed".to_vec();
+		changed[70].1 = b"changed".to_vec();
+		let (db_b, root_b) = build::<L>(&changed);
+		let difference = Some(key_path(&42u32.to_be_bytes()));
+		assert_eq!(tries_equal::<L>(&db_a, &root_a, &db_b, &root_b).unwrap(), difference);
+		assert_eq!(tries_equal::<L>(&db_b, &root_b, &db_a, &root_a).unwrap(), difference);
+
+		let mut extended = base.clone();
+		extended.push((b"\x00\x00\x00\x07\x01".to_vec(), vec![1; 40]));
+		extended.remove(90);
+		let (db_b, root_b) = build::<L>(&extended);
+		let difference = Some(key_path(b"\x00\x00\x00\x07\x01"));
+		assert_eq!(tries_equal::<L>(&db_a, &root_a, &db_b, &root_b).unwrap(), difference);
+
+		let (db_b, root_b) = build::<L>(&[]);
+		let difference = Some(key_path(&0u32.to_be_bytes()));
+		assert_eq!(tries_equal::<L>(&db_a, &root_a, &db_b, &root_b).unwrap(), difference);
+	}
+
+	fn check_key_sets<L: TrieLayout<Hash = KeccakHasher>>() {
+		let data_a: Vec<_> = (0..100u32).map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 40])).collect();
+		let mut data_b: Vec<_> = (50..150u32).map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 40])).collect();
+		data_b[10].1 = b"changed".to_vec();
+		data_b.push((b"\x00\x00\x00\x07\x01".to_vec(), vec![1; 40]));
+		let (db_a, root_a) = build::<L>(&data_a);
+		let (db_b, root_b) = build::<L>(&data_b);
+
+		let keys = |range: std::ops::Range<u32>| range.map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>();
+		let intersection: Vec<_> = trie_intersection::<L>(&db_a, &root_a, &db_b, &root_b)
+			.map(Result::unwrap)
+			.collect();
+		assert_eq!(intersection, keys(50..100));
+		let difference: Vec<_> = trie_difference::<L>(&db_a, &root_a, &db_b, &root_b)
+			.map(Result::unwrap)
+			.collect();
+		assert_eq!(difference, keys(0..50));
+		let difference: Vec<_> = trie_difference::<L>(&db_b, &root_b, &db_a, &root_a)
+			.map(Result::unwrap)
+			.collect();
+		let mut expected = vec![b"\x00\x00\x00\x07\x01".to_vec()];
+		expected.extend(keys(100..150));
+		assert_eq!(difference, expected);
+
+		let (db_empty, root_empty) = build::<L>(&[]);
+		assert_eq!(trie_intersection::<L>(&db_a, &root_a, &db_empty, &root_empty).count(), 0);
+		assert_eq!(trie_difference::<L>(&db_a, &root_a, &db_empty, &root_empty).count(), 100);
+		assert_eq!(trie_difference::<L>(&db_a, &root_a, &db_a, &root_a).count(), 0);
+		assert_eq!(trie_intersection::<L>(&db_a, &root_a, &db_a, &root_a).count(), 100);
+	}
+
+	#[test]
+	fn key_sets_with_extension() {
+		check_key_sets::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn key_sets_without_extension() {
+		check_key_sets::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn tries_equal_with_extension() {
+		check_tries_equal::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn tries_equal_without_extension() {
+		check_tries_equal::<NoExtensionLayout>();
+	}
+}
@@ -0,0 +1,119 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Database values shared without copying.
+
+use crate::rstd::{borrow::Borrow, fmt, ops::Deref, sync::Arc, vec::Vec};
+
+/// Database value. Clones share the bytes, so a value can be held by the database,
+/// a cache and callers at once without being copied.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DBValue(Arc<[u8]>);
+
+impl DBValue {
+	/// Copy the bytes of the value.
+	pub fn to_vec(&self) -> Vec<u8> {
+		self.0.to_vec()
+	}
+}
+
+impl Default for DBValue {
+	fn default() -> Self {
+		DBValue(Arc::from(&[][..]))
+	}
+}
+
+impl fmt::Debug for DBValue {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fmt::Debug::fmt(&self.0[..], f)
+	}
+}
+
+impl Deref for DBValue {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl AsRef<[u8]> for DBValue {
+	fn as_ref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl Borrow<[u8]> for DBValue {
+	fn borrow(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl<'a> From<&'a [u8]> for DBValue {
+	fn from(bytes: &'a [u8]) -> Self {
+		DBValue(Arc::from(bytes))
+	}
+}
+
+impl From<Vec<u8>> for DBValue {
+	fn from(bytes: Vec<u8>) -> Self {
+		DBValue(Arc::from(bytes))
+	}
+}
+
+impl From<DBValue> for Vec<u8> {
+	fn from(value: DBValue) -> Self {
+		value.0.to_vec()
+	}
+}
+
+impl PartialEq<[u8]> for DBValue {
+	fn eq(&self, other: &[u8]) -> bool {
+		self.0[..] == *other
+	}
+}
+
+impl<'a> PartialEq<&'a [u8]> for DBValue {
+	fn eq(&self, other: &&'a [u8]) -> bool {
+		self.0[..] == **other
+	}
+}
+
+impl PartialEq<Vec<u8>> for DBValue {
+	fn eq(&self, other: &Vec<u8>) -> bool {
+		self.0[..] == other[..]
+	}
+}
+
+impl PartialEq<DBValue> for Vec<u8> {
+	fn eq(&self, other: &DBValue) -> bool {
+		self[..] == other.0[..]
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::DBValue;
+
+	#[test]
+	fn clones_share_bytes() {
+		let value = DBValue::from(vec![1, 2, 3]);
+		let clone = value.clone();
+		assert_eq!(clone.as_ptr(), value.as_ptr());
+		assert_eq!(clone, vec![1, 2, 3]);
+		assert_eq!(clone, &[1, 2, 3][..]);
+		assert_eq!(Vec::from(clone), vec![1, 2, 3]);
+		assert!(DBValue::default().is_empty());
+	}
+}
@@ -0,0 +1,347 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Computation of the nodes that become unreachable between two trie states.
+
+use hash_db::HashDBRef;
+use hashbrown::{HashMap, HashSet};
+use crate::nibble::NibbleVec;
+use crate::node::{Node, NodeHandle, decode_hash};
+use crate::node_codec::NodeCodec;
+use crate::rstd::{boxed::Box, vec, vec::Vec};
+use super::{DBValue, Result, TrieError, TrieLayout, CError, TrieHash, decode_options};
+
+/// Owned representation of a node prefix, used as a map key.
+type OwnedPrefix = (Vec<u8>, Option<u8>);
+
+fn owned_prefix(path: &NibbleVec) -> OwnedPrefix {
+	let (bytes, last) = path.as_prefix();
+	(bytes.to_vec(), last)
+}
+
+/// Returns the hash-referenced nodes of `old_root` that are not part of `new_root`.
+///
+/// Nodes are identified by their hash and the nibble path at which they are stored (the
+/// prefix passed to `HashDB`), which matches how `TrieDBMut` releases nodes on commit.
+/// Both tries are walked together and any subtree whose root hash is identical at the same
+/// path in both tries is skipped without being fetched.
+/// Each returned entry is meant to be passed to `HashDB::remove` with `path.as_prefix()`.
+///
+/// This is exact for databases storing nodes under their prefix, such as `MemoryDB` with
+/// `PrefixedKey`. A database keyed by hash only must reference count its nodes, as
+/// `MemoryDB` with `HashKey` does: a node stored at several paths is then returned for each
+/// path it is dropped from, even if it is still reachable from `new_root` at another path,
+/// and removing every returned entry once leaves it referenced. Databases keyed by hash
+/// that do not reference count their nodes should use `nodes_removed_by_hash` instead.
+pub fn nodes_removed<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	old_root: &TrieHash<L>,
+	new_root: &TrieHash<L>,
+) -> Result<Vec<(TrieHash<L>, NibbleVec)>, TrieHash<L>, CError<L>> {
+	let mut removed = Vec::new();
+	if old_root == new_root || *old_root == L::Codec::hashed_null_node() {
+		return Ok(removed);
+	}
+
+	// Hash-referenced nodes of the new trie that were discovered but not expanded yet.
+	let mut new_pending: HashMap<OwnedPrefix, (NibbleVec, TrieHash<L>)> = HashMap::new();
+	if *new_root != L::Codec::hashed_null_node() {
+		new_pending.insert(owned_prefix(&NibbleVec::new()), (NibbleVec::new(), *new_root));
+	}
+	// Hash-referenced nodes of the new trie keyed by their path, once expanded.
+	let mut new_expanded: HashMap<OwnedPrefix, TrieHash<L>> = HashMap::new();
+
+	let mut old_stack = vec![(NibbleVec::new(), *old_root)];
+	while let Some((path, hash)) = old_stack.pop() {
+		let new_hash = new_hash_at::<L>(db, &path, &mut new_pending, &mut new_expanded)?;
+		if new_hash == Some(hash) {
+			continue;
+		}
+		let node_data = fetch::<L>(db, &hash, &path)?;
+		hashed_children::<L>(hash, &node_data, path.clone(), &mut |child_path, child_hash| {
+			old_stack.push((child_path, child_hash));
+		})?;
+		removed.push((hash, path));
+	}
+
+	Ok(removed)
+}
+
+/// Returns the hashes of the nodes reachable from `old_root` but not from `new_root`, each
+/// hash once.
+///
+/// Unlike `nodes_removed`, a node still reachable from `new_root` at another path than the
+/// one it is dropped from is not returned, so every returned hash can be deleted from a
+/// database keyed by hash only, without reference counting. This is at the cost of walking
+/// the distinct nodes of `new_root` until all the nodes dropped from `old_root` are ruled
+/// out or found, shared subtrees included.
+pub fn nodes_removed_by_hash<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	old_root: &TrieHash<L>,
+	new_root: &TrieHash<L>,
+) -> Result<Vec<TrieHash<L>>, TrieHash<L>, CError<L>> {
+	let mut removed = Vec::new();
+	let mut candidates = HashSet::new();
+	for (hash, _) in nodes_removed::<L>(db, old_root, new_root)? {
+		if candidates.insert(hash) {
+			removed.push(hash);
+		}
+	}
+	if candidates.is_empty() || *new_root == L::Codec::hashed_null_node() {
+		return Ok(removed);
+	}
+
+	let mut seen = HashSet::new();
+	let mut new_stack = vec![(NibbleVec::new(), *new_root)];
+	while let Some((path, hash)) = new_stack.pop() {
+		if !seen.insert(hash) {
+			continue;
+		}
+		if candidates.remove(&hash) && candidates.is_empty() {
+			break;
+		}
+		let node_data = fetch::<L>(db, &hash, &path)?;
+		hashed_children::<L>(hash, &node_data, path, &mut |child_path, child_hash| {
+			new_stack.push((child_path, child_hash));
+		})?;
+	}
+	removed.retain(|hash| candidates.contains(hash));
+	Ok(removed)
+}
+
+/// Get the hash of the new trie node stored at `path`, expanding only the
+/// nodes of the new trie that are strict ancestors of `path`.
+fn new_hash_at<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	path: &NibbleVec,
+	pending: &mut HashMap<OwnedPrefix, (NibbleVec, TrieHash<L>)>,
+	expanded: &mut HashMap<OwnedPrefix, TrieHash<L>>,
+) -> Result<Option<TrieHash<L>>, TrieHash<L>, CError<L>> {
+	// Ancestors are expanded from the root down, so children registered by a shorter
+	// prefix are found when checking the longer ones.
+	let mut ancestor = NibbleVec::new();
+	for i in 0..path.len() {
+		if let Some((pending_path, hash)) = pending.remove(&owned_prefix(&ancestor)) {
+			let node_data = fetch::<L>(db, &hash, &pending_path)?;
+			hashed_children::<L>(hash, &node_data, pending_path.clone(), &mut |child_path, child_hash| {
+				pending.insert(owned_prefix(&child_path), (child_path, child_hash));
+			})?;
+			expanded.insert(owned_prefix(&pending_path), hash);
+		}
+		ancestor.push(path.at(i));
+	}
+	let key = owned_prefix(path);
+	Ok(pending.get(&key).map(|(_, hash)| *hash).or_else(|| expanded.get(&key).cloned()))
+}
+
+fn fetch<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	hash: &TrieHash<L>,
+	path: &NibbleVec,
+) -> Result<DBValue, TrieHash<L>, CError<L>> {
+	db.get(hash, path.as_prefix()).ok_or_else(|| Box::new(if path.is_empty() {
+		TrieError::InvalidStateRoot(*hash)
+	} else {
+		TrieError::IncompleteDatabase(*hash)
+	}))
+}
+
+/// Call `f` with the path and hash of every hash-referenced child of a node,
+/// looking through inline children.
+fn hashed_children<L: TrieLayout>(
+	node_hash: TrieHash<L>,
+	node_data: &[u8],
+	path: NibbleVec,
+	f: &mut dyn FnMut(NibbleVec, TrieHash<L>),
+) -> Result<(), TrieHash<L>, CError<L>> {
+	let node = decode_options::<L>().decode::<L::Codec>(node_data)
+		.map_err(|e| Box::new(e.into_trie_error(node_hash)))?;
+	let mut child = |handle: NodeHandle, child_path: NibbleVec| -> Result<(), TrieHash<L>, CError<L>> {
+		match handle {
+			NodeHandle::Hash(data) => {
+				let hash = decode_hash::<L::Hash>(data)
+					.ok_or_else(|| Box::new(TrieError::InvalidHash(node_hash, data.to_vec())))?;
+				f(child_path, hash);
+				Ok(())
+			},
+			NodeHandle::Inline(data) => hashed_children::<L>(node_hash, data, child_path, &mut *f),
+		}
+	};
+	match node {
+		Node::Empty | Node::Leaf(..) => Ok(()),
+		Node::Extension(partial, handle) => {
+			let mut child_path = path;
+			child_path.append_partial(partial.right());
+			child(handle, child_path)
+		},
+		Node::Branch(children, _) => {
+			for (i, handle) in children.iter().enumerate() {
+				if let Some(handle) = handle {
+					let mut child_path = path.clone();
+					child_path.push(i as u8);
+					child(*handle, child_path)?;
+				}
+			}
+			Ok(())
+		},
+		Node::NibbledBranch(partial, children, _) => {
+			let mut base = path;
+			base.append_partial(partial.right());
+			for (i, handle) in children.iter().enumerate() {
+				if let Some(handle) = handle {
+					let mut child_path = base.clone();
+					child_path.push(i as u8);
+					child(*handle, child_path)?;
+				}
+			}
+			Ok(())
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use hash_db::{HashDB, EMPTY_PREFIX};
+	use memory_db::{MemoryDB, HashKey, PrefixedKey, prefixed_key};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{
+		ExtensionLayout, NoExtensionLayout, TrieDBMut, TrieLayout, TrieMut, nodes_removed,
+		nodes_removed_by_hash,
+	};
+	use reference_trie::DBValue;
+
+	fn check_nodes_removed<L: TrieLayout<Hash = KeccakHasher>>() {
+		let mut old_db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut old_root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut old_db, &mut old_root);
+			for i in 0..100u32 {
+				t.insert(&i.to_be_bytes(), &[i as u8; 40]).unwrap();
+			}
+		}
+
+		let mut new_db = old_db.clone();
+		let mut new_root = old_root;
+		{
+			let mut t = TrieDBMut::<L>::from_existing(&mut new_db, &mut new_root).unwrap();
+			t.insert(&7u32.to_be_bytes(), b"changed").unwrap();
+			t.remove(&42u32.to_be_bytes()).unwrap();
+			t.insert(&1000u32.to_be_bytes(), &[1u8; 40]).unwrap();
+		}
+
+		// Database holding the nodes of both tries.
+		let mut both_db = old_db.clone();
+		both_db.consolidate(new_db.clone());
+
+		let mut removed: Vec<Vec<u8>> = nodes_removed::<L>(&both_db, &old_root, &new_root)
+			.unwrap()
+			.iter()
+			.map(|(hash, path)| prefixed_key::<KeccakHasher>(hash, path.as_prefix()))
+			.collect();
+		removed.sort();
+
+		let new_keys = new_db.keys();
+		let mut expected: Vec<Vec<u8>> = old_db.keys().into_iter()
+			.filter(|(key, _)| new_keys.get(key).map_or(true, |rc| *rc <= 0))
+			.map(|(key, _)| key)
+			.collect();
+		expected.sort();
+
+		assert!(!removed.is_empty());
+		assert_eq!(removed, expected);
+		assert!(nodes_removed::<L>(&both_db, &new_root, &new_root).unwrap().is_empty());
+
+		let mut by_hash = nodes_removed_by_hash::<L>(&both_db, &old_root, &new_root).unwrap();
+		by_hash.sort();
+		// prefixed keys end with the node hash.
+		let new_hashes: Vec<Vec<u8>> = new_keys.iter()
+			.filter(|(_, rc)| **rc > 0)
+			.map(|(key, _)| key[key.len() - 32..].to_vec())
+			.collect();
+		let mut expected_hashes: Vec<_> = nodes_removed::<L>(&both_db, &old_root, &new_root)
+			.unwrap()
+			.into_iter()
+			.map(|(hash, _)| hash)
+			.filter(|hash| !new_hashes.iter().any(|new_hash| &new_hash[..] == hash.as_ref()))
+			.collect();
+		expected_hashes.sort();
+		expected_hashes.dedup();
+		assert_eq!(by_hash, expected_hashes);
+	}
+
+	#[test]
+	fn nodes_removed_hash_keyed() {
+		// leaves with the same partial key and value under different branch children
+		// are the same node, stored once per path.
+		let old_items: Vec<_> = (0..16u8).map(|i| (vec![i, 0xaa], vec![0x55; 40])).collect();
+		let new_items: Vec<_> = old_items.iter().skip(2).cloned().collect();
+		let build = |items: &[(Vec<u8>, Vec<u8>)]| {
+			let mut db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+			let mut root = Default::default();
+			{
+				let mut t = TrieDBMut::<ExtensionLayout>::new(&mut db, &mut root);
+				for (key, value) in items {
+					t.insert(key, value).unwrap();
+				}
+			}
+			(db, root)
+		};
+		let (old_db, old_root) = build(&old_items);
+		let (new_db, new_root) = build(&new_items);
+
+		let mut both = old_db.clone();
+		both.consolidate(new_db.clone());
+		let mut pruned = both.clone();
+		let removed = nodes_removed::<ExtensionLayout>(&pruned, &old_root, &new_root).unwrap();
+		// the two dropped leaves are the node still reachable under the other children.
+		assert_eq!(removed.iter().filter(|(hash, _)| new_db.contains(hash, EMPTY_PREFIX)).count(), 2);
+		for (hash, path) in removed {
+			pruned.remove(&hash, path.as_prefix());
+		}
+		pruned.purge();
+		let live = |db: &MemoryDB<KeccakHasher, HashKey<_>, DBValue>| {
+			let mut keys: Vec<_> = db.keys().into_iter().map(|(key, _)| key).collect();
+			keys.sort();
+			keys
+		};
+		assert_eq!(live(&pruned), live(&new_db));
+
+		// by hash, the shared leaf is kept and every returned node can be deleted outright.
+		let removed = nodes_removed_by_hash::<ExtensionLayout>(&both, &old_root, &new_root).unwrap();
+		assert!(!removed.is_empty());
+		assert!(removed.iter().all(|hash| !new_db.contains(hash, EMPTY_PREFIX)));
+		let mut dedup = removed.clone();
+		dedup.sort();
+		dedup.dedup();
+		assert_eq!(dedup.len(), removed.len());
+		let mut pruned = both.clone();
+		for hash in removed {
+			while pruned.contains(&hash, EMPTY_PREFIX) {
+				pruned.remove(&hash, EMPTY_PREFIX);
+			}
+		}
+		pruned.purge();
+		assert_eq!(live(&pruned), live(&new_db));
+	}
+
+	#[test]
+	fn nodes_removed_with_extension() {
+		check_nodes_removed::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn nodes_removed_without_extension() {
+		check_nodes_removed::<NoExtensionLayout>();
+	}
+}
@@ -12,14 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use hash_db::{HashDBRef, Hasher};
+use hash_db::{HashDBRef, Hasher, EMPTY_PREFIX};
 use super::{Result, DBValue, TrieDB, Trie, TrieDBIterator, TrieItem, TrieIterator, Query,
-	TrieLayout, CError, TrieHash};
+	TrieLayout, TrieError, CError, TrieHash, KeySpacedDB};
 
-use crate::rstd::boxed::Box;
+use crate::rstd::{boxed::Box, vec::Vec};
+
+/// Keyspace of the key preimages stored by `FatDBMut`, see `KeySpacedDB`.
+///
+/// With a database keying values by prefix, the preimages are kept apart from the trie
+/// nodes and can be pruned on their own. Preimages written by earlier versions under
+/// the empty prefix are still read, and removed by `FatDBMut`.
+pub const FATDB_PREIMAGE_KEYSPACE: &[u8] = b":fatdb:preimage:";
+
+/// Get the preimage stored under `aux_hash`, falling back to the empty prefix used
+/// before `FATDB_PREIMAGE_KEYSPACE`.
+fn preimage<H: Hasher>(db: &dyn HashDBRef<H, DBValue>, aux_hash: &H::Out) -> Option<DBValue> {
+	KeySpacedDB::new(db, FATDB_PREIMAGE_KEYSPACE)
+		.get(aux_hash, EMPTY_PREFIX)
+		.or_else(|| db.get(aux_hash, EMPTY_PREFIX))
+}
 
 /// A `Trie` implementation which hashes keys and uses a generic `HashDB` backing database.
-/// Additionaly it stores inserted hash-key mappings for later retrieval.
+/// Additionaly it stores inserted hash-key mappings for later retrieval,
+/// under `FATDB_PREIMAGE_KEYSPACE`.
 ///
 /// Use it as a `Trie` or `TrieMut` trait object.
 pub struct FatDB<'db, L>
@@ -45,6 +61,14 @@ where
 
 	/// Get the backing database.
 	pub fn db(&self) -> &dyn HashDBRef<L::Hash, DBValue> { self.raw.db() }
+
+	/// Iterate over the pairs whose key starts with `prefix`, see
+	/// `FatDBIterator::new_prefixed`.
+	pub fn iter_prefixed<'a>(&'a self, prefix: &[u8])
+		-> Result<FatDBIterator<'a, L>, TrieHash<L>, CError<L>>
+	{
+		FatDBIterator::new_prefixed(&self.raw, prefix)
+	}
 }
 
 impl<'db, L> Trie<L> for FatDB<'db, L>
@@ -71,6 +95,10 @@ where
 	> {
 		FatDBIterator::<L>::new(&self.raw).map(|iter| Box::new(iter) as Box<_>)
 	}
+
+	fn len(&self) -> Result<usize, TrieHash<L>, CError<L>> {
+		self.raw.len()
+	}
 }
 
 /// Itarator over inserted pairs of key values.
@@ -80,6 +108,8 @@ where
 {
 	trie_iterator: TrieDBIterator<'db, L>,
 	trie: &'db TrieDB<'db, L>,
+	prefix: Vec<u8>,
+	pending: Option<TrieItem<'db, TrieHash<L>, CError<L>>>,
 }
 
 impl<'db, L> FatDBIterator<'db, L>
@@ -88,11 +118,32 @@ where
 {
 	/// Creates new iterator.
 	pub fn new(trie: &'db TrieDB<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		Self::new_prefixed(trie, &[])
+	}
+
+	/// Creates an iterator over the pairs whose key starts with `prefix`.
+	/// Keys being hashed in the trie, all the pairs are visited to find them.
+	pub fn new_prefixed(trie: &'db TrieDB<L>, prefix: &[u8]) -> Result<Self, TrieHash<L>, CError<L>> {
 		Ok(FatDBIterator {
 			trie_iterator: TrieDBIterator::new(trie)?,
 			trie: trie,
+			prefix: prefix.to_vec(),
+			pending: None,
 		})
 	}
+
+	/// Position the iterator right after `key`.
+	/// Pairs are iterated in the order of the hashes of their keys, so this lets a consumer
+	/// resume iteration from the last key it got.
+	pub fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let hashed_key = L::Hash::hash(key);
+		self.trie_iterator.seek(hashed_key.as_ref())?;
+		self.pending = match self.trie_iterator.next() {
+			Some(Ok((ref hash, _))) if &hash[..] == hashed_key.as_ref() => None,
+			next => next,
+		};
+		Ok(())
+	}
 }
 
 impl<'db, L> TrieIterator<L> for FatDBIterator<'db, L>
@@ -101,6 +152,7 @@ where
 {
 	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
 		let hashed_key = L::Hash::hash(key);
+		self.pending = None;
 		self.trie_iterator.seek(hashed_key.as_ref())
 	}
 }
@@ -112,26 +164,33 @@ where
 	type Item = TrieItem<'db, TrieHash<L>, CError<L>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		self.trie_iterator.next()
-			.map(|res| {
-				res.map(|(hash, value)| {
-					let aux_hash = L::Hash::hash(&hash);
-					(
-						self.trie.db().get(&aux_hash, Default::default())
-							.expect("Missing fatdb hash"),
-						value,
-					)
-				})
-			})
+		loop {
+			let item = match self.pending.take() {
+				Some(item) => item,
+				None => self.trie_iterator.next()?,
+			};
+			let (hash, value) = match item {
+				Ok(pair) => pair,
+				Err(e) => return Some(Err(e)),
+			};
+			let aux_hash = L::Hash::hash(&hash);
+			let key = match preimage(self.trie.db(), &aux_hash) {
+				Some(key) => key,
+				None => return Some(Err(Box::new(TrieError::IncompleteDatabase(aux_hash)))),
+			};
+			if key.starts_with(&self.prefix) {
+				return Some(Ok((key.to_vec(), value)));
+			}
+		}
 	}
 }
 
 #[cfg(test)]
 mod test {
 	use memory_db::{MemoryDB, HashKey};
-	use crate::DBValue;
+	use reference_trie::DBValue;
 	use keccak_hasher::KeccakHasher;
-	use reference_trie::{RefFatDBMut, RefFatDB, Trie, TrieMut};
+	use reference_trie::{RefFatDBMut, RefFatDB, Trie, TrieMut, TrieIterator};
 
 	#[test]
 	fn fatdb_to_trie() {
@@ -145,7 +204,116 @@ mod test {
 		assert_eq!(t.get(&[0x01u8, 0x23]).unwrap().unwrap(), vec![0x01u8, 0x23]);
 		assert_eq!(
 			t.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(),
-			vec![(vec![0x01u8, 0x23], vec![0x01u8, 0x23])]
+			vec![(vec![0x01u8, 0x23], vec![0x01u8, 0x23].into())]
+		);
+	}
+
+	#[test]
+	fn fatdb_preimages_in_keyspace() {
+		use hash_db::{HashDB, HashDBRef, Hasher, EMPTY_PREFIX};
+		use memory_db::PrefixedKey;
+		use crate::{KeySpacedDB, FATDB_PREIMAGE_KEYSPACE};
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefFatDBMut::new(&mut memdb, &mut root);
+			t.insert(b"alfa", b"1").unwrap();
+			t.insert(b"beta", b"2").unwrap();
+		}
+		let aux_hash = KeccakHasher::hash(&KeccakHasher::hash(b"alfa"));
+		assert_eq!(HashDB::get(&memdb, &aux_hash, EMPTY_PREFIX), None);
+		assert_eq!(
+			KeySpacedDB::new(&memdb, FATDB_PREIMAGE_KEYSPACE).get(&aux_hash, EMPTY_PREFIX),
+			Some(b"alfa".to_vec().into()),
 		);
+
+		let t = RefFatDB::new(&memdb, &root).unwrap();
+		let mut pairs: Vec<_> = t.iter().unwrap().map(Result::unwrap).collect();
+		pairs.sort();
+		assert_eq!(pairs, vec![(b"alfa".to_vec(), b"1".to_vec().into()), (b"beta".to_vec(), b"2".to_vec().into())]);
+	}
+
+	#[test]
+	fn fatdb_preimages_from_empty_prefix() {
+		use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
+		use memory_db::PrefixedKey;
+		use crate::{KeySpacedDBMut, FATDB_PREIMAGE_KEYSPACE};
+		use reference_trie::TrieError;
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefFatDBMut::new(&mut memdb, &mut root);
+			t.insert(b"alfa", b"1").unwrap();
+			t.insert(b"beta", b"2").unwrap();
+		}
+		// move the preimage of `alfa` where earlier versions wrote it.
+		let aux_hash = KeccakHasher::hash(&KeccakHasher::hash(b"alfa"));
+		KeySpacedDBMut::new(&mut memdb, FATDB_PREIMAGE_KEYSPACE).remove(&aux_hash, EMPTY_PREFIX);
+		memdb.emplace(aux_hash, EMPTY_PREFIX, b"alfa".to_vec().into());
+		{
+			let t = RefFatDB::new(&memdb, &root).unwrap();
+			let mut pairs: Vec<_> = t.iter().unwrap().map(Result::unwrap).collect();
+			pairs.sort();
+			assert_eq!(pairs, vec![(b"alfa".to_vec(), b"1".to_vec().into()), (b"beta".to_vec(), b"2".to_vec().into())]);
+		}
+
+		// removing the key removes its preimage from the empty prefix.
+		{
+			let mut t = RefFatDBMut::from_existing(&mut memdb, &mut root).unwrap();
+			t.remove(b"alfa").unwrap();
+		}
+		assert!(!HashDB::contains(&memdb, &aux_hash, EMPTY_PREFIX));
+
+		// a missing preimage is reported as an error.
+		let aux_hash = KeccakHasher::hash(&KeccakHasher::hash(b"beta"));
+		KeySpacedDBMut::new(&mut memdb, FATDB_PREIMAGE_KEYSPACE).remove(&aux_hash, EMPTY_PREFIX);
+		let t = RefFatDB::new(&memdb, &root).unwrap();
+		let error = t.iter().unwrap().find_map(|item| item.err());
+		assert_eq!(error.map(|e| *e), Some(TrieError::IncompleteDatabase(aux_hash)));
+	}
+
+	#[test]
+	fn fatdb_iterator_seek_and_prefix() {
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let keys: Vec<&[u8]> = vec![b"alfa", b"alfabet", b"beta", b"gamma", b"alpha"];
+		{
+			let mut t = RefFatDBMut::new(&mut memdb, &mut root);
+			for key in keys.iter() {
+				t.insert(key, key).unwrap();
+			}
+		}
+		let t = RefFatDB::new(&memdb, &root).unwrap();
+
+		// page through the keys, two at a time
+		let all: Vec<_> = t.iter().unwrap().map(|item| item.unwrap().0).collect();
+		assert_eq!(all.len(), keys.len());
+		let mut paged = Vec::new();
+		let mut last: Option<Vec<u8>> = None;
+		loop {
+			let mut iter = t.iter_prefixed(&[]).unwrap();
+			if let Some(ref last) = last {
+				iter.seek_after(last).unwrap();
+			}
+			let page: Vec<_> = iter.take(2).map(|item| item.unwrap().0).collect();
+			if page.is_empty() {
+				break;
+			}
+			last = page.last().cloned();
+			paged.extend(page);
+		}
+		assert_eq!(paged, all);
+
+		let mut iter = t.iter_prefixed(&[]).unwrap();
+		iter.seek(&all[2]).unwrap();
+		assert_eq!(iter.next().unwrap().unwrap().0, all[2]);
+
+		let mut prefixed: Vec<_> = t.iter_prefixed(b"alf").unwrap()
+			.map(|item| item.unwrap().0)
+			.collect();
+		prefixed.sort();
+		assert_eq!(prefixed, vec![b"alfa".to_vec(), b"alfabet".to_vec()]);
 	}
 }
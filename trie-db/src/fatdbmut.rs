@@ -13,10 +13,12 @@
 // limitations under the License.
 
 use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
-use super::{Result, DBValue, TrieDBMut, TrieMut, TrieLayout, TrieHash, CError};
+use super::{Result, DBValue, TrieDBMut, TrieMut, TrieLayout, TrieHash, CError, KeySpacedDBMut};
+use crate::fatdb::FATDB_PREIMAGE_KEYSPACE;
 
 /// A mutable `Trie` implementation which hashes keys and uses a generic `HashDB` backing database.
-/// Additionaly it stores inserted hash-key mappings for later retrieval.
+/// Additionaly it stores inserted hash-key mappings for later retrieval,
+/// under `FATDB_PREIMAGE_KEYSPACE`.
 ///
 /// Use it as a `Trie` or `TrieMut` trait object.
 pub struct FatDBMut<'db, L>
@@ -83,12 +85,12 @@ where
 	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
 		let hash = L::Hash::hash(key);
 		let out = self.raw.insert(hash.as_ref(), value)?;
-		let db = self.raw.db_mut();
 
 		// insert if it doesn't exist.
 		if out.is_none() {
 			let aux_hash = L::Hash::hash(hash.as_ref());
-			db.emplace(aux_hash, EMPTY_PREFIX, key.to_vec());
+			KeySpacedDBMut::new(self.raw.db_mut(), FATDB_PREIMAGE_KEYSPACE)
+				.emplace(aux_hash, EMPTY_PREFIX, DBValue::from(key));
 		}
 		Ok(out)
 	}
@@ -97,10 +99,15 @@ where
 		let hash = L::Hash::hash(key);
 		let out = self.raw.remove(hash.as_ref())?;
 
-		// remove if it already exists.
+		// remove if it already exists, from the empty prefix if written by earlier versions.
 		if out.is_some() {
 			let aux_hash = L::Hash::hash(hash.as_ref());
-			self.raw.db_mut().remove(&aux_hash, EMPTY_PREFIX);
+			let mut preimages = KeySpacedDBMut::new(self.raw.db_mut(), FATDB_PREIMAGE_KEYSPACE);
+			if preimages.contains(&aux_hash, EMPTY_PREFIX) {
+				preimages.remove(&aux_hash, EMPTY_PREFIX);
+			} else {
+				self.raw.db_mut().remove(&aux_hash, EMPTY_PREFIX);
+			}
 		}
 
 		Ok(out)
@@ -110,9 +117,10 @@ where
 #[cfg(test)]
 mod test {
 	use memory_db::{MemoryDB, HashKey};
-	use hash_db::{Hasher, EMPTY_PREFIX};
+	use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
 	use keccak_hasher::KeccakHasher;
 	use reference_trie::{RefFatDBMut, RefTrieDB, Trie, TrieMut};
+	use crate::{KeySpacedDBMut, FATDB_PREIMAGE_KEYSPACE};
 
 	#[test]
 	fn fatdbmut_to_trie() {
@@ -125,7 +133,7 @@ mod test {
 		let t = RefTrieDB::new(&memdb, &root).unwrap();
 		assert_eq!(
 			t.get(&KeccakHasher::hash(&[0x01u8, 0x23])),
-			Ok(Some(vec![0x01u8, 0x23])),
+			Ok(Some(vec![0x01u8, 0x23].into())),
 		);
 	}
 
@@ -139,9 +147,11 @@ mod test {
 		let aux_hash = KeccakHasher::hash(&key_hash);
 		let mut t = RefFatDBMut::new(&mut memdb, &mut root);
 		t.insert(&key, &val).unwrap();
-		assert_eq!(t.get(&key), Ok(Some(val.to_vec())));
-		assert_eq!(t.db().get(&aux_hash, EMPTY_PREFIX), Some(key.to_vec()));
+		assert_eq!(t.get(&key), Ok(Some(val.to_vec().into())));
+		let preimages = KeySpacedDBMut::new(t.db_mut(), FATDB_PREIMAGE_KEYSPACE);
+		assert_eq!(preimages.get(&aux_hash, EMPTY_PREFIX), Some(key.to_vec().into()));
 		t.remove(&key).unwrap();
-		assert_eq!(t.db().get(&aux_hash, EMPTY_PREFIX), None);
+		let preimages = KeySpacedDBMut::new(t.db_mut(), FATDB_PREIMAGE_KEYSPACE);
+		assert_eq!(preimages.get(&aux_hash, EMPTY_PREFIX), None);
 	}
 }
@@ -0,0 +1,119 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `HashDBRef` adapter fetching missing nodes on demand.
+
+use hash_db::{HashDB, HashDBRef, Hasher, Prefix};
+use crate::rstd::cell::RefCell;
+use super::DBValue;
+
+/// `HashDBRef` adapter calling `fetch` for the nodes missing from a local database,
+/// eg to request them from a remote peer.
+///
+/// Fetched nodes are checked against their hash and cached in the local database, so
+/// that a `TrieDB` over this adapter only fetches each node once.
+pub struct FetchingHashDB<H: Hasher, DB, F> {
+	db: RefCell<DB>,
+	fetch: F,
+	_marker: crate::rstd::marker::PhantomData<H>,
+}
+
+impl<H, DB, F> FetchingHashDB<H, DB, F>
+where
+	H: Hasher,
+	DB: HashDB<H, DBValue>,
+	F: Fn(&H::Out, Prefix) -> Option<DBValue>,
+{
+	/// Fetch the nodes missing from `db` with `fetch`.
+	pub fn new(db: DB, fetch: F) -> Self {
+		FetchingHashDB { db: RefCell::new(db), fetch, _marker: Default::default() }
+	}
+
+	/// Get back the local database, with the fetched nodes.
+	pub fn into_inner(self) -> DB {
+		self.db.into_inner()
+	}
+}
+
+impl<H, DB, F> HashDBRef<H, DBValue> for FetchingHashDB<H, DB, F>
+where
+	H: Hasher,
+	DB: HashDB<H, DBValue>,
+	F: Fn(&H::Out, Prefix) -> Option<DBValue>,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<DBValue> {
+		if let Some(value) = self.db.borrow().get(key, prefix) {
+			return Some(value);
+		}
+		let value = (self.fetch)(key, prefix)?;
+		if H::hash(&value) != *key {
+			return None;
+		}
+		self.db.borrow_mut().emplace(*key, prefix, value.clone());
+		Some(value)
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		let cached = self.db.borrow().contains(key, prefix);
+		cached || self.get(key, prefix).is_some()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+	use hash_db::{HashDB, HashDBRef};
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, FetchingHashDB};
+	use reference_trie::DBValue;
+
+	type MemDB = MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>;
+
+	#[test]
+	fn fetches_missing_nodes_once() {
+		let mut remote = MemDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut remote, &mut root);
+			t.insert(b"alfa", &[1; 40]).unwrap();
+			t.insert(b"alfabet", &[2; 40]).unwrap();
+			t.insert(b"beta", &[3; 40]).unwrap();
+		}
+
+		let fetched = Cell::new(0);
+		let db = FetchingHashDB::<KeccakHasher, _, _>::new(MemDB::default(), |key: &[u8; 32], prefix: hash_db::Prefix| {
+			fetched.set(fetched.get() + 1);
+			HashDBRef::get(&remote, key, prefix)
+		});
+		{
+			let t = RefTrieDB::new(&db, &root).unwrap();
+			assert_eq!(t.get(b"alfabet").unwrap(), Some(vec![2; 40].into()));
+			let first_lookup = fetched.get();
+			assert!(first_lookup > 0);
+			assert_eq!(t.get(b"alfabet").unwrap(), Some(vec![2; 40].into()));
+			assert_eq!(fetched.get(), first_lookup);
+			assert_eq!(t.get(b"beta").unwrap(), Some(vec![3; 40].into()));
+			assert_eq!(t.get(b"gamma").unwrap(), None);
+		}
+		let local = db.into_inner();
+		assert!(HashDB::contains(&local, &root, hash_db::EMPTY_PREFIX));
+
+		// nodes not matching their hash are rejected
+		let db = FetchingHashDB::<KeccakHasher, _, _>::new(MemDB::default(), |_: &[u8; 32], _: hash_db::Prefix| {
+			Some(vec![0; 40].into())
+		});
+		assert!(RefTrieDB::new(&db, &root).is_err());
+	}
+}
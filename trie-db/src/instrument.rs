@@ -0,0 +1,47 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `tracing` instrumentation, compiled out unless the `tracing` feature is enabled.
+
+/// Enter a `trie` debug span until the end of the enclosing block.
+macro_rules! trie_span {
+	($name:expr $(, $($field:tt)*)?) => {
+		#[cfg(feature = "tracing")]
+		let span = tracing::debug_span!(target: "trie", $name $(, $($field)*)?);
+		#[cfg(feature = "tracing")]
+		let _enter = span.enter();
+	};
+}
+
+/// Emit a `trie` trace event.
+macro_rules! trie_event {
+	($($arg:tt)*) => {
+		#[cfg(feature = "tracing")]
+		tracing::trace!(target: "trie", $($arg)*);
+	};
+}
+
+/// Displays a hash, or any bytes, as hexadecimal in trace fields.
+#[cfg(feature = "tracing")]
+pub(crate) struct Hex<'a>(pub &'a [u8]);
+
+#[cfg(feature = "tracing")]
+impl<'a> crate::rstd::fmt::Display for Hex<'a> {
+	fn fmt(&self, f: &mut crate::rstd::fmt::Formatter) -> crate::rstd::fmt::Result {
+		for byte in self.0 {
+			write!(f, "{:02x}", byte)?;
+		}
+		Ok(())
+	}
+}
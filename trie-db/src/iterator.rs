@@ -12,13 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{CError, DBValue, Result, Trie, TrieHash, TrieIterator, TrieLayout};
+use super::{CError, DBValue, Result, Trie, TrieError, TrieHash, TrieIterator, TrieLayout};
 use hash_db::{Hasher, EMPTY_PREFIX};
 use crate::triedb::TrieDB;
 use crate::node::{NodePlan, NodeHandle, OwnedNode};
 use crate::nibble::{NibbleSlice, NibbleVec, nibble_ops};
 
-use crate::rstd::{rc::Rc, vec::Vec};
+use crate::rstd::{boxed::Box, rc::Rc, vec::Vec};
 
 #[cfg_attr(feature = "std", derive(Debug))]
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -74,17 +74,32 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 			NodeHandle::Hash(db.root().as_ref()),
 			EMPTY_PREFIX
 		)?;
-		r.descend(root_node, root_hash);
+		r.descend(root_node, root_hash)?;
 		Ok(r)
 	}
 
 	/// Descend into a payload.
-	fn descend(&mut self, node: OwnedNode<DBValue>, node_hash: Option<TrieHash<L>>) {
+	/// Fails if this would exceed the maximum depth of the trie, with the hash of the node
+	/// or, for inline nodes, of its closest hash-referenced ancestor.
+	fn descend(
+		&mut self,
+		node: OwnedNode<DBValue>,
+		node_hash: Option<TrieHash<L>>,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		if let Some(max_depth) = self.db.max_depth() {
+			if self.trail.len() >= max_depth {
+				let hash = node_hash
+					.or_else(|| self.trail.iter().rev().filter_map(|crumb| crumb.hash).next())
+					.expect("only inline nodes have no hash and the root node is not inline; qed");
+				return Err(Box::new(TrieError::MaxDepthExceeded(hash)));
+			}
+		}
 		self.trail.push(Crumb {
 			hash: node_hash,
 			status: Status::Entering,
 			node: Rc::new(node),
 		});
+		Ok(())
 	}
 }
 
@@ -113,7 +128,7 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 		let mut full_key_nibbles = 0;
 		loop {
 			let (next_node, next_node_hash) = {
-				self.descend(node, node_hash);
+				self.descend(node, node_hash)?;
 				let crumb = self.trail.last_mut()
 					.expect(
 						"descend_into_node pushes a crumb onto the trial; \
@@ -229,6 +244,12 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 		}
 	}
 
+	/// Path of the last node yielded, or of the subtree that failed to load
+	/// when the last item is an error.
+	pub(crate) fn key_nibbles(&self) -> &NibbleVec {
+		&self.key_nibbles
+	}
+
 	/// Advance the iterator into a prefix, no value out of the prefix will be accessed
 	/// or returned after this operation.
 	pub fn prefix(&mut self, prefix: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
@@ -356,7 +377,15 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 						.increment();
 				},
 				IterStep::Descend::<TrieHash<L>, CError<L>>(Ok((node, node_hash))) => {
-					self.descend(node, node_hash);
+					if let Err(err) = self.descend(node, node_hash) {
+						// Increment here as there is an implicit PopTrail.
+						self.trail.last_mut()
+							.expect(
+								"descend fails before pushing on a non-empty trail; qed"
+							)
+							.increment();
+						return Some(Err(err));
+					}
 				},
 				IterStep::Descend::<TrieHash<L>, CError<L>>(Err(err)) => {
 					// Increment here as there is an implicit PopTrail.
@@ -385,7 +414,7 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 
 #[cfg(test)]
 mod tests {
-	use crate::DBValue;
+	use reference_trie::DBValue;
 	use hex_literal::hex;
 	use hash_db::{HashDB, Hasher};
 	use keccak_hasher::KeccakHasher;
@@ -436,6 +465,23 @@ mod tests {
 		v
 	}
 
+	#[test]
+	fn max_depth_reports_inline_node_parent() {
+		let pairs = vec![
+			(hex!("01").to_vec(), b"a".to_vec()),
+			(hex!("02").to_vec(), b"b".to_vec()),
+		];
+		let (memdb, root) = build_trie_db_with_extension(&pairs);
+		let trie = RefTrieDB::new(&memdb, &root).unwrap().with_max_depth(1);
+
+		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+		assert!(iter.next().unwrap().is_ok());
+		match iter.next().map(|result| result.map_err(|e| *e)) {
+			Some(Err(TrieError::MaxDepthExceeded(hash))) => assert_eq!(hash, root),
+			result => panic!("expected MaxDepthExceeded, got {:?}", result),
+		}
+	}
+
 	#[test]
 	fn iterator_works_with_extension() {
 		let pairs = vec![
@@ -0,0 +1,149 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `HashDB` adapters isolating tries sharing a database into keyspaces.
+
+use hash_db::{HashDB, HashDBRef, Hasher, AsHashDB, Prefix};
+use crate::rstd::{vec, vec::Vec};
+
+/// Derive a prefix from a keyspace and a node prefix.
+pub fn keyspace_as_prefix_alloc(keyspace: &[u8], prefix: Prefix) -> (Vec<u8>, Option<u8>) {
+	let mut result = vec![0; keyspace.len() + prefix.0.len()];
+	result[..keyspace.len()].copy_from_slice(keyspace);
+	result[keyspace.len()..].copy_from_slice(prefix.0);
+	(result, prefix.1)
+}
+
+/// `HashDBRef` adapter prepending a keyspace to the prefix of every node.
+///
+/// Used with a database keying nodes by prefix (eg `memory_db::PrefixedKey`), it
+/// lets several tries share a database without their nodes being shared.
+/// Keyspaces must not be prefixes of one another (eg use fixed length keyspaces).
+pub struct KeySpacedDB<'a, H: Hasher, T>(&'a dyn HashDBRef<H, T>, &'a [u8]);
+
+/// `HashDB` adapter prepending a keyspace to the prefix of every node.
+/// This is the mutable counterpart of `KeySpacedDB`.
+pub struct KeySpacedDBMut<'a, H: Hasher, T>(&'a mut dyn HashDB<H, T>, &'a [u8]);
+
+impl<'a, H: Hasher, T> KeySpacedDB<'a, H, T> {
+	/// Instantiate a new keyspaced db.
+	#[inline]
+	pub fn new(db: &'a dyn HashDBRef<H, T>, keyspace: &'a [u8]) -> Self {
+		KeySpacedDB(db, keyspace)
+	}
+}
+
+impl<'a, H: Hasher, T> KeySpacedDBMut<'a, H, T> {
+	/// Instantiate a new keyspaced db.
+	#[inline]
+	pub fn new(db: &'a mut dyn HashDB<H, T>, keyspace: &'a [u8]) -> Self {
+		KeySpacedDBMut(db, keyspace)
+	}
+}
+
+impl<'a, H: Hasher, T> HashDBRef<H, T> for KeySpacedDB<'a, H, T> {
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<T> {
+		let derived_prefix = keyspace_as_prefix_alloc(self.1, prefix);
+		self.0.get(key, (&derived_prefix.0[..], derived_prefix.1))
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		let derived_prefix = keyspace_as_prefix_alloc(self.1, prefix);
+		self.0.contains(key, (&derived_prefix.0[..], derived_prefix.1))
+	}
+}
+
+impl<'a, H, T> HashDB<H, T> for KeySpacedDBMut<'a, H, T>
+where
+	H: Hasher,
+	T: Default + PartialEq<T> + for<'b> From<&'b [u8]> + Clone + Send + Sync,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<T> {
+		let derived_prefix = keyspace_as_prefix_alloc(self.1, prefix);
+		self.0.get(key, (&derived_prefix.0[..], derived_prefix.1))
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		let derived_prefix = keyspace_as_prefix_alloc(self.1, prefix);
+		self.0.contains(key, (&derived_prefix.0[..], derived_prefix.1))
+	}
+
+	fn insert(&mut self, prefix: Prefix, value: &[u8]) -> H::Out {
+		let derived_prefix = keyspace_as_prefix_alloc(self.1, prefix);
+		self.0.insert((&derived_prefix.0[..], derived_prefix.1), value)
+	}
+
+	fn emplace(&mut self, key: H::Out, prefix: Prefix, value: T) {
+		let derived_prefix = keyspace_as_prefix_alloc(self.1, prefix);
+		self.0.emplace(key, (&derived_prefix.0[..], derived_prefix.1), value)
+	}
+
+	fn remove(&mut self, key: &H::Out, prefix: Prefix) {
+		let derived_prefix = keyspace_as_prefix_alloc(self.1, prefix);
+		self.0.remove(key, (&derived_prefix.0[..], derived_prefix.1))
+	}
+}
+
+impl<'a, H, T> HashDBRef<H, T> for KeySpacedDBMut<'a, H, T>
+where
+	H: Hasher,
+	T: Default + PartialEq<T> + for<'b> From<&'b [u8]> + Clone + Send + Sync,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<T> { HashDB::get(self, key, prefix) }
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool { HashDB::contains(self, key, prefix) }
+}
+
+impl<'a, H, T> AsHashDB<H, T> for KeySpacedDBMut<'a, H, T>
+where
+	H: Hasher,
+	T: Default + PartialEq<T> + for<'b> From<&'b [u8]> + Clone + Send + Sync,
+{
+	fn as_hash_db(&self) -> &dyn HashDB<H, T> { &*self }
+	fn as_hash_db_mut<'b>(&'b mut self) -> &'b mut (dyn HashDB<H, T> + 'b) { &mut *self }
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut};
+	use reference_trie::DBValue;
+	use super::{KeySpacedDB, KeySpacedDBMut};
+
+	#[test]
+	fn keyspaces_are_isolated() {
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root1 = Default::default();
+		let mut root2 = Default::default();
+		for (keyspace, root) in vec![(b"ks1", &mut root1), (b"ks2", &mut root2)] {
+			let mut db = KeySpacedDBMut::new(&mut db, keyspace);
+			let mut t = RefTrieDBMut::new(&mut db, root);
+			t.insert(b"A", &[1; 40]).unwrap();
+			t.insert(b"B", &[2; 40]).unwrap();
+		}
+		assert_eq!(root1, root2);
+
+		{
+			let mut db = KeySpacedDBMut::new(&mut db, b"ks1");
+			let mut t = RefTrieDBMut::from_existing(&mut db, &mut root1).unwrap();
+			t.remove(b"A").unwrap();
+			t.remove(b"B").unwrap();
+		}
+
+		let db = KeySpacedDB::new(&db, b"ks2");
+		let t = RefTrieDB::new(&db, &root2).unwrap();
+		assert_eq!(t.get(b"A").unwrap(), Some(vec![1; 40].into()));
+		assert_eq!(t.get(b"B").unwrap(), Some(vec![2; 40].into()));
+	}
+}
@@ -20,15 +20,17 @@ extern crate alloc;
 
 #[cfg(feature = "std")]
 mod rstd {
-	pub use std::{borrow, boxed, cmp, convert, fmt, hash, iter, marker, mem, ops, rc, result, vec};
+	pub use std::{borrow, boxed, cell, cmp, convert, fmt, hash, iter, marker, mem, ops, rc, result, sync, vec};
+	pub use std::borrow::Cow;
 	pub use std::collections::VecDeque;
 	pub use std::error::Error;
 }
 
 #[cfg(not(feature = "std"))]
 mod rstd {
-	pub use core::{borrow, convert, cmp, iter, fmt, hash, marker, mem, ops, result};
-	pub use alloc::{boxed, rc, vec};
+	pub use core::{borrow, cell, convert, cmp, iter, fmt, hash, marker, mem, ops, result};
+	pub use alloc::{boxed, rc, sync, vec};
+	pub use alloc::borrow::Cow;
 	pub use alloc::collections::VecDeque;
 	pub trait Error {}
 	impl<T> Error for T {}
@@ -40,6 +42,9 @@ use self::rstd::{fmt, Error};
 use hash_db::MaybeDebug;
 use self::rstd::{boxed::Box, vec::Vec};
 
+#[macro_use]
+mod instrument;
+
 pub mod node;
 pub mod proof;
 pub mod triedb;
@@ -48,36 +53,67 @@ pub mod sectriedb;
 pub mod sectriedbmut;
 pub mod recorder;
 
+mod blobdb;
+mod blobdbmut;
+mod cache;
+mod check;
+mod compare;
+mod db_value;
+mod diff;
 mod fatdb;
 mod fatdbmut;
+mod fetching_db;
 mod iter_build;
 mod iterator;
+mod key_spaced_db;
 mod lookup;
+mod merge;
+mod meter;
 mod nibble;
 mod node_codec;
+mod op_log;
+mod subtrie;
+mod transcode;
 mod trie_codec;
-
-pub use hash_db::{HashDB, HashDBRef, Hasher};
-pub use self::triedb::{TrieDB, TrieDBIterator};
-pub use self::triedbmut::{TrieDBMut, ChildReference};
+mod triedb_owned;
+mod visitor;
+
+pub use hash_db::{HashDB, HashDBRef, Hasher, HashWriter, StreamingHasher};
+pub use self::triedb::{TrieDB, TrieDBIterator, TrieDBEntryIterator, TrieEntry, FaultTolerantIterator,
+	LargeValueIterator};
+pub use self::triedb_owned::TrieDBOwned;
+pub use self::triedbmut::{TrieDBMut, ChildReference, InvariantError, Savepoint};
 pub use self::sectriedbmut::SecTrieDBMut;
 pub use self::sectriedb::SecTrieDB;
-pub use self::fatdb::{FatDB, FatDBIterator};
+pub use self::fatdb::{FatDB, FatDBIterator, FATDB_PREIMAGE_KEYSPACE};
 pub use self::fatdbmut::FatDBMut;
-pub use self::recorder::{Recorder, Record};
+pub use self::blobdb::{BlobDB, BlobDBIterator};
+pub use self::blobdbmut::BlobDBMut;
+pub use self::fetching_db::FetchingHashDB;
+pub use self::recorder::{Recorder, Record, Recording};
 pub use self::lookup::Lookup;
+pub use self::meter::{TrieMeter, NoMeter, CountingMeter};
+pub use self::cache::TrieCache;
 pub use self::nibble::{NibbleSlice, NibbleVec, nibble_ops};
-pub use crate::node_codec::{NodeCodec, Partial};
-pub use crate::iter_build::{trie_visit, ProcessEncodedNode,
+pub use crate::node_codec::{NodeCodec, Partial, EncodeNodeError};
+pub use crate::iter_build::{trie_visit, ProcessEncodedNode, BranchCallback,
 	 TrieBuilder, TrieRoot, TrieRootUnhashed};
 pub use crate::iterator::TrieDBNodeIterator;
-pub use crate::trie_codec::{decode_compact, encode_compact};
+pub use crate::trie_codec::{decode_compact, decode_compact_with_max_depth, encode_compact};
+pub use crate::check::{check_trie, CheckError, CheckReport};
+pub use crate::compare::{tries_equal, trie_difference, trie_intersection, KeySetIterator};
+pub use crate::diff::{nodes_removed, nodes_removed_by_hash};
+pub use crate::subtrie::{copy_trie, extract_subtrie};
+pub use crate::merge::merge_tries;
+pub use crate::transcode::{transcode_trie, TranscodeError};
+pub use crate::op_log::{LoggingTrieMut, OpLog, TrieOp};
+pub use crate::visitor::TrieVisitor;
+pub use crate::key_spaced_db::{KeySpacedDB, KeySpacedDBMut, keyspace_as_prefix_alloc};
 
 #[cfg(feature = "std")]
 pub use crate::iter_build::TrieRootPrint;
 
-/// Database value
-pub type DBValue = Vec<u8>;
+pub use crate::db_value::DBValue;
 
 /// Trie Errors.
 ///
@@ -96,6 +132,20 @@ pub enum TrieError<T, E> {
 	/// Corrupt Trie item
 	DecoderError(T, E),
 	InvalidHash(T, Vec<u8>),
+	/// A node could not be encoded, eg because its partial key is too long for the codec.
+	EncoderError(E),
+	/// Traversal went deeper than the configured maximum depth.
+	/// The parameter is the hash of the last hash-referenced node traversed.
+	MaxDepthExceeded(T),
+	/// Compact decoding went deeper than the configured maximum depth. The parameter is
+	/// the index of the first encoded node too deep, whose hash is not known yet.
+	CompactMaxDepthExceeded(usize),
+	/// The node under the hash is longer than the layout `MAX_NODE_SIZE`.
+	/// The second parameter is its length.
+	NodeTooLarge(T, usize),
+	/// The node under the hash is followed by unconsumed bytes, with the layout
+	/// `STRICT_DECODING`. The second parameter is their number.
+	TrailingBytes(T, usize),
 }
 
 #[cfg(feature = "std")]
@@ -117,6 +167,16 @@ impl<T, E> fmt::Display for TrieError<T, E> where T: MaybeDebug, E: MaybeDebug {
 					"Encoded node {:?} contains invalid hash reference with length: {}",
 					hash, data.len()
 				),
+			TrieError::EncoderError(ref encoder_err) =>
+				write!(f, "Encoding failed; err: {:?}", encoder_err),
+			TrieError::MaxDepthExceeded(ref hash) =>
+				write!(f, "Maximum trie depth exceeded below node {:?}", hash),
+			TrieError::CompactMaxDepthExceeded(index) =>
+				write!(f, "Maximum trie depth exceeded at encoded node {}", index),
+			TrieError::NodeTooLarge(ref hash, len) =>
+				write!(f, "Encoded node {:?} exceeds the maximum node size with length: {}", hash, len),
+			TrieError::TrailingBytes(ref hash, len) =>
+				write!(f, "Encoded node {:?} is followed by {} unexpected bytes", hash, len),
 		}
 	}
 }
@@ -130,6 +190,11 @@ impl<T, E> Error for TrieError<T, E> where T: fmt::Debug, E: Error {
 			TrieError::ValueAtIncompleteKey(_, _) => "Value at incomplete key",
 			TrieError::DecoderError(_, ref err) => err.description(),
 			TrieError::InvalidHash(_, _) => "Encoded node contains invalid hash reference",
+			TrieError::EncoderError(_) => "Encoding failed",
+			TrieError::MaxDepthExceeded(_) => "Maximum trie depth exceeded",
+			TrieError::CompactMaxDepthExceeded(_) => "Maximum trie depth exceeded",
+			TrieError::NodeTooLarge(_, _) => "Encoded node exceeds the maximum node size",
+			TrieError::TrailingBytes(_, _) => "Encoded node is followed by unexpected bytes",
 		}
 	}
 }
@@ -146,7 +211,8 @@ pub type TrieItem<'a, U, E> = Result<(Vec<u8>, DBValue), U, E>;
 ///
 /// This is implemented for any &mut recorder (where the query will return
 /// a DBValue), any function taking raw bytes (where no recording will be made),
-/// or any tuple of (&mut Recorder, FnOnce(&[u8]))
+/// or any tuple of (&mut Recorder, FnOnce(&[u8])).
+/// `Recorder::recording` wraps any query into one which also records the nodes.
 pub trait Query<H: Hasher> {
 	/// Output item.
 	type Item;
@@ -156,14 +222,26 @@ pub trait Query<H: Hasher> {
 
 	/// Record that a node has been passed through.
 	fn record(&mut self, _hash: &H::Out, _data: &[u8], _depth: u32) {}
+
+	/// Record that `key` is about to be looked up. This is the key given to the trie,
+	/// before hashing with layouts using `HASH_KEYS`.
+	fn record_key(&mut self, _key: &[u8]) {}
+
+	/// Observe the encoding of a node decoded by the lookup, inline nodes included.
+	/// `depth` is the number of nodes decoded before this one, so unlike the depth
+	/// given to `record` it is also incremented by inline nodes.
+	fn record_node(&mut self, _depth: u32, _encoded: &[u8]) {}
 }
 
 impl<'a, H: Hasher> Query<H> for &'a mut Recorder<H::Out> {
 	type Item = DBValue;
-	fn decode(self, value: &[u8]) -> DBValue { value.to_vec() }
+	fn decode(self, value: &[u8]) -> DBValue { DBValue::from(value) }
 	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
 		(&mut **self).record(hash, data, depth);
 	}
+	fn record_key(&mut self, key: &[u8]) {
+		(&mut **self).record_key(key);
+	}
 }
 
 impl<F, T, H: Hasher> Query<H> for F where F: for<'a> FnOnce(&'a [u8]) -> T {
@@ -177,6 +255,25 @@ impl<'a, F, T, H: Hasher> Query<H> for (&'a mut Recorder<H::Out>, F) where F: Fn
 	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
 		self.0.record(hash, data, depth)
 	}
+	fn record_key(&mut self, key: &[u8]) {
+		self.0.record_key(key)
+	}
+}
+
+impl<'a, Q, H: Hasher> Query<H> for Recording<'a, H::Out, Q> where Q: Query<H> {
+	type Item = Q::Item;
+	fn decode(self, value: &[u8]) -> Q::Item { self.query.decode(value) }
+	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
+		self.recorder.record(hash, data, depth);
+		self.query.record(hash, data, depth);
+	}
+	fn record_key(&mut self, key: &[u8]) {
+		self.recorder.record_key(key);
+		self.query.record_key(key);
+	}
+	fn record_node(&mut self, depth: u32, encoded: &[u8]) {
+		self.query.record_node(depth, encoded);
+	}
 }
 
 /// A key-value datastore implemented as a database-backed modified Merkle tree.
@@ -197,7 +294,7 @@ pub trait Trie<L: TrieLayout> {
 		&'a self,
 		key: &'key [u8],
 	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> where 'a: 'key {
-		self.get_with(key, |v: &[u8]| v.to_vec() )
+		self.get_with(key, |v: &[u8]| DBValue::from(v) )
 	}
 
 	/// Search for the key with the given query parameter. See the docs of the `Query`
@@ -214,6 +311,20 @@ pub trait Trie<L: TrieLayout> {
 		TrieHash<L>,
 		CError<L>
 	>;
+
+	/// Count the elements of the trie. This walks the whole trie, unless the layout
+	/// provides the count from the root hash with `TrieLayout::value_count`.
+	fn len(&self) -> Result<usize, TrieHash<L>, CError<L>> {
+		if let Some(count) = L::value_count(self.root()) {
+			return Ok(count);
+		}
+		let mut len = 0;
+		for item in self.iter()? {
+			item?;
+			len += 1;
+		}
+		Ok(len)
+	}
 }
 
 /// A key-value datastore implemented as a database-backed modified Merkle tree.
@@ -246,6 +357,19 @@ pub trait TrieMut<L: TrieLayout> {
 	/// Remove a `key` from the trie. Equivalent to making it equal to the empty
 	/// value. Returns the old value associated with this key, if it existed.
 	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>;
+
+	/// Get the value of `key`, first inserting the value computed by `f` if `key` is absent.
+	/// `f` is only called for an absent key. Returns the value of `key` in the trie.
+	fn get_or_insert_with<F>(&mut self, key: &[u8], f: F) -> Result<DBValue, TrieHash<L>, CError<L>>
+		where F: FnOnce() -> DBValue, Self: Sized
+	{
+		if let Some(value) = self.get(key)? {
+			return Ok(value);
+		}
+		let value = f();
+		self.insert(key, &value)?;
+		Ok(value)
+	}
 }
 
 /// A trie iterator that also supports random access (`seek()`).
@@ -392,46 +516,73 @@ pub trait TrieLayout {
 	/// no partial in branch, if false the trie will only
 	/// use branch and node with partials in both.
 	const USE_EXTENSION: bool;
+	/// If true, keys are hashed with `Hash` before being used as a trie path,
+	/// making the trie a secure trie without going through `SecTrieDB`.
+	/// Iteration and proofs then operate over the hashed keyspace, and the
+	/// `SecTrieDB` and `FatDB` wrappers must not be used on top of it.
+	const HASH_KEYS: bool = false;
+	/// Maximum size in bytes of an encoded node read from the database or a proof.
+	/// Larger nodes are rejected with `TrieError::NodeTooLarge`, before being decoded.
+	/// No limit if `None`.
+	const MAX_NODE_SIZE: Option<usize> = None;
+	/// If true, encoded nodes read from the database or a proof must not contain
+	/// any bytes after the node body, as reported by `NodeCodec::decode_plan_prefix`.
+	/// Such nodes are rejected with `TrieError::TrailingBytes`.
+	const STRICT_DECODING: bool = false;
+	/// Leaves with values of at least this many bytes are hashed in two parts, their
+	/// encoding up to the value then the value, when they are hashed without being stored:
+	/// by `TrieRoot` and by `TrieDBMut` committing with `with_hashes_only`. This saves
+	/// copying large values into the node encoding, for hashers implementing
+	/// `Hasher::hash_parts` and codecs implementing `NodeCodec::leaf_node_head_into`.
+	const STREAM_HASH_THRESHOLD: usize = 4096;
 	/// Hasher to use for this trie.
 	type Hash: Hasher;
 	/// Codec to use (needs to match hasher and nibble ops).
 	type Codec: NodeCodec<HashOut=<Self::Hash as Hasher>::Out>;
+
+	/// Number of values of the trie under `root`, for layouts whose root hash commits to
+	/// it. `Trie::len` then returns it instead of walking the trie. `None` by default.
+	fn value_count(_root: &TrieHash<Self>) -> Option<usize> {
+		None
+	}
 }
 
 /// This trait associates a trie definition with preferred methods.
 /// It also contains own default implementations and can be
 /// used to allow switching implementation.
+/// Its methods fail with the codec error if a key cannot be encoded by the
+/// layout codec.
 pub trait TrieConfiguration: Sized + TrieLayout {
 	/// Operation to build a trie db from its ordered iterator over its key/values.
-	fn trie_build<DB, I, A, B>(db: &mut DB, input: I) -> <Self::Hash as Hasher>::Out where
-	DB: HashDB<Self::Hash, usize>,
+	fn trie_build<DB, I, A, B>(db: &mut DB, input: I) -> rstd::result::Result<<Self::Hash as Hasher>::Out, CError<Self>> where
+	DB: HashDB<Self::Hash, DBValue>,
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
 	{
 		let mut cb = TrieBuilder::new(db);
-		trie_visit::<Self, _, _, _, _>(input.into_iter(), &mut cb);
-		cb.root.unwrap_or(Default::default())
+		visit::<Self, _, _, _, _>(input, &mut cb)?;
+		Ok(cb.root.unwrap_or_default())
 	}
 	/// Determines a trie root given its ordered contents, closed form.
-	fn trie_root<I, A, B>(input: I) -> <Self::Hash as Hasher>::Out where
+	fn trie_root<I, A, B>(input: I) -> rstd::result::Result<<Self::Hash as Hasher>::Out, CError<Self>> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
 	{
 		let mut cb = TrieRoot::<Self::Hash, _>::default();
-		trie_visit::<Self, _, _, _, _>(input.into_iter(), &mut cb);
-		cb.root.unwrap_or(Default::default())
+		visit::<Self, _, _, _, _>(input, &mut cb)?;
+		Ok(cb.root.unwrap_or_default())
 	}
 	/// Determines a trie root node's data given its ordered contents, closed form.
-	fn trie_root_unhashed<I, A, B>(input: I) -> Vec<u8> where
+	fn trie_root_unhashed<I, A, B>(input: I) -> rstd::result::Result<Vec<u8>, CError<Self>> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
 	{
 		let mut cb = TrieRootUnhashed::<Self::Hash>::default();
-		trie_visit::<Self, _, _, _, _>(input.into_iter(), &mut cb);
-		cb.root.unwrap_or(Default::default())
+		visit::<Self, _, _, _, _>(input, &mut cb)?;
+		Ok(cb.root.unwrap_or_default())
 	}
 	/// Encoding of index as a key (when reusing general trie for
 	/// indexed trie).
@@ -441,7 +592,7 @@ pub trait TrieConfiguration: Sized + TrieLayout {
 	}
 	/// A trie root formed from the items, with keys attached according to their
 	/// compact-encoded index (using `parity-codec` crate).
-	fn ordered_trie_root<I, A>(input: I) -> <Self::Hash as Hasher>::Out
+	fn ordered_trie_root<I, A>(input: I) -> rstd::result::Result<<Self::Hash as Hasher>::Out, CError<Self>>
 	where
 		I: IntoIterator<Item = A>,
 		A: AsRef<[u8]>,
@@ -454,6 +605,127 @@ pub trait TrieConfiguration: Sized + TrieLayout {
 	}
 }
 
+/// Declares a unit struct implementing `TrieLayout` and `TrieConfiguration`, with
+/// optional `TrieDB`, `TrieDBMut` and `Lookup` aliases for it.
+/// `hash_keys`, `max_node_size`, `strict_decoding`, `stream_hash_threshold` and
+/// `value_count`, a function implementing `TrieLayout::value_count`, can be omitted to
+/// use the `TrieLayout` defaults.
+///
+/// ```
+/// use reference_trie::{ReferenceNodeCodec, TrieMut};
+/// use keccak_hasher::KeccakHasher;
+/// use memory_db::{MemoryDB, HashKey};
+///
+/// trie_db::impl_trie_layout! {
+/// 	/// Layout with extension nodes and hashed keys.
+/// 	pub struct MyLayout {
+/// 		hash: KeccakHasher,
+/// 		codec: ReferenceNodeCodec<KeccakHasher>,
+/// 		use_extension: true,
+/// 		hash_keys: true,
+/// 	}
+/// 	aliases { trie_db: MyTrieDB, trie_db_mut: MyTrieDBMut, lookup: MyLookup }
+/// }
+///
+/// let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+/// let mut root = Default::default();
+/// MyTrieDBMut::new(&mut memdb, &mut root).insert(b"foo", b"bar").unwrap();
+/// let t = MyTrieDB::new(&memdb, &root).unwrap();
+/// assert_eq!(trie_db::Trie::get(&t, b"foo").unwrap(), Some(b"bar".to_vec().into()));
+/// ```
+#[macro_export]
+macro_rules! impl_trie_layout {
+	(
+		$(#[$attr:meta])*
+		$vis:vis struct $name:ident {
+			hash: $hash:ty,
+			codec: $codec:ty,
+			use_extension: $use_extension:expr
+			$(, hash_keys: $hash_keys:expr)?
+			$(, max_node_size: $max_node_size:expr)?
+			$(, strict_decoding: $strict_decoding:expr)?
+			$(, stream_hash_threshold: $stream_hash_threshold:expr)?
+			$(, value_count: $value_count:expr)?
+			$(,)?
+		}
+		$(aliases {
+			trie_db: $trie_db:ident,
+			trie_db_mut: $trie_db_mut:ident,
+			lookup: $lookup:ident
+			$(,)?
+		})?
+	) => {
+		$(#[$attr])*
+		$vis struct $name;
+
+		impl $crate::TrieLayout for $name {
+			const USE_EXTENSION: bool = $use_extension;
+			$(const HASH_KEYS: bool = $hash_keys;)?
+			$(const MAX_NODE_SIZE: Option<usize> = $max_node_size;)?
+			$(const STRICT_DECODING: bool = $strict_decoding;)?
+			$(const STREAM_HASH_THRESHOLD: usize = $stream_hash_threshold;)?
+			type Hash = $hash;
+			type Codec = $codec;
+			$(
+				fn value_count(root: &$crate::TrieHash<Self>) -> Option<usize> {
+					($value_count)(root)
+				}
+			)?
+		}
+
+		impl $crate::TrieConfiguration for $name { }
+
+		$(
+			$vis type $trie_db<'a> = $crate::TrieDB<'a, $name>;
+			$vis type $trie_db_mut<'a> = $crate::TrieDBMut<'a, $name>;
+			$vis type $lookup<'a, Q> = $crate::Lookup<'a, $name, Q>;
+		)?
+	};
+}
+
+/// Options to use when decoding nodes read from the database or a proof.
+pub(crate) fn decode_options<L: TrieLayout>() -> node_codec::DecodeOptions {
+	node_codec::DecodeOptions {
+		max_size: L::MAX_NODE_SIZE,
+		strict: L::STRICT_DECODING,
+	}
+}
+
+/// Path of `key` in the trie: its hash if the layout uses `HASH_KEYS`, the key itself
+/// otherwise.
+pub(crate) fn layout_key<L: TrieLayout>(key: &[u8]) -> rstd::Cow<'_, [u8]> {
+	if L::HASH_KEYS {
+		rstd::Cow::Owned(L::Hash::hash(key).as_ref().to_vec())
+	} else {
+		rstd::Cow::Borrowed(key)
+	}
+}
+
+/// Hash the concatenation of `parts`, without copying them to a single buffer if the
+/// hasher supports it.
+pub(crate) fn hash_parts<H: Hasher>(parts: &[&[u8]]) -> H::Out {
+	H::hash_parts(parts).unwrap_or_else(|| H::hash(&parts.concat()))
+}
+
+/// Call `trie_visit` on `input`, hashing keys first if the layout uses `HASH_KEYS`.
+fn visit<L, I, A, B, F>(input: I, callback: &mut F) -> rstd::result::Result<(), CError<L>> where
+	L: TrieLayout,
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]> + Ord,
+	B: AsRef<[u8]>,
+	F: ProcessEncodedNode<TrieHash<L>>,
+{
+	if !L::HASH_KEYS {
+		return trie_visit::<L, _, _, _, _>(input.into_iter(), callback);
+	}
+	// hashed keys are not in the order of the keys, `trie_visit` expects sorted input.
+	let mut hashed: Vec<_> = input.into_iter()
+		.map(|(k, v)| (layout_key::<L>(k.as_ref()).into_owned(), v))
+		.collect();
+	hashed.sort_by(|a, b| a.0.cmp(&b.0));
+	trie_visit::<L, _, _, _, _>(hashed.into_iter(), callback)
+}
+
 /// Alias accessor to hasher hash output type from a `TrieLayout`.
 pub type TrieHash<L> = <<L as TrieLayout>::Hash as Hasher>::Out;
 /// Alias accessor to `NodeCodec` associated `Error` type from a `TrieLayout`.
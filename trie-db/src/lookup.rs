@@ -16,10 +16,12 @@
 
 use hash_db::HashDBRef;
 use crate::nibble::NibbleSlice;
-use crate::node::{Node, NodeHandle, decode_hash};
-use crate::node_codec::NodeCodec;
-use crate::rstd::boxed::Box;
-use super::{DBValue, Result, TrieError, Query, TrieLayout, CError, TrieHash};
+use crate::node::{Node, NodeHandle, OwnedNode, decode_hash};
+use crate::rstd::{boxed::Box, sync::Arc, vec::Vec, Cow};
+use super::{DBValue, Result, TrieError, Query, TrieLayout, TrieMeter, NoMeter, TrieCache, CError,
+	TrieHash, decode_options, layout_key};
+#[cfg(feature = "tracing")]
+use crate::instrument::Hex;
 
 /// Trie lookup helper object.
 pub struct Lookup<'a, L: TrieLayout, Q: Query<L::Hash>> {
@@ -29,6 +31,13 @@ pub struct Lookup<'a, L: TrieLayout, Q: Query<L::Hash>> {
 	pub query: Q,
 	/// Hash to start at
 	pub hash: TrieHash<L>,
+	/// Maximum number of nodes, inline nodes included, to traverse before
+	/// aborting with `TrieError::MaxDepthExceeded`. No limit if `None`.
+	max_depth: Option<usize>,
+	/// Meter receiving the node accesses.
+	meter: &'a dyn TrieMeter,
+	/// Cache of decoded nodes read through by `look_up`.
+	cache: Option<&'a dyn TrieCache<TrieHash<L>>>,
 }
 
 impl<'a, L, Q> Lookup<'a, L, Q>
@@ -36,98 +45,363 @@ where
 	L: TrieLayout,
 	Q: Query<L::Hash>,
 {
+	/// Create a lookup of the trie under `hash` in `db`, without depth limit.
+	pub fn new(db: &'a dyn HashDBRef<L::Hash, DBValue>, query: Q, hash: TrieHash<L>) -> Self {
+		Lookup { db, query, hash, max_depth: None, meter: &NoMeter, cache: None }
+	}
+
+	/// Abort lookups traversing more than `max_depth` nodes, inline nodes included,
+	/// with `TrieError::MaxDepthExceeded`. No limit if `None`.
+	pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+		self.max_depth = max_depth;
+		self
+	}
+
+	/// Report the node accesses of lookups to `meter`.
+	pub fn with_meter(mut self, meter: &'a dyn TrieMeter) -> Self {
+		self.meter = meter;
+		self
+	}
+
+	/// Take the nodes walked by `look_up` decoded from `cache`, adding the nodes read
+	/// from the database to it. No cache if `None`.
+	pub fn with_cache(mut self, cache: Option<&'a dyn TrieCache<TrieHash<L>>>) -> Self {
+		self.cache = cache;
+		self
+	}
+
+	/// Look up the given key, hashing it first if the layout uses `HASH_KEYS`.
+	/// If the value is found, it will be passed to the given function to decode or copy.
+	pub fn look_up_key(
+		mut self,
+		key: &[u8],
+	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
+		self.query.record_key(key);
+		let key = layout_key::<L>(key);
+		self.look_up(NibbleSlice::new(&key))
+	}
+
 	/// Look up the given key. If the value is found, it will be passed to the given
 	/// function to decode or copy.
+	/// The key is used as is, even if the layout uses `HASH_KEYS`.
 	pub fn look_up(
 		mut self,
 		key: NibbleSlice,
 	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
-		let mut partial = key;
+		trie_span!("lookup", root = %Hex(self.hash.as_ref()));
+		let mut cursor = Cursor::new(key);
 		let mut hash = self.hash;
-		let mut key_nibbles = 0;
 
 		// this loop iterates through non-inline nodes.
 		for depth in 0.. {
-			let node_data = match self.db.get(&hash, key.mid(key_nibbles).left()) {
-				Some(value) => value,
-				None => return Err(Box::new(match depth {
-					0 => TrieError::InvalidStateRoot(hash),
-					_ => TrieError::IncompleteDatabase(hash),
-				})),
+			let node = self.fetch_node(&hash, key, &cursor, depth)?;
+			match self.walk_fetched(&hash, &node, &mut cursor)? {
+				Step::Found(value) => return Ok(value.map(move |value| self.query.decode(value))),
+				Step::Descend(child) => hash = child,
+			}
+		}
+		Ok(None)
+	}
+
+	/// Same as `look_up`, also returning the hash and depth of every stored node
+	/// traversed, from the root to the node ending the lookup.
+	pub fn look_up_with_path(
+		mut self,
+		key: NibbleSlice,
+	) -> Result<(Option<Q::Item>, Vec<(TrieHash<L>, u32)>), TrieHash<L>, CError<L>> {
+		trie_span!("lookup", root = %Hex(self.hash.as_ref()));
+		let mut cursor = Cursor::new(key);
+		let mut hash = self.hash;
+		let mut path = Vec::new();
+
+		for depth in 0.. {
+			let node = self.fetch_node(&hash, key, &cursor, depth)?;
+			path.push((hash, depth));
+			match self.walk_fetched(&hash, &node, &mut cursor)? {
+				Step::Found(value) => {
+					let value = value.map(move |value| self.query.decode(value));
+					return Ok((value, path))
+				},
+				Step::Descend(child) => hash = child,
+			}
+		}
+		Ok((None, path))
+	}
+
+	/// Look up the given key, hashing it first if the layout uses `HASH_KEYS`.
+	/// The value is borrowed from the database when it lends its values, see
+	/// `HashDBRef::lend`, and copied otherwise. The query only records the traversed nodes.
+	pub fn look_up_key_ref(
+		mut self,
+		key: &[u8],
+	) -> Result<Option<Cow<'a, [u8]>>, TrieHash<L>, CError<L>> {
+		self.query.record_key(key);
+		let key = layout_key::<L>(key);
+		self.look_up_ref(NibbleSlice::new(&key))
+	}
+
+	/// Same as `look_up_key_ref`, but the key is used as is, even if the layout uses
+	/// `HASH_KEYS`.
+	pub fn look_up_ref(
+		mut self,
+		key: NibbleSlice,
+	) -> Result<Option<Cow<'a, [u8]>>, TrieHash<L>, CError<L>> {
+		trie_span!("lookup", root = %Hex(self.hash.as_ref()));
+		let mut cursor = Cursor::new(key);
+		let mut hash = self.hash;
+
+		for depth in 0.. {
+			hash = match self.fetch(&hash, key, &cursor, depth)? {
+				Cow::Borrowed(node_data) => match self.walk(&hash, &node_data[..], &mut cursor)? {
+					Step::Found(value) => return Ok(value.map(Cow::Borrowed)),
+					Step::Descend(child) => child,
+				},
+				Cow::Owned(node_data) => match self.walk(&hash, &node_data[..], &mut cursor)? {
+					Step::Found(value) => return Ok(value.map(|value| Cow::Owned(value.to_vec()))),
+					Step::Descend(child) => child,
+				},
+			};
+		}
+		Ok(None)
+	}
+
+	/// Look up the given key, hashing it first if the layout uses `HASH_KEYS`, and call
+	/// `sink` once with the value, as a single slice borrowed from the node holding it.
+	/// Unlike `look_up_key_ref`, the value is not copied even if the database does not
+	/// lend its values, but the node holding it is still read whole.
+	/// The query only records the traversed nodes. Returns whether the key was found.
+	pub fn get_streamed(
+		mut self,
+		key: &[u8],
+		mut sink: impl FnMut(&[u8]),
+	) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.query.record_key(key);
+		let key = layout_key::<L>(key);
+		let key = NibbleSlice::new(&key);
+		trie_span!("lookup", root = %Hex(self.hash.as_ref()));
+		let mut cursor = Cursor::new(key);
+		let mut hash = self.hash;
+
+		for depth in 0.. {
+			let node_data = self.fetch(&hash, key, &cursor, depth)?;
+			hash = match self.walk(&hash, &node_data[..], &mut cursor)? {
+				Step::Found(Some(value)) => {
+					sink(value);
+					return Ok(true)
+				},
+				Step::Found(None) => return Ok(false),
+				Step::Descend(child) => child,
 			};
+		}
+		Ok(false)
+	}
+
+	/// Get the node stored at `hash`, borrowing it if the database lends its values.
+	fn fetch(
+		&mut self,
+		hash: &TrieHash<L>,
+		key: NibbleSlice,
+		cursor: &Cursor,
+		depth: u32,
+	) -> Result<Cow<'a, DBValue>, TrieHash<L>, CError<L>> {
+		let partial = key.mid(cursor.key_nibbles);
+		let prefix = partial.left();
+		let node_data = match self.db.lend(hash, prefix) {
+			Some(value) => Cow::Borrowed(value),
+			None => match self.db.get(hash, prefix) {
+				Some(value) => Cow::Owned(value),
+				None => {
+					trie_event!(depth, hash = %Hex(hash.as_ref()), "node missing");
+					self.meter.db_miss();
+					return Err(Box::new(match depth {
+						0 => TrieError::InvalidStateRoot(*hash),
+						_ => TrieError::IncompleteDatabase(*hash),
+					}));
+				},
+			},
+		};
+		self.meter.node_read(node_data.len());
+		trie_event!(depth, hash = %Hex(hash.as_ref()), len = node_data.len(), "node read");
+
+		self.query.record(hash, &node_data, depth);
+		Ok(node_data)
+	}
+
+	/// Get the node stored at `hash`, decoded from the cache if any. Nodes missing from the
+	/// cache are read from the database, then decoded and added to the cache.
+	fn fetch_node(
+		&mut self,
+		hash: &TrieHash<L>,
+		key: NibbleSlice,
+		cursor: &Cursor,
+		depth: u32,
+	) -> Result<Fetched<'a>, TrieHash<L>, CError<L>> {
+		let cache = match self.cache {
+			Some(cache) => cache,
+			None => return self.fetch(hash, key, cursor, depth).map(Fetched::Data),
+		};
+		if let Some(node) = cache.node(hash) {
+			trie_event!(depth, hash = %Hex(hash.as_ref()), len = node.data().len(), "node cached");
+			self.query.record(hash, node.data(), depth);
+			return Ok(Fetched::Node(node));
+		}
+		let node_data = self.fetch(hash, key, cursor, depth)?.into_owned();
+		self.meter.node_decoded(node_data.len());
+		let node = OwnedNode::new_checked::<L::Codec>(node_data, decode_options::<L>())
+			.map_err(|e| Box::new(e.into_trie_error(*hash)))?;
+		let node = Arc::new(node);
+		cache.insert_node(*hash, node.clone());
+		Ok(Fetched::Node(node))
+	}
+
+	/// Same as `walk` for a node returned by `fetch_node`.
+	fn walk_fetched<'n>(
+		&mut self,
+		hash: &TrieHash<L>,
+		node: &'n Fetched,
+		cursor: &mut Cursor,
+	) -> Result<Step<'n, TrieHash<L>>, TrieHash<L>, CError<L>> {
+		match node {
+			Fetched::Data(node_data) => self.walk(hash, &node_data[..], cursor),
+			Fetched::Node(node) => self.walk_decoded(hash, node.data(), Some(node.node()), cursor),
+		}
+	}
 
-			self.query.record(&hash, &node_data, depth);
+	/// Walk down the node stored at `hash` and its inline children, returning the value
+	/// or the hash of the next node to look into.
+	pub(crate) fn walk<'n>(
+		&mut self,
+		hash: &TrieHash<L>,
+		node_data: &'n [u8],
+		cursor: &mut Cursor,
+	) -> Result<Step<'n, TrieHash<L>>, TrieHash<L>, CError<L>> {
+		self.walk_decoded(hash, node_data, None, cursor)
+	}
 
-			// this loop iterates through all inline children (usually max 1)
-			// without incrementing the depth.
-			let mut node_data = &node_data[..];
-			loop {
-				let decoded = match L::Codec::decode(node_data) {
-					Ok(node) => node,
-					Err(e) => {
-						return Err(Box::new(TrieError::DecoderError(hash, e)))
+	/// Same as `walk`, with `decoded` the node already decoded from `node_data`, if any.
+	fn walk_decoded<'n>(
+		&mut self,
+		hash: &TrieHash<L>,
+		node_data: &'n [u8],
+		mut decoded: Option<Node<'n>>,
+		cursor: &mut Cursor,
+	) -> Result<Step<'n, TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let hash = *hash;
+		let mut node_data = node_data;
+		// this loop iterates through all inline children (usually max 1)
+		// without incrementing the depth.
+		loop {
+			cursor.nodes_traversed += 1;
+			if self.max_depth.map_or(false, |max_depth| cursor.nodes_traversed > max_depth) {
+				return Err(Box::new(TrieError::MaxDepthExceeded(hash)));
+			}
+			self.query.record_node(cursor.nodes_traversed as u32 - 1, node_data);
+			let decoded = match decoded.take() {
+				Some(node) => node,
+				None => {
+					self.meter.node_decoded(node_data.len());
+					trie_event!(nibbles = cursor.key_nibbles, len = node_data.len(), "node decoded");
+					match decode_options::<L>().decode::<L::Codec>(node_data) {
+						Ok(node) => node,
+						Err(e) => {
+							trie_event!(hash = %Hex(hash.as_ref()), "node decode failed");
+							return Err(Box::new(e.into_trie_error(hash)))
+						}
 					}
-				};
-				let next_node = match decoded {
-					Node::Leaf(slice, value) => {
-						return Ok(match slice == partial {
-							true => Some(self.query.decode(value)),
-							false => None,
-						})
+				},
+			};
+			let partial = &mut cursor.partial;
+			let next_node = match decoded {
+				Node::Leaf(slice, value) => {
+					return Ok(Step::Found(match slice == *partial {
+						true => Some(value),
+						false => None,
+					}))
+				}
+				Node::Extension(slice, item) => {
+					if partial.starts_with(&slice) {
+						*partial = partial.mid(slice.len());
+						cursor.key_nibbles += slice.len();
+						item
+					} else {
+						return Ok(Step::Found(None))
 					}
-					Node::Extension(slice, item) => {
-						if partial.starts_with(&slice) {
-							partial = partial.mid(slice.len());
-							key_nibbles += slice.len();
-							item
-						} else {
-							return Ok(None)
+				}
+				Node::Branch(children, value) => match partial.is_empty() {
+					true => return Ok(Step::Found(value)),
+					false => match children[partial.at(0) as usize] {
+						Some(x) => {
+							*partial = partial.mid(1);
+							cursor.key_nibbles += 1;
+							x
 						}
+						None => return Ok(Step::Found(None))
+					}
+				},
+				Node::NibbledBranch(slice, children, value) => {
+					if !partial.starts_with(&slice) {
+						return Ok(Step::Found(None))
 					}
-					Node::Branch(children, value) => match partial.is_empty() {
-						true => return Ok(value.map(move |val| self.query.decode(val))),
-						false => match children[partial.at(0) as usize] {
+
+					match partial.len() == slice.len() {
+						true => return Ok(Step::Found(value)),
+						false => match children[partial.at(slice.len()) as usize] {
 							Some(x) => {
-								partial = partial.mid(1);
-								key_nibbles += 1;
+								*partial = partial.mid(slice.len() + 1);
+								cursor.key_nibbles += slice.len() + 1;
 								x
 							}
-							None => return Ok(None)
-						}
-					},
-					Node::NibbledBranch(slice, children, value) => {
-						if !partial.starts_with(&slice) {
-							return Ok(None)
+							None => return Ok(Step::Found(None))
 						}
+					}
+				},
+				Node::Empty => return Ok(Step::Found(None)),
+			};
 
-						match partial.len() == slice.len() {
-							true => return Ok(value.map(move |val| self.query.decode(val))),
-							false => match children[partial.at(slice.len()) as usize] {
-								Some(x) => {
-									partial = partial.mid(slice.len() + 1);
-									key_nibbles += slice.len() + 1;
-									x
-								}
-								None => return Ok(None)
-							}
-						}
-					},
-					Node::Empty => return Ok(None),
-				};
-
-				// check if new node data is inline or hash.
-				match next_node {
-					NodeHandle::Hash(data) => {
-						hash = decode_hash::<L::Hash>(data)
-							.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, data.to_vec())))?;
-						break;
-					},
-					NodeHandle::Inline(data) => {
-						node_data = data;
-					},
-				}
+			// check if new node data is inline or hash.
+			match next_node {
+				NodeHandle::Hash(data) => {
+					let child = decode_hash::<L::Hash>(data)
+						.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, data.to_vec())))?;
+					return Ok(Step::Descend(child));
+				},
+				NodeHandle::Inline(data) => {
+					node_data = data;
+				},
 			}
 		}
-		Ok(None)
 	}
 }
+
+/// Position of a lookup in the looked up key.
+pub(crate) struct Cursor<'k> {
+	/// Remaining part of the key.
+	partial: NibbleSlice<'k>,
+	/// Number of nibbles of the key already consumed.
+	pub(crate) key_nibbles: usize,
+	/// Number of nodes traversed so far, inline nodes included.
+	nodes_traversed: usize,
+}
+
+impl<'k> Cursor<'k> {
+	/// Start a lookup of `key`.
+	pub(crate) fn new(key: NibbleSlice<'k>) -> Self {
+		Cursor { partial: key, key_nibbles: 0, nodes_traversed: 0 }
+	}
+}
+
+/// A node returned by `Lookup::fetch_node`.
+enum Fetched<'a> {
+	/// The encoded node, read from the database.
+	Data(Cow<'a, DBValue>),
+	/// The decoded node, when reading through a cache.
+	Node(Arc<OwnedNode<DBValue>>),
+}
+
+/// Outcome of walking down a node.
+pub(crate) enum Step<'n, HO> {
+	/// The lookup ended, with the value if the key is in the trie.
+	Found(Option<&'n [u8]>),
+	/// The lookup continues at the node with the given hash.
+	Descend(HO),
+}
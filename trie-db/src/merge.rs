@@ -0,0 +1,418 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Union of two tries stored in the same database.
+
+use hash_db::{HashDB, HashDBRef, Hasher, EMPTY_PREFIX};
+use crate::nibble::{NibbleSlice, NibbleVec};
+use crate::node::{Node, NodeHandle};
+use crate::node_codec::NodeCodec;
+use crate::rstd::{self, boxed::Box, convert::TryFrom, vec, vec::Vec};
+use crate::triedbmut::ChildReference;
+use super::{DBValue, Result, TrieError, TrieLayout, CError, TrieHash, decode_options};
+
+/// A node seen as a partial key, an optional value and children indexed by the
+/// nibble following the partial key. Extension nodes are merged with their branch.
+pub(crate) struct NodeView<HO> {
+	pub(crate) partial: NibbleVec,
+	pub(crate) value: Option<DBValue>,
+	pub(crate) children: [Option<ChildReference<HO>>; 16],
+}
+
+impl<HO: Copy> NodeView<HO> {
+	pub(crate) fn new(partial: NibbleVec, value: Option<DBValue>) -> Self {
+		NodeView { partial, value, children: [None; 16] }
+	}
+
+	/// Drop the first `count` nibbles of the partial key.
+	pub(crate) fn drop_partial(mut self, count: usize) -> Self {
+		let mut partial = NibbleVec::new();
+		for i in count..self.partial.len() {
+			partial.push(self.partial.at(i));
+		}
+		self.partial = partial;
+		self
+	}
+}
+
+pub(crate) fn to_nibble_vec(slice: NibbleSlice) -> NibbleVec {
+	let mut nibbles = NibbleVec::new();
+	for nibble in slice.iter() {
+		nibbles.push(nibble);
+	}
+	nibbles
+}
+
+/// Left-padded bytes holding the nibbles of `nibbles`, with the padding length,
+/// to be used with `NibbleSlice::new_offset`.
+fn padded_bytes(nibbles: &NibbleVec) -> (Vec<u8>, usize) {
+	let offset = nibbles.len() % 2;
+	let mut bytes = vec![0u8; (nibbles.len() + offset) / 2];
+	for i in 0..nibbles.len() {
+		let at = i + offset;
+		bytes[at / 2] |= if at % 2 == 0 { nibbles.at(i) << 4 } else { nibbles.at(i) };
+	}
+	(bytes, offset)
+}
+
+pub(crate) fn joined(path: &NibbleVec, partial: &NibbleVec, index: Option<u8>) -> NibbleVec {
+	let mut joined = path.clone();
+	joined.append(partial);
+	if let Some(index) = index {
+		joined.push(index);
+	}
+	joined
+}
+
+/// Returns the root of a trie containing the keys of the tries at `root_a` and
+/// `root_b`, both stored in `db`. The nodes of the new trie are added to `db`.
+///
+/// `resolve` is called with the key and both values for each key with different values
+/// in the two tries, and returns the value to keep. Keys are trie paths: with a layout
+/// using `HASH_KEYS` they are key hashes.
+///
+/// Both tries are walked together, and subtrees only found in one of them or identical
+/// in both are reused as is, so only the nodes along the paths where the tries differ
+/// are fetched and encoded.
+pub fn merge_tries<L, F>(
+	db: &mut dyn HashDB<L::Hash, DBValue>,
+	root_a: &TrieHash<L>,
+	root_b: &TrieHash<L>,
+	resolve: F,
+) -> Result<TrieHash<L>, TrieHash<L>, CError<L>>
+	where
+		L: TrieLayout,
+		F: FnMut(&[u8], &[u8], &[u8]) -> DBValue,
+{
+	let null_root = L::Codec::hashed_null_node();
+	if root_a == root_b || *root_b == null_root {
+		return Ok(*root_a);
+	}
+	if *root_a == null_root {
+		return Ok(*root_b);
+	}
+
+	let mut merge = Merge::<L, F> { db, resolve };
+	let root_path = NibbleVec::new();
+	let a = merge.load(&ChildReference::Hash(*root_a), &root_path)?;
+	let b = merge.load(&ChildReference::Hash(*root_b), &root_path)?;
+	let merged = merge.merge(a, b, &root_path)?;
+	match merge.encode(merged, &root_path, true)? {
+		ChildReference::Hash(root) => Ok(root),
+		ChildReference::Inline(..) => unreachable!("root nodes are always hashed; qed"),
+	}
+}
+
+struct Merge<'a, L: TrieLayout, F> {
+	db: &'a mut dyn HashDB<L::Hash, DBValue>,
+	resolve: F,
+}
+
+impl<'a, L, F> Merge<'a, L, F>
+	where
+		L: TrieLayout,
+		F: FnMut(&[u8], &[u8], &[u8]) -> DBValue,
+{
+	/// Fetch and decode the node at `path`.
+	fn load(
+		&self,
+		reference: &ChildReference<TrieHash<L>>,
+		path: &NibbleVec,
+	) -> Result<NodeView<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let db: &dyn HashDB<L::Hash, DBValue> = &*self.db;
+		load_view::<L>(&db, reference, path)
+	}
+
+	/// Merge two nodes starting at `path`.
+	fn merge(
+		&mut self,
+		mut a: NodeView<TrieHash<L>>,
+		mut b: NodeView<TrieHash<L>>,
+		path: &NibbleVec,
+	) -> Result<NodeView<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let common = (0..a.partial.len().min(b.partial.len()))
+			.take_while(|i| a.partial.at(*i) == b.partial.at(*i))
+			.count();
+
+		if common < a.partial.len() && common < b.partial.len() {
+			// the keys diverge: new branch with both nodes as children
+			let mut partial = NibbleVec::new();
+			for i in 0..common {
+				partial.push(a.partial.at(i));
+			}
+			let mut view = NodeView::new(partial, None);
+			for node in vec![a, b] {
+				let index = node.partial.at(common);
+				let child_path = joined(path, &view.partial, Some(index));
+				let child = node.drop_partial(common + 1);
+				view.children[index as usize] = Some(self.encode(child, &child_path, false)?);
+			}
+			return Ok(view);
+		}
+		if common < b.partial.len() {
+			return self.graft(a, b, true, path);
+		}
+		if common < a.partial.len() {
+			return self.graft(b, a, false, path);
+		}
+
+		a.value = match (a.value.take(), b.value.take()) {
+			(Some(value_a), Some(value_b)) if value_a != value_b => {
+				let key = joined(path, &a.partial, None);
+				Some((self.resolve)(key.as_prefix().0, &value_a, &value_b))
+			},
+			(value_a, value_b) => value_a.or(value_b),
+		};
+		for i in 0..16 {
+			a.children[i] = match (a.children[i], b.children[i]) {
+				(Some(child_a), Some(child_b)) if child_a != child_b => {
+					let child_path = joined(path, &a.partial, Some(i as u8));
+					let child_a = self.load(&child_a, &child_path)?;
+					let child_b = self.load(&child_b, &child_path)?;
+					let merged = self.merge(child_a, child_b, &child_path)?;
+					Some(self.encode(merged, &child_path, false)?)
+				},
+				(child_a, child_b) => child_a.or(child_b),
+			};
+		}
+		Ok(a)
+	}
+
+	/// Merge `child`, whose partial key extends the one of `parent`, into the
+	/// matching child of `parent`.
+	fn graft(
+		&mut self,
+		mut parent: NodeView<TrieHash<L>>,
+		child: NodeView<TrieHash<L>>,
+		child_is_b: bool,
+		path: &NibbleVec,
+	) -> Result<NodeView<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let index = child.partial.at(parent.partial.len());
+		let child_path = joined(path, &parent.partial, Some(index));
+		let child = child.drop_partial(parent.partial.len() + 1);
+		let merged = match parent.children[index as usize] {
+			Some(existing) => {
+				let existing = self.load(&existing, &child_path)?;
+				if child_is_b {
+					self.merge(existing, child, &child_path)?
+				} else {
+					self.merge(child, existing, &child_path)?
+				}
+			},
+			None => child,
+		};
+		parent.children[index as usize] = Some(self.encode(merged, &child_path, false)?);
+		Ok(parent)
+	}
+
+	/// Encode the node starting at `path`, storing it in the database unless it
+	/// gets inlined in its parent.
+	fn encode(
+		&mut self,
+		view: NodeView<TrieHash<L>>,
+		path: &NibbleVec,
+		is_root: bool,
+	) -> Result<ChildReference<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let db = &mut *self.db;
+		encode_view::<L>(view, path, is_root, &mut |encoded, path, is_root| {
+			inline_reference::<L>(&encoded, is_root).unwrap_or_else(|| {
+				let prefix = if is_root { EMPTY_PREFIX } else { path.as_prefix() };
+				ChildReference::Hash(db.insert(prefix, &encoded))
+			})
+		}).map_err(|e| Box::new(TrieError::EncoderError(e)))
+	}
+}
+
+/// Fetch and decode the node at `path`, merging an extension with its branch.
+pub(crate) fn load_view<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	reference: &ChildReference<TrieHash<L>>,
+	path: &NibbleVec,
+) -> Result<NodeView<TrieHash<L>>, TrieHash<L>, CError<L>> {
+	let (hash, data) = match reference {
+		ChildReference::Hash(hash) => {
+			let data = db.get(hash, path.as_prefix()).ok_or_else(|| Box::new(
+				if path.is_empty() {
+					TrieError::InvalidStateRoot(*hash)
+				} else {
+					TrieError::IncompleteDatabase(*hash)
+				}
+			))?;
+			(*hash, data)
+		},
+		ChildReference::Inline(data, len) => (Default::default(), DBValue::from(&data.as_ref()[..*len])),
+	};
+	let node = decode_options::<L>().decode::<L::Codec>(&data)
+		.map_err(|e| Box::new(e.into_trie_error(hash)))?;
+	let child_reference = |child: NodeHandle| ChildReference::try_from(child)
+		.map_err(|data| Box::new(TrieError::InvalidHash(hash, data)));
+
+	Ok(match node {
+		Node::Empty => NodeView::new(NibbleVec::new(), None),
+		Node::Leaf(partial, value) => NodeView::new(to_nibble_vec(partial), Some(DBValue::from(value))),
+		Node::Extension(partial, child) => {
+			let partial = to_nibble_vec(partial);
+			let branch = load_view::<L>(db, &child_reference(child)?, &joined(path, &partial, None))?;
+			NodeView { partial, ..branch }
+		},
+		Node::Branch(children, value) => {
+			let mut view = NodeView::new(NibbleVec::new(), value.map(DBValue::from));
+			for (i, child) in children.iter().enumerate() {
+				if let Some(child) = child {
+					view.children[i] = Some(child_reference(*child)?);
+				}
+			}
+			view
+		},
+		Node::NibbledBranch(partial, children, value) => {
+			let mut view = NodeView::new(to_nibble_vec(partial), value.map(DBValue::from));
+			for (i, child) in children.iter().enumerate() {
+				if let Some(child) = child {
+					view.children[i] = Some(child_reference(*child)?);
+				}
+			}
+			view
+		},
+	})
+}
+
+/// Encode the node starting at `path`. The encoded nodes, the node itself and the
+/// branch of an extension, are passed to `store` with their path and whether they
+/// are the root, and `store` returns the reference to them.
+pub(crate) fn encode_view<L: TrieLayout>(
+	view: NodeView<TrieHash<L>>,
+	path: &NibbleVec,
+	is_root: bool,
+	store: &mut dyn FnMut(Vec<u8>, &NibbleVec, bool) -> ChildReference<TrieHash<L>>,
+) -> rstd::result::Result<ChildReference<TrieHash<L>>, CError<L>> {
+	let (bytes, offset) = padded_bytes(&view.partial);
+	let partial = NibbleSlice::new_offset(&bytes, offset);
+	let value = view.value.as_ref().map(|value| &value[..]);
+	let encoded = if view.children.iter().all(Option::is_none) {
+		match value {
+			Some(value) => L::Codec::leaf_node(partial.right(), value)?,
+			None => L::Codec::empty_node().to_vec(),
+		}
+	} else if L::USE_EXTENSION {
+		let branch = L::Codec::branch_node(view.children.iter(), value)?;
+		if partial.is_empty() {
+			branch
+		} else {
+			let branch_path = joined(path, &view.partial, None);
+			let branch = store(branch, &branch_path, false);
+			L::Codec::extension_node(partial.right_iter(), partial.len(), branch)?
+		}
+	} else {
+		L::Codec::branch_node_nibbled(partial.right_iter(), partial.len(), view.children.iter(), value)?
+	};
+	Ok(store(encoded, path, is_root))
+}
+
+/// The inline reference to `encoded` if it is to be inlined in its parent node.
+pub(crate) fn inline_reference<L: TrieLayout>(
+	encoded: &[u8],
+	is_root: bool,
+) -> Option<ChildReference<TrieHash<L>>> {
+	let len = encoded.len();
+	if is_root || len >= <L::Hash as Hasher>::LENGTH {
+		return None;
+	}
+	let mut inline = TrieHash::<L>::default();
+	inline.as_mut()[..len].copy_from_slice(encoded);
+	Some(ChildReference::Inline(inline, len))
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{
+		ExtensionLayout, NoExtensionLayout, TrieLayout, TrieDB, TrieDBMut, Trie, TrieMut,
+		merge_tries,
+	};
+	use reference_trie::DBValue;
+
+	fn build<L: TrieLayout<Hash = KeccakHasher>>(
+		db: &mut MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>,
+		data: &[(Vec<u8>, Vec<u8>)],
+	) -> <KeccakHasher as hash_db::Hasher>::Out {
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(db, &mut root);
+			for (k, v) in data {
+				t.insert(k, v).unwrap();
+			}
+		}
+		root
+	}
+
+	fn check<L: TrieLayout<Hash = KeccakHasher>>(
+		data_a: &[(Vec<u8>, Vec<u8>)],
+		data_b: &[(Vec<u8>, Vec<u8>)],
+	) {
+		let mut db = MemoryDB::default();
+		let root_a = build::<L>(&mut db, data_a);
+		let root_b = build::<L>(&mut db, data_b);
+
+		// different values of a key in both tries get concatenated
+		let mut union: Vec<_> = data_a.to_vec();
+		for (k, v) in data_b {
+			match union.iter_mut().find(|(key, _)| key == k) {
+				Some((_, value)) if value != v => value.extend_from_slice(v),
+				Some(_) => (),
+				None => union.push((k.clone(), v.clone())),
+			}
+		}
+		let mut expected_db = MemoryDB::default();
+		let expected_root = build::<L>(&mut expected_db, &union);
+
+		let root = merge_tries::<L, _>(&mut db, &root_a, &root_b, |key, a, b| {
+			assert!(data_a.iter().any(|(k, _)| &k[..] == key));
+			let mut value = a.to_vec();
+			value.extend_from_slice(b);
+			value.into()
+		}).unwrap();
+		assert_eq!(root, expected_root);
+		let t = TrieDB::<L>::new(&db, &root).unwrap();
+		assert_eq!(t.iter().unwrap().count(), union.len());
+		for (k, v) in &union {
+			assert_eq!(t.get(k).unwrap().as_deref(), Some(&v[..]));
+		}
+	}
+
+	#[test]
+	fn merge_tries_works() {
+		let a = vec![
+			(b"alfa".to_vec(), vec![1; 40]),
+			(b"alfabet".to_vec(), vec![2; 40]),
+			(b"beta".to_vec(), vec![3]),
+			(b"delta".to_vec(), vec![4; 40]),
+		];
+		let b = vec![
+			(b"alfa".to_vec(), vec![5; 40]),
+			(b"alfalfa".to_vec(), vec![6; 40]),
+			(b"al".to_vec(), vec![7]),
+			(b"bet".to_vec(), vec![8; 40]),
+			(b"gamma".to_vec(), vec![9]),
+			(b"delta".to_vec(), vec![4; 40]),
+		];
+		let c = vec![
+			(b"alfabet_soup".to_vec(), vec![10; 40]),
+		];
+		for (data_a, data_b) in &[(&a, &b), (&b, &a), (&a, &c), (&c, &b), (&a, &a), (&a, &vec![])] {
+			check::<ExtensionLayout>(data_a, data_b);
+			check::<NoExtensionLayout>(data_a, data_b);
+		}
+	}
+}
@@ -0,0 +1,76 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metering of the trie node accesses.
+
+use crate::rstd::cell::Cell;
+
+/// Receives the trie node accesses made by lookups, iteration and `TrieDBMut`,
+/// eg to charge for them or to monitor them.
+pub trait TrieMeter {
+	/// A node of encoded length `len` was read from the database.
+	fn node_read(&self, _len: usize) {}
+
+	/// A node of encoded length `len`, possibly inline, was decoded.
+	fn node_decoded(&self, _len: usize) {}
+
+	/// A node was not found in the database.
+	fn db_miss(&self) {}
+}
+
+/// `TrieMeter` ignoring all accesses, used by default.
+#[derive(Default, Clone, Copy)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct NoMeter;
+
+impl TrieMeter for NoMeter {}
+
+/// `TrieMeter` counting the node accesses.
+#[derive(Default)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct CountingMeter {
+	nodes_read: Cell<usize>,
+	bytes_read: Cell<usize>,
+	bytes_decoded: Cell<usize>,
+	db_misses: Cell<usize>,
+}
+
+impl CountingMeter {
+	/// Number of nodes read from the database.
+	pub fn nodes_read(&self) -> usize { self.nodes_read.get() }
+
+	/// Number of bytes read from the database.
+	pub fn bytes_read(&self) -> usize { self.bytes_read.get() }
+
+	/// Number of bytes of decoded nodes, inline nodes included.
+	pub fn bytes_decoded(&self) -> usize { self.bytes_decoded.get() }
+
+	/// Number of nodes not found in the database.
+	pub fn db_misses(&self) -> usize { self.db_misses.get() }
+}
+
+impl TrieMeter for CountingMeter {
+	fn node_read(&self, len: usize) {
+		self.nodes_read.set(self.nodes_read.get() + 1);
+		self.bytes_read.set(self.bytes_read.get() + len);
+	}
+
+	fn node_decoded(&self, len: usize) {
+		self.bytes_decoded.set(self.bytes_decoded.get() + len);
+	}
+
+	fn db_miss(&self) {
+		self.db_misses.set(self.db_misses.get() + 1);
+	}
+}
@@ -28,14 +28,16 @@ pub mod nibble_ops {
 	use super::*;
 
 	/// Single nibble length in bit.
+	/// Other constants of this module derive from it.
 	pub const BIT_PER_NIBBLE : usize = 4;
 	/// Number of nibble per byte.
-	pub const NIBBLE_PER_BYTE : usize = 2;
+	pub const NIBBLE_PER_BYTE : usize = 8 / BIT_PER_NIBBLE;
 	/// Number of child for a branch (trie radix).
-	pub const NIBBLE_LENGTH : usize = 16;
+	pub const NIBBLE_LENGTH : usize = 1 << BIT_PER_NIBBLE;
 	/// Nibble (half a byte).
-	pub const PADDING_BITMASK: u8 = 0x0F;
+	pub const PADDING_BITMASK: u8 = (NIBBLE_LENGTH - 1) as u8;
 	/// Size of header.
+	#[deprecated(since = "0.20.1", note = "unused, codecs define their own header size")]
 	pub const CONTENT_HEADER_SIZE: u8 = 1;
 
 	/// Mask a byte, keeping left nibble.
@@ -96,7 +98,7 @@ pub mod nibble_ops {
 	/// The nibble shifts needed to align.
 	/// We use two value, one is a left shift and
 	/// the other is a right shift.
-	pub const SPLIT_SHIFTS: (usize, usize) = (4, 4);
+	pub const SPLIT_SHIFTS: (usize, usize) = (BIT_PER_NIBBLE, 8 - BIT_PER_NIBBLE);
 
 	/// Count the biggest common depth between two left aligned packed nibble slice.
 	pub fn biggest_depth(v1: &[u8], v2: &[u8]) -> usize {
@@ -113,7 +115,7 @@ pub mod nibble_ops {
 	#[inline(always)]
 	pub fn left_common(a: u8, b: u8) -> usize {
 		if a == b {
-			2
+			NIBBLE_PER_BYTE
 		} else if pad_left(a) == pad_left(b) {
 			1
 		} else {
@@ -187,6 +189,7 @@ pub struct NibbleVec {
 pub struct NibbleSlice<'a> {
 	data: &'a [u8],
 	offset: usize,
+	end: usize,
 }
 
 /// Iterator type for a nibble slice.
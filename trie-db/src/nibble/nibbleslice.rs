@@ -44,6 +44,18 @@ impl<'a> NibbleSlice<'a> {
 		NibbleSlice {
 			data,
 			offset,
+			end: data.len() * nibble_ops::NIBBLE_PER_BYTE,
+		}
+	}
+
+	/// Create a new nibble slice over the first `len` nibbles of the given byte-slice,
+	/// ending in the middle of a byte when `len` is odd.
+	pub fn new_len(data: &'a [u8], len: usize) -> Self {
+		debug_assert!(len <= data.len() * nibble_ops::NIBBLE_PER_BYTE);
+		NibbleSlice {
+			data,
+			offset: 0,
+			end: len,
 		}
 	}
 
@@ -59,9 +71,23 @@ impl<'a> NibbleSlice<'a> {
 
 	/// Helper function to create a owned `NodeKey` from this `NibbleSlice`.
 	pub fn to_stored(&self) -> NodeKey {
+		if nibble_ops::number_padding(self.end) != 0 {
+			// stored keys are right aligned, shift the nibbles into place.
+			let len = self.len();
+			let offset = nibble_ops::number_padding(len);
+			let mut data = BackingByteVec::from_elem(0, (offset + len) / nibble_ops::NIBBLE_PER_BYTE);
+			for i in 0..len {
+				let ix = offset + i;
+				let byte = &mut data[ix / nibble_ops::NIBBLE_PER_BYTE];
+				*byte = nibble_ops::push_at_left((ix % nibble_ops::NIBBLE_PER_BYTE) as u8, self.at(i), *byte);
+			}
+			return (offset, data)
+		}
 		let split = self.offset / nibble_ops::NIBBLE_PER_BYTE;
 		let offset = self.offset % nibble_ops::NIBBLE_PER_BYTE;
-		(offset, self.data[split..].into())
+		let mut key = (offset, self.data[split..self.end / nibble_ops::NIBBLE_PER_BYTE].into());
+		clear_padding(&mut key);
+		key
 	}
 
 	/// Helper function to create a owned `NodeKey` from this `NibbleSlice`,
@@ -74,10 +100,12 @@ impl<'a> NibbleSlice<'a> {
 			// aligned
 			let start = self.offset / nibble_ops::NIBBLE_PER_BYTE;
 			let end = (self.offset + nb) / nibble_ops::NIBBLE_PER_BYTE;
-			(
+			let mut key = (
 				self.offset % nibble_ops::NIBBLE_PER_BYTE,
 				BackingByteVec::from_slice(&self.data[start..end]),
-			)
+			);
+			clear_padding(&mut key);
+			key
 		} else {
 			// unaligned
 			let start = self.offset / nibble_ops::NIBBLE_PER_BYTE;
@@ -88,6 +116,7 @@ impl<'a> NibbleSlice<'a> {
 			let mut result = (ea_offset, ea);
 			nibble_ops::shift_key(&mut result, n_offset);
 			result.1.pop();
+			clear_padding(&mut result);
 			result
 		}
 	}
@@ -97,7 +126,7 @@ impl<'a> NibbleSlice<'a> {
 
 	/// Get the length (in nibbles, naturally) of this slice.
 	#[inline]
-	pub fn len(&self) -> usize { self.data.len() * nibble_ops::NIBBLE_PER_BYTE - self.offset }
+	pub fn len(&self) -> usize { self.end - self.offset }
 
 	/// Get the nibble at position `i`.
 	#[inline(always)]
@@ -110,6 +139,7 @@ impl<'a> NibbleSlice<'a> {
 		NibbleSlice {
 			data: self.data,
 			offset: self.offset + i,
+			end: self.end,
 		}
 	}
 
@@ -124,6 +154,7 @@ impl<'a> NibbleSlice<'a> {
 		NibbleSlice {
 			data: self.data,
 			offset: i,
+			end: self.end,
 		}
 	}
 
@@ -143,7 +174,9 @@ impl<'a> NibbleSlice<'a> {
 
 	/// Return `Partial` representation of this slice:
 	/// first encoded byte and following slice.
+	/// The slice must end on a byte boundary, see `to_stored` otherwise.
 	pub fn right(&'a self) -> Partial {
+		debug_assert!(nibble_ops::number_padding(self.end) == 0);
 		let split = self.offset / nibble_ops::NIBBLE_PER_BYTE;
 		let nb = (self.len() % nibble_ops::NIBBLE_PER_BYTE) as u8;
 		if nb > 0 {
@@ -233,8 +266,18 @@ impl<'a> NibbleSlice<'a> {
 	}
 }
 
+/// Zero the padding nibble of a key, holding the nibble before the slice it was built from.
+fn clear_padding(key: &mut NodeKey) {
+	if key.0 != 0 {
+		key.1[0] = nibble_ops::pad_right(key.1[0]);
+	}
+}
+
 impl<'a> Into<NodeKey> for NibbleSlice<'a> {
 	fn into(self) -> NodeKey {
+		if nibble_ops::number_padding(self.end) != 0 {
+			return self.to_stored()
+		}
 		(self.offset, self.data.into())
 	}
 }
@@ -329,7 +372,20 @@ mod tests {
 		assert_eq!(n.to_stored(), (0, BackingByteVec::from_slice(&[0x01, 0x23, 0x45])));
 		assert_eq!(n.mid(1).to_stored(), (1, BackingByteVec::from_slice(&[0x01, 0x23, 0x45])));
 		assert_eq!(n.mid(2).to_stored(), (0, BackingByteVec::from_slice(&[0x23, 0x45])));
-		assert_eq!(n.mid(3).to_stored(), (1, BackingByteVec::from_slice(&[0x23, 0x45])));
+		assert_eq!(n.mid(3).to_stored(), (1, BackingByteVec::from_slice(&[0x03, 0x45])));
+	}
+
+	#[test]
+	fn odd_length() {
+		let n = NibbleSlice::new_len(D, 5);
+		assert_eq!(n.len(), 5);
+		assert_eq!(n.iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+		assert_eq!(n.to_stored(), (1, BackingByteVec::from_slice(&[0x00, 0x12, 0x34])));
+		assert_eq!(n.mid(1).to_stored(), (0, BackingByteVec::from_slice(&[0x12, 0x34])));
+		assert_eq!(n.mid(3).left(), (&[0x01][..], Some(0x20)));
+		assert_eq!(n.mid(1), NibbleSlice::from_stored(&n.mid(1).to_stored()));
+		assert!(n.starts_with(&NibbleSlice::new_len(D, 3)));
+		assert!(NibbleSlice::new(D) > n);
 	}
 
 	#[test]
@@ -15,9 +15,9 @@
 use hash_db::Hasher;
 use crate::nibble::{self, NibbleSlice};
 use crate::nibble::nibble_ops;
-use crate::node_codec::NodeCodec;
+use crate::node_codec::{NodeCodec, DecodeOptions, DecodeError};
 
-use crate::rstd::{borrow::Borrow, ops::Range};
+use crate::rstd::{borrow::Borrow, cmp::max, ops::Range};
 
 /// Partial node key type: offset and owned value of a nibbleslice.
 /// Offset is applied on first byte of array (bytes are right aligned).
@@ -30,6 +30,33 @@ pub enum NodeHandle<'a> {
 	Inline(&'a [u8]),
 }
 
+impl<'a> NodeHandle<'a> {
+	/// Decode the handle into a `ChildHandle`, reading hash references into the hash type
+	/// of the codec. Returns the raw data of a hash reference with the wrong length as error.
+	pub fn decode<C: NodeCodec>(self) -> Result<ChildHandle<'a, C::HashOut>, &'a [u8]> {
+		match self {
+			NodeHandle::Hash(data) => {
+				let mut hash = C::HashOut::default();
+				if data.len() != hash.as_ref().len() {
+					return Err(data);
+				}
+				hash.as_mut().copy_from_slice(data);
+				Ok(ChildHandle::Hash(hash))
+			},
+			NodeHandle::Inline(data) => Ok(ChildHandle::Inline(data)),
+		}
+	}
+}
+
+/// A reference to a child node with its hash decoded, see `NodeHandle::decode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildHandle<'a, HO> {
+	/// Hash of a node stored in the database.
+	Hash(HO),
+	/// Encoded node inlined in its parent.
+	Inline(&'a [u8]),
+}
+
 /// Read a hash from a slice into a Hasher output. Returns None if the slice is the wrong length.
 pub fn decode_hash<H: Hasher>(data: &[u8]) -> Option<H::Out> {
 	if data.len() != H::LENGTH {
@@ -57,6 +84,37 @@ pub enum Node<'a> {
 	NibbledBranch(NibbleSlice<'a>, [Option<NodeHandle<'a>>; nibble_ops::NIBBLE_LENGTH], Option<&'a [u8]>),
 }
 
+impl<'a> Node<'a> {
+	/// Returns the child of a branch at nibble `index`, or the child of an extension
+	/// if its partial key starts with nibble `index`.
+	pub fn child(&self, index: u8) -> Option<NodeHandle<'a>> {
+		match self {
+			Node::Extension(partial, child) if !partial.is_empty() && partial.at(0) == index =>
+				Some(*child),
+			Node::Extension(..) => None,
+			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) =>
+				children.get(index as usize).and_then(|child| *child),
+			Node::Empty | Node::Leaf(..) => None,
+		}
+	}
+
+	/// Iterates over the children of the node with their nibble index, which is `None`
+	/// for the child of an extension.
+	pub fn children<'b>(&'b self) -> impl Iterator<Item = (Option<u8>, NodeHandle<'a>)> + 'b {
+		let (extension_child, children) = match self {
+			Node::Extension(_, child) => (Some(*child), None),
+			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) =>
+				(None, Some(children)),
+			Node::Empty | Node::Leaf(..) => (None, None),
+		};
+		extension_child.map(|child| (None, child)).into_iter()
+			.chain(children.into_iter().flat_map(|children| children.iter()
+				.enumerate()
+				.filter_map(|(i, child)| child.map(|child| (Some(i as u8), child)))
+			))
+	}
+}
+
 /// A `NodeHandlePlan` is a decoding plan for constructing a `NodeHandle` from an encoded trie
 /// node. This is used as a substructure of `NodePlan`. See `NodePlan` for details.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -144,6 +202,30 @@ pub enum NodePlan {
 }
 
 impl NodePlan {
+	/// End of the last byte range of the plan: the length of the node for codecs
+	/// encoding nothing after its last partial key, value or child.
+	pub fn end(&self) -> usize {
+		let handle_end = |child: &NodeHandlePlan| match child {
+			NodeHandlePlan::Hash(range) | NodeHandlePlan::Inline(range) => range.end,
+		};
+		let children_end = |children: &[Option<NodeHandlePlan>]| children.iter()
+			.flatten()
+			.map(&handle_end)
+			.max()
+			.unwrap_or(0);
+		let value_end = |value: &Option<Range<usize>>| value.as_ref().map_or(0, |value| value.end);
+		match self {
+			NodePlan::Empty => 0,
+			NodePlan::Leaf { partial, value } => max(partial.bytes.end, value.end),
+			NodePlan::Extension { partial, child } =>
+				max(partial.bytes.end, handle_end(child)),
+			NodePlan::Branch { value, children } =>
+				max(value_end(value), children_end(children)),
+			NodePlan::NibbledBranch { partial, value, children } =>
+				max(partial.bytes.end, max(value_end(value), children_end(children))),
+		}
+	}
+
 	/// Build a node by decoding a byte slice according to the node plan. It is the responsibility
 	/// of the caller to ensure that the node plan was created for the argument data, otherwise the
 	/// call may decode incorrectly or panic.
@@ -190,6 +272,15 @@ impl<D: Borrow<[u8]>> OwnedNode<D> {
 		Ok(OwnedNode { data, plan })
 	}
 
+	/// Same as `new`, but applies the checks requested in `options`.
+	pub(crate) fn new_checked<C: NodeCodec>(
+		data: D,
+		options: DecodeOptions,
+	) -> Result<Self, DecodeError<C::Error>> {
+		let plan = options.decode_plan::<C>(data.borrow())?;
+		Ok(OwnedNode { data, plan })
+	}
+
 	/// Returns a reference to the backing data.
 	pub fn data(&self) -> &[u8] {
 		self.data.borrow()
@@ -205,3 +296,66 @@ impl<D: Borrow<[u8]>> OwnedNode<D> {
 		self.plan.build(self.data.borrow())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{ChildReference, NibbleSlice, NodeCodec, ReferenceNodeCodec, ReferenceNodeCodecNoExt};
+	use reference_trie::node::{ChildHandle, Node, NodeHandle};
+
+	type Codec = ReferenceNodeCodec<KeccakHasher>;
+	type NoExtCodec = ReferenceNodeCodecNoExt<KeccakHasher>;
+
+	#[test]
+	fn node_children() {
+		let hash = [7u8; 32];
+		let mut children = [None; 16];
+		children[2] = Some(NodeHandle::Hash(&hash[..]));
+		children[9] = Some(NodeHandle::Inline(&[1, 2, 3]));
+		let branch = Node::Branch(children, None);
+		assert_eq!(branch.child(2), Some(NodeHandle::Hash(&hash[..])));
+		assert_eq!(branch.child(3), None);
+		assert_eq!(
+			branch.children().collect::<Vec<_>>(),
+			vec![(Some(2), NodeHandle::Hash(&hash[..])), (Some(9), NodeHandle::Inline(&[1, 2, 3]))],
+		);
+		assert_eq!(branch.child(2).unwrap().decode::<Codec>(), Ok(ChildHandle::Hash(hash)));
+		assert_eq!(branch.child(9).unwrap().decode::<Codec>(), Ok(ChildHandle::Inline(&[1, 2, 3][..])));
+		assert_eq!(NodeHandle::Hash(&[1, 2]).decode::<Codec>(), Err(&[1, 2][..]));
+
+		let extension = Node::Extension(NibbleSlice::new(b"a"), NodeHandle::Inline(&[4]));
+		assert_eq!(extension.child(6), Some(NodeHandle::Inline(&[4])));
+		assert_eq!(extension.child(0), None);
+		assert_eq!(extension.children().collect::<Vec<_>>(), vec![(None, NodeHandle::Inline(&[4]))]);
+
+		let leaf = Node::Leaf(NibbleSlice::new(b"a"), &[5]);
+		assert_eq!(leaf.children().count(), 0);
+	}
+
+	#[test]
+	fn node_plan_end_is_encoded_length() {
+		let hash = [7u8; 32];
+		let mut children = vec![None; 16];
+		children[2] = Some(ChildReference::Hash(hash));
+		children[9] = Some(ChildReference::Inline([1u8; 32], 3));
+		let nodes = vec![
+			Codec::leaf_node(((0, 0), &[1, 2]), &[3; 4]).unwrap(),
+			Codec::extension_node(vec![0x12].into_iter(), 2, ChildReference::Hash(hash)).unwrap(),
+			Codec::branch_node(children.iter(), Some(&[5; 3])).unwrap(),
+			Codec::branch_node(children.iter(), None).unwrap(),
+		];
+		for node in nodes {
+			let (plan, len) = Codec::decode_plan_prefix(&node).unwrap();
+			assert_eq!(plan.end(), len);
+		}
+		let nodes = vec![
+			NoExtCodec::leaf_node(((0, 0), &[1, 2]), &[3; 4]).unwrap(),
+			NoExtCodec::branch_node_nibbled(vec![0x12].into_iter(), 2, children.iter(), Some(&[5; 3])).unwrap(),
+			NoExtCodec::branch_node_nibbled(vec![0x12].into_iter(), 2, children.iter(), None).unwrap(),
+		];
+		for node in nodes {
+			let (plan, len) = NoExtCodec::decode_plan_prefix(&node).unwrap();
+			assert_eq!(plan.end(), len);
+		}
+	}
+}
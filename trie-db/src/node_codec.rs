@@ -16,10 +16,11 @@
 //! to parametrize the hashes used in the codec.
 
 use crate::MaybeDebug;
-use crate::node::{Node, NodePlan};
-use crate::ChildReference;
+use crate::node::{Node, NodeHandle, NodePlan};
+use crate::nibble_ops::{NIBBLE_LENGTH, NIBBLE_PER_BYTE};
+use crate::{ChildReference, TrieError};
 
-use crate::rstd::{borrow::Borrow, Error, hash, vec::Vec};
+use crate::rstd::{borrow::Borrow, convert::TryFrom, Error, hash, vec::Vec};
 
 
 /// Representation of a nible slice (right aligned).
@@ -28,6 +29,66 @@ use crate::rstd::{borrow::Borrow, Error, hash, vec::Vec};
 /// the remaining bytes.
 pub type Partial<'a> = ((u8, u8), &'a[u8]);
 
+/// Checks applied to nodes read from the database or a proof, on top of what
+/// the codec checks.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub(crate) struct DecodeOptions {
+	/// Reject encoded nodes longer than this many bytes, before decoding them.
+	/// No limit if `None`.
+	pub max_size: Option<usize>,
+	/// Reject encoded nodes with unconsumed bytes after the node body.
+	pub strict: bool,
+}
+
+/// Error decoding a node with `DecodeOptions`.
+pub(crate) enum DecodeError<E> {
+	/// The codec failed to decode the node.
+	Codec(E),
+	/// The encoded node is longer than `max_size`, the parameter is its length.
+	TooLarge(usize),
+	/// The node body is followed by this number of unconsumed bytes.
+	TrailingBytes(usize),
+}
+
+impl<E> DecodeError<E> {
+	/// Convert to the `TrieError` reported for the node under `hash`.
+	pub(crate) fn into_trie_error<T>(self, hash: T) -> TrieError<T, E> {
+		match self {
+			DecodeError::Codec(e) => TrieError::DecoderError(hash, e),
+			DecodeError::TooLarge(len) => TrieError::NodeTooLarge(hash, len),
+			DecodeError::TrailingBytes(len) => TrieError::TrailingBytes(hash, len),
+		}
+	}
+}
+
+impl DecodeOptions {
+	/// Decode bytes to a `NodePlan` with codec `C`, applying these checks.
+	pub(crate) fn decode_plan<C: NodeCodec>(self, data: &[u8]) -> Result<NodePlan, DecodeError<C::Error>> {
+		if self.max_size.map_or(false, |max_size| data.len() > max_size) {
+			return Err(DecodeError::TooLarge(data.len()));
+		}
+		if !self.strict {
+			return C::decode_plan(data).map_err(DecodeError::Codec);
+		}
+		let (plan, len) = C::decode_plan_prefix(data).map_err(DecodeError::Codec)?;
+		if len != data.len() {
+			return Err(DecodeError::TrailingBytes(data.len() - len));
+		}
+		Ok(plan)
+	}
+
+	/// Decode bytes to a `Node` with codec `C`, applying these checks.
+	pub(crate) fn decode<C: NodeCodec>(self, data: &[u8]) -> Result<Node<'_>, DecodeError<C::Error>> {
+		Ok(self.decode_plan::<C>(data)?.build(data))
+	}
+
+	/// Options for the inline children of a node decoded with these options: their
+	/// size is already bounded by the size of their parent.
+	pub(crate) fn inline(self) -> Self {
+		DecodeOptions { max_size: None, ..self }
+	}
+}
+
 /// Trait for trie node encoding/decoding.
 pub trait NodeCodec: Sized {
 	/// Codec error type.
@@ -44,18 +105,84 @@ pub trait NodeCodec: Sized {
 	fn decode_plan(data: &[u8]) -> Result<NodePlan, Self::Error>;
 
 	/// Decode bytes to a `Node`. Returns `Self::E` on failure.
-	fn decode(data: &[u8]) -> Result<Node, Self::Error> {
+	fn decode(data: &[u8]) -> Result<Node<'_>, Self::Error> {
 		Ok(Self::decode_plan(data)?.build(data))
 	}
 
+	/// Decode the node at the start of `data` to a `NodePlan`, also returning the number
+	/// of bytes it spans, to parse nodes concatenated without a length prefix.
+	/// Layouts with `STRICT_DECODING` reject nodes for which this is not `data.len()`.
+	/// The default implementation decodes `data` with `decode_plan` and ends the node
+	/// at the end of the last range of its plan, codecs encoding a node past its last
+	/// partial key, value or child should override it.
+	fn decode_plan_prefix(data: &[u8]) -> Result<(NodePlan, usize), Self::Error> {
+		let plan = Self::decode_plan(data)?;
+		let len = match plan {
+			NodePlan::Empty => Self::empty_node().len(),
+			_ => plan.end(),
+		};
+		Ok((plan, len))
+	}
+
+	/// Decode bytes to a `NodePlan`, also returning the version of the node encoding,
+	/// for codecs encoding nodes in several versions of their format.
+	/// The default implementation reports version 0 for every node.
+	fn decode_plan_versioned(data: &[u8]) -> Result<(NodePlan, u8), Self::Error> {
+		Ok((Self::decode_plan(data)?, 0))
+	}
+
+	/// Decode the node at the start of `data` to a `Node`, also returning the number
+	/// of bytes it spans.
+	fn decode_prefix(data: &[u8]) -> Result<(Node<'_>, usize), Self::Error> {
+		let (plan, len) = Self::decode_plan_prefix(data)?;
+		Ok((plan.build(data), len))
+	}
+
+	/// Encode a decoded node, the inverse of `decode`: decoding, modifying and encoding
+	/// back a node gives the node this codec builds from the modified content.
+	fn encode_node(node: &Node) -> Result<Vec<u8>, EncodeNodeError<Self::Error>> {
+		match node {
+			Node::Empty => Ok(Self::empty_node().to_vec()),
+			Node::Leaf(partial, value) => Self::leaf_node(partial.right(), value)
+				.map_err(EncodeNodeError::Codec),
+			Node::Extension(partial, child) => {
+				let child = ChildReference::try_from(*child).map_err(EncodeNodeError::InvalidChild)?;
+				Self::extension_node(partial.right_iter(), partial.len(), child)
+					.map_err(EncodeNodeError::Codec)
+			},
+			Node::Branch(children, value) => {
+				let children = child_references(children)?;
+				Self::branch_node(children.iter(), *value).map_err(EncodeNodeError::Codec)
+			},
+			Node::NibbledBranch(partial, children, value) => {
+				let children = child_references(children)?;
+				Self::branch_node_nibbled(partial.right_iter(), partial.len(), children.iter(), *value)
+					.map_err(EncodeNodeError::Codec)
+			},
+		}
+	}
+
+	/// Check that `data` is the canonical encoding of a node, that is the exact bytes this
+	/// codec produces when encoding the decoded node. This rejects encodings that decode
+	/// fine but hash differently, eg non-minimal lengths, non-zero padding nibbles or
+	/// bitmaps that do not match the children.
+	fn is_canonical(data: &[u8]) -> bool {
+		Self::decode(data).ok()
+			.and_then(|node| Self::encode_node(&node).ok())
+			.map_or(false, |encoded| encoded == data)
+	}
+
 	/// Check if the provided bytes correspond to the codecs "empty" node.
 	fn is_empty_node(data: &[u8]) -> bool;
 
 	/// Returns an encoded empty node.
 	fn empty_node() -> &'static [u8];
 
-	/// Returns an encoded leaf node
-	fn leaf_node(partial: Partial, value: &[u8]) -> Vec<u8>;
+	/// Returns an encoded leaf node.
+	/// Fails if the node cannot be encoded, eg if its partial key is too long.
+	/// Encoding must not fail for a partial key shorter than one that was
+	/// successfully encoded in a leaf.
+	fn leaf_node(partial: Partial, value: &[u8]) -> Result<Vec<u8>, Self::Error>;
 
 	/// Returns an encoded extension node
 	/// Note that number_nibble is the number of element of the iterator
@@ -65,14 +192,14 @@ pub trait NodeCodec: Sized {
 		partial: impl Iterator<Item = u8>,
 		number_nibble: usize,
 		child_ref: ChildReference<Self::HashOut>,
-	) -> Vec<u8>;
+	) -> Result<Vec<u8>, Self::Error>;
 
 	/// Returns an encoded branch node.
 	/// Takes an iterator yielding `ChildReference<Self::HashOut>` and an optional value.
 	fn branch_node(
 		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
 		value: Option<&[u8]>,
-	) -> Vec<u8>;
+	) -> Result<Vec<u8>, Self::Error>;
 
 	/// Returns an encoded branch node with a possible partial path.
 	/// `number_nibble` is the partial path length as in `extension_node`.
@@ -81,5 +208,178 @@ pub trait NodeCodec: Sized {
 		number_nibble: usize,
 		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
 		value: Option<&[u8]>
-	) -> Vec<u8>;
+	) -> Result<Vec<u8>, Self::Error>;
+
+	/// Same as `leaf_node`, appending the encoded node to `output` so that its buffer
+	/// can be reused. The default implementation copies the result of `leaf_node`.
+	fn leaf_node_into(
+		output: &mut Vec<u8>,
+		partial: Partial,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		output.extend_from_slice(&Self::leaf_node(partial, value)?);
+		Ok(())
+	}
+
+	/// Same as `leaf_node`, the partial key being given as in `extension_node`:
+	/// an iterator over its right aligned bytes and its number of nibbles.
+	fn leaf_node_iter(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value: &[u8],
+	) -> Result<Vec<u8>, Self::Error> {
+		let mut output = Vec::new();
+		Self::leaf_node_iter_into(&mut output, partial, number_nibble, value)?;
+		Ok(output)
+	}
+
+	/// Same as `leaf_node_iter`, appending the encoded node to `output`. The default
+	/// implementation collects the partial key for `leaf_node_into`, codecs should
+	/// override it to encode the partial key as it is iterated.
+	fn leaf_node_iter_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value: &[u8],
+	) -> Result<(), Self::Error> {
+		let partial: Vec<u8> = partial.collect();
+		let partial = match (number_nibble % NIBBLE_PER_BYTE, partial.split_first()) {
+			(1, Some((first, rest))) => ((1, *first), rest),
+			_ => ((0, 0), &partial[..]),
+		};
+		Self::leaf_node_into(output, partial, value)
+	}
+
+	/// Append to `output` the encoding of a leaf node up to its value, for a value of
+	/// `value_len` bytes, the partial key being given as in `leaf_node_iter`. For codecs
+	/// encoding leaves as these bytes followed by the value, this lets large values be
+	/// hashed or written after the rest of the node without being copied into its encoding.
+	/// Returns `false` without writing to `output` if the codec encodes leaves otherwise,
+	/// which is the default.
+	fn leaf_node_head_into(
+		_output: &mut Vec<u8>,
+		_partial: impl Iterator<Item = u8>,
+		_number_nibble: usize,
+		_value_len: usize,
+	) -> Result<bool, Self::Error> {
+		Ok(false)
+	}
+
+	/// Same as `leaf_node_iter`, the value of `value_len` bytes being given as consecutive
+	/// chunks, so that it does not have to be held in a single buffer.
+	fn leaf_node_chunked<'c>(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value_len: usize,
+		chunks: impl IntoIterator<Item = &'c [u8]>,
+	) -> Result<Vec<u8>, EncodeNodeError<Self::Error>> {
+		let mut output = Vec::new();
+		Self::leaf_node_chunked_into(&mut output, partial, number_nibble, value_len, chunks)?;
+		Ok(output)
+	}
+
+	/// Same as `leaf_node_chunked`, appending the encoded node to `output`. The chunks are
+	/// appended after the output of `leaf_node_head_into`. Codecs without it need the
+	/// whole value to encode a leaf: the chunks are then collected into a buffer for
+	/// `leaf_node_iter_into`.
+	///
+	/// Fails with `EncodeNodeError::ValueLength`, leaving `output` unchanged, if `chunks`
+	/// does not yield exactly `value_len` bytes.
+	fn leaf_node_chunked_into<'c>(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		value_len: usize,
+		chunks: impl IntoIterator<Item = &'c [u8]>,
+	) -> Result<(), EncodeNodeError<Self::Error>> {
+		let partial: Vec<u8> = partial.collect();
+		let start = output.len();
+		let has_head = Self::leaf_node_head_into(output, partial.iter().cloned(), number_nibble, value_len)
+			.map_err(EncodeNodeError::Codec)?;
+		let value_start = output.len();
+		if !has_head {
+			output.reserve(value_len);
+		}
+		for chunk in chunks {
+			output.extend_from_slice(chunk);
+		}
+		let actual = output.len() - value_start;
+		if actual != value_len {
+			output.truncate(start);
+			return Err(EncodeNodeError::ValueLength { expected: value_len, actual });
+		}
+		if !has_head {
+			let value = output.split_off(value_start);
+			Self::leaf_node_iter_into(output, partial.into_iter(), number_nibble, &value)
+				.map_err(EncodeNodeError::Codec)?;
+		}
+		Ok(())
+	}
+
+	/// Same as `extension_node`, appending the encoded node to `output`.
+	fn extension_node_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child_ref: ChildReference<Self::HashOut>,
+	) -> Result<(), Self::Error> {
+		output.extend_from_slice(&Self::extension_node(partial, number_nibble, child_ref)?);
+		Ok(())
+	}
+
+	/// Same as `branch_node`, appending the encoded node to `output`.
+	fn branch_node_into(
+		output: &mut Vec<u8>,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+	) -> Result<(), Self::Error> {
+		output.extend_from_slice(&Self::branch_node(children, value)?);
+		Ok(())
+	}
+
+	/// Same as `branch_node_nibbled`, appending the encoded node to `output`.
+	fn branch_node_nibbled_into(
+		output: &mut Vec<u8>,
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+	) -> Result<(), Self::Error> {
+		output.extend_from_slice(&Self::branch_node_nibbled(partial, number_nibble, children, value)?);
+		Ok(())
+	}
+}
+
+/// Error of `NodeCodec::encode_node`.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum EncodeNodeError<E> {
+	/// A child cannot be referenced: a hash of the wrong length, or an inline
+	/// node too large to be inlined. Holds the data of the child handle.
+	InvalidChild(Vec<u8>),
+	/// The codec cannot encode the node, eg if its partial key is too long.
+	Codec(E),
+	/// The value chunks given to `NodeCodec::leaf_node_chunked` do not have the
+	/// announced length.
+	ValueLength {
+		/// Length the value was announced with.
+		expected: usize,
+		/// Total length of the chunks.
+		actual: usize,
+	},
+}
+
+/// Child references of a decoded branch.
+fn child_references<HO, E>(
+	children: &[Option<NodeHandle>; NIBBLE_LENGTH],
+) -> Result<[Option<ChildReference<HO>>; NIBBLE_LENGTH], EncodeNodeError<E>>
+	where HO: AsRef<[u8]> + AsMut<[u8]> + Default + Clone + Copy
+{
+	let mut refs = [None; NIBBLE_LENGTH];
+	for (child, child_ref) in children.iter().zip(refs.iter_mut()) {
+		if let Some(child) = child {
+			*child_ref = Some(ChildReference::try_from(*child).map_err(EncodeNodeError::InvalidChild)?);
+		}
+	}
+	Ok(refs)
 }
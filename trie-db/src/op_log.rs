@@ -0,0 +1,193 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording of the changes made to a trie, to replay them on another trie.
+
+use crate::rstd::{marker::PhantomData, vec::Vec};
+use super::{DBValue, Result, TrieMut, TrieLayout, CError, TrieHash};
+
+/// A change made to a trie through `TrieMut::insert` or `TrieMut::remove`.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct TrieOp {
+	/// The changed key.
+	pub key: Vec<u8>,
+	/// The value of the key before the change.
+	pub old_value: Option<DBValue>,
+	/// The inserted value, `None` for a removal.
+	pub new_value: Option<DBValue>,
+}
+
+/// Changes made to a trie, in order.
+#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct OpLog {
+	ops: Vec<TrieOp>,
+}
+
+impl OpLog {
+	/// Create an empty log.
+	pub fn new() -> Self {
+		OpLog { ops: Vec::new() }
+	}
+
+	/// The recorded changes.
+	pub fn ops(&self) -> &[TrieOp] {
+		&self.ops
+	}
+
+	/// Append a change to the log.
+	pub fn push(&mut self, op: TrieOp) {
+		self.ops.push(op);
+	}
+
+	/// Apply the recorded changes to `trie`.
+	/// Returns the index of the first change whose old value differs from the value
+	/// found in `trie`, if any, all the changes being applied regardless.
+	pub fn replay<L, T>(&self, trie: &mut T) -> Result<Option<usize>, TrieHash<L>, CError<L>>
+		where
+			L: TrieLayout,
+			T: TrieMut<L> + ?Sized,
+	{
+		let mut divergence = None;
+		for (i, op) in self.ops.iter().enumerate() {
+			let old_value = match op.new_value {
+				Some(ref value) => trie.insert(&op.key, value)?,
+				None => trie.remove(&op.key)?,
+			};
+			if divergence.is_none() && old_value != op.old_value {
+				divergence = Some(i);
+			}
+		}
+		Ok(divergence)
+	}
+}
+
+/// `TrieMut` wrapper recording the changes made through it in an `OpLog`.
+pub struct LoggingTrieMut<L, T> {
+	trie: T,
+	log: OpLog,
+	_marker: PhantomData<L>,
+}
+
+impl<L, T> LoggingTrieMut<L, T>
+	where
+		L: TrieLayout,
+		T: TrieMut<L>,
+{
+	/// Record the changes made to `trie`.
+	pub fn new(trie: T) -> Self {
+		LoggingTrieMut { trie, log: OpLog::new(), _marker: PhantomData }
+	}
+
+	/// The changes recorded so far.
+	pub fn log(&self) -> &OpLog {
+		&self.log
+	}
+
+	/// Take the changes recorded so far, leaving an empty log.
+	pub fn take_log(&mut self) -> OpLog {
+		crate::rstd::mem::replace(&mut self.log, OpLog::new())
+	}
+
+	/// Get back the wrapped trie and the recorded changes.
+	pub fn into_inner(self) -> (T, OpLog) {
+		(self.trie, self.log)
+	}
+}
+
+impl<L, T> TrieMut<L> for LoggingTrieMut<L, T>
+	where
+		L: TrieLayout,
+		T: TrieMut<L>,
+{
+	fn root(&mut self) -> &TrieHash<L> {
+		self.trie.root()
+	}
+
+	fn is_empty(&self) -> bool {
+		self.trie.is_empty()
+	}
+
+	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
+		where 'a: 'key
+	{
+		self.trie.get(key)
+	}
+
+	fn insert(
+		&mut self,
+		key: &[u8],
+		value: &[u8],
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let old_value = self.trie.insert(key, value)?;
+		self.log.push(TrieOp {
+			key: key.to_vec(),
+			old_value: old_value.clone(),
+			new_value: Some(DBValue::from(value)),
+		});
+		Ok(old_value)
+	}
+
+	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let old_value = self.trie.remove(key)?;
+		self.log.push(TrieOp {
+			key: key.to_vec(),
+			old_value: old_value.clone(),
+			new_value: None,
+		});
+		Ok(old_value)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{RefTrieDBMut, TrieMut, LoggingTrieMut};
+	use reference_trie::DBValue;
+
+	#[test]
+	fn replay_op_log() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = LoggingTrieMut::new(RefTrieDBMut::new(&mut memdb, &mut root));
+		t.insert(b"alfa", &[1; 40]).unwrap();
+		t.insert(b"beta", &[2]).unwrap();
+		t.insert(b"alfa", &[3; 40]).unwrap();
+		t.remove(b"beta").unwrap();
+		t.remove(b"gamma").unwrap();
+		let expected_root = *t.root();
+		let (_, log) = t.into_inner();
+		assert_eq!(log.ops().len(), 5);
+		assert_eq!(log.ops()[2].old_value, Some(vec![1; 40].into()));
+		assert_eq!(log.ops()[2].new_value, Some(vec![3; 40].into()));
+		assert_eq!(log.ops()[3].old_value, Some(vec![2].into()));
+		assert_eq!(log.ops()[4].old_value, None);
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		assert_eq!(log.replay(&mut t).unwrap(), None);
+		assert_eq!(*t.root(), expected_root);
+
+		// replaying onto a trie in a different state reports the first divergence
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		t.insert(b"beta", &[4]).unwrap();
+		assert_eq!(log.replay(&mut t).unwrap(), Some(1));
+		assert_eq!(*t.root(), expected_root);
+	}
+}
@@ -0,0 +1,62 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Estimation of proof sizes.
+
+use hash_db::{HashDBRef, Hasher};
+use hashbrown::HashSet;
+use crate::{
+	CError, DBValue, Query, Result as TrieResult, Trie, TrieDB, TrieHash, TrieLayout,
+};
+
+/// Query adding the length of each node it passes through to `size`, once per node.
+struct SizeQuery<'a, HO> {
+	seen: &'a mut HashSet<HO>,
+	size: &'a mut usize,
+}
+
+impl<'a, H: Hasher> Query<H> for SizeQuery<'a, H::Out> {
+	type Item = ();
+
+	fn decode(self, _value: &[u8]) {}
+
+	fn record(&mut self, hash: &H::Out, data: &[u8], _depth: u32) {
+		if self.seen.insert(*hash) {
+			*self.size += data.len();
+		}
+	}
+}
+
+/// Returns the total length of the encoded nodes traversed by the lookups of `keys`,
+/// counting each node once, without copying the nodes. This is the size of the nodes a
+/// `Recorder` would record, and an upper bound of the size of the compact proof
+/// generated by `generate_proof` for the same keys.
+pub fn estimate_proof_size<'a, L, I, K>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	keys: I,
+) -> TrieResult<usize, TrieHash<L>, CError<L>>
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a K>,
+		K: 'a + AsRef<[u8]>,
+{
+	let trie = TrieDB::<L>::new(db, root)?;
+	let mut seen = HashSet::new();
+	let mut size = 0;
+	for key in keys {
+		trie.get_with(key.as_ref(), SizeQuery { seen: &mut seen, size: &mut size })?;
+	}
+	Ok(size)
+}
@@ -23,7 +23,7 @@ use hash_db::Hasher;
 use crate::{
 	CError, ChildReference, nibble::LeftNibbleSlice, nibble_ops::NIBBLE_LENGTH, NibbleSlice, node::{NodeHandle, NodeHandlePlan, NodePlan, OwnedNode}, NodeCodec, Recorder,
 	Result as TrieResult, Trie, TrieError, TrieHash,
-	TrieLayout,
+	TrieLayout, layout_key,
 };
 
 struct StackEntry<'a, C: NodeCodec> {
@@ -76,14 +76,14 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	/// Encode this entry to an encoded trie node with data properly omitted.
 	fn encode_node(mut self) -> TrieResult<Vec<u8>, C::HashOut, C::Error> {
 		let node_data = self.node.data();
-		Ok(match self.node.node_plan() {
-			NodePlan::Empty => node_data.to_vec(),
-			NodePlan::Leaf { .. } if !self.omit_value => node_data.to_vec(),
+		let encoded = match self.node.node_plan() {
+			NodePlan::Empty => Ok(node_data.to_vec()),
+			NodePlan::Leaf { .. } if !self.omit_value => Ok(node_data.to_vec()),
 			NodePlan::Leaf { partial, value: _ } => {
 				let partial = partial.build(node_data);
 				C::leaf_node(partial.right(), &[])
 			}
-			NodePlan::Extension { .. } if self.child_index == 0 => node_data.to_vec(),
+			NodePlan::Extension { .. } if self.child_index == 0 => Ok(node_data.to_vec()),
 			NodePlan::Extension { partial: partial_plan, child: _ } => {
 				let partial = partial_plan.build(node_data);
 				let child = self.children[0]
@@ -125,7 +125,8 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 					value_with_omission(node_data, value, self.omit_value)
 				)
 			},
-		})
+		};
+		encoded.map_err(|err| Box::new(TrieError::EncoderError(err)))
 	}
 
 	/// Populate the remaining references in `children` with references copied from
@@ -226,9 +227,12 @@ pub fn generate_proof<'a, T, L, I, K>(trie: &T, keys: I)
 		I: IntoIterator<Item=&'a K>,
 		K: 'a + AsRef<[u8]>
 {
-	// Sort and deduplicate keys.
+	// Sort and deduplicate keys, along with the path they are stored at in the trie.
 	let mut keys = keys.into_iter()
-		.map(|key| key.as_ref())
+		.map(|key| {
+			let key = key.as_ref();
+			(layout_key::<L>(key).into_owned(), key)
+		})
 		.collect::<Vec<_>>();
 	keys.sort();
 	keys.dedup();
@@ -240,8 +244,8 @@ pub fn generate_proof<'a, T, L, I, K>(trie: &T, keys: I)
 	// The mutated trie nodes comprising the final proof.
 	let mut proof_nodes = Vec::new();
 
-	for key_bytes in keys {
-		let key = LeftNibbleSlice::new(key_bytes);
+	for (path, key_bytes) in keys.iter() {
+		let key = LeftNibbleSlice::new(path);
 
 		// Unwind the stack until the new entry is a child of the last entry on the stack.
 		unwind_stack(&mut stack, &mut proof_nodes, Some(&key))?;
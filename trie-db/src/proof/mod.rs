@@ -30,22 +30,26 @@
 //! and the hashes of other reconstructed nodes. Since the nodes in the proof are arranged in
 //! pre-order traversal order, the construction can be done efficiently using a stack.
 
+pub use self::estimate::estimate_proof_size;
 pub use self::generate::generate_proof;
+pub use self::range::verify_range;
 pub use self::verify::{Error as VerifyError, verify_proof};
 
+mod estimate;
 mod generate;
+mod range;
 mod verify;
 
 #[cfg(test)]
 mod tests {
 	use hash_db::Hasher;
 	use reference_trie::{
-		ExtensionLayout, NoExtensionLayout,
-		proof::{generate_proof, verify_proof, VerifyError}, Trie, TrieDB, TrieDBMut, TrieLayout,
+		ExtensionLayout, NoExtensionLayout, HashedKeyExtensionLayout,
+		proof::{estimate_proof_size, generate_proof, verify_proof, verify_range, VerifyError}, Trie, TrieDB, TrieDBMut, TrieLayout,
 		TrieMut,
 	};
 
-	use crate::DBValue;
+	use reference_trie::DBValue;
 
 	type MemoryDB<H> = memory_db::MemoryDB<H, memory_db::HashKey<H>, DBValue>;
 
@@ -133,6 +137,22 @@ mod tests {
 		verify_proof::<NoExtensionLayout, _, _, _>(&root, &proof, items.iter()).unwrap();
 	}
 
+	#[test]
+	fn trie_proof_works_with_hashed_keys() {
+		let (root, proof, items) = test_generate_proof::<HashedKeyExtensionLayout>(
+			test_entries(),
+			vec![
+				b"do",
+				b"dog",
+				b"bravo",
+				b"alfabet", // None
+				b"halp", // None
+			],
+		);
+
+		verify_proof::<HashedKeyExtensionLayout, _, _, _>(&root, &proof, items.iter()).unwrap();
+	}
+
 	#[test]
 	fn trie_proof_works_for_empty_trie() {
 		let (root, proof, items) = test_generate_proof::<NoExtensionLayout>(
@@ -296,6 +316,30 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_verify_trailing_bytes() {
+		struct StrictLayout;
+
+		impl TrieLayout for StrictLayout {
+			const USE_EXTENSION: bool = false;
+			const STRICT_DECODING: bool = true;
+			type Hash = <NoExtensionLayout as TrieLayout>::Hash;
+			type Codec = <NoExtensionLayout as TrieLayout>::Codec;
+		}
+
+		let (root, mut proof, items) = test_generate_proof::<NoExtensionLayout>(
+			test_entries(),
+			vec![b"bravo"],
+		);
+		assert!(verify_proof::<StrictLayout, _, _, _>(&root, &proof, items.iter()).is_ok());
+
+		proof[0].push(0);
+		assert_eq!(
+			verify_proof::<StrictLayout, _, _, _>(&root, &proof, items.iter()),
+			Err(VerifyError::TrailingBytes(1))
+		);
+	}
+
 	#[test]
 	fn test_verify_decode_error() {
 		let (root, mut proof, items) = test_generate_proof::<NoExtensionLayout>(
@@ -309,4 +353,125 @@ mod tests {
 			result => panic!("expected VerifyError::DecodeError, got {:?}", result),
 		}
 	}
+
+	fn range_entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut entries: Vec<_> = (0..60u8)
+			.map(|i| (format!("k{}", (i as usize * 7) % 61).into_bytes(), vec![i; (i as usize % 3) * 20 + 1]))
+			.collect();
+		entries.sort();
+		entries
+	}
+
+	fn test_verify_range<L: TrieLayout>() {
+		let entries = range_entries();
+		let mut db = <MemoryDB<L::Hash>>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = <TrieDBMut<L>>::new(&mut db, &mut root);
+			for (key, value) in entries.iter() {
+				trie.insert(key, value).unwrap();
+			}
+		}
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+
+		let mut synced = <MemoryDB<L::Hash>>::default();
+		let mut start = Vec::new();
+		let mut ranges = 0;
+		loop {
+			let items: Vec<_> = entries.iter()
+				.filter(|(key, _)| key[..] >= start[..])
+				.take(7)
+				.cloned()
+				.collect();
+			let mut proof = trie.get_with_proof(&start).unwrap().1;
+			if let Some((last, _)) = items.last() {
+				proof.extend(trie.get_with_proof(last).unwrap().1);
+			}
+			ranges += 1;
+			match verify_range::<L, _, _>(&root, &start, &items, &proof, &mut synced).unwrap() {
+				Some(next) => start = next,
+				None => break,
+			}
+		}
+		assert_eq!(ranges, 9);
+
+		let trie = <TrieDB<L>>::new(&synced, &root).unwrap();
+		for (key, value) in entries.iter() {
+			assert_eq!(trie.get(key).unwrap().as_deref(), Some(&value[..]));
+		}
+	}
+
+	#[test]
+	fn verify_range_works() {
+		test_verify_range::<ExtensionLayout>();
+		test_verify_range::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn verify_range_rejects_incomplete_ranges() {
+		let entries = range_entries();
+		let mut db = <MemoryDB<<ExtensionLayout as TrieLayout>::Hash>>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = <TrieDBMut<ExtensionLayout>>::new(&mut db, &mut root);
+			for (key, value) in entries.iter() {
+				trie.insert(key, value).unwrap();
+			}
+		}
+		let trie = <TrieDB<ExtensionLayout>>::new(&db, &root).unwrap();
+		let start = entries[10].0.clone();
+		let mut items = entries[10..20].to_vec();
+		let mut proof = trie.get_with_proof(&start).unwrap().1;
+		proof.extend(trie.get_with_proof(&entries[19].0).unwrap().1);
+		let mut synced = <MemoryDB<<ExtensionLayout as TrieLayout>::Hash>>::default();
+
+		let mut missing = items.clone();
+		missing.remove(4);
+		assert!(verify_range::<ExtensionLayout, _, _>(&root, &start, &missing, &proof, &mut synced).is_err());
+
+		let mut extra = items.clone();
+		extra.insert(6, (b"k22b".to_vec(), vec![1]));
+		assert!(verify_range::<ExtensionLayout, _, _>(&root, &start, &extra, &proof, &mut synced).is_err());
+
+		items[4].1 = vec![0; 40];
+		match verify_range::<ExtensionLayout, _, _>(&root, &start, &items, &proof, &mut synced) {
+			Err(VerifyError::RootMismatch(_)) => {}
+			result => panic!("expected VerifyError::RootMismatch, got {:?}", result),
+		}
+		assert!(synced.keys().is_empty());
+
+		assert_eq!(
+			verify_range::<ExtensionLayout, _, _>(&root, &start, &entries[10..20], &proof, &mut synced)
+				.unwrap(),
+			Some([&entries[19].0[..], &[0]].concat()),
+		);
+	}
+
+	#[test]
+	fn estimate_proof_size_works() {
+		let keys: Vec<&[u8]> = vec![b"do", b"dog", b"doge", b"alfabet", b"horse"];
+		let mut db = <MemoryDB<<ExtensionLayout as TrieLayout>::Hash>>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = <TrieDBMut<ExtensionLayout>>::new(&mut db, &mut root);
+			for (key, value) in test_entries().iter() {
+				trie.insert(key, value).unwrap();
+			}
+		}
+
+		let size = estimate_proof_size::<ExtensionLayout, _, _>(&db, &root, keys.iter()).unwrap();
+		let trie = <TrieDB<ExtensionLayout>>::new(&db, &root).unwrap();
+		let mut nodes = Vec::new();
+		for key in keys.iter() {
+			for node in trie.get_with_proof(key).unwrap().1 {
+				if !nodes.contains(&node) {
+					nodes.push(node);
+				}
+			}
+		}
+		assert_eq!(size, nodes.iter().map(|node| node.len()).sum::<usize>());
+
+		let proof = generate_proof::<_, ExtensionLayout, _, _>(&trie, keys.iter()).unwrap();
+		assert!(proof.iter().map(|node| node.len()).sum::<usize>() <= size);
+	}
 }
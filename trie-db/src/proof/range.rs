@@ -0,0 +1,369 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of a range of consecutive key/value pairs of a trie.
+
+use hash_db::{HashDB, Hasher};
+use hashbrown::HashMap;
+use crate::rstd::{cmp::Ordering, convert::TryFrom, result::Result, vec, vec::Vec};
+use crate::merge::{NodeView, encode_view, inline_reference, joined, to_nibble_vec};
+use crate::nibble::{NibbleSlice, NibbleVec};
+use crate::node::{Node, NodeHandle};
+use crate::{CError, ChildReference, DBValue, NodeCodec, TrieHash, TrieLayout, decode_options};
+use super::verify::Error;
+
+/// Verifies that `items` are all the key/value pairs of the trie at `root` with a key
+/// from `start` up to the key of the last item, and stores the trie nodes needed to
+/// access them in `db`. With no items, the range extends to the end of the trie.
+///
+/// `proof` holds the encoded nodes traversed by lookups of `start` and of the key of the
+/// last item, in any order (eg as returned by `TrieDB::get_with_proof`). Items must be
+/// sorted by key. Keys are trie paths: with a layout using `HASH_KEYS` they are key hashes.
+///
+/// Returns the key to request the next range from, or `None` if the range reaches the end
+/// of the trie. Nothing is stored in `db` if the verification fails.
+pub fn verify_range<L, K, V>(
+	root: &TrieHash<L>,
+	start: &[u8],
+	items: &[(K, V)],
+	proof: &[Vec<u8>],
+	db: &mut dyn HashDB<L::Hash, DBValue>,
+) -> Result<Option<Vec<u8>>, Error<TrieHash<L>, CError<L>>>
+	where
+		L: TrieLayout,
+		K: AsRef<[u8]>,
+		V: AsRef<[u8]>,
+{
+	for pair in items.windows(2) {
+		if pair[0].0.as_ref() >= pair[1].0.as_ref() {
+			return Err(Error::DuplicateKey(pair[1].0.as_ref().to_vec()));
+		}
+	}
+	if let Some((first, _)) = items.first() {
+		if first.as_ref() < start {
+			return Err(Error::ValueMismatch(first.as_ref().to_vec()));
+		}
+	}
+
+	let mut range = RangeCheck::<L, K, V> {
+		proof: proof.iter()
+			.map(|node| (L::Hash::hash(node), &node[..]))
+			.collect(),
+		start: to_nibble_vec(NibbleSlice::new(start)),
+		end: items.last().map(|(key, _)| to_nibble_vec(NibbleSlice::new(key.as_ref()))),
+		items,
+		used: vec![false; items.len()],
+		more: false,
+		nodes: Vec::new(),
+	};
+
+	let root_path = NibbleVec::new();
+	let computed = match range.view(ChildReference::Hash(*root), &root_path)? {
+		Some(view) => match range.encode(view, &root_path, true)? {
+			ChildReference::Hash(hash) => hash,
+			ChildReference::Inline(..) => unreachable!("root nodes are always hashed; qed"),
+		},
+		None => *root,
+	};
+	if computed != *root {
+		return Err(Error::RootMismatch(computed));
+	}
+	if let Some(unused) = range.used.iter().position(|used| !used) {
+		return Err(Error::ValueMismatch(items[unused].0.as_ref().to_vec()));
+	}
+
+	for (path, node) in range.nodes {
+		db.insert(path.as_prefix(), &node);
+	}
+	Ok(match (range.more, items.last()) {
+		(true, Some((last, _))) => {
+			let mut next = last.as_ref().to_vec();
+			next.push(0);
+			Some(next)
+		},
+		_ => None,
+	})
+}
+
+/// Position of the keys under a path relative to the verified range.
+enum Position {
+	/// All keys are before the range.
+	Before,
+	/// All keys are in the range.
+	Inside,
+	/// All keys are after the range.
+	After,
+	/// Some keys may be in the range, others not.
+	Boundary,
+}
+
+/// Order of a path relative to a range bound.
+enum Relation {
+	Less,
+	Greater,
+	/// The path is a prefix of the bound, or is the bound.
+	PrefixOf,
+	/// The bound is a strict prefix of the path.
+	Extends,
+}
+
+fn relation(path: &NibbleVec, bound: &NibbleVec) -> Relation {
+	for i in 0..path.len() {
+		if i == bound.len() {
+			return Relation::Extends;
+		}
+		if path.at(i) != bound.at(i) {
+			return if path.at(i) < bound.at(i) { Relation::Less } else { Relation::Greater };
+		}
+	}
+	Relation::PrefixOf
+}
+
+/// Order of `key` relative to the keys starting with `prefix`: `Equal` if `key` starts
+/// with `prefix`. Sorted keys starting with a same prefix are contiguous.
+fn cmp_prefix(key: &[u8], prefix: &NibbleVec) -> Ordering {
+	let key = NibbleSlice::new(key);
+	for i in 0..prefix.len() {
+		if i == key.len() {
+			return Ordering::Less;
+		}
+		match key.at(i).cmp(&prefix.at(i)) {
+			Ordering::Equal => (),
+			ordering => return ordering,
+		}
+	}
+	Ordering::Equal
+}
+
+struct RangeCheck<'a, L: TrieLayout, K, V> {
+	proof: HashMap<TrieHash<L>, &'a [u8]>,
+	start: NibbleVec,
+	/// Last key of the range, `None` if the range extends to the end of the trie.
+	end: Option<NibbleVec>,
+	items: &'a [(K, V)],
+	/// Which items were found in the trie.
+	used: Vec<bool>,
+	/// Whether the trie has keys after the range.
+	more: bool,
+	/// The reconstructed nodes with their path.
+	nodes: Vec<(NibbleVec, Vec<u8>)>,
+}
+
+impl<'a, L, K, V> RangeCheck<'a, L, K, V>
+	where
+		L: TrieLayout,
+		K: AsRef<[u8]>,
+		V: AsRef<[u8]>,
+{
+	fn position(&self, path: &NibbleVec) -> Position {
+		let left_boundary = match relation(path, &self.start) {
+			Relation::Less => return Position::Before,
+			Relation::PrefixOf => path.len() < self.start.len(),
+			Relation::Greater | Relation::Extends => false,
+		};
+		let right_boundary = match self.end.as_ref().map(|end| relation(path, end)) {
+			Some(Relation::Greater) | Some(Relation::Extends) => return Position::After,
+			Some(Relation::PrefixOf) => true,
+			Some(Relation::Less) | None => false,
+		};
+		if left_boundary || right_boundary {
+			Position::Boundary
+		} else {
+			Position::Inside
+		}
+	}
+
+	fn in_range(&self, key: &NibbleVec) -> bool {
+		let after_start = match relation(key, &self.start) {
+			Relation::Less => false,
+			Relation::PrefixOf => key.len() == self.start.len(),
+			Relation::Greater | Relation::Extends => true,
+		};
+		after_start && match self.end.as_ref().map(|end| relation(key, end)) {
+			Some(Relation::Greater) | Some(Relation::Extends) => false,
+			Some(Relation::Less) | Some(Relation::PrefixOf) | None => true,
+		}
+	}
+
+	/// Take the value of the item with key `key`.
+	fn take_item(&mut self, key: &NibbleVec) -> Option<DBValue> {
+		let index = self.items.binary_search_by(|(item_key, _)|
+			cmp_prefix(item_key.as_ref(), key).then((item_key.as_ref().len() * 2).cmp(&key.len()))
+		).ok()?;
+		self.used[index] = true;
+		Some(DBValue::from(self.items[index].1.as_ref()))
+	}
+
+	/// The node of the trie at `reference`, with the keys in the range replaced by the
+	/// items. Returns `None` if the node has no key in the range and is kept as is.
+	fn view(
+		&mut self,
+		reference: ChildReference<TrieHash<L>>,
+		path: &NibbleVec,
+	) -> Result<Option<NodeView<TrieHash<L>>>, Error<TrieHash<L>, CError<L>>> {
+		let data = match reference {
+			ChildReference::Hash(hash) if hash == L::Codec::hashed_null_node() =>
+				L::Codec::empty_node().to_vec(),
+			ChildReference::Hash(hash) => self.proof.get(&hash)
+				.ok_or(Error::IncompleteProof)?
+				.to_vec(),
+			ChildReference::Inline(data, len) => data.as_ref()[..len].to_vec(),
+		};
+		let node = decode_options::<L>().decode::<L::Codec>(&data)?;
+		let child_reference = |child: NodeHandle| ChildReference::try_from(child)
+			.map_err(Error::InvalidChildReference);
+
+		let mut view = match node {
+			Node::Empty => NodeView::new(NibbleVec::new(), None),
+			Node::Leaf(partial, value) => NodeView::new(to_nibble_vec(partial), Some(DBValue::from(value))),
+			Node::Extension(partial, child) => {
+				let partial = to_nibble_vec(partial);
+				let child_path = joined(path, &partial, None);
+				let branch = match self.position(&child_path) {
+					Position::Before => return Ok(None),
+					Position::After => {
+						self.more = true;
+						return Ok(None);
+					},
+					Position::Inside => self.build(&child_path, child_path.len())?
+						.unwrap_or_else(|| NodeView::new(NibbleVec::new(), None)),
+					Position::Boundary => self.view(child_reference(child)?, &child_path)?
+						.ok_or(Error::IncompleteProof)?,
+				};
+				let mut joined_partial = partial;
+				joined_partial.append(&branch.partial);
+				return Ok(Some(NodeView { partial: joined_partial, ..branch }));
+			},
+			Node::Branch(children, value) => {
+				let mut view = NodeView::new(NibbleVec::new(), value.map(DBValue::from));
+				for (i, child) in children.iter().enumerate() {
+					if let Some(child) = child {
+						view.children[i] = Some(child_reference(*child)?);
+					}
+				}
+				view
+			},
+			Node::NibbledBranch(partial, children, value) => {
+				let mut view = NodeView::new(to_nibble_vec(partial), value.map(DBValue::from));
+				for (i, child) in children.iter().enumerate() {
+					if let Some(child) = child {
+						view.children[i] = Some(child_reference(*child)?);
+					}
+				}
+				view
+			},
+		};
+
+		let key = joined(path, &view.partial, None);
+		if self.in_range(&key) {
+			view.value = self.take_item(&key);
+		} else if view.value.is_some() {
+			if let Position::After = self.position(&key) {
+				self.more = true;
+			}
+		}
+		for i in 0..16 {
+			let child_path = joined(path, &view.partial, Some(i as u8));
+			view.children[i] = match (self.position(&child_path), view.children[i]) {
+				(Position::Before, child) => child,
+				(Position::After, child) => {
+					self.more |= child.is_some();
+					child
+				},
+				(Position::Inside, _) => match self.build(&child_path, child_path.len())? {
+					Some(child) => Some(self.encode(child, &child_path, false)?),
+					None => None,
+				},
+				(Position::Boundary, Some(child)) => match self.view(child, &child_path)? {
+					Some(child) => Some(self.encode(child, &child_path, false)?),
+					None => Some(child),
+				},
+				(Position::Boundary, None) => None,
+			};
+		}
+		Ok(Some(view))
+	}
+
+	/// Build the node at `path` from the items under it. `depth` is the length of `path`.
+	fn build(
+		&mut self,
+		path: &NibbleVec,
+		depth: usize,
+	) -> Result<Option<NodeView<TrieHash<L>>>, Error<TrieHash<L>, CError<L>>> {
+		let lo = self.items.partition_point(|(key, _)|
+			cmp_prefix(key.as_ref(), path) == Ordering::Less
+		);
+		let hi = lo + self.items[lo..].partition_point(|(key, _)|
+			cmp_prefix(key.as_ref(), path) == Ordering::Equal
+		);
+		if lo == hi {
+			return Ok(None);
+		}
+		self.build_items(lo, hi, path, depth).map(Some)
+	}
+
+	/// Build the node at `path` from the items `lo..hi`, all under `path`.
+	fn build_items(
+		&mut self,
+		mut lo: usize,
+		hi: usize,
+		path: &NibbleVec,
+		depth: usize,
+	) -> Result<NodeView<TrieHash<L>>, Error<TrieHash<L>, CError<L>>> {
+		let items = self.items;
+		let first = NibbleSlice::new(items[lo].0.as_ref());
+		let last = NibbleSlice::new(items[hi - 1].0.as_ref());
+		let common = (depth..first.len().min(last.len()))
+			.take_while(|i| first.at(*i) == last.at(*i))
+			.count();
+		let mut partial = NibbleVec::new();
+		for i in depth..depth + common {
+			partial.push(first.at(i));
+		}
+		let mut view = NodeView::new(partial, None);
+		if first.len() == depth + common {
+			view.value = Some(DBValue::from(items[lo].1.as_ref()));
+			self.used[lo] = true;
+			lo += 1;
+		}
+		let at = depth + common;
+		while lo < hi {
+			let index = NibbleSlice::new(items[lo].0.as_ref()).at(at);
+			let end = lo + items[lo..hi].iter()
+				.take_while(|(key, _)| NibbleSlice::new(key.as_ref()).at(at) == index)
+				.count();
+			let child_path = joined(path, &view.partial, Some(index));
+			let child = self.build_items(lo, end, &child_path, at + 1)?;
+			view.children[index as usize] = Some(self.encode(child, &child_path, false)?);
+			lo = end;
+		}
+		Ok(view)
+	}
+
+	fn encode(
+		&mut self,
+		view: NodeView<TrieHash<L>>,
+		path: &NibbleVec,
+		is_root: bool,
+	) -> Result<ChildReference<TrieHash<L>>, Error<TrieHash<L>, CError<L>>> {
+		let nodes = &mut self.nodes;
+		encode_view::<L>(view, path, is_root, &mut |encoded, path, is_root| {
+			inline_reference::<L>(&encoded, is_root).unwrap_or_else(|| {
+				let hash = L::Hash::hash(&encoded);
+				nodes.push((path.clone(), encoded));
+				ChildReference::Hash(hash)
+			})
+		}).map_err(Error::EncodeError)
+	}
+}
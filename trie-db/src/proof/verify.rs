@@ -17,7 +17,8 @@ use crate::rstd::{
 };
 use crate::{
 	CError, ChildReference, nibble::LeftNibbleSlice, nibble_ops::NIBBLE_LENGTH,
-	node::{Node, NodeHandle}, NodeCodec, TrieHash, TrieLayout,
+	node::{Node, NodeHandle}, node_codec::{DecodeOptions, DecodeError}, NodeCodec, TrieHash, TrieLayout,
+	decode_options, layout_key,
 };
 use hash_db::Hasher;
 
@@ -48,6 +49,24 @@ pub enum Error<HO, CE> {
 	RootMismatch(HO),
 	/// One of the proof nodes could not be decoded.
 	DecodeError(CE),
+	/// One of the reconstructed nodes could not be encoded.
+	EncodeError(CE),
+	/// One of the proof nodes is longer than the layout `MAX_NODE_SIZE`.
+	/// The parameter is its length.
+	NodeTooLarge(usize),
+	/// One of the proof nodes is followed by unconsumed bytes, with the layout
+	/// `STRICT_DECODING`. The parameter is their number.
+	TrailingBytes(usize),
+}
+
+impl<HO, CE> From<DecodeError<CE>> for Error<HO, CE> {
+	fn from(error: DecodeError<CE>) -> Self {
+		match error {
+			DecodeError::Codec(e) => Error::DecodeError(e),
+			DecodeError::TooLarge(len) => Error::NodeTooLarge(len),
+			DecodeError::TrailingBytes(len) => Error::TrailingBytes(len),
+		}
+	}
 }
 
 #[cfg(feature = "std")]
@@ -80,6 +99,12 @@ impl<HO: std::fmt::Debug, CE: std::error::Error> std::fmt::Display for Error<HO,
 				write!(f, "Computed incorrect root {:?} from proof", hash),
 			Error::DecodeError(err) =>
 				write!(f, "Unable to decode proof node: {}", err),
+			Error::EncodeError(err) =>
+				write!(f, "Unable to encode reconstructed node: {}", err),
+			Error::NodeTooLarge(len) =>
+				write!(f, "Proof node exceeds the maximum node size with length: {}", len),
+			Error::TrailingBytes(len) =>
+				write!(f, "Proof node is followed by {} unexpected bytes", len),
 		}
 	}
 }
@@ -89,6 +114,7 @@ impl<HO: std::fmt::Debug, CE: std::error::Error + 'static> std::error::Error for
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match self {
 			Error::DecodeError(err) => Some(err),
+			Error::EncodeError(err) => Some(err),
 			_ => None,
 		}
 	}
@@ -110,11 +136,14 @@ struct StackEntry<'a, C: NodeCodec> {
 }
 
 impl<'a, C: NodeCodec> StackEntry<'a, C> {
-	fn new(node_data: &'a [u8], prefix: LeftNibbleSlice<'a>, is_inline: bool)
-		   -> Result<Self, Error<C::HashOut, C::Error>>
+	fn new(
+		node_data: &'a [u8],
+		prefix: LeftNibbleSlice<'a>,
+		is_inline: bool,
+		options: DecodeOptions,
+	) -> Result<Self, Error<C::HashOut, C::Error>>
 	{
-		let node = C::decode(node_data)
-			.map_err(Error::DecodeError)?;
+		let node = options.decode::<C>(node_data)?;
 		let children_len = match node {
 			Node::Empty | Node::Leaf(..) => 0,
 			Node::Extension(..) => 1,
@@ -139,9 +168,9 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	/// Encode this entry to an encoded trie node with data properly reconstructed.
 	fn encode_node(mut self) -> Result<Vec<u8>, Error<C::HashOut, C::Error>> {
 		self.complete_children()?;
-		Ok(match self.node {
+		let encoded = match self.node {
 			Node::Empty =>
-				C::empty_node().to_vec(),
+				Ok(C::empty_node().to_vec()),
 			Node::Leaf(partial, _) => {
 				let value = self.value
 					.expect(
@@ -172,13 +201,15 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 					self.children.iter(),
 					self.value,
 				),
-		})
+		};
+		encoded.map_err(Error::EncodeError)
 	}
 
 	fn advance_child_index<I>(
 		&mut self,
 		child_prefix: LeftNibbleSlice<'a>,
 		proof_iter: &mut I,
+		options: DecodeOptions,
 	) -> Result<Self, Error<C::HashOut, C::Error>>
 		where
 			I: Iterator<Item=&'a Vec<u8>>,
@@ -187,7 +218,7 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 			Node::Extension(_, child) => {
 				// Guaranteed because of sorted keys order.
 				assert_eq!(self.child_index, 0);
-				Self::make_child_entry(proof_iter, child, child_prefix)
+				Self::make_child_entry(proof_iter, child, child_prefix, options)
 			}
 			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) => {
 				// because this is a branch
@@ -205,7 +236,7 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 				}
 				let child = children[self.child_index]
 					.expect("guaranteed by advance_item");
-				Self::make_child_entry(proof_iter, child, child_prefix)
+				Self::make_child_entry(proof_iter, child, child_prefix, options)
 			}
 			_ => panic!("cannot have children"),
 		}
@@ -239,6 +270,7 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 		proof_iter: &mut I,
 		child: NodeHandle<'a>,
 		prefix: LeftNibbleSlice<'a>,
+		options: DecodeOptions,
 	) -> Result<Self, Error<C::HashOut, C::Error>>
 		where
 			I: Iterator<Item=&'a Vec<u8>>,
@@ -248,9 +280,9 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 				if data.is_empty() {
 					let node_data = proof_iter.next()
 						.ok_or(Error::IncompleteProof)?;
-					StackEntry::new(node_data, prefix, false)
+					StackEntry::new(node_data, prefix, false, options)
 				} else {
-					StackEntry::new(data, prefix, true)
+					StackEntry::new(data, prefix, true, options.inline())
 				}
 			}
 			NodeHandle::Hash(data) => {
@@ -396,10 +428,15 @@ pub fn verify_proof<'a, L, I, K, V>(root: &<L::Hash as Hasher>::Out, proof: &[Ve
 		K: 'a + AsRef<[u8]>,
 		V: 'a + AsRef<[u8]>,
 {
-	// Sort items.
-	let mut items = items.into_iter()
+	// Sort items, by hashed keys when the layout stores values under hashed keys.
+	let items = items.into_iter()
 		.map(|(k, v)| (k.as_ref(), v.as_ref().map(|v| v.as_ref())))
 		.collect::<Vec<_>>();
+	let keys = items.iter().map(|(k, _)| layout_key::<L>(k)).collect::<Vec<_>>();
+	let mut items = items.into_iter()
+		.zip(keys.iter())
+		.map(|((_, v), key)| (key.as_ref(), v))
+		.collect::<Vec<_>>();
 	items.sort();
 
 	if items.is_empty() {
@@ -432,13 +469,18 @@ pub fn verify_proof<'a, L, I, K, V>(root: &<L::Hash as Hasher>::Out, proof: &[Ve
 	let mut last_entry = StackEntry::new(
 		root_node,
 		LeftNibbleSlice::new(&[]),
-		false
+		false,
+		decode_options::<L>(),
 	)?;
 	loop {
 		// Insert omitted value.
 		match last_entry.advance_item(&mut items_iter)? {
 			Step::Descend(child_prefix) => {
-				let next_entry = last_entry.advance_child_index(child_prefix, &mut proof_iter)?;
+				let next_entry = last_entry.advance_child_index(
+					child_prefix,
+					&mut proof_iter,
+					decode_options::<L>(),
+				)?;
 				stack.push(last_entry);
 				last_entry = next_entry;
 			}
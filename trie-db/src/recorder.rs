@@ -14,7 +14,10 @@
 
 //! Trie query recorder.
 
-use crate::rstd::vec::Vec;
+use hashbrown::{HashMap, HashSet};
+use crate::node::{ChildHandle, Node};
+use crate::node_codec::NodeCodec;
+use crate::rstd::{hash::Hash, vec::Vec};
 
 /// A record of a visited node.
 #[cfg_attr(feature = "std", derive(Debug))]
@@ -30,11 +33,20 @@ pub struct Record<HO> {
 	pub hash: HO,
 }
 
+/// Query recording the traversed nodes in a `Recorder` and decoding the value
+/// with an inner query, see `Recorder::recording`.
+pub struct Recording<'a, HO, Q> {
+	pub(crate) recorder: &'a mut Recorder<HO>,
+	pub(crate) query: Q,
+}
+
 /// Records trie nodes as they pass it.
 #[cfg_attr(feature = "std", derive(Debug))]
 pub struct Recorder<HO> {
 	nodes: Vec<Record<HO>>,
 	min_depth: u32,
+	key_prefix: Option<Vec<u8>>,
+	recording: bool,
 }
 
 impl<HO: Copy> Default for Recorder<HO> {
@@ -55,12 +67,29 @@ impl<HO: Copy> Recorder<HO> {
 		Recorder {
 			nodes: Vec::new(),
 			min_depth: depth,
+			key_prefix: None,
+			recording: true,
 		}
 	}
 
+	/// Create a `Recorder` which only records the nodes traversed by lookups of keys
+	/// starting with `prefix`.
+	pub fn with_key_prefix(prefix: &[u8]) -> Self {
+		Recorder {
+			key_prefix: Some(prefix.to_vec()),
+			..Recorder::new()
+		}
+	}
+
+	/// Record that `key` is about to be looked up: the nodes visited next are only
+	/// recorded if `key` starts with the key prefix of the recorder, if any.
+	pub fn record_key(&mut self, key: &[u8]) {
+		self.recording = self.key_prefix.as_ref().map_or(true, |prefix| key.starts_with(prefix));
+	}
+
 	/// Record a visited node, given its hash, data, and depth.
 	pub fn record(&mut self, hash: &HO, data: &[u8], depth: u32) {
-		if depth >= self.min_depth {
+		if self.recording && depth >= self.min_depth {
 			self.nodes.push(Record {
 				depth: depth,
 				data: data.into(),
@@ -69,10 +98,81 @@ impl<HO: Copy> Recorder<HO> {
 		}
 	}
 
+	/// Wrap `query` into a query which also records the traversed nodes in this recorder,
+	/// eg to get a value and its proof with a single `get_with`:
+	/// `trie.get_with(key, recorder.recording(|v: &[u8]| decode(v)))`.
+	/// The nodes are recorded whether the value is found or not.
+	pub fn recording<Q>(&mut self, query: Q) -> Recording<'_, HO, Q> {
+		Recording { recorder: self, query }
+	}
+
 	/// Drain all visited records.
 	pub fn drain(&mut self) -> Vec<Record<HO>> {
 		crate::rstd::mem::replace(&mut self.nodes, Vec::new())
 	}
+
+	/// Drain all visited records in trie pre-order traversal order, children in nibble order,
+	/// keeping the first record of each node only. Nodes are decoded with `C` to find their
+	/// children. The order is that of the records for nodes not under any other recorded node.
+	pub fn drain_ordered<C>(&mut self) -> Vec<Record<HO>>
+		where
+			C: NodeCodec<HashOut = HO>,
+			HO: Eq + Hash,
+	{
+		let mut records = HashMap::new();
+		let mut order = Vec::new();
+		for record in self.drain() {
+			if !records.contains_key(&record.hash) {
+				order.push(record.hash);
+				records.insert(record.hash, record);
+			}
+		}
+
+		let mut children = HashMap::new();
+		let mut is_child = HashSet::new();
+		for hash in order.iter() {
+			let mut node_children = Vec::new();
+			hashed_children::<C>(&records[hash].data, &mut node_children);
+			node_children.retain(|child| records.contains_key(child));
+			is_child.extend(node_children.iter().cloned());
+			children.insert(*hash, node_children);
+		}
+
+		let mut ordered = Vec::with_capacity(order.len());
+		let mut stack: Vec<HO> = order.iter().rev()
+			.filter(|hash| !is_child.contains(*hash))
+			.cloned()
+			.collect();
+		while let Some(hash) = stack.pop() {
+			if let Some(record) = records.remove(&hash) {
+				stack.extend(children[&hash].iter().rev().cloned());
+				ordered.push(record);
+			}
+		}
+		// nodes only reachable through a cycle of hashes, which cannot happen with a
+		// sound hasher.
+		ordered.extend(order.into_iter().filter_map(|hash| records.remove(&hash)));
+		ordered
+	}
+}
+
+/// Collects the hashes of the children of an encoded node, looking into inline children.
+/// Nodes that cannot be decoded are seen as having no children.
+fn hashed_children<C: NodeCodec>(data: &[u8], hashes: &mut Vec<C::HashOut>) {
+	let node = match C::decode(data) {
+		Ok(node) => node,
+		Err(_) => return,
+	};
+	if let Node::Empty | Node::Leaf(..) = node {
+		return;
+	}
+	for (_, child) in node.children() {
+		match child.decode::<C>() {
+			Ok(ChildHandle::Hash(hash)) => hashes.push(hash),
+			Ok(ChildHandle::Inline(data)) => hashed_children::<C>(data, hashes),
+			Err(_) => (),
+		}
+	}
 }
 
 #[cfg(test)]
@@ -80,7 +180,7 @@ mod tests {
 	use memory_db::{MemoryDB, HashKey};
 	use hash_db::Hasher;
 	use keccak_hasher::KeccakHasher;
-	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, Recorder, Record};
+	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, Recorder, Record, ReferenceNodeCodec};
 
 	#[test]
 	fn basic_recorder() {
@@ -188,4 +288,82 @@ mod tests {
 			]
 		]);
 	}
+
+	#[test]
+	fn drain_ordered_dedups_in_traversal_order() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		let mut root = Default::default();
+		{
+			let mut x = RefTrieDBMut::new(&mut db, &mut root);
+			x.insert(b"dog", b"cat").unwrap();
+			x.insert(b"lunch", b"time").unwrap();
+			x.insert(b"notdog", b"notcat").unwrap();
+			x.insert(b"hotdog", b"hotcat").unwrap();
+			x.insert(b"letter", b"confusion").unwrap();
+			x.insert(b"insert", b"remove").unwrap();
+			x.insert(b"pirate", b"aargh!").unwrap();
+			x.insert(b"yo ho ho", b"and a bottle of rum").unwrap();
+		}
+
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let mut recorder = Recorder::new();
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		let letter = recorder.drain();
+		trie.get_with(b"pirate", &mut recorder).unwrap().unwrap();
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		let pirate = recorder.drain();
+
+		trie.get_with(b"pirate", &mut recorder).unwrap().unwrap();
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		let ordered = recorder.drain_ordered::<ReferenceNodeCodec<KeccakHasher>>();
+		assert_eq!(ordered, vec![letter[0].clone(), letter[1].clone(), pirate[1].clone()]);
+		assert_eq!(ordered.iter().map(|r| r.depth).collect::<Vec<_>>(), vec![0, 1, 1]);
+	}
+
+	#[test]
+	fn recorder_key_prefix() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		let mut root = Default::default();
+		{
+			let mut x = RefTrieDBMut::new(&mut db, &mut root);
+			x.insert(b"alice/balance", &[1; 40]).unwrap();
+			x.insert(b"alice/nonce", &[2; 40]).unwrap();
+			x.insert(b"bob/balance", &[3; 40]).unwrap();
+		}
+
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let mut recorder = Recorder::with_key_prefix(b"alice/");
+		trie.get_with(b"bob/balance", &mut recorder).unwrap().unwrap();
+		assert!(recorder.drain().is_empty());
+
+		trie.get_with(b"alice/nonce", &mut recorder).unwrap().unwrap();
+		trie.get_with(b"bob/balance", &mut recorder).unwrap().unwrap();
+		let mut expected = Recorder::new();
+		trie.get_with(b"alice/nonce", &mut expected).unwrap().unwrap();
+		assert_eq!(recorder.drain(), expected.drain());
+	}
+
+	#[test]
+	fn recording_decodes_and_records() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		let mut root = Default::default();
+		{
+			let mut x = RefTrieDBMut::new(&mut db, &mut root);
+			x.insert(b"dog", &[1; 40]).unwrap();
+			x.insert(b"doge", &[2; 40]).unwrap();
+		}
+
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let mut recorder = Recorder::new();
+		let len = trie.get_with(b"doge", recorder.recording(|v: &[u8]| v.len())).unwrap();
+		assert_eq!(len, Some(40));
+		let mut expected = Recorder::new();
+		trie.get_with(b"doge", &mut expected).unwrap();
+		assert_eq!(recorder.drain(), expected.drain());
+
+		// nodes proving an absence are recorded too.
+		let len = trie.get_with(b"dot", recorder.recording(|v: &[u8]| v.len())).unwrap();
+		assert_eq!(len, None);
+		assert!(!recorder.drain().is_empty());
+	}
 }
@@ -81,6 +81,10 @@ where
 	> {
 		TrieDB::iter(&self.raw)
 	}
+
+	fn len(&self) -> Result<usize, TrieHash<L>, CError<L>> {
+		self.raw.len()
+	}
 }
 
 #[cfg(test)]
@@ -89,7 +93,7 @@ mod test {
 	use hash_db::Hasher;
 	use keccak_hasher::KeccakHasher;
 	use reference_trie::{RefTrieDBMut, RefSecTrieDB, Trie, TrieMut};
-	use crate::DBValue;
+	use reference_trie::DBValue;
 
 	#[test]
 	fn trie_to_sectrie() {
@@ -23,7 +23,8 @@ pub struct SecTrieDBMut<'db, L>
 where
 	L: TrieLayout
 {
-	raw: TrieDBMut<'db, L>
+	raw: TrieDBMut<'db, L>,
+	preimages: Option<&'db mut dyn FnMut(&TrieHash<L>, &[u8])>,
 }
 
 impl<'db, L> SecTrieDBMut<'db, L>
@@ -34,7 +35,7 @@ where
 	/// Initialise to the state entailed by the genesis block.
 	/// This guarantees the trie is built correctly.
 	pub fn new(db: &'db mut dyn HashDB<L::Hash, DBValue>, root: &'db mut TrieHash<L>) -> Self {
-		SecTrieDBMut { raw: TrieDBMut::new(db, root) }
+		SecTrieDBMut { raw: TrieDBMut::new(db, root), preimages: None }
 	}
 
 	/// Create a new trie with the backing database `db` and `root`.
@@ -44,7 +45,15 @@ where
 		db: &'db mut dyn HashDB<L::Hash, DBValue>,
 		root: &'db mut TrieHash<L>,
 	) -> Result<Self, TrieHash<L>, CError<L>> {
-		Ok(SecTrieDBMut { raw: TrieDBMut::from_existing(db, root)? })
+		Ok(SecTrieDBMut { raw: TrieDBMut::from_existing(db, root)?, preimages: None })
+	}
+
+	/// Give the hashed key and the key of every insert to `sink`, eg to keep key preimages
+	/// out of the trie database. Unlike `FatDBMut`, nothing is written to the database
+	/// and preimages are not removed with their key.
+	pub fn with_preimage_sink(mut self, sink: &'db mut dyn FnMut(&TrieHash<L>, &[u8])) -> Self {
+		self.preimages = Some(sink);
+		self
 	}
 
 	/// Get the backing database.
@@ -80,7 +89,12 @@ where
 		&mut self, key: &[u8],
 		value: &[u8],
 	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
-		self.raw.insert(&L::Hash::hash(key).as_ref(), value)
+		let hash = L::Hash::hash(key);
+		let out = self.raw.insert(hash.as_ref(), value)?;
+		if let Some(sink) = self.preimages.as_mut() {
+			sink(&hash, key);
+		}
+		Ok(out)
 	}
 
 	 fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
@@ -94,7 +108,7 @@ mod test {
 	use hash_db::Hasher;
 	use keccak_hasher::KeccakHasher;
 	use reference_trie::{RefTrieDB, RefSecTrieDBMut, Trie, TrieMut};
-	use crate::DBValue;
+	use reference_trie::DBValue;
 
 	#[test]
 	fn sectrie_to_trie() {
@@ -110,4 +124,32 @@ mod test {
 			vec![0x01u8, 0x23],
 		);
 	}
+
+	#[test]
+	fn sectrie_records_preimages() {
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut preimages = Vec::new();
+		let mut sink = |hash: &[u8; 32], key: &[u8]| preimages.push((*hash, key.to_vec()));
+		{
+			let mut t = RefSecTrieDBMut::new(&mut memdb, &mut root).with_preimage_sink(&mut sink);
+			t.insert(b"alfa", b"1").unwrap();
+			t.insert(b"beta", b"2").unwrap();
+			t.remove(b"alfa").unwrap();
+		}
+		assert_eq!(preimages, vec![
+			(KeccakHasher::hash(b"alfa"), b"alfa".to_vec()),
+			(KeccakHasher::hash(b"beta"), b"beta".to_vec()),
+		]);
+
+		// nothing but the trie nodes is stored.
+		let mut other_db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut other_root = Default::default();
+		{
+			let mut t = RefSecTrieDBMut::new(&mut other_db, &mut other_root);
+			t.insert(b"beta", b"2").unwrap();
+		}
+		assert_eq!(root, other_root);
+		assert_eq!(memdb.keys(), other_db.keys());
+	}
 }
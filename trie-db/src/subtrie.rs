@@ -0,0 +1,320 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copy of tries, or of the part of a trie under a given prefix, between databases.
+
+use hash_db::{HashDB, HashDBRef, EMPTY_PREFIX};
+use crate::nibble::{NibbleSlice, NibbleVec};
+use crate::node::{ChildHandle, Node, NodeHandle, OwnedNode};
+use crate::node_codec::NodeCodec;
+use crate::rstd::{boxed::Box, convert::TryFrom, vec, vec::Vec};
+use crate::triedb::TrieDB;
+use crate::triedbmut::ChildReference;
+use super::{DBValue, Result, TrieError, TrieLayout, CError, TrieHash, decode_options};
+
+/// Copies the nodes of the trie at `root` holding the keys starting with `prefix` into
+/// `target`, as a trie containing these keys with `prefix` stripped.
+/// Returns the root of the new trie, which is the empty trie root if no key starts
+/// with `prefix`.
+///
+/// Only the node rooting the prefix gets re-encoded (its partial key is shortened), the
+/// nodes under it are copied as is and stored under their path in the new trie.
+/// `prefix` is a path in the trie: it is not hashed, even if the layout uses `HASH_KEYS`.
+pub fn extract_subtrie<L, DB>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	prefix: &[u8],
+	target: &mut DB,
+) -> Result<TrieHash<L>, TrieHash<L>, CError<L>>
+	where
+		L: TrieLayout,
+		DB: HashDB<L::Hash, DBValue>,
+{
+	let trie = TrieDB::<L>::new(db, root)?;
+	let (node, reference, key_nibbles) = match trie.subtree_node(prefix)? {
+		Some(subtree) => subtree,
+		None => return Ok(L::Codec::hashed_null_node()),
+	};
+	let parent_hash = match reference {
+		ChildReference::Hash(hash) => hash,
+		ChildReference::Inline(..) => *root,
+	};
+	let prefix_slice = NibbleSlice::new(prefix);
+	let mut prefix_nibbles = NibbleVec::new();
+	for nibble in prefix_slice.iter() {
+		prefix_nibbles.push(nibble);
+	}
+	// Nibbles of the node partial key that are part of `prefix`.
+	let overlap = prefix.len() * 2 - key_nibbles;
+
+	let extension_child = match node.node() {
+		// `prefix` ends right after the extension: its child becomes the root.
+		Node::Extension(slice, child) if slice.len() == overlap =>
+			Some(trie.get_raw_or_lookup(parent_hash, child, prefix_nibbles.as_prefix())?),
+		_ => None,
+	};
+	let (node, parent_hash, overlap) = match extension_child {
+		Some((child, child_hash)) => (child, child_hash.unwrap_or(parent_hash), 0),
+		None => (node, parent_hash, overlap),
+	};
+
+	let encoded = match overlap {
+		0 => node.data().to_vec(),
+		_ => shorten_partial::<L>(&node, overlap, parent_hash)?,
+	};
+	let new_root = target.insert(EMPTY_PREFIX, &encoded[..]);
+
+	let mut stack = vec![(node, parent_hash, NibbleVec::new(), overlap)];
+	while let Some((node, parent_hash, path, skip)) = stack.pop() {
+		let mut child_path = path;
+		let decoded = node.node();
+		match decoded {
+			Node::Extension(slice, _) | Node::NibbledBranch(slice, _, _) => {
+				let partial = slice.mid(skip);
+				child_path.append_partial(partial.right());
+			},
+			Node::Empty | Node::Leaf(..) | Node::Branch(..) => (),
+		}
+		for (index, child) in decoded.children() {
+			let mut path = child_path.clone();
+			if let Some(index) = index {
+				path.push(index);
+			}
+			let mut source_path = prefix_nibbles.clone();
+			source_path.append(&path);
+			let (child, child_hash) = trie.get_raw_or_lookup(
+				parent_hash,
+				child,
+				source_path.as_prefix(),
+			)?;
+			if let Some(hash) = child_hash {
+				target.emplace(hash, path.as_prefix(), DBValue::from(child.data()));
+			}
+			stack.push((child, child_hash.unwrap_or(parent_hash), path, 0));
+		}
+	}
+	Ok(new_root)
+}
+
+/// Copies the nodes of the trie at `root` from `src` to `dst`, skipping the nodes
+/// already in `dst` along with the nodes under them. Each copied node is stored once in
+/// `dst`, so that reference counts match the ones of a trie committed to `dst`.
+/// Returns the number of copied nodes.
+///
+/// A node in `dst` is expected to have its whole subtree in `dst` too, which holds for
+/// databases only written to by committing tries.
+pub fn copy_trie<L, DB>(
+	src: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	dst: &mut DB,
+) -> Result<usize, TrieHash<L>, CError<L>>
+	where
+		L: TrieLayout,
+		DB: HashDB<L::Hash, DBValue>,
+{
+	let mut copied = 0;
+	let mut stack = vec![(*root, NibbleVec::new())];
+	while let Some((hash, path)) = stack.pop() {
+		if dst.contains(&hash, path.as_prefix()) {
+			continue;
+		}
+		let data = src.get(&hash, path.as_prefix()).ok_or_else(|| Box::new(
+			if path.is_empty() {
+				TrieError::InvalidStateRoot(hash)
+			} else {
+				TrieError::IncompleteDatabase(hash)
+			}
+		))?;
+		push_hashed_children::<L>(hash, &data, &path, &mut stack)?;
+		dst.emplace(hash, path.as_prefix(), data);
+		copied += 1;
+	}
+	Ok(copied)
+}
+
+/// Pushes the hash and path of the hash-referenced children of the node at `path`,
+/// looking into inline children.
+fn push_hashed_children<L: TrieLayout>(
+	hash: TrieHash<L>,
+	data: &[u8],
+	path: &NibbleVec,
+	stack: &mut Vec<(TrieHash<L>, NibbleVec)>,
+) -> Result<(), TrieHash<L>, CError<L>> {
+	let node = decode_options::<L>().decode::<L::Codec>(data)
+		.map_err(|e| Box::new(e.into_trie_error(hash)))?;
+	let mut child_path = path.clone();
+	match node {
+		Node::Extension(ref partial, _) | Node::NibbledBranch(ref partial, _, _) =>
+			child_path.append_partial(partial.right()),
+		Node::Empty | Node::Leaf(..) | Node::Branch(..) => (),
+	}
+	for (index, child) in node.children() {
+		let mut path = child_path.clone();
+		if let Some(index) = index {
+			path.push(index);
+		}
+		match child.decode::<L::Codec>() {
+			Ok(ChildHandle::Hash(child_hash)) => stack.push((child_hash, path)),
+			Ok(ChildHandle::Inline(data)) => push_hashed_children::<L>(hash, data, &path, stack)?,
+			Err(data) => return Err(Box::new(TrieError::InvalidHash(hash, data.to_vec()))),
+		}
+	}
+	Ok(())
+}
+
+/// Encodes `node` without the first `skip` nibbles of its partial key.
+fn shorten_partial<L: TrieLayout>(
+	node: &OwnedNode<DBValue>,
+	skip: usize,
+	parent_hash: TrieHash<L>,
+) -> Result<Vec<u8>, TrieHash<L>, CError<L>> {
+	let child_reference = |child: NodeHandle| ChildReference::try_from(child)
+		.map_err(|data| Box::new(TrieError::InvalidHash(parent_hash, data)));
+	let encoded = match node.node() {
+		Node::Leaf(slice, value) => {
+			let partial = slice.mid(skip);
+			L::Codec::leaf_node(partial.right(), value)
+		},
+		Node::Extension(slice, child) => {
+			let partial = slice.mid(skip);
+			L::Codec::extension_node(partial.right_iter(), partial.len(), child_reference(child)?)
+		},
+		Node::NibbledBranch(slice, children, value) => {
+			let partial = slice.mid(skip);
+			let mut references = Vec::with_capacity(children.len());
+			for child in children.iter() {
+				references.push(match child {
+					Some(child) => Some(child_reference(*child)?),
+					None => None,
+				});
+			}
+			L::Codec::branch_node_nibbled(
+				partial.right_iter(),
+				partial.len(),
+				references.iter(),
+				value,
+			)
+		},
+		Node::Empty | Node::Branch(..) =>
+			unreachable!("only nodes with a partial key overlap the prefix; qed"),
+	};
+	encoded.map_err(|e| Box::new(TrieError::EncoderError(e)))
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{
+		ExtensionLayout, NoExtensionLayout, TrieLayout, TrieDB, TrieDBMut, Trie, TrieMut,
+		copy_trie, extract_subtrie,
+	};
+	use reference_trie::DBValue;
+
+	fn check<L: TrieLayout<Hash = KeccakHasher>>(data: &[(Vec<u8>, Vec<u8>)], prefix: &[u8]) {
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+			for (k, v) in data {
+				t.insert(k, v).unwrap();
+			}
+		}
+		let mut expected_db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut expected_root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut expected_db, &mut expected_root);
+			for (k, v) in data.iter().filter(|(k, _)| k.starts_with(prefix)) {
+				t.insert(&k[prefix.len()..], v).unwrap();
+			}
+		}
+
+		let mut target = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let new_root = extract_subtrie::<L, _>(&db, &root, prefix, &mut target).unwrap();
+		assert_eq!(new_root, expected_root);
+
+		// every node is stored at its path in the new trie
+		let t = TrieDB::<L>::new(&target, &new_root).unwrap();
+		let extracted: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+		let expected: Vec<_> = TrieDB::<L>::new(&expected_db, &expected_root).unwrap()
+			.iter().unwrap().map(|item| item.unwrap()).collect();
+		assert_eq!(extracted, expected);
+		// and only the nodes of the new trie are copied
+		assert_eq!(target.keys(), expected_db.keys());
+	}
+
+	#[test]
+	fn copy_trie_skips_existing_nodes() {
+		fn build(db: &mut MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, data: &[(&[u8], &[u8])])
+			-> <KeccakHasher as hash_db::Hasher>::Out
+		{
+			let mut root = Default::default();
+			{
+				let mut t = TrieDBMut::<ExtensionLayout>::new(db, &mut root);
+				for (k, v) in data {
+					t.insert(k, v).unwrap();
+				}
+			}
+			root
+		}
+
+		let data_a: &[(&[u8], &[u8])] = &[
+			(b"alfa", &[1; 40]),
+			(b"alfabet", &[2; 40]),
+			(b"alfalfa", &[3]),
+			(b"beta", &[4; 40]),
+		];
+		let mut data_b = data_a.to_vec();
+		data_b.push((b"gamma", &[5; 40]));
+
+		let mut src = MemoryDB::default();
+		let root_a = build(&mut src, data_a);
+		let root_b = build(&mut src, &data_b);
+		let mut nodes_a = MemoryDB::default();
+		build(&mut nodes_a, data_a);
+		let mut nodes_b = MemoryDB::default();
+		build(&mut nodes_b, &data_b);
+
+		let mut dst = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		assert_eq!(copy_trie::<ExtensionLayout, _>(&src, &root_a, &mut dst).unwrap(), nodes_a.keys().len());
+		assert_eq!(dst.keys(), nodes_a.keys());
+		assert_eq!(copy_trie::<ExtensionLayout, _>(&src, &root_a, &mut dst).unwrap(), 0);
+
+		// only the nodes of `b` missing from `dst` get copied
+		let keys_a = nodes_a.keys();
+		let shared = nodes_b.keys().keys().filter(|key| keys_a.contains_key(*key)).count();
+		let copied = copy_trie::<ExtensionLayout, _>(&src, &root_b, &mut dst).unwrap();
+		assert_eq!(copied, nodes_b.keys().len() - shared);
+		assert!(dst.keys().values().all(|rc| *rc == 1));
+		let t = TrieDB::<ExtensionLayout>::new(&dst, &root_b).unwrap();
+		assert_eq!(t.iter().unwrap().count(), data_b.len());
+	}
+
+	#[test]
+	fn extract_subtrie_works() {
+		let data = vec![
+			(b"alfa".to_vec(), vec![1; 40]),
+			(b"alfabet".to_vec(), vec![2; 40]),
+			(b"alfalfa".to_vec(), vec![3]),
+			(b"alpha".to_vec(), vec![4; 40]),
+			(b"beta".to_vec(), vec![5; 40]),
+			(b"bet".to_vec(), vec![6]),
+			(b"gamma".to_vec(), vec![7; 40]),
+		];
+		for prefix in &[&b""[..], b"a", b"al", b"alf", b"alfa", b"alfab", b"b", b"bet", b"c", b"gamma"] {
+			check::<ExtensionLayout>(&data, prefix);
+			check::<NoExtensionLayout>(&data, prefix);
+		}
+	}
+}
@@ -0,0 +1,126 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversion of a trie between layouts.
+
+use hash_db::{HashDB, HashDBRef};
+use crate::iter_build::{trie_visit, TrieBuilder};
+use crate::node_codec::NodeCodec;
+use crate::rstd::{boxed::Box, result};
+use crate::triedb::TrieDB;
+use super::{DBValue, Trie, TrieError, TrieLayout, CError, TrieHash};
+
+/// Error of `transcode_trie`.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum TranscodeError<HO, CE, TCE> {
+	/// The source trie cannot be read.
+	Read(Box<TrieError<HO, CE>>),
+	/// A node cannot be encoded by the target layout, eg a key too long for its codec.
+	Encode(TCE),
+}
+
+/// Rebuilds the trie at `root` in `src`, of layout `F`, as a trie of layout `T` stored
+/// in `dst`, and returns its root. The entries are streamed from the source trie to
+/// `trie_visit`, so they are never all held in memory.
+///
+/// Keys are trie paths and are copied as is: with a source layout using `HASH_KEYS`
+/// they are key hashes, so both layouts are expected to agree on `HASH_KEYS`.
+pub fn transcode_trie<F, T, DB>(
+	src: &dyn HashDBRef<F::Hash, DBValue>,
+	root: &TrieHash<F>,
+	dst: &mut DB,
+) -> result::Result<TrieHash<T>, TranscodeError<TrieHash<F>, CError<F>, CError<T>>>
+	where
+		F: TrieLayout,
+		T: TrieLayout,
+		DB: HashDB<T::Hash, DBValue>,
+{
+	let trie = TrieDB::<F>::new(src, root).map_err(TranscodeError::Read)?;
+	let mut read_error = None;
+	let entries = trie.iter().map_err(TranscodeError::Read)?
+		.scan((), |_, entry| match entry {
+			Ok(entry) => Some(entry),
+			Err(e) => {
+				read_error = Some(e);
+				None
+			},
+		});
+	let mut builder = TrieBuilder::<T::Hash, TrieHash<T>, DBValue, DB>::new(dst);
+	trie_visit::<T, _, _, _, _>(entries, &mut builder).map_err(TranscodeError::Encode)?;
+	if let Some(e) = read_error {
+		return Err(TranscodeError::Read(e));
+	}
+	Ok(builder.root.unwrap_or_else(T::Codec::hashed_null_node))
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use hash_db::{HashDB, EMPTY_PREFIX};
+	use reference_trie::{RefTrieDBMut, RefTrieDBMutNoExt, TrieMut, ExtensionLayout,
+		NoExtensionLayout, transcode_trie, TranscodeError};
+	use reference_trie::DBValue;
+
+	#[test]
+	fn transcode_between_extension_layouts() {
+		let entries: Vec<_> = (0..100u8).map(|i| (vec![i / 7, i], vec![i; (i % 40) as usize + 1])).collect();
+
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in &entries {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let mut expected_db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut expected_root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut expected_db, &mut expected_root);
+			for (key, value) in &entries {
+				t.insert(key, value).unwrap();
+			}
+		}
+
+		let mut no_ext_db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let no_ext_root = transcode_trie::<ExtensionLayout, NoExtensionLayout, _>(
+			&db,
+			&root,
+			&mut no_ext_db,
+		).unwrap();
+		assert_eq!(no_ext_root, expected_root);
+		assert!(no_ext_db == expected_db);
+
+		let mut ext_db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let ext_root = transcode_trie::<NoExtensionLayout, ExtensionLayout, _>(
+			&no_ext_db,
+			&no_ext_root,
+			&mut ext_db,
+		).unwrap();
+		assert_eq!(ext_root, root);
+
+		HashDB::remove(&mut db, &root, EMPTY_PREFIX);
+		let result = transcode_trie::<ExtensionLayout, NoExtensionLayout, _>(
+			&db,
+			&root,
+			&mut MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default(),
+		);
+		assert!(match result {
+			Err(TranscodeError::Read(_)) => true,
+			_ => false,
+		});
+	}
+}
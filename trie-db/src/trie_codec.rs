@@ -28,7 +28,7 @@
 use hash_db::HashDB;
 use crate::{
 	CError, ChildReference, DBValue, NibbleVec, NodeCodec, Result,
-	TrieHash, TrieError, TrieDB, TrieDBNodeIterator, TrieLayout,
+	TrieHash, TrieError, TrieDB, TrieDBNodeIterator, TrieLayout, decode_options,
 	nibble_ops::NIBBLE_LENGTH, node::{Node, NodeHandle, NodeHandlePlan, NodePlan, OwnedNode},
 };
 use crate::rstd::{
@@ -97,7 +97,7 @@ impl<C: NodeCodec> EncoderStackEntry<C> {
 	/// Generates the encoding of the subtrie rooted at this entry.
 	fn encode_node(&self) -> Result<Vec<u8>, C::HashOut, C::Error> {
 		let node_data = self.node.data();
-		Ok(match self.node.node_plan() {
+		let encoded = match self.node.node_plan() {
 			NodePlan::Empty | NodePlan::Leaf { .. } => node_data.to_vec(),
 			NodePlan::Extension { partial, child: _ } => {
 				if !self.omit_children[0] {
@@ -106,13 +106,14 @@ impl<C: NodeCodec> EncoderStackEntry<C> {
 					let partial = partial.build(node_data);
 					let empty_child = ChildReference::Inline(C::HashOut::default(), 0);
 					C::extension_node(partial.right_iter(), partial.len(), empty_child)
+						.map_err(|err| Box::new(TrieError::EncoderError(err)))?
 				}
 			}
 			NodePlan::Branch { value, children } => {
 				C::branch_node(
 					Self::branch_children(node_data, &children, &self.omit_children)?.iter(),
 					value.clone().map(|range| &node_data[range])
-				)
+				).map_err(|err| Box::new(TrieError::EncoderError(err)))?
 			}
 			NodePlan::NibbledBranch { partial, value, children } => {
 				let partial = partial.build(node_data);
@@ -121,9 +122,10 @@ impl<C: NodeCodec> EncoderStackEntry<C> {
 					partial.len(),
 					Self::branch_children(node_data, &children, &self.omit_children)?.iter(),
 					value.clone().map(|range| &node_data[range])
-				)
+				).map_err(|err| Box::new(TrieError::EncoderError(err)))?
 			}
-		})
+		};
+		Ok(encoded)
 	}
 
 	/// Generate the list of child references for a branch node with certain children omitted.
@@ -347,10 +349,10 @@ impl<'a, C: NodeCodec> DecoderStackEntry<'a, C> {
 	///
 	/// Preconditions:
 	/// - if node is an extension node, then `children[0]` is Some.
-	fn encode_node(self) -> Vec<u8> {
-		match self.node {
+	fn encode_node(self) -> Result<Vec<u8>, C::HashOut, C::Error> {
+		let encoded = match self.node {
 			Node::Empty =>
-				C::empty_node().to_vec(),
+				Ok(C::empty_node().to_vec()),
 			Node::Leaf(partial, value) =>
 				C::leaf_node(partial.right(), value),
 			Node::Extension(partial, _) =>
@@ -369,7 +371,8 @@ impl<'a, C: NodeCodec> DecoderStackEntry<'a, C> {
 					self.children.iter(),
 					value,
 				),
-		}
+		};
+		encoded.map_err(|err| Box::new(TrieError::EncoderError(err)))
 	}
 }
 
@@ -390,6 +393,20 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 	where
 		L: TrieLayout,
 		DB: HashDB<L::Hash, T>,
+{
+	decode_compact_with_max_depth::<L, DB, T>(db, encoded, None)
+}
+
+/// Same as `decode_compact`, but fails with `TrieError::CompactMaxDepthExceeded` if a path of
+/// the encoded trie holds more than `max_depth` encoded nodes.
+pub fn decode_compact_with_max_depth<L, DB, T>(
+	db: &mut DB,
+	encoded: &[Vec<u8>],
+	max_depth: Option<usize>,
+) -> Result<(TrieHash<L>, usize), TrieHash<L>, CError<L>>
+	where
+		L: TrieLayout,
+		DB: HashDB<L::Hash, T>,
 {
 	// The stack of nodes through a path in the trie. Each entry is a child node of the preceding
 	// entry.
@@ -399,8 +416,8 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 	let mut prefix = NibbleVec::new();
 
 	for (i, encoded_node) in encoded.iter().enumerate() {
-		let node = L::Codec::decode(encoded_node)
-			.map_err(|err| Box::new(TrieError::DecoderError(<TrieHash<L>>::default(), err)))?;
+		let node = decode_options::<L>().decode::<L::Codec>(encoded_node)
+			.map_err(|err| Box::new(err.into_trie_error(<TrieHash<L>>::default())))?;
 
 		let children_len = match node {
 			Node::Empty | Node::Leaf(..) => 0,
@@ -416,6 +433,9 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 
 		loop {
 			if !last_entry.advance_child_index()? {
+				if max_depth.map_or(false, |max_depth| stack.len() >= max_depth) {
+					return Err(Box::new(TrieError::CompactMaxDepthExceeded(i)));
+				}
 				last_entry.push_to_prefix(&mut prefix);
 				stack.push(last_entry);
 				break;
@@ -423,7 +443,7 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 
 			// Since `advance_child_index` returned true, the preconditions for `encode_node` are
 			// satisfied.
-			let node_data = last_entry.encode_node();
+			let node_data = last_entry.encode_node()?;
 			let node_hash = db.insert(prefix.as_prefix(), node_data.as_ref());
 
 			if let Some(entry) = stack.pop() {
@@ -443,12 +463,12 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 
 #[cfg(test)]
 mod tests {
-	use crate::DBValue;
+	use reference_trie::DBValue;
 	use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
 	use reference_trie::{
 		ExtensionLayout, NoExtensionLayout,
 		Trie, TrieMut, TrieDB, TrieError, TrieDBMut, TrieLayout, Recorder,
-		encode_compact, decode_compact,
+		encode_compact, decode_compact, decode_compact_with_max_depth,
 	};
 
 	type MemoryDB<H> = memory_db::MemoryDB<H, memory_db::HashKey<H>, DBValue>;
@@ -607,4 +627,34 @@ mod tests {
 			_ => panic!("decode was unexpectedly successful"),
 		}
 	}
+
+	#[test]
+	fn trie_decoding_fails_above_max_depth() {
+		let (root, encoded, _) = test_encode_compact::<ExtensionLayout>(
+			vec![
+				(b"alfa", &[0; 32]),
+				(b"alfabet", &[1; 32]),
+			],
+			vec![
+				b"alfabet",
+			],
+		);
+
+		let mut db = MemoryDB::default();
+		match decode_compact_with_max_depth::<ExtensionLayout, _, _>(&mut db, &encoded, Some(1)) {
+			Err(err) => match *err {
+				TrieError::CompactMaxDepthExceeded(1) => {}
+				_ => panic!("got unexpected TrieError"),
+			}
+			_ => panic!("decode was unexpectedly successful"),
+		}
+
+		let mut db = MemoryDB::default();
+		let (decoded_root, _) = decode_compact_with_max_depth::<ExtensionLayout, _, _>(
+			&mut db,
+			&encoded,
+			Some(encoded.len()),
+		).unwrap();
+		assert_eq!(decoded_root, root);
+	}
 }
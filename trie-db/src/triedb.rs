@@ -12,18 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use hash_db::{HashDBRef, Prefix, EMPTY_PREFIX};
-use crate::nibble::NibbleSlice;
+use hash_db::{HashDB, HashDBRef, Hasher, Prefix, EMPTY_PREFIX};
 use crate::iterator::TrieDBNodeIterator;
-use crate::rstd::boxed::Box;
+use crate::check::{CheckReport, check_trie_to_depth};
+use crate::visitor::{TrieVisitor, visit_node};
+use crate::rstd::{self, boxed::Box, convert::TryFrom, rc::Rc, vec, vec::Vec, Cow};
+use crate::triedbmut::ChildReference;
+use hashbrown::{HashMap, HashSet};
 use super::node::{NodeHandle, Node, OwnedNode, decode_hash};
-use super::lookup::Lookup;
+use super::lookup::{Cursor, Lookup, Step};
+use super::nibble::NibbleSlice;
 use super::{Result, DBValue, Trie, TrieItem, TrieError, TrieIterator, Query,
-	TrieLayout, CError, TrieHash};
+	TrieLayout, TrieMeter, NoMeter, TrieCache, CError, TrieHash, Recorder, decode_options,
+	layout_key};
 use super::nibble::NibbleVec;
+#[cfg(feature = "tracing")]
+use crate::instrument::Hex;
 
 #[cfg(feature = "std")]
-use crate::rstd::{fmt, vec::Vec};
+use crate::rstd::fmt;
 
 /// A `Trie` implementation using a generic `HashDB` backing database, a `Hasher`
 /// implementation to generate keys and a `NodeCodec` implementation to encode/decode
@@ -55,6 +62,12 @@ where
 	root: &'db TrieHash<L>,
 	/// The number of hashes performed so far in operations on this trie.
 	hash_count: usize,
+	/// Maximum number of nodes traversed on a single path by lookups and iteration.
+	max_depth: Option<usize>,
+	/// Meter receiving the node accesses of lookups and iteration.
+	meter: &'db dyn TrieMeter,
+	/// Cache of decoded nodes and value hashes read through by lookups.
+	cache: Option<&'db dyn TrieCache<TrieHash<L>>>,
 }
 
 impl<'db, L> TrieDB<'db, L>
@@ -70,13 +83,380 @@ where
 		if !db.contains(root, EMPTY_PREFIX) {
 			Err(Box::new(TrieError::InvalidStateRoot(*root)))
 		} else {
-			Ok(TrieDB {db, root, hash_count: 0})
+			Ok(Self::new_unchecked(db, root))
 		}
 	}
 
+	/// Create a new trie with the backing database `db` and `root`, without checking
+	/// that `root` exists, eg for databases populated lazily. A missing root is then
+	/// reported by the first access as `TrieError::InvalidStateRoot`.
+	pub fn new_unchecked(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>
+	) -> Self {
+		TrieDB {db, root, hash_count: 0, max_depth: None, meter: &NoMeter, cache: None}
+	}
+
+	/// Create a new trie with the backing database `db` and `root`, checking the root node
+	/// with `verify_root`. Returns the report of the check if it found a problem.
+	pub fn new_verified(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>,
+		check_children: bool,
+	) -> rstd::result::Result<Self, CheckReport<TrieHash<L>, CError<L>>> {
+		let trie = Self::new_unchecked(db, root);
+		let report = trie.verify_root(check_children);
+		if report.is_ok() {
+			Ok(trie)
+		} else {
+			Err(report)
+		}
+	}
+
+	/// Insert the encoded nodes of a proof in `db` and create a trie over it
+	/// with the given `root`.
+	/// Proofs carry no prefix information, so nodes are inserted with an empty prefix
+	/// and `db` should not be using prefixed keys.
+	/// Returns an error if `root` is not part of the proof.
+	pub fn new_from_proof<D, I>(
+		db: &'db mut D,
+		root: &'db TrieHash<L>,
+		proof: I,
+	) -> Result<Self, TrieHash<L>, CError<L>>
+	where
+		D: HashDB<L::Hash, DBValue> + HashDBRef<L::Hash, DBValue>,
+		I: IntoIterator<Item = Vec<u8>>,
+	{
+		for node in proof {
+			db.insert(EMPTY_PREFIX, &node[..]);
+		}
+		let db: &'db D = db;
+		Self::new(db, root)
+	}
+
+	/// Limit lookups and iteration to paths of at most `max_depth` nodes, inline
+	/// nodes included. Traversing deeper fails with `TrieError::MaxDepthExceeded`.
+	pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+		self.max_depth = Some(max_depth);
+		self
+	}
+
+	/// Get the maximum depth of traversal, if any.
+	pub fn max_depth(&self) -> Option<usize> { self.max_depth }
+
+	/// Report the node accesses of lookups and iteration to `meter`.
+	pub fn with_meter(mut self, meter: &'db dyn TrieMeter) -> Self {
+		self.meter = meter;
+		self
+	}
+
+	/// Read the nodes walked by lookups and the hashes of `get_hash` through `cache`.
+	/// Nodes found in the cache are neither read from the database nor decoded again.
+	pub fn with_cache(mut self, cache: &'db dyn TrieCache<TrieHash<L>>) -> Self {
+		self.cache = Some(cache);
+		self
+	}
+
+	/// Check that the root node is stored under its hash and decodes, and with
+	/// `check_children` that so do the nodes it references, to detect a corrupted
+	/// database before traversing the trie. See `check_trie` to check the whole trie.
+	pub fn verify_root(&self, check_children: bool) -> CheckReport<TrieHash<L>, CError<L>> {
+		check_trie_to_depth::<L>(self.db, self.root, Some(if check_children { 1 } else { 0 }))
+	}
+
+	/// Iterate over the key-value pairs still readable: a missing or corrupt node yields
+	/// an error item with the path of its subtree, which is skipped.
+	/// Fails only if the root node cannot be read.
+	pub fn iter_fault_tolerant<'a>(
+		&'a self,
+	) -> Result<FaultTolerantIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Ok(FaultTolerantIterator { inner: TrieDBNodeIterator::new(self)? })
+	}
+
+	/// Traverse the trie in pre-order, calling back `visitor` on each node.
+	pub fn visit<V: TrieVisitor<L>>(&self, visitor: &mut V) -> Result<(), TrieHash<L>, CError<L>> {
+		let mut path = NibbleVec::new();
+		visit_node(self, *self.root, NodeHandle::Hash(self.root.as_ref()), &mut path, 0, visitor)
+	}
+
+	/// Iterate over the key-value pairs of the trie as `TrieEntry`, whose key and value
+	/// are only copied on demand.
+	pub fn entries<'a>(&'a self) -> Result<TrieDBEntryIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Ok(TrieDBEntryIterator { inner: TrieDBNodeIterator::new(self)? })
+	}
+
+	/// Iterate over the keys of the trie.
+	pub fn keys<'a>(
+		&'a self,
+	) -> Result<impl Iterator<Item = Result<Vec<u8>, TrieHash<L>, CError<L>>> + 'a, TrieHash<L>, CError<L>> {
+		Ok(TrieDBIterator::new(self)?.map(|item| item.map(|(key, _)| key)))
+	}
+
+	/// Iterate over the values of the trie, in the order of their keys.
+	pub fn values<'a>(
+		&'a self,
+	) -> Result<impl Iterator<Item = Result<DBValue, TrieHash<L>, CError<L>>> + 'a, TrieHash<L>, CError<L>> {
+		Ok(TrieDBIterator::new(self)?.map(|item| item.map(|(_, value)| value)))
+	}
+
+	/// Iterate over the key-value pairs whose value is longer than `len` bytes.
+	/// Values are checked in their node, so shorter values are never copied.
+	pub fn iter_values_longer_than<'a>(
+		&'a self,
+		len: usize,
+	) -> Result<LargeValueIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Ok(LargeValueIterator { inner: TrieDBNodeIterator::new(self)?, len })
+	}
+
+	/// Get the `n`-th key of the trie in key order, starting from 0, or `None` if the
+	/// trie holds `n` keys or fewer. This walks the first `n + 1` keys.
+	pub fn nth_key(&self, n: usize) -> Result<Option<Vec<u8>>, TrieHash<L>, CError<L>> {
+		match TrieDBIterator::new(self)?.nth(n) {
+			Some(item) => item.map(|(key, _)| Some(key)),
+			None => Ok(None),
+		}
+	}
+
+	/// Sample `k` distinct keys uniformly at random, or all the keys if the trie holds
+	/// `k` keys or fewer. The keys are in no particular order.
+	/// `rng(n)` must return a uniformly random number in `0..n`.
+	/// This walks the whole trie (reservoir sampling).
+	pub fn sample_keys<R>(&self, mut rng: R, k: usize) -> Result<Vec<Vec<u8>>, TrieHash<L>, CError<L>>
+		where R: FnMut(usize) -> usize,
+	{
+		let mut sample = Vec::with_capacity(k);
+		for (i, item) in TrieDBIterator::new(self)?.enumerate() {
+			let (key, _) = item?;
+			if i < k {
+				sample.push(key);
+			} else {
+				let j = rng(i + 1);
+				if j < k {
+					sample[j] = key;
+				}
+			}
+		}
+		Ok(sample)
+	}
+
 	/// Get the backing database.
 	pub fn db(&'db self) -> &'db dyn HashDBRef<L::Hash, DBValue> { self.db }
 
+	/// Get the value at `key`, borrowing it from the database node when the database lends
+	/// its values (see `HashDBRef::lend`) instead of copying it.
+	pub fn get_ref(&self, key: &[u8]) -> Result<Option<Cow<'db, [u8]>>, TrieHash<L>, CError<L>> {
+		Lookup::<L, _>::new(self.db, |_: &[u8]| (), *self.root)
+			.with_max_depth(self.max_depth)
+			.with_meter(self.meter)
+			.look_up_key_ref(key)
+	}
+
+	/// Call `sink` once with the value at `key`, as a single slice borrowed from the node
+	/// holding it, without copying it. Returns whether the key was found.
+	pub fn get_streamed(
+		&self,
+		key: &[u8],
+		sink: impl FnMut(&[u8]),
+	) -> Result<bool, TrieHash<L>, CError<L>> {
+		Lookup::<L, _>::new(self.db, |_: &[u8]| (), *self.root)
+			.with_max_depth(self.max_depth)
+			.with_meter(self.meter)
+			.get_streamed(key, sink)
+	}
+
+	/// Get the value at a key given in nibbles, which may end in the middle of a byte.
+	/// The key is used as is, even if the layout uses `HASH_KEYS`.
+	pub fn get_nibbles(&self, key: &NibbleVec) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		Lookup::<L, _>::new(self.db, |value: &[u8]| DBValue::from(value), *self.root)
+			.with_max_depth(self.max_depth)
+			.with_meter(self.meter)
+			.with_cache(self.cache)
+			.look_up(NibbleSlice::new_len(key.inner(), key.len()))
+	}
+
+	/// Look up the values of `keys`, reading the nodes along all the lookup paths with one
+	/// `HashDBRef::get_batch` call per trie level instead of one read per node.
+	pub fn prefetch(&self, keys: &[&[u8]]) -> Result<Vec<Option<DBValue>>, TrieHash<L>, CError<L>> {
+		let keys: Vec<_> = keys.iter().map(|key| layout_key::<L>(key)).collect();
+		let mut lookup = Lookup::<L, _>::new(self.db, |_: &[u8]| (), *self.root)
+			.with_max_depth(self.max_depth)
+			.with_meter(self.meter);
+		let mut values = vec![None; keys.len()];
+		// lookups in progress, with the hash of the next node to walk down.
+		let mut pending: Vec<_> = keys.iter()
+			.enumerate()
+			.map(|(i, key)| (i, Cursor::new(NibbleSlice::new(key)), *self.root))
+			.collect();
+		let mut depth = 0;
+		while !pending.is_empty() {
+			let partials: Vec<_> = pending.iter()
+				.map(|(i, cursor, _)| NibbleSlice::new(&keys[*i]).mid(cursor.key_nibbles))
+				.collect();
+			let mut requests = Vec::new();
+			let mut request_index = HashMap::new();
+			let indices: Vec<_> = pending.iter()
+				.zip(partials.iter())
+				.map(|((_, _, hash), partial)| *request_index.entry(*hash).or_insert_with(|| {
+					requests.push((*hash, partial.left()));
+					requests.len() - 1
+				}))
+				.collect();
+			let mut nodes = vec![None; requests.len()];
+			self.db.get_batch(&requests, &mut nodes);
+			for node in nodes.iter() {
+				match node {
+					Some(node) => self.meter.node_read(node.len()),
+					None => self.meter.db_miss(),
+				}
+			}
+
+			let mut next = Vec::with_capacity(pending.len());
+			for ((i, mut cursor, hash), index) in pending.into_iter().zip(indices) {
+				let node = nodes[index].as_ref().ok_or_else(|| Box::new(match depth {
+					0 => TrieError::InvalidStateRoot(hash),
+					_ => TrieError::IncompleteDatabase(hash),
+				}))?;
+				match lookup.walk(&hash, node, &mut cursor)? {
+					Step::Found(value) => values[i] = value.map(DBValue::from),
+					Step::Descend(child) => next.push((i, cursor, child)),
+				}
+			}
+			pending = next;
+			depth += 1;
+		}
+		Ok(values)
+	}
+
+	/// Get the hash of the value at `key`, from the cache set with `with_cache` when it
+	/// holds it. The hash is otherwise computed from the value and added to the cache.
+	pub fn get_hash(&self, key: &[u8]) -> Result<Option<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		if let Some(hash) = self.cache.and_then(|cache| cache.value_hash(self.root, key)) {
+			return Ok(hash);
+		}
+		let hash = self.get_with(key, |value: &[u8]| L::Hash::hash(value))?;
+		if let Some(cache) = self.cache {
+			cache.insert_value_hash(*self.root, key, hash);
+		}
+		Ok(hash)
+	}
+
+	/// Get the length of the value at `key`. The value is read in place from its node,
+	/// without being copied.
+	pub fn value_length(&self, key: &[u8]) -> Result<Option<usize>, TrieHash<L>, CError<L>> {
+		self.get_with(key, |value: &[u8]| value.len())
+	}
+
+	/// Get the value at `key` together with the encoded nodes traversed by the lookup.
+	/// The nodes are deduplicated and are sufficient to check the value (or its absence)
+	/// against the trie root.
+	pub fn get_with_proof(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<DBValue>, Vec<Vec<u8>>), TrieHash<L>, CError<L>> {
+		let mut recorder = Recorder::new();
+		let value = self.get_with(key, &mut recorder)?;
+		let mut seen = HashSet::new();
+		let proof = recorder.drain()
+			.into_iter()
+			.filter(|record| seen.insert(record.hash))
+			.map(|record| record.data)
+			.collect();
+		Ok((value, proof))
+	}
+
+	/// Get a reference to the node rooting all the keys starting with `prefix`, or `None`
+	/// if the trie has no such key. The reference is the node hash, or the node encoding
+	/// if the node is inlined in its parent.
+	/// `prefix` is a path in the trie: it is not hashed, even if the layout uses `HASH_KEYS`.
+	pub fn subtree_root(
+		&self,
+		prefix: &[u8],
+	) -> Result<Option<ChildReference<TrieHash<L>>>, TrieHash<L>, CError<L>> {
+		Ok(self.subtree_node(prefix)?.map(|(_, reference, _)| reference))
+	}
+
+	/// Get the node rooting all the keys starting with `prefix`, with its reference
+	/// and the number of nibbles of the path leading to it.
+	pub(crate) fn subtree_node(
+		&self,
+		prefix: &[u8],
+	) -> Result<Option<(OwnedNode<DBValue>, ChildReference<TrieHash<L>>, usize)>, TrieHash<L>, CError<L>> {
+		let key = NibbleSlice::new(prefix);
+		let mut parent_hash = *self.root;
+		let mut reference = ChildReference::Hash(*self.root);
+		let (mut node, _) = self.get_raw_or_lookup(
+			parent_hash,
+			NodeHandle::Hash(self.root.as_ref()),
+			EMPTY_PREFIX,
+		)?;
+		let mut key_nibbles = 0;
+		let mut nodes_traversed = 1;
+		loop {
+			if self.max_depth.map_or(false, |max_depth| nodes_traversed > max_depth) {
+				return Err(Box::new(TrieError::MaxDepthExceeded(parent_hash)));
+			}
+			let partial = key.mid(key_nibbles);
+			let child = match node.node() {
+				Node::Empty => return Ok(None),
+				Node::Leaf(slice, _) => match slice.starts_with(&partial) {
+					true => break,
+					false => return Ok(None),
+				},
+				Node::Extension(slice, child) => {
+					if slice.starts_with(&partial) {
+						break;
+					}
+					if !partial.starts_with(&slice) {
+						return Ok(None);
+					}
+					key_nibbles += slice.len();
+					child
+				},
+				Node::Branch(children, _) => {
+					if partial.is_empty() {
+						break;
+					}
+					match children[partial.at(0) as usize] {
+						Some(child) => {
+							key_nibbles += 1;
+							child
+						},
+						None => return Ok(None),
+					}
+				},
+				Node::NibbledBranch(slice, children, _) => {
+					if slice.starts_with(&partial) {
+						break;
+					}
+					if !partial.starts_with(&slice) {
+						return Ok(None);
+					}
+					match children[partial.at(slice.len()) as usize] {
+						Some(child) => {
+							key_nibbles += slice.len() + 1;
+							child
+						},
+						None => return Ok(None),
+					}
+				},
+			};
+
+			reference = ChildReference::try_from(child)
+				.map_err(|data| Box::new(TrieError::InvalidHash(parent_hash, data)))?;
+			let (child_node, child_hash) = self.get_raw_or_lookup(
+				parent_hash,
+				child,
+				key.mid(key_nibbles).left(),
+			)?;
+			if let Some(hash) = child_hash {
+				parent_hash = hash;
+			}
+			node = child_node;
+			nodes_traversed += 1;
+		}
+		Ok(Some((node, reference, key_nibbles)))
+	}
+
 	/// Given some node-describing data `node`, and node key return the actual node RLP.
 	/// This could be a simple identity operation in the case that the node is sufficiently small,
 	/// but may require a database lookup.
@@ -98,6 +478,7 @@ where
 				let node_data = self.db
 					.get(&node_hash, partial_key)
 					.ok_or_else(|| {
+						self.meter.db_miss();
 						if partial_key == EMPTY_PREFIX {
 							Box::new(TrieError::InvalidStateRoot(node_hash))
 						} else {
@@ -107,10 +488,21 @@ where
 
 				(Some(node_hash), node_data)
 			}
-			NodeHandle::Inline(data) => (None, data.to_vec()),
+			NodeHandle::Inline(data) => (None, DBValue::from(data)),
 		};
-		let owned_node = OwnedNode::new::<L::Codec>(node_data)
-			.map_err(|e| Box::new(TrieError::DecoderError(node_hash.unwrap_or(parent_hash), e)))?;
+		if node_hash.is_some() {
+			self.meter.node_read(node_data.len());
+		}
+		self.meter.node_decoded(node_data.len());
+		trie_event!(
+			hash = %Hex(node_hash.unwrap_or(parent_hash).as_ref()),
+			inline = node_hash.is_none(),
+			nibbles = partial_key.0.len() * 2 + partial_key.1.is_some() as usize,
+			len = node_data.len(),
+			"node decoded"
+		);
+		let owned_node = OwnedNode::new_checked::<L::Codec>(node_data, decode_options::<L>())
+			.map_err(|e| Box::new(e.into_trie_error(node_hash.unwrap_or(parent_hash))))?;
 		Ok((owned_node, node_hash))
 	}
 }
@@ -128,11 +520,11 @@ where
 	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
 		where 'a: 'key,
 	{
-		Lookup::<L, Q> {
-			db: self.db,
-			query: query,
-			hash: self.root.clone(),
-		}.look_up(NibbleSlice::new(key))
+		Lookup::<L, Q>::new(self.db, query, *self.root)
+			.with_max_depth(self.max_depth)
+			.with_meter(self.meter)
+			.with_cache(self.cache)
+			.look_up_key(key)
 	}
 
 	fn iter<'a>(&'a self)-> Result<
@@ -142,6 +534,25 @@ where
 	> {
 		TrieDBIterator::new(self).map(|iter| Box::new(iter) as Box<_>)
 	}
+
+	fn len(&self) -> Result<usize, TrieHash<L>, CError<L>> {
+		if let Some(count) = L::value_count(self.root) {
+			return Ok(count);
+		}
+		let mut len = 0;
+		for item in TrieDBNodeIterator::new(self)? {
+			let (_, _, node) = item?;
+			let has_value = match node.node() {
+				Node::Leaf(..) => true,
+				Node::Branch(_, value) | Node::NibbledBranch(_, _, value) => value.is_some(),
+				Node::Empty | Node::Extension(..) => false,
+			};
+			if has_value {
+				len += 1;
+			}
+		}
+		Ok(len)
+	}
 }
 
 
@@ -261,6 +672,20 @@ where
 	}
 }
 
+impl<'a, 'db, L: TrieLayout> IntoIterator for &'a TrieDB<'db, L> {
+	type Item = TrieItem<'a, TrieHash<L>, CError<L>>;
+	type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+	/// Iterate over the key-value pairs of the trie. Failing to read the root node
+	/// yields a single error.
+	fn into_iter(self) -> Self::IntoIter {
+		match TrieDBIterator::new(self) {
+			Ok(iter) => Box::new(iter),
+			Err(err) => Box::new(rstd::iter::once(Err(err))),
+		}
+	}
+}
+
 /// Iterator for going through all values in the trie in pre-order traversal order.
 pub struct TrieDBIterator<'a, L: TrieLayout> {
 	inner: TrieDBNodeIterator<'a, L>,
@@ -298,28 +723,35 @@ impl<'a, L: TrieLayout> Iterator for TrieDBIterator<'a, L> {
 	fn next(&mut self) -> Option<Self::Item> {
 		while let Some(item) = self.inner.next() {
 			match item {
-				Ok((mut prefix, _, node)) => {
-					let maybe_value = match node.node() {
-						Node::Leaf(partial, value) => {
-							prefix.append_partial(partial.right());
-							Some(value)
-						}
-						Node::Branch(_, value) => value,
-						Node::NibbledBranch(partial, _, value) => {
-							prefix.append_partial(partial.right());
-							value
-						}
-						_ => None,
-					};
-					if let Some(value) = maybe_value {
-						let (key_slice, maybe_extra_nibble) = prefix.as_prefix();
-						let key = key_slice.to_vec();
-						if let Some(extra_nibble) = maybe_extra_nibble {
-							return Some(Err(Box::new(
-								TrieError::ValueAtIncompleteKey(key, extra_nibble)
-							)));
-						}
-						return Some(Ok((key, value.to_vec())));
+				Ok((prefix, _, node)) => if let Some(item) = node_item::<L>(prefix, &node) {
+					return Some(item);
+				},
+				Err(err) => return Some(Err(err)),
+			}
+		}
+		None
+	}
+}
+
+/// Iterator over the key-value pairs of a trie with values longer than a given length,
+/// see `TrieDB::iter_values_longer_than`.
+pub struct LargeValueIterator<'a, L: TrieLayout> {
+	inner: TrieDBNodeIterator<'a, L>,
+	len: usize,
+}
+
+impl<'a, L: TrieLayout> Iterator for LargeValueIterator<'a, L> {
+	type Item = TrieItem<'a, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while let Some(item) = self.inner.next() {
+			match item {
+				Ok((prefix, _, node)) => {
+					if node_value_len(&node).map_or(true, |len| len <= self.len) {
+						continue;
+					}
+					if let Some(item) = node_item::<L>(prefix, &node) {
+						return Some(item);
 					}
 				},
 				Err(err) => return Some(Err(err)),
@@ -329,13 +761,142 @@ impl<'a, L: TrieLayout> Iterator for TrieDBIterator<'a, L> {
 	}
 }
 
+/// Key-value pair of a trie, see `TrieDB::entries`. The key is kept as nibbles and the
+/// value in its node, their bytes being only copied on demand.
+pub struct TrieEntry {
+	key: NibbleVec,
+	node: Rc<OwnedNode<DBValue>>,
+}
+
+impl TrieEntry {
+	/// Key of the entry, as nibbles.
+	pub fn key_nibbles(&self) -> &NibbleVec { &self.key }
+
+	/// Key of the entry.
+	pub fn key_bytes(&self) -> Vec<u8> { self.key.as_prefix().0.to_vec() }
+
+	/// Value of the entry.
+	pub fn value(&self) -> &[u8] {
+		match self.node.node() {
+			Node::Leaf(_, value)
+				| Node::Branch(_, Some(value))
+				| Node::NibbledBranch(_, _, Some(value)) => value,
+			_ => unreachable!("Entries are only built for nodes with a value; qed"),
+		}
+	}
+}
+
+/// Iterator over the key-value pairs of a trie as `TrieEntry`, see `TrieDB::entries`.
+pub struct TrieDBEntryIterator<'a, L: TrieLayout> {
+	inner: TrieDBNodeIterator<'a, L>,
+}
+
+impl<'a, L: TrieLayout> TrieIterator<L> for TrieDBEntryIterator<'a, L> {
+	/// Position the iterator on the first element with key >= `key`
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		TrieIterator::seek(&mut self.inner, key)
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for TrieDBEntryIterator<'a, L> {
+	type Item = Result<TrieEntry, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while let Some(item) = self.inner.next() {
+			let (mut key, _, node) = match item {
+				Ok(item) => item,
+				Err(err) => return Some(Err(err)),
+			};
+			match node.node() {
+				Node::Leaf(partial, _) | Node::NibbledBranch(partial, _, Some(_)) =>
+					key.append_partial(partial.right()),
+				Node::Branch(_, Some(_)) => (),
+				_ => continue,
+			}
+			if let (key_slice, Some(extra_nibble)) = key.as_prefix() {
+				let key = key_slice.to_vec();
+				return Some(Err(Box::new(TrieError::ValueAtIncompleteKey(key, extra_nibble))));
+			}
+			return Some(Ok(TrieEntry { key, node }));
+		}
+		None
+	}
+}
+
+/// Get the length of the value held by a node, if any.
+fn node_value_len(node: &OwnedNode<DBValue>) -> Option<usize> {
+	match node.node() {
+		Node::Leaf(_, value) => Some(value.len()),
+		Node::Branch(_, value) | Node::NibbledBranch(_, _, value) => value.map(|value| value.len()),
+		_ => None,
+	}
+}
+
+/// Get the key-value pair held by a node at path `prefix`, if any.
+fn node_item<'a, L: TrieLayout>(
+	mut prefix: NibbleVec,
+	node: &OwnedNode<DBValue>,
+) -> Option<TrieItem<'a, TrieHash<L>, CError<L>>> {
+	let value = match node.node() {
+		Node::Leaf(partial, value) => {
+			prefix.append_partial(partial.right());
+			value
+		}
+		Node::Branch(_, value) => value?,
+		Node::NibbledBranch(partial, _, value) => {
+			prefix.append_partial(partial.right());
+			value?
+		}
+		_ => return None,
+	};
+	let (key_slice, maybe_extra_nibble) = prefix.as_prefix();
+	let key = key_slice.to_vec();
+	if let Some(extra_nibble) = maybe_extra_nibble {
+		return Some(Err(Box::new(TrieError::ValueAtIncompleteKey(key, extra_nibble))));
+	}
+	Some(Ok((key, DBValue::from(value))))
+}
+
+/// Iterator over the key-value pairs of a trie that goes on after failing to read a node,
+/// see `TrieDB::iter_fault_tolerant`.
+pub struct FaultTolerantIterator<'a, L: TrieLayout> {
+	inner: TrieDBNodeIterator<'a, L>,
+}
+
+impl<'a, L: TrieLayout> Iterator for FaultTolerantIterator<'a, L> {
+	/// A key-value pair, or the path of a subtree that could not be read along with the
+	/// error. The iteration continues with the next sibling of the subtree.
+	type Item = rstd::result::Result<
+		(Vec<u8>, DBValue),
+		(NibbleVec, Box<TrieError<TrieHash<L>, CError<L>>>),
+	>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while let Some(item) = self.inner.next() {
+			match item {
+				Ok((prefix, _, node)) => match node_item::<L>(prefix.clone(), &node) {
+					Some(Ok(item)) => return Some(Ok(item)),
+					Some(Err(err)) => return Some(Err((prefix, err))),
+					None => (),
+				},
+				Err(err) => return Some(Err((self.inner.key_nibbles().clone(), err))),
+			}
+		}
+		None
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use memory_db::{MemoryDB, PrefixedKey};
 	use keccak_hasher::KeccakHasher;
-	use crate::DBValue;
+	use reference_trie::DBValue;
 	use reference_trie::{RefTrieDB, RefTrieDBMut, RefLookup, Trie, TrieMut, NibbleSlice};
-	use reference_trie::{RefTrieDBNoExt, RefTrieDBMutNoExt};
+	use reference_trie::{RefTrieDBNoExt, RefTrieDBMutNoExt, RefSecTrieDBMut};
+	use reference_trie::{HashedKeyExtensionLayout, TrieConfiguration, TrieDB, TrieDBMut, TrieError};
+	use reference_trie::ChildReference;
+	use hash_db::Hasher;
+	use memory_db::HashKey;
 	use hex_literal::hex;
 
 	#[test]
@@ -417,7 +978,7 @@ mod tests {
 			iter.next().unwrap().unwrap(),
 			(
 				hex!("0103000000000000000464").to_vec(),
-				hex!("fffffffffe").to_vec(),
+				hex!("fffffffffe").to_vec().into(),
 			)
 		);
 		iter.seek(&hex!("00")[..]).unwrap();
@@ -458,7 +1019,7 @@ mod tests {
 		let mut iter = t.iter().unwrap();
 		assert_eq!(
 			iter.next().unwrap().unwrap(),
-			(hex!("0103000000000000000464").to_vec(), hex!("fffffffffe").to_vec())
+			(hex!("0103000000000000000464").to_vec(), hex!("fffffffffe").to_vec().into())
 		);
 		iter.seek(&hex!("00")[..]).unwrap();
 		assert_eq!(
@@ -550,7 +1111,7 @@ mod tests {
 
 		let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
 		let mut iter = t.iter().unwrap();
-		assert_eq!(iter.next().unwrap().unwrap(), (b"A".to_vec(), b"A".to_vec()));
+		assert_eq!(iter.next().unwrap().unwrap(), (b"A".to_vec(), b"A".to_vec().into()));
 		iter.seek(b"!").unwrap();
 		assert_eq!(d, iter.map(|x| x.unwrap().1).collect::<Vec<_>>());
 		let mut iter = t.iter().unwrap();
@@ -608,6 +1169,550 @@ mod tests {
 		assert_eq!(t.get_with(b"C", |x: &[u8]| x.len()).unwrap(), None);
 	}
 
+	#[test]
+	fn query_observes_decoded_nodes() {
+		use hash_db::HashDB;
+		use reference_trie::Query;
+
+		#[derive(Default)]
+		struct NodeLog {
+			stored: Vec<u32>,
+			decoded: Vec<(u32, Vec<u8>)>,
+		}
+
+		impl<'a> Query<KeccakHasher> for &'a mut NodeLog {
+			type Item = usize;
+			fn decode(self, value: &[u8]) -> usize { value.len() }
+			fn record(&mut self, _hash: &<KeccakHasher as Hasher>::Out, _data: &[u8], depth: u32) {
+				self.stored.push(depth);
+			}
+			fn record_node(&mut self, depth: u32, encoded: &[u8]) {
+				self.decoded.push((depth, encoded.to_vec()));
+			}
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", b"ABC").unwrap();
+			t.insert(b"B", &[7; 32]).unwrap();
+		}
+
+		// extension at the root, then a stored branch holding the inline leaf of `A`.
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut log = NodeLog::default();
+		assert_eq!(t.get_with(b"A", &mut log).unwrap(), Some(3));
+		assert_eq!(log.stored, vec![0, 1]);
+		assert_eq!(log.decoded.iter().map(|(depth, _)| *depth).collect::<Vec<_>>(), vec![0, 1, 2]);
+		assert_eq!(log.decoded[0].1, memdb.get(&root, hash_db::EMPTY_PREFIX).unwrap());
+		assert!(log.decoded[2].1.len() < KeccakHasher::LENGTH);
+	}
+
+	#[test]
+	fn hashed_keys_layout_matches_sec_trie() {
+		let pairs = vec![
+			(b"A".to_vec(), b"ABC".to_vec()),
+			(b"B".to_vec(), b"ABCBAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_vec()),
+		];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<HashedKeyExtensionLayout>::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+
+		let mut sec_memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut sec_root = Default::default();
+		{
+			let mut t = RefSecTrieDBMut::new(&mut sec_memdb, &mut sec_root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+		assert_eq!(root, sec_root);
+		assert_eq!(HashedKeyExtensionLayout::trie_root(pairs.clone()).unwrap(), root);
+
+		let t = TrieDB::<HashedKeyExtensionLayout>::new(&memdb, &root).unwrap();
+		assert_eq!(t.get(b"A").unwrap(), Some(b"ABC".to_vec().into()));
+		assert_eq!(t.get(b"C").unwrap(), None);
+
+		let mut expected: Vec<_> = pairs.iter()
+			.map(|(k, v)| (KeccakHasher::hash(k).to_vec(), DBValue::from(v.clone())))
+			.collect();
+		expected.sort();
+		assert_eq!(t.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(), expected);
+	}
+
+	#[test]
+	fn get_ref_borrows_lent_values() {
+		use std::borrow::Cow;
+		use hash_db::HashDB;
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", &[1; 40]).unwrap();
+			t.insert(b"AB", b"short").unwrap();
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		match t.get_ref(b"A").unwrap() {
+			Some(Cow::Borrowed(value)) => assert_eq!(value, &[1; 40][..]),
+			other => panic!("unexpected {:?}", other),
+		}
+		assert_eq!(t.get_ref(b"AB").unwrap().as_deref(), Some(&b"short"[..]));
+		assert_eq!(t.get_ref(b"B").unwrap(), None);
+
+		// databases not lending their values get the value copied
+		let db: &dyn HashDB<KeccakHasher, DBValue> = &memdb;
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		match t.get_ref(b"A").unwrap() {
+			Some(Cow::Owned(value)) => assert_eq!(value, vec![1; 40]),
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn fault_tolerant_iterator_skips_unreadable_subtrees() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let pairs: Vec<_> = (0..60u8).map(|i| (vec![i, i / 4], vec![i; 40])).collect();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (key, value) in pairs.iter() {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let mut nodes: Vec<_> = memdb.keys().into_iter()
+			.map(|(key, _)| key)
+			.filter(|key| key != &root)
+			.collect();
+		nodes.sort();
+		HashDB::remove(&mut memdb, &nodes[0], EMPTY_PREFIX);
+		HashDB::remove(&mut memdb, &nodes[nodes.len() / 2], EMPTY_PREFIX);
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut found = Vec::new();
+		let mut unreadable = Vec::new();
+		for item in t.iter_fault_tolerant().unwrap() {
+			match item {
+				Ok((key, value)) => found.push((key, value.to_vec())),
+				Err((path, _)) => unreadable.push(path),
+			}
+		}
+		assert!(!unreadable.is_empty());
+		assert!(!found.is_empty());
+		// every pair is either found or under an unreadable subtree.
+		for pair in pairs.iter() {
+			let key = NibbleSlice::new(&pair.0);
+			let skipped = unreadable.iter()
+				.any(|path| (0..path.len()).all(|i| key.at(i) == path.at(i)));
+			assert_ne!(found.contains(pair), skipped);
+		}
+	}
+
+	#[test]
+	fn len_counts_values() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let t = RefTrieDBMut::new(&mut memdb, &mut root);
+			assert!(t.is_empty());
+		}
+		assert_eq!(RefTrieDB::new(&memdb, &root).unwrap().len().unwrap(), 0);
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			// values at branches and at inline leaves are counted too.
+			for key in [&b"do"[..], b"dog", b"doge", b"horse", b"house"].iter() {
+				t.insert(key, b"v").unwrap();
+			}
+		}
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		assert_eq!(t.len().unwrap(), 5);
+		assert_eq!(t.iter().unwrap().count(), 5);
+		assert!(!t.is_empty());
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefSecTrieDBMut::new(&mut memdb, &mut root);
+			for i in 0..10u8 {
+				t.insert(&[i], &[i; 40]).unwrap();
+			}
+		}
+		assert_eq!(reference_trie::RefSecTrieDB::new(&memdb, &root).unwrap().len().unwrap(), 10);
+	}
+
+	#[test]
+	fn nth_key_and_sample_keys() {
+		use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let keys: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i, i / 7]).collect();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for key in keys.iter() {
+				t.insert(key, &[7; 40]).unwrap();
+			}
+		}
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		assert_eq!(t.nth_key(0).unwrap(), Some(keys[0].clone()));
+		assert_eq!(t.nth_key(42).unwrap(), Some(keys[42].clone()));
+		assert_eq!(t.nth_key(100).unwrap(), None);
+
+		let mut rng = SmallRng::seed_from_u64(7);
+		let mut sample = t.sample_keys(|n| rng.gen_range(0, n), 10).unwrap();
+		assert_eq!(sample.len(), 10);
+		sample.sort();
+		sample.dedup();
+		assert_eq!(sample.len(), 10);
+		assert!(sample.iter().all(|key| keys.contains(key)));
+
+		let mut all = t.sample_keys(|n| rng.gen_range(0, n), 200).unwrap();
+		all.sort();
+		assert_eq!(all, keys);
+	}
+
+	#[test]
+	fn meter_counts_node_accesses() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+		use reference_trie::{CountingMeter, TrieError};
+
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for i in 0..20u8 {
+				t.insert(&[i, 1], &[i; 40]).unwrap();
+			}
+		}
+
+		let meter = CountingMeter::default();
+		{
+			let t = RefTrieDB::new(&memdb, &root).unwrap().with_meter(&meter);
+			assert_eq!(t.get(&[3, 1]).unwrap(), Some(vec![3; 40].into()));
+		}
+		assert!(meter.nodes_read() >= 2);
+		assert!(meter.bytes_decoded() >= meter.bytes_read());
+		assert_eq!(meter.db_misses(), 0);
+
+		let child = memdb.keys().into_iter()
+			.map(|(key, _)| key)
+			.find(|key| key != &root)
+			.expect("the trie has more than one node; qed");
+		HashDB::remove(&mut memdb, &child, EMPTY_PREFIX);
+		let meter = CountingMeter::default();
+		let t = RefTrieDB::new(&memdb, &root).unwrap().with_meter(&meter);
+		let incomplete = (0..20u8).filter(|i| match t.get(&[*i, 1]) {
+			Err(e) => match *e {
+				TrieError::IncompleteDatabase(_) => true,
+				_ => false,
+			},
+			Ok(_) => false,
+		}).count();
+		assert!(incomplete > 0);
+		assert_eq!(meter.db_misses(), incomplete);
+	}
+
+	#[test]
+	fn prefetch_batches_reads() {
+		use std::cell::Cell;
+		use hash_db::{HashDBRef, Prefix};
+
+		struct BatchCounter<'a> {
+			db: &'a MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>,
+			batches: Cell<usize>,
+		}
+
+		impl<'a> HashDBRef<KeccakHasher, DBValue> for BatchCounter<'a> {
+			fn get(&self, key: &[u8; 32], prefix: Prefix) -> Option<DBValue> {
+				HashDBRef::get(self.db, key, prefix)
+			}
+			fn contains(&self, key: &[u8; 32], prefix: Prefix) -> bool {
+				HashDBRef::contains(self.db, key, prefix)
+			}
+			fn get_batch(&self, keys: &[([u8; 32], Prefix)], values: &mut [Option<DBValue>]) {
+				self.batches.set(self.batches.get() + 1);
+				for ((key, prefix), value) in keys.iter().zip(values.iter_mut()) {
+					*value = self.get(key, *prefix);
+				}
+			}
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let keys: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i, i.wrapping_mul(7), 1]).collect();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for key in keys.iter() {
+				t.insert(key, &[key[0]; 40]).unwrap();
+			}
+		}
+
+		let db = BatchCounter { db: &memdb, batches: Cell::new(0) };
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		let mut lookups: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+		lookups.push(b"missing");
+		let values = t.prefetch(&lookups).unwrap();
+		assert_eq!(values.len(), lookups.len());
+		for (key, value) in lookups.iter().zip(values) {
+			assert_eq!(value, t.get(key).unwrap());
+		}
+		assert!(db.batches.get() <= 4);
+	}
+
+	#[test]
+	fn get_with_proof_works() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", b"ABC").unwrap();
+			t.insert(b"B", b"ABCBAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		for key in &[&b"A"[..], &b"B"[..], &b"C"[..]] {
+			let (value, proof) = t.get_with_proof(key).unwrap();
+			assert_eq!(value, t.get(key).unwrap());
+
+			let proof_db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::from_proof(proof.clone());
+			let proof_trie = RefTrieDB::new(&proof_db, &root).unwrap();
+			assert_eq!(proof_trie.get(key).unwrap(), value);
+
+			let mut proof_db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+			let proof_trie = RefTrieDB::new_from_proof(&mut proof_db, &root, proof).unwrap();
+			assert_eq!(proof_trie.get(key).unwrap(), value);
+		}
+	}
+
+	#[test]
+	fn value_length_works() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", b"ABC").unwrap();
+			t.insert(b"B", &[0x42; 100]).unwrap();
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		assert_eq!(t.value_length(b"A").unwrap(), Some(3));
+		assert_eq!(t.value_length(b"B").unwrap(), Some(100));
+		assert_eq!(t.value_length(b"C").unwrap(), None);
+	}
+
+	#[test]
+	fn iter_values_longer_than_skips_short_values() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", &[1; 8]).unwrap();
+			t.insert(b"AB", &[2; 40]).unwrap();
+			t.insert(b"B", &[3; 41]).unwrap();
+			t.insert(b"C", &[4; 2]).unwrap();
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let large: Vec<_> = t.iter_values_longer_than(8).unwrap().map(Result::unwrap).collect();
+		assert_eq!(large, vec![(b"AB".to_vec(), vec![2; 40].into()), (b"B".to_vec(), vec![3; 41].into())]);
+		assert_eq!(t.iter_values_longer_than(40).unwrap().count(), 1);
+	}
+
+	#[test]
+	fn keys_values_and_for_loops() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", b"a").unwrap();
+			t.insert(b"AB", b"ab").unwrap();
+			t.insert(b"B", b"b").unwrap();
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let keys: Vec<_> = t.keys().unwrap().map(Result::unwrap).collect();
+		assert_eq!(keys, vec![b"A".to_vec(), b"AB".to_vec(), b"B".to_vec()]);
+		let values: Vec<_> = t.values().unwrap().map(Result::unwrap).collect();
+		assert_eq!(values, vec![b"a".to_vec(), b"ab".to_vec(), b"b".to_vec()]);
+
+		let mut count = 0;
+		for item in &t {
+			let (key, value) = item.unwrap();
+			assert_eq!(key.to_ascii_lowercase(), value);
+			count += 1;
+		}
+		assert_eq!(count, 3);
+
+		let missing_root = [1; 32];
+		let t = RefTrieDB::new_unchecked(&memdb, &missing_root);
+		let items: Vec<_> = (&t).into_iter().collect();
+		assert_eq!(items.len(), 1);
+		assert!(items[0].is_err());
+	}
+
+	#[test]
+	fn entries_copy_on_demand() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(&[0x12], b"a").unwrap();
+			t.insert(&[0x12, 0x34], b"ab").unwrap();
+			t.insert(&[0x56], b"b").unwrap();
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let entries: Vec<_> = t.entries().unwrap().map(Result::unwrap).collect();
+		assert_eq!(
+			entries.iter().map(|entry| (entry.key_bytes(), DBValue::from(entry.value()))).collect::<Vec<_>>(),
+			t.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(),
+		);
+		let under_1: Vec<_> = entries.iter()
+			.filter(|entry| entry.key_nibbles().at(0) == 1)
+			.map(|entry| entry.value())
+			.collect();
+		assert_eq!(under_1, vec![&b"a"[..], &b"ab"[..]]);
+		assert_eq!(entries[1].key_nibbles().len(), 4);
+	}
+
+	#[test]
+	fn max_depth_is_enforced() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", &[1; 40]).unwrap();
+			t.insert(b"AB", &[2; 40]).unwrap();
+			t.insert(b"ABC", &[3; 40]).unwrap();
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap().with_max_depth(2);
+		assert_eq!(t.get(b"A").unwrap(), Some(vec![1; 40].into()));
+		match t.get(b"ABC").map_err(|e| *e) {
+			Err(TrieError::MaxDepthExceeded(_)) => {},
+			result => panic!("expected MaxDepthExceeded, got {:?}", result),
+		}
+		assert!(t.iter().unwrap().any(|item| match item.map_err(|e| *e) {
+			Err(TrieError::MaxDepthExceeded(_)) => true,
+			_ => false,
+		}));
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap().with_max_depth(16);
+		assert_eq!(t.get(b"ABC").unwrap(), Some(vec![3; 40].into()));
+		assert_eq!(t.iter().unwrap().count(), 3);
+	}
+
+	#[test]
+	fn subtree_root_works() {
+		fn build(values: &[(&[u8], &[u8])]) -> (MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, <KeccakHasher as Hasher>::Out) {
+			let mut memdb = MemoryDB::default();
+			let mut root = Default::default();
+			{
+				let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+				for (k, v) in values {
+					t.insert(k, v).unwrap();
+				}
+			}
+			(memdb, root)
+		}
+
+		let (memdb1, root1) = build(&[
+			(&[0x01, 0x23], &[1; 40]),
+			(&[0x01, 0x24], &[2; 40]),
+			(&[0x02, 0x00], &[3; 40]),
+			(&[0x03], &[4]),
+		]);
+		let (memdb2, root2) = build(&[
+			(&[0x01, 0x23], &[1; 40]),
+			(&[0x01, 0x24], &[2; 40]),
+			(&[0x02, 0x00], &[5; 40]),
+		]);
+		let t1 = RefTrieDB::new(&memdb1, &root1).unwrap();
+		let t2 = RefTrieDB::new(&memdb2, &root2).unwrap();
+
+		assert_eq!(t1.subtree_root(&[]).unwrap(), Some(ChildReference::Hash(root1)));
+		let prefix_root = t1.subtree_root(&[0x01]).unwrap();
+		assert!(matches!(prefix_root, Some(ChildReference::Hash(_))));
+		assert_eq!(prefix_root, t2.subtree_root(&[0x01]).unwrap());
+		assert_ne!(t1.subtree_root(&[0x01, 0x23]).unwrap(), prefix_root);
+		assert_ne!(t1.subtree_root(&[0x02]).unwrap(), t2.subtree_root(&[0x02]).unwrap());
+		assert!(matches!(t1.subtree_root(&[0x03]).unwrap(), Some(ChildReference::Inline(..))));
+		assert_eq!(t2.subtree_root(&[0x03]).unwrap(), None);
+		assert_eq!(t1.subtree_root(&[0x01, 0x25]).unwrap(), None);
+		assert_eq!(t1.subtree_root(&[0x04]).unwrap(), None);
+	}
+
+	#[test]
+	fn max_node_size_is_enforced() {
+		struct BoundedLayout;
+
+		impl reference_trie::TrieLayout for BoundedLayout {
+			const USE_EXTENSION: bool = true;
+			const MAX_NODE_SIZE: Option<usize> = Some(80);
+			type Hash = KeccakHasher;
+			type Codec = reference_trie::ReferenceNodeCodec<KeccakHasher>;
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", &[1; 40]).unwrap();
+			t.insert(b"B", &[2; 100]).unwrap();
+		}
+
+		let t = TrieDB::<BoundedLayout>::new(&memdb, &root).unwrap();
+		assert_eq!(t.get(b"A").unwrap(), Some(vec![1; 40].into()));
+		match t.get(b"B").map_err(|e| *e) {
+			Err(TrieError::NodeTooLarge(_, len)) => assert!(len > 80),
+			result => panic!("expected NodeTooLarge, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn strict_decoding_rejects_trailing_bytes() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+
+		struct StrictLayout;
+
+		impl reference_trie::TrieLayout for StrictLayout {
+			const USE_EXTENSION: bool = true;
+			const STRICT_DECODING: bool = true;
+			type Hash = KeccakHasher;
+			type Codec = reference_trie::ReferenceNodeCodec<KeccakHasher>;
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", &[1; 40]).unwrap();
+		}
+		let mut encoded = memdb.get(&root, EMPTY_PREFIX).unwrap().to_vec();
+		memdb.remove(&root, EMPTY_PREFIX);
+		encoded.push(0);
+		memdb.emplace(root, EMPTY_PREFIX, encoded.into());
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		assert_eq!(t.get(b"A").unwrap(), Some(vec![1; 40].into()));
+
+		let t = TrieDB::<StrictLayout>::new(&memdb, &root).unwrap();
+		match t.get(b"A").map_err(|e| *e) {
+			Err(TrieError::TrailingBytes(hash, 1)) => assert_eq!(hash, root),
+			result => panic!("expected TrailingBytes, got {:?}", result),
+		}
+	}
+
 	#[test]
 	fn debug_output_supports_pretty_print() {
 		let d = vec![
@@ -697,8 +1802,66 @@ mod tests {
 
 		// query for an invalid data type to trigger an error
 		let q = |x: &[u8]| x.len() < 64;
-		let lookup = RefLookup { db: t.db(), query: q, hash: root };
+		let lookup = RefLookup::new(t.db(), q, root);
 		let query_result = lookup.look_up(NibbleSlice::new(b"A"));
 		assert_eq!(query_result.unwrap().unwrap(), true);
 	}
+
+	#[test]
+	fn get_streamed_passes_value_slice() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", b"ABC").unwrap();
+			t.insert(b"B", &[7; 100_000]).unwrap();
+		}
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let streamed = |key: &[u8]| {
+			let mut value = Vec::new();
+			let mut calls = 0;
+			let found = t.get_streamed(key, |slice| {
+				calls += 1;
+				value.extend_from_slice(slice);
+			}).unwrap();
+			assert_eq!(calls, found as usize);
+			(found, value)
+		};
+		assert_eq!(streamed(b"A"), (true, b"ABC".to_vec()));
+		assert_eq!(streamed(b"B"), (true, vec![7; 100_000]));
+		assert_eq!(streamed(b"C"), (false, Vec::new()));
+		assert_eq!(
+			RefLookup::new(t.db(), |_: &[u8]| (), root).get_streamed(b"B", |_| ()).unwrap(),
+			true,
+		);
+	}
+
+	#[test]
+	fn test_lookup_with_path() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", b"ABC").unwrap();
+			t.insert(b"B", &[7; 32]).unwrap();
+		}
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let lookup = |key: &[u8]| RefLookup::new(t.db(), |value: &[u8]| value.to_vec(), root)
+			.look_up_with_path(NibbleSlice::new(key)).unwrap();
+
+		// the leaf of `A` is inline in the branch, the one of `B` is stored.
+		let (value, path) = lookup(b"A");
+		assert_eq!(value, Some(b"ABC".to_vec()));
+		assert_eq!(path.len(), 2);
+		let (value, path_b) = lookup(b"B");
+		assert_eq!(value, Some(vec![7; 32]));
+		assert_eq!(path_b.len(), 3);
+		assert_eq!(path_b[..2], path[..]);
+		assert_eq!(path[0], (root, 0));
+		assert_eq!(path_b.iter().map(|(_, depth)| *depth).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+		let (value, path) = lookup(b"C");
+		assert_eq!(value, None);
+		assert_eq!(path, vec![(root, 0), path_b[1]]);
+	}
 }
@@ -0,0 +1,130 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use hash_db::{HashDBRef, EMPTY_PREFIX};
+use super::{Result, DBValue, Trie, TrieDB, TrieError, Query, TrieLayout, CError, TrieHash};
+
+use crate::rstd::{boxed::Box, sync::Arc};
+
+/// Shared handle to the database of a `TrieDBOwned`.
+type SharedDB<L> = Arc<dyn HashDBRef<<L as TrieLayout>::Hash, DBValue> + Send + Sync>;
+
+/// A read-only trie owning a shared handle to its backing database and its root,
+/// so that it can be stored in long-lived structures and moved across threads.
+///
+/// Lookups are done directly on it; iteration and the other `Trie` operations go through
+/// a `TrieDB` borrowing it, see `TrieDBOwned::trie`.
+pub struct TrieDBOwned<L: TrieLayout> {
+	db: SharedDB<L>,
+	root: TrieHash<L>,
+	max_depth: Option<usize>,
+}
+
+impl<L: TrieLayout> Clone for TrieDBOwned<L> {
+	fn clone(&self) -> Self {
+		TrieDBOwned {
+			db: self.db.clone(),
+			root: self.root,
+			max_depth: self.max_depth,
+		}
+	}
+}
+
+impl<L: TrieLayout> TrieDBOwned<L> {
+	/// Create a new trie with the backing database `db` and `root`.
+	/// Returns an error if `root` does not exist.
+	pub fn new(db: SharedDB<L>, root: TrieHash<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		if !db.contains(&root, EMPTY_PREFIX) {
+			return Err(Box::new(TrieError::InvalidStateRoot(root)));
+		}
+		Ok(TrieDBOwned { db, root, max_depth: None })
+	}
+
+	/// Limit lookups and iteration to paths of at most `max_depth` nodes,
+	/// see `TrieDB::with_max_depth`.
+	pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+		self.max_depth = Some(max_depth);
+		self
+	}
+
+	/// Get the backing database.
+	pub fn db(&self) -> &SharedDB<L> { &self.db }
+
+	/// Return the root of the trie.
+	pub fn root(&self) -> &TrieHash<L> { &self.root }
+
+	/// Get a `TrieDB` over this trie, to iterate over it or use it as a `Trie`.
+	pub fn trie(&self) -> TrieDB<'_, L> {
+		let trie = TrieDB::new_unchecked(&*self.db, &self.root);
+		match self.max_depth {
+			Some(max_depth) => trie.with_max_depth(max_depth),
+			None => trie,
+		}
+	}
+
+	/// Does the trie contain a given key?
+	pub fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.trie().contains(key)
+	}
+
+	/// What is the value of the given key in this trie?
+	pub fn get(&self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.trie().get(key)
+	}
+
+	/// Search for the key with the given query parameter, see `Trie::get_with`.
+	pub fn get_with<Q: Query<L::Hash>>(
+		&self,
+		key: &[u8],
+		query: Q,
+	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
+		self.trie().get_with(key, query)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::thread;
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::DBValue;
+	use reference_trie::{ExtensionLayout, RefTrieDBMut, Trie, TrieDBOwned, TrieError, TrieMut};
+
+	fn owned_trie(pairs: &[(&[u8], &[u8])]) -> TrieDBOwned<ExtensionLayout> {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (key, value) in pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+		TrieDBOwned::new(Arc::new(memdb), root).unwrap()
+	}
+
+	#[test]
+	fn owned_trie_outlives_its_builder() {
+		let trie = owned_trie(&[(b"A", b"ABC"), (b"B", b"ABCBAAAAAAAAAAAAAAAAAAAAAAAAAAAA")]);
+		let shared = trie.clone();
+		let value = thread::spawn(move || shared.get(b"A").unwrap()).join().unwrap();
+		assert_eq!(value, Some(b"ABC".to_vec().into()));
+		assert!(!trie.contains(b"C").unwrap());
+		assert_eq!(trie.get_with(b"B", |value: &[u8]| value.len()).unwrap(), Some(32));
+		assert_eq!(trie.trie().iter().unwrap().count(), 2);
+
+		let missing = TrieDBOwned::<ExtensionLayout>::new(trie.db().clone(), [1; 32]);
+		assert!(matches!(missing.err().map(|e| *e), Some(TrieError::InvalidStateRoot(_))));
+	}
+}
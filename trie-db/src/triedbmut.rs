@@ -15,21 +15,24 @@
 //! In-memory trie representation.
 
 use super::{DBValue, node::NodeKey};
-use super::{Result, TrieError, TrieMut, TrieLayout, TrieHash, CError};
+use super::{Result, TrieError, TrieMut, TrieLayout, TrieHash, CError, TrieMeter, NoMeter, decode_options,
+	layout_key, hash_parts};
 use super::lookup::Lookup;
 use super::node::{NodeHandle as EncodedNodeHandle, Node as EncodedNode, decode_hash};
 
 use hash_db::{HashDB, Hasher, Prefix, EMPTY_PREFIX};
 use hashbrown::HashSet;
 
-use crate::node_codec::NodeCodec;
+use crate::node_codec::{NodeCodec, DecodeOptions};
 use crate::nibble::{NibbleVec, NibbleSlice, nibble_ops, BackingByteVec};
 use crate::rstd::{
-	boxed::Box, convert::TryFrom, hash::Hash, mem, ops::Index, result, vec::Vec, VecDeque,
+	boxed::Box, convert::TryFrom, hash::Hash, iter, mem, ops::Index, result, vec::Vec, VecDeque,
 };
 
 #[cfg(feature = "std")]
-use log::trace;
+use log::{error, trace};
+#[cfg(feature = "tracing")]
+use crate::instrument::Hex;
 
 #[cfg(feature = "std")]
 use crate::rstd::fmt::{self, Debug};
@@ -55,6 +58,16 @@ impl<H> From<StorageHandle> for NodeHandle<H> {
 	}
 }
 
+impl<H: Clone> NodeHandle<H> {
+	// a copy of the handle, only to be used when saving the trie state.
+	fn duplicate(&self) -> Self {
+		match self {
+			NodeHandle::InMemory(StorageHandle(x)) => NodeHandle::InMemory(StorageHandle(*x)),
+			NodeHandle::Hash(h) => NodeHandle::Hash(h.clone()),
+		}
+	}
+}
+
 fn empty_children<H>() -> Box<[Option<NodeHandle<H>>; 16]> {
 	Box::new([
 		None, None, None, None, None, None, None, None,
@@ -108,9 +121,30 @@ impl<H: Debug> Debug for Node<H> {
 			Self::Extension((ref a, ref b), ref c) =>
 				write!(fmt, "Extension({:?}, {:?})", (a, ToHex(&*b)), c),
 			Self::Branch(ref a, ref b) =>
-				write!(fmt, "Branch({:?}, {:?}", a, b.as_ref().map(Vec::as_slice).map(ToHex)),
+				write!(fmt, "Branch({:?}, {:?}", a, b.as_ref().map(|b| ToHex(b))),
 			Self::NibbledBranch((ref a, ref b), ref c, ref d) =>
-				write!(fmt, "NibbledBranch({:?}, {:?}, {:?})", (a, ToHex(&*b)), c, d.as_ref().map(Vec::as_slice).map(ToHex)),
+				write!(fmt, "NibbledBranch({:?}, {:?}, {:?})", (a, ToHex(&*b)), c, d.as_ref().map(|d| ToHex(d))),
+		}
+	}
+}
+
+impl<H: Clone> Node<H> {
+	// a copy of the node, only to be used when journaling storage changes.
+	fn duplicate(&self) -> Self {
+		let duplicate_children = |children: &[Option<NodeHandle<H>>; 16]| {
+			let mut duplicate = empty_children();
+			for (duplicate, child) in duplicate.iter_mut().zip(children.iter()) {
+				*duplicate = child.as_ref().map(NodeHandle::duplicate);
+			}
+			duplicate
+		};
+		match self {
+			Node::Empty => Node::Empty,
+			Node::Leaf(key, value) => Node::Leaf(key.clone(), value.clone()),
+			Node::Extension(key, child) => Node::Extension(key.clone(), child.duplicate()),
+			Node::Branch(children, value) => Node::Branch(duplicate_children(children), value.clone()),
+			Node::NibbledBranch(key, children, value) =>
+				Node::NibbledBranch(key.clone(), duplicate_children(children), value.clone()),
 		}
 	}
 }
@@ -124,6 +158,7 @@ where
 	fn inline_or_hash<C, H>(
 		parent_hash: H::Out,
 		child: EncodedNodeHandle,
+		options: DecodeOptions,
 		db: &dyn HashDB<H, DBValue>,
 		storage: &mut NodeStorage<H::Out>
 	) -> Result<NodeHandle<H::Out>, H::Out, C::Error>
@@ -138,37 +173,39 @@ where
 				NodeHandle::Hash(hash)
 			},
 			EncodedNodeHandle::Inline(data) => {
-				let child = Node::from_encoded::<C, H>(parent_hash, data, db, storage)?;
+				let child = Node::from_encoded::<C, H>(parent_hash, data, options, db, storage)?;
 				NodeHandle::InMemory(storage.alloc(Stored::New(child)))
 			},
 		};
 		Ok(handle)
 	}
 
-	// Decode a node from encoded bytes.
+	// Decode a node from encoded bytes, applying the checks from `options`.
 	fn from_encoded<'a, 'b, C, H>(
 		node_hash: H::Out,
 		data: &'a[u8],
+		options: DecodeOptions,
 		db: &dyn HashDB<H, DBValue>,
 		storage: &'b mut NodeStorage<H::Out>,
 	) -> Result<Self, H::Out, C::Error>
 		where
 			C: NodeCodec<HashOut = O>, H: Hasher<Out = O>,
 	{
-		let encoded_node = C::decode(data)
-			.map_err(|e| Box::new(TrieError::DecoderError(node_hash, e)))?;
+		let encoded_node = options.decode::<C>(data)
+			.map_err(|e| Box::new(e.into_trie_error(node_hash)))?;
+		let inline_options = options.inline();
 		let node = match encoded_node {
 			EncodedNode::Empty => Node::Empty,
-			EncodedNode::Leaf(k, v) => Node::Leaf(k.into(), v.to_vec()),
+			EncodedNode::Leaf(k, v) => Node::Leaf(k.into(), DBValue::from(v)),
 			EncodedNode::Extension(key, cb) => {
 				Node::Extension(
 					key.into(),
-					Self::inline_or_hash::<C, H>(node_hash, cb, db, storage)?
+					Self::inline_or_hash::<C, H>(node_hash, cb, inline_options, db, storage)?
 				)
 			},
 			EncodedNode::Branch(encoded_children, val) => {
 				let mut child = |i:usize| match encoded_children[i] {
-					Some(child) => Self::inline_or_hash::<C, H>(node_hash, child, db, storage)
+					Some(child) => Self::inline_or_hash::<C, H>(node_hash, child, inline_options, db, storage)
 						.map(Some),
 					None => Ok(None),
 				};
@@ -180,11 +217,11 @@ where
 					child(12)?, child(13)?, child(14)?, child(15)?,
 				]);
 
-				Node::Branch(children, val.map(|v| v.to_vec()))
+				Node::Branch(children, val.map(DBValue::from))
 			},
 			EncodedNode::NibbledBranch(k, encoded_children, val) => {
 				let mut child = |i:usize| match encoded_children[i] {
-					Some(child) => Self::inline_or_hash::<C, H>(node_hash, child, db, storage)
+					Some(child) => Self::inline_or_hash::<C, H>(node_hash, child, inline_options, db, storage)
 						.map(Some),
 					None => Ok(None),
 				};
@@ -196,65 +233,70 @@ where
 					child(12)?, child(13)?, child(14)?, child(15)?,
 				]);
 
-				Node::NibbledBranch(k.into(), children, val.map(|v| v.to_vec()))
+				Node::NibbledBranch(k.into(), children, val.map(DBValue::from))
 			},
 		};
 		Ok(node)
 	}
 
 	// TODO: parallelize
-	fn into_encoded<F, C, H>(self, mut child_cb: F) -> Vec<u8>
+	/// Encode the node, appending it to `output`.
+	fn encode_into<F, C, H>(
+		self,
+		output: &mut Vec<u8>,
+		mut child_cb: F,
+	) -> result::Result<(), C::Error>
 	where
 		C: NodeCodec<HashOut=O>,
-		F: FnMut(NodeHandle<H::Out>, Option<&NibbleSlice>, Option<u8>) -> ChildReference<H::Out>,
+		F: FnMut(NodeHandle<H::Out>, Option<&NibbleSlice>, Option<u8>)
+			-> result::Result<ChildReference<H::Out>, C::Error>,
 		H: Hasher<Out = O>,
 	{
 		match self {
-			Node::Empty => C::empty_node().to_vec(),
+			Node::Empty => {
+				output.extend_from_slice(C::empty_node());
+				Ok(())
+			},
 			Node::Leaf(partial, value) => {
 				let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
-				C::leaf_node(pr.right(), &value)
+				C::leaf_node_iter_into(output, pr.right_iter(), pr.len(), &value)
 			},
 			Node::Extension(partial, child) => {
 				let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
 				let it = pr.right_iter();
-				let c = child_cb(child, Some(&pr), None);
-				C::extension_node(
+				let c = child_cb(child, Some(&pr), None)?;
+				C::extension_node_into(
+					output,
 					it,
 					pr.len(),
 					c,
 				)
 			},
 			Node::Branch(mut children, value) => {
-				C::branch_node(
-					// map the `NodeHandle`s from the Branch to `ChildReferences`
-					children.iter_mut()
-						.map(Option::take)
-						.enumerate()
-						.map(|(i, maybe_child)| {
-							maybe_child.map(|child| child_cb(child, None, Some(i as u8)))
-						}),
-					value.as_ref().map(|v| &v[..])
-				)
+				// map the `NodeHandle`s from the Branch to `ChildReferences`
+				let mut references = [None; nibble_ops::NIBBLE_LENGTH];
+				for (i, child) in children.iter_mut().enumerate() {
+					if let Some(child) = child.take() {
+						references[i] = Some(child_cb(child, None, Some(i as u8))?);
+					}
+				}
+				C::branch_node_into(output, references.iter(), value.as_ref().map(|v| &v[..]))
 			},
 			Node::NibbledBranch(partial, mut children, value) => {
 				let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
-				let it = pr.right_iter();
-				C::branch_node_nibbled(
-					it,
+				// map the `NodeHandle`s from the Branch to `ChildReferences`
+				let mut references = [None; nibble_ops::NIBBLE_LENGTH];
+				for (i, child) in children.iter_mut().enumerate() {
+					if let Some(child) = child.take() {
+						references[i] = Some(child_cb(child, Some(&pr), Some(i as u8))?);
+					}
+				}
+				C::branch_node_nibbled_into(
+					output,
+					pr.right_iter(),
 					pr.len(),
-					// map the `NodeHandle`s from the Branch to `ChildReferences`
-					children.iter_mut()
-						.map(Option::take)
-						.enumerate()
-						.map(|(i, maybe_child)| {
-							//let branch_index = [i as u8];
-							maybe_child.map(|child| {
-								let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
-								child_cb(child, Some(&pr), Some(i as u8))
-							})
-						}),
-					value.as_ref().map(|v| &v[..])
+					references.iter(),
+					value.as_ref().map(|v| &v[..]),
 				)
 			},
 		}
@@ -303,14 +345,62 @@ enum Stored<H> {
 	Cached(Node<H>, H),
 }
 
+impl<H: Clone> Stored<H> {
+	// a copy of the stored node, only to be used when journaling storage changes.
+	fn duplicate(&self) -> Self {
+		match self {
+			Stored::New(node) => Stored::New(node.duplicate()),
+			Stored::Cached(node, hash) => Stored::Cached(node.duplicate(), hash.clone()),
+		}
+	}
+}
+
 /// Used to build a collection of child nodes from a collection of `NodeHandle`s
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "std", derive(Debug))]
 pub enum ChildReference<HO> { // `HO` is e.g. `H256`, i.e. the output of a `Hasher`
 	Hash(HO),
 	Inline(HO, usize), // usize is the length of the node data we store in the `H::Out`
 }
 
+/// Structural invariant broken by a node held in memory by a `TrieDBMut`,
+/// see `TrieDBMut::validate`. Carries the nibble path of the node.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum InvariantError {
+	/// An empty node below the root.
+	EmptyNode(NibbleVec),
+	/// A branch without value and with less than two children.
+	RedundantBranch(NibbleVec),
+	/// An extension with an empty partial key.
+	EmptyExtension(NibbleVec),
+	/// An extension whose child is not a branch.
+	ExtensionChild(NibbleVec),
+	/// A partial key whose padding offset is out of range.
+	InvalidPartial(NibbleVec),
+	/// A partial key with an odd number of nibbles whose padding nibble is not zero.
+	BadPadding(NibbleVec),
+	/// A node kind that the layout does not use, eg an extension without `USE_EXTENSION`.
+	UnexpectedNode(NibbleVec),
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for InvariantError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			InvariantError::EmptyNode(path) => write!(f, "Empty node at {:?}", path),
+			InvariantError::RedundantBranch(path) => write!(f, "Redundant branch at {:?}", path),
+			InvariantError::EmptyExtension(path) => write!(f, "Empty extension at {:?}", path),
+			InvariantError::ExtensionChild(path) =>
+				write!(f, "Extension without branch child at {:?}", path),
+			InvariantError::InvalidPartial(path) => write!(f, "Invalid partial key at {:?}", path),
+			InvariantError::BadPadding(path) => write!(f, "Non-zero partial key padding at {:?}", path),
+			InvariantError::UnexpectedNode(path) =>
+				write!(f, "Node unexpected for the layout at {:?}", path),
+		}
+	}
+}
+
 impl<'a, HO> TryFrom<EncodedNodeHandle<'a>> for ChildReference<HO>
 	where HO: AsRef<[u8]> + AsMut<[u8]> + Default + Clone + Copy
 {
@@ -338,28 +428,60 @@ impl<'a, HO> TryFrom<EncodedNodeHandle<'a>> for ChildReference<HO>
 	}
 }
 
+/// A change to a `NodeStorage`, journaled to undo it on rollback.
+enum StorageChange<H> {
+	/// A slot was overwritten, holding this node before.
+	Slot(usize, Stored<H>),
+	/// A slot was appended.
+	Push,
+	/// A free index was taken from the front of the free list.
+	PopFree(usize),
+	/// A free index was appended to the free list.
+	PushFree,
+}
+
 /// Compact and cache-friendly storage for Trie nodes.
 struct NodeStorage<H> {
 	nodes: Vec<Stored<H>>,
 	free_indices: VecDeque<usize>,
+	/// Changes since the journal was started, `None` when not journaling.
+	journal: Option<Vec<StorageChange<H>>>,
 }
 
-impl<H> NodeStorage<H> {
+impl<H: Clone> NodeStorage<H> {
 	/// Create a new storage.
 	fn empty() -> Self {
 		NodeStorage {
 			nodes: Vec::new(),
 			free_indices: VecDeque::new(),
+			journal: None,
 		}
 	}
 
+	fn record(&mut self, change: StorageChange<H>) {
+		if let Some(journal) = self.journal.as_mut() {
+			journal.push(change);
+		}
+	}
+
+	/// Overwrite a slot, journaling its previous node.
+	fn replace(&mut self, idx: usize, stored: Stored<H>) -> Stored<H> {
+		let previous = mem::replace(&mut self.nodes[idx], stored);
+		if self.journal.is_some() {
+			self.record(StorageChange::Slot(idx, previous.duplicate()));
+		}
+		previous
+	}
+
 	/// Allocate a new node in the storage.
 	fn alloc(&mut self, stored: Stored<H>) -> StorageHandle {
 		if let Some(idx) = self.free_indices.pop_front() {
-			self.nodes[idx] = stored;
+			self.record(StorageChange::PopFree(idx));
+			self.replace(idx, stored);
 			StorageHandle(idx)
 		} else {
 			self.nodes.push(stored);
+			self.record(StorageChange::Push);
 			StorageHandle(self.nodes.len() - 1)
 		}
 	}
@@ -368,8 +490,51 @@ impl<H> NodeStorage<H> {
 	fn destroy(&mut self, handle: StorageHandle) -> Stored<H> {
 		let idx = handle.0;
 
-		self.free_indices.push_back(idx);
-		mem::replace(&mut self.nodes[idx], Stored::New(Node::Empty))
+		self.free(handle);
+		self.replace(idx, Stored::New(Node::Empty))
+	}
+
+	/// Take a node out of the storage, to `restore` or `free` its slot afterwards.
+	fn take(&mut self, handle: &StorageHandle) -> Stored<H> {
+		self.replace(handle.0, Stored::New(Node::Empty))
+	}
+
+	/// Put back a node in the slot of a taken node.
+	fn restore(&mut self, handle: &StorageHandle, stored: Stored<H>) {
+		self.replace(handle.0, stored);
+	}
+
+	/// Free the slot of a taken node.
+	fn free(&mut self, handle: StorageHandle) {
+		self.free_indices.push_back(handle.0);
+		self.record(StorageChange::PushFree);
+	}
+
+	/// Start journaling changes if not already, returning the current journal length.
+	fn journal_len(&mut self) -> usize {
+		self.journal.get_or_insert_with(Vec::new).len()
+	}
+
+	/// Undo the journaled changes past the first `len`.
+	fn rollback(&mut self, len: usize) {
+		let journal = match self.journal.as_mut() {
+			Some(journal) => journal,
+			None => return,
+		};
+		while journal.len() > len {
+			match journal.pop() {
+				Some(StorageChange::Slot(idx, stored)) => self.nodes[idx] = stored,
+				Some(StorageChange::Push) => { self.nodes.pop(); },
+				Some(StorageChange::PopFree(idx)) => self.free_indices.push_front(idx),
+				Some(StorageChange::PushFree) => { self.free_indices.pop_back(); },
+				None => break,
+			}
+		}
+	}
+
+	/// Stop journaling, dropping the journaled changes.
+	fn clear_journal(&mut self) {
+		self.journal = None;
 	}
 }
 
@@ -384,6 +549,21 @@ impl<'a, H> Index<&'a StorageHandle> for NodeStorage<H> {
 	}
 }
 
+/// A node to remove from the database on commit, with its prefix.
+type DeathRowEntry<H> = (H, (BackingByteVec, Option<u8>));
+
+/// In-memory state of a `TrieDBMut`, to restore with `TrieDBMut::rollback_to`.
+pub struct Savepoint<L: TrieLayout> {
+	root: TrieHash<L>,
+	root_handle: NodeHandle<TrieHash<L>>,
+	/// Length of the storage journal.
+	storage_changes: usize,
+	/// Length of the death row journal.
+	death_row_changes: usize,
+	/// Identifier of the savepoint in `TrieDBMut::savepoints`.
+	id: usize,
+}
+
 /// A `Trie` implementation using a generic `HashDB` backing database.
 ///
 /// Use it as a `TrieMut` trait object. You can use `db()` to get the backing database object.
@@ -419,10 +599,28 @@ where
 	db: &'a mut dyn HashDB<L::Hash, DBValue>,
 	root: &'a mut TrieHash<L>,
 	root_handle: NodeHandle<TrieHash<L>>,
-	death_row: HashSet<(TrieHash<L>, (BackingByteVec, Option<u8>))>,
+	death_row: HashSet<DeathRowEntry<TrieHash<L>>>,
+	/// Entries added to the death row since the oldest savepoint, `None` without savepoints.
+	death_row_journal: Option<Vec<DeathRowEntry<TrieHash<L>>>>,
 	/// The number of hash operations this trie has performed.
 	/// Note that none are performed until changes are committed.
 	hash_count: usize,
+	/// Identifiers of the savepoints that can still be rolled back to, oldest first.
+	/// Cleared by commits writing to the database, truncated by rollbacks.
+	savepoints: Vec<usize>,
+	/// Identifier of the next savepoint.
+	next_savepoint: usize,
+	meter: &'a dyn TrieMeter,
+	/// Buffers to encode nodes into on commit, reused across nodes and commits.
+	encode_buffers: Vec<Vec<u8>>,
+	/// Keep committed nodes in memory, see `with_retained_nodes`.
+	retain_nodes: bool,
+	/// Do not write to the database, see `with_hashes_only`.
+	hashes_only: bool,
+	/// Whether the codec of the layout encodes the node kinds the layout uses.
+	encodable_layout: bool,
+	/// Keys up to this length were checked to be encodable as a leaf.
+	encodable_key_len: usize,
 }
 
 impl<'a, L> TrieDBMut<'a, L>
@@ -440,30 +638,133 @@ where
 			root,
 			root_handle,
 			death_row: HashSet::new(),
+			death_row_journal: None,
 			hash_count: 0,
+			savepoints: Vec::new(),
+			next_savepoint: 0,
+			meter: &NoMeter,
+			encode_buffers: Vec::new(),
+			retain_nodes: false,
+			hashes_only: false,
+			encodable_layout: check_layout::<L>().is_ok(),
+			encodable_key_len: 0,
 		}
 	}
 
 	/// Create a new trie with the backing database `db` and `root.
-	/// Returns an error if `root` does not exist.
+	/// Returns an error if `root` does not exist, or if the codec of the layout
+	/// cannot encode the node kinds the layout uses.
 	pub fn from_existing(
 		db: &'a mut dyn HashDB<L::Hash, DBValue>,
 		root: &'a mut TrieHash<L>,
 	) -> Result<Self, TrieHash<L>, CError<L>> {
+		check_layout::<L>().map_err(|e| Box::new(TrieError::EncoderError(e)))?;
 		if !db.contains(root, EMPTY_PREFIX) {
 			return Err(Box::new(TrieError::InvalidStateRoot(*root)));
 		}
+		Ok(Self::from_existing_unchecked(db, root))
+	}
 
+	/// Create a new trie with the backing database `db` and `root`, without checking
+	/// that `root` exists, eg for databases populated lazily. A missing root is then
+	/// reported by the first access as `TrieError::InvalidStateRoot`.
+	pub fn from_existing_unchecked(
+		db: &'a mut dyn HashDB<L::Hash, DBValue>,
+		root: &'a mut TrieHash<L>,
+	) -> Self {
 		let root_handle = NodeHandle::Hash(*root);
-		Ok(TrieDBMut {
+		TrieDBMut {
 			storage: NodeStorage::empty(),
 			db,
 			root,
 			root_handle,
 			death_row: HashSet::new(),
+			death_row_journal: None,
 			hash_count: 0,
-		})
+			savepoints: Vec::new(),
+			next_savepoint: 0,
+			meter: &NoMeter,
+			encode_buffers: Vec::new(),
+			retain_nodes: false,
+			hashes_only: false,
+			encodable_layout: check_layout::<L>().is_ok(),
+			encodable_key_len: 0,
+		}
+	}
+
+	/// Report node reads and decodes to `meter`.
+	pub fn with_meter(mut self, meter: &'a dyn TrieMeter) -> Self {
+		self.meter = meter;
+		self
+	}
+
+	/// Keep the nodes in memory on commit instead of dropping them, so that reads and
+	/// changes after a commit do not fetch them from the database again, and the next
+	/// commit only hashes the nodes changed since. Memory use then grows with every
+	/// node touched during the lifetime of the trie.
+	pub fn with_retained_nodes(mut self) -> Self {
+		self.retain_nodes = true;
+		self
+	}
+
+	/// Only compute the root on commit: new nodes are hashed but not written to the
+	/// database and replaced nodes are not removed from it. The database is only read.
+	/// Committed nodes are retained as with `with_retained_nodes`, since the database
+	/// cannot provide them afterwards.
+	pub fn with_hashes_only(mut self) -> Self {
+		self.hashes_only = true;
+		self.retain_nodes = true;
+		self
+	}
+
+	/// Save the in-memory state of the trie, to undo the changes made after this
+	/// call with `rollback_to`. Changes are journaled from the first savepoint until
+	/// the next commit writing to the database.
+	pub fn savepoint(&mut self) -> Savepoint<L> {
+		let id = self.next_savepoint;
+		self.next_savepoint += 1;
+		self.savepoints.push(id);
+		Savepoint {
+			root: *self.root,
+			root_handle: self.root_handle.duplicate(),
+			storage_changes: self.storage.journal_len(),
+			death_row_changes: self.death_row_journal.get_or_insert_with(Vec::new).len(),
+			id,
+		}
 	}
+
+	/// Restore the state saved by `savepoint`, undoing the changes made since.
+	/// Returns `false` and leaves the trie unchanged if the savepoint is no longer live:
+	/// changes were committed after it was taken, since changes written to the database
+	/// cannot be undone, or the trie was rolled back to an older savepoint.
+	/// Savepoints taken after `savepoint` are no longer live once rolled back to it.
+	pub fn rollback_to(&mut self, savepoint: Savepoint<L>) -> bool {
+		match self.savepoints.iter().position(|id| *id == savepoint.id) {
+			Some(position) => self.savepoints.truncate(position),
+			None => return false,
+		}
+		self.storage.rollback(savepoint.storage_changes);
+		if let Some(journal) = self.death_row_journal.as_mut() {
+			for entry in journal.drain(savepoint.death_row_changes..) {
+				self.death_row.remove(&entry);
+			}
+		}
+		*self.root = savepoint.root;
+		self.root_handle = savepoint.root_handle;
+		true
+	}
+
+	/// Schedule the removal of a replaced node from the database on commit.
+	fn kill(&mut self, hash: TrieHash<L>, prefix: (BackingByteVec, Option<u8>)) {
+		let entry = (hash, prefix);
+		if let Some(journal) = self.death_row_journal.as_mut() {
+			if !self.death_row.contains(&entry) {
+				journal.push(entry.clone());
+			}
+		}
+		self.death_row.insert(entry);
+	}
+
 	/// Get the backing database.
 	pub fn db(&self) -> &dyn HashDB<L::Hash, DBValue> {
 		self.db
@@ -474,17 +775,102 @@ where
 		self.db
 	}
 
+	/// Get the value at a key given in nibbles, which may end in the middle of a byte.
+	/// The key is used as is, even if the layout uses `HASH_KEYS`.
+	pub fn get_nibbles(&self, key: &NibbleVec) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.lookup(NibbleSlice::new_len(key.inner(), key.len()), &self.root_handle)
+	}
+
+	/// Insert a value at a key given in nibbles, which may end in the middle of a byte,
+	/// returning the old value. The key is used as is, even if the layout uses `HASH_KEYS`.
+	/// Values at such keys are skipped by `TrieDB` iterators, which only yield byte keys.
+	pub fn insert_nibbles(
+		&mut self,
+		key: &NibbleVec,
+		value: &[u8],
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		if value.is_empty() { return self.remove_nibbles(key) }
+		self.check_encodable(key.inner())?;
+		self.insert_partial(NibbleSlice::new_len(key.inner(), key.len()), value)
+	}
+
+	/// Remove the value at a key given in nibbles, returning it.
+	/// The key is used as is, even if the layout uses `HASH_KEYS`.
+	pub fn remove_nibbles(&mut self, key: &NibbleVec) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.remove_partial(NibbleSlice::new_len(key.inner(), key.len()))
+	}
+
+	// Insert a value at a full key, checked encodable.
+	fn insert_partial(
+		&mut self,
+		mut key: NibbleSlice,
+		value: &[u8],
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let root_handle = self.root_handle();
+		let mut old_val = None;
+		let (new_handle, _changed) = self.insert_at(
+			root_handle,
+			&mut key,
+			DBValue::from(value),
+			&mut old_val,
+		)?;
+
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "insert: altered trie={}", _changed);
+		self.root_handle = NodeHandle::InMemory(new_handle);
+
+		Ok(old_val)
+	}
+
+	// Remove the value at a full key.
+	fn remove_partial(&mut self, mut key: NibbleSlice) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		// Removal may merge nodes into ones of another kind.
+		self.check_encodable(&[])?;
+
+		let root_handle = self.root_handle();
+		let mut old_val = None;
+
+		match self.remove_at(root_handle, &mut key, &mut old_val)? {
+			Some((handle, _changed)) => {
+				#[cfg(feature = "std")]
+				trace!(target: "trie", "remove: altered trie={}", _changed);
+				self.root_handle = NodeHandle::InMemory(handle);
+			}
+			None => {
+				#[cfg(feature = "std")]
+				trace!(target: "trie", "remove: obliterated trie");
+				self.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
+				*self.root = L::Codec::hashed_null_node();
+			}
+		}
+
+		Ok(old_val)
+	}
+
 	// Cache a node by hash.
 	fn cache(
 		&mut self,
 		hash: TrieHash<L>,
 		key: Prefix,
 	) -> Result<StorageHandle, TrieHash<L>, CError<L>> {
-		let node_encoded = self.db.get(&hash, key)
-			.ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash)))?;
+		let node_encoded = match self.db.get(&hash, key) {
+			Some(node_encoded) => node_encoded,
+			None => {
+				trie_event!(hash = %Hex(hash.as_ref()), "node missing");
+				self.meter.db_miss();
+				if hash == *self.root {
+					return Err(Box::new(TrieError::InvalidStateRoot(hash)));
+				}
+				return Err(Box::new(TrieError::IncompleteDatabase(hash)));
+			}
+		};
+		self.meter.node_read(node_encoded.len());
+		self.meter.node_decoded(node_encoded.len());
+		trie_event!(hash = %Hex(hash.as_ref()), len = node_encoded.len(), "node decoded");
 		let node = Node::from_encoded::<L::Codec, L::Hash>(
 			hash,
 			&node_encoded,
+			decode_options::<L>(),
 			&*self.db,
 			&mut self.storage
 		)?;
@@ -512,15 +898,19 @@ where
 				Action::Replace(node) => Some((Stored::New(node), true)),
 				Action::Delete => None,
 			},
-			Stored::Cached(node, hash) => match inspector(self, node, key)? {
-				Action::Restore(node) => Some((Stored::Cached(node, hash), false)),
-				Action::Replace(node) => {
-					self.death_row.insert((hash, key.left_owned()));
-					Some((Stored::New(node), true))
-				}
-				Action::Delete => {
-					self.death_row.insert((hash, key.left_owned()));
-					None
+			Stored::Cached(node, hash) => {
+				// the inspector advances `key` past the node.
+				let prefix = key.left_owned();
+				match inspector(self, node, key)? {
+					Action::Restore(node) => Some((Stored::Cached(node, hash), false)),
+					Action::Replace(node) => {
+						self.kill(hash, prefix);
+						Some((Stored::New(node), true))
+					}
+					Action::Delete => {
+						self.kill(hash, prefix);
+						None
+					}
 				}
 			},
 		})
@@ -537,16 +927,15 @@ where
 		let mut handle = handle;
 		loop {
 			let (mid, child) = match *handle {
-				NodeHandle::Hash(ref hash) => return Lookup::<L, _> {
-					db: &self.db,
-					query: |v: &[u8]| v.to_vec(),
-					hash: hash.clone(),
-				}.look_up(partial),
+				NodeHandle::Hash(ref hash) =>
+					return Lookup::<L, _>::new(&self.db, |v: &[u8]| DBValue::from(v), *hash)
+						.with_meter(self.meter)
+						.look_up(partial),
 				NodeHandle::InMemory(ref handle) => match self.storage[handle] {
 					Node::Empty => return Ok(None),
 					Node::Leaf(ref key, ref value) => {
 						if NibbleSlice::from_stored(key) == partial {
-							return Ok(Some(value.to_vec()));
+							return Ok(Some(value.clone()));
 						} else {
 							return Ok(None);
 						}
@@ -561,7 +950,7 @@ where
 					},
 					Node::Branch(ref children, ref value) => {
 						if partial.is_empty() {
-							return Ok(value.as_ref().map(|v| v.to_vec()));
+							return Ok(value.clone());
 						} else {
 							let idx = partial.at(0);
 							match children[idx as usize].as_ref() {
@@ -572,16 +961,16 @@ where
 					},
 					Node::NibbledBranch(ref slice, ref children, ref value) => {
 						let slice = NibbleSlice::from_stored(slice);
-						if partial.is_empty() {
-							return Ok(value.as_ref().map(|v| v.to_vec()));
-						} else if partial.starts_with(&slice) {
-							let idx = partial.at(0);
+						if !partial.starts_with(&slice) {
+							return Ok(None)
+						} else if partial.len() == slice.len() {
+							return Ok(value.clone());
+						} else {
+							let idx = partial.at(slice.len());
 							match children[idx as usize].as_ref() {
 								Some(child) => (1 + slice.len(), child),
 								None => return Ok(None),
 							}
-						} else {
-							return Ok(None)
 						}
 					},
 				}
@@ -592,6 +981,91 @@ where
 		}
 	}
 
+	/// Walk the trie to `key` like `lookup`, loading the nodes read from the database into
+	/// memory, so that an insertion at `key` following it does not read them again.
+	fn lookup_cached(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let mut key = NibbleSlice::new(key);
+		let mut handle = match self.root_handle {
+			NodeHandle::Hash(hash) => {
+				let handle = self.cache(hash, EMPTY_PREFIX)?;
+				let index = handle.0;
+				self.root_handle = NodeHandle::InMemory(handle);
+				index
+			},
+			NodeHandle::InMemory(ref handle) => handle.0,
+		};
+		loop {
+			// Number of nibbles consumed by the node, and index of the child to descend into.
+			let (mid, index) = match self.storage[&StorageHandle(handle)] {
+				Node::Empty => return Ok(None),
+				Node::Leaf(ref partial, ref value) => {
+					let found = NibbleSlice::from_stored(partial) == key;
+					return Ok(if found { Some(value.clone()) } else { None });
+				},
+				Node::Extension(ref partial, _) => {
+					let partial = NibbleSlice::from_stored(partial);
+					if !key.starts_with(&partial) {
+						return Ok(None);
+					}
+					(partial.len(), None)
+				},
+				Node::Branch(ref children, ref value) => {
+					if key.is_empty() {
+						return Ok(value.clone());
+					}
+					let index = key.at(0);
+					if children[index as usize].is_none() {
+						return Ok(None);
+					}
+					(1, Some(index))
+				},
+				Node::NibbledBranch(ref partial, ref children, ref value) => {
+					let partial = NibbleSlice::from_stored(partial);
+					if !key.starts_with(&partial) {
+						return Ok(None);
+					}
+					if key.len() == partial.len() {
+						return Ok(value.clone());
+					}
+					let index = key.at(partial.len());
+					if children[index as usize].is_none() {
+						return Ok(None);
+					}
+					(partial.len() + 1, Some(index))
+				},
+			};
+			key.advance(mid);
+			let child = match *self.child_mut(handle, index) {
+				NodeHandle::InMemory(ref child) => Ok(child.0),
+				NodeHandle::Hash(hash) => Err(hash),
+			};
+			handle = match child {
+				Ok(child) => child,
+				Err(hash) => {
+					let child = self.cache(hash, key.left())?;
+					let child_index = child.0;
+					*self.child_mut(handle, index) = NodeHandle::InMemory(child);
+					child_index
+				},
+			};
+		}
+	}
+
+	/// Get the child at `index` of the branch stored at `handle`, or the child of the
+	/// extension stored at `handle` for `None`.
+	fn child_mut(&mut self, handle: usize, index: Option<u8>) -> &mut NodeHandle<TrieHash<L>> {
+		let node = match self.storage.nodes[handle] {
+			Stored::New(ref mut node) | Stored::Cached(ref mut node, _) => node,
+		};
+		match (node, index) {
+			(Node::Extension(_, child), None) => child,
+			(Node::Branch(children, _), Some(index))
+				| (Node::NibbledBranch(_, children, _), Some(index)) =>
+				children[index as usize].as_mut().expect("Child checked by the caller; qed"),
+			_ => unreachable!("Node checked by the caller; qed"),
+		}
+	}
+
 	/// Insert a key-value pair into the trie, creating new nodes if necessary.
 	fn insert_at(
 		&mut self,
@@ -1001,7 +1475,8 @@ where
 				// always replace since we took the value out.
 				Action::Replace(self.fix(Node::Branch(children, None), key.clone())?)
 			},
-			(Node::NibbledBranch(n, children, Some(val)), true) => {
+			(Node::NibbledBranch(n, children, Some(val)), true)
+				if NibbleSlice::from_stored(&n).is_empty() => {
 				*old_val = Some(val);
 				// always replace since we took the value out.
 				Action::Replace(self.fix(Node::NibbledBranch(n, children, None), key.clone())?)
@@ -1041,7 +1516,7 @@ where
 					Action::Restore(Node::Branch(children, value))
 				}
 			},
-			(Node::NibbledBranch(encoded, mut children, value), false) => {
+			(Node::NibbledBranch(encoded, mut children, value), _) => {
 				let (common, existing_length) = {
 					let existing_key = NibbleSlice::from_stored(&encoded);
 					(existing_key.common_prefix(&partial), existing_key.len())
@@ -1265,10 +1740,7 @@ where
 						let child_node = match stored {
 							Stored::New(node) => node,
 							Stored::Cached(node, hash) => {
-								self.death_row.insert((
-									hash,
-									(child_prefix.0[..].into(), child_prefix.1),
-								));
+								self.kill(hash, (child_prefix.0[..].into(), child_prefix.1));
 								node
 							},
 						};
@@ -1315,21 +1787,16 @@ where
 				}
 			},
 			Node::Extension(partial, child) => {
-				// We could advance key, but this code can also be called
-				// recursively, so there might be some prefix from branch.
-				let last = partial.1[partial.1.len() - 1] & (255 >> 4);
-				let mut key2 = key.clone();
-				key2.advance((partial.1.len() * nibble_ops::NIBBLE_PER_BYTE) - partial.0 - 1);
-				let (start, alloc_start, prefix_end) = match key2.left() {
-					(start, None) => (start, None, Some(nibble_ops::push_at_left(0, last, 0))),
-					(start, Some(v)) => {
-						let mut so: BackingByteVec = start.into();
-						// Complete last byte with `last`.
-						so.push(nibble_ops::pad_left(v) | last);
-						(start, Some(so), None)
-					},
-				};
-				let child_prefix = (alloc_start.as_ref().map(|start| &start[..]).unwrap_or(start), prefix_end);
+				// `key` is positioned at the start of the extension, but does not
+				// necessarily cover its partial: extensions built from a branch or
+				// combined with a child extension go past the key being removed.
+				let (start, last) = key.left();
+				let mut child_key = NibbleVec::from(NibbleSlice::new(start));
+				if let Some(nibble) = last {
+					child_key.push(nibble >> nibble_ops::BIT_PER_NIBBLE);
+				}
+				child_key.append_partial(NibbleSlice::from_stored(&partial).right());
+				let child_prefix = child_key.as_prefix();
 
 				let stored = match child {
 					NodeHandle::InMemory(h) => self.storage.destroy(h),
@@ -1349,9 +1816,7 @@ where
 						// combine with node below.
 						if let Some(hash) = maybe_hash {
 							// delete the cached child since we are going to replace it.
-							self.death_row.insert(
-								(hash, (child_prefix.0[..].into(), child_prefix.1)),
-							);
+							self.kill(hash, (child_prefix.0[..].into(), child_prefix.1));
 						}
 						// subpartial
 						let mut partial = partial;
@@ -1368,7 +1833,7 @@ where
 						// combine with node below.
 						if let Some(hash) = maybe_hash {
 							// delete the cached child since we are going to replace it.
-							self.death_row.insert((hash, (child_prefix.0[..].into(), child_prefix.1)));
+							self.kill(hash, (child_prefix.0[..].into(), child_prefix.1));
 						}
 						// subpartial oly
 						let mut partial = partial;
@@ -1400,41 +1865,123 @@ where
 		}
 	}
 
+	/// Check that the layout was found encodable on creation and that a leaf
+	/// for `key` can be encoded. Leaf keys only get checked once per length.
+	fn check_encodable(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		if !self.encodable_layout {
+			check_layout::<L>().map_err(|e| Box::new(TrieError::EncoderError(e)))?;
+			self.encodable_layout = true;
+		}
+		if key.len() > self.encodable_key_len {
+			L::Codec::leaf_node(NibbleSlice::new(key).right(), &[])
+				.map_err(|e| Box::new(TrieError::EncoderError(e)))?;
+			self.encodable_key_len = key.len();
+		}
+		Ok(())
+	}
+
 	/// Commit the in-memory changes to disk, freeing their storage and
 	/// updating the state root.
+	///
+	/// Encoding errors cannot happen for keys and layouts accepted on insertion;
+	/// should one still occur it is logged and the root is left unchanged. Use
+	/// `try_commit` to get the error.
 	pub fn commit(&mut self) {
+		if let Err(_e) = self.try_commit() {
+			#[cfg(feature = "std")]
+			error!(target: "trie", "Failed to commit trie changes: {:?}", _e);
+		}
+	}
+
+	/// Commit the in-memory changes to disk, freeing their storage and
+	/// updating the state root.
+	///
+	/// On error the root is left unchanged, but nodes may have been written to
+	/// or removed from the database and the trie should not be used further.
+	pub fn try_commit(&mut self) -> Result<(), TrieHash<L>, CError<L>> {
 		#[cfg(feature = "std")]
 		trace!(target: "trie", "Committing trie changes to db.");
+		trie_span!("commit", removed = self.death_row.len());
+		debug_assert!(
+			self.validate().is_ok(),
+			"mutations keep the trie structurally valid; qed",
+		);
+
+		let root_is_new = match self.root_handle {
+			NodeHandle::InMemory(StorageHandle(x)) => match self.storage.nodes[x] {
+				Stored::New(_) => true,
+				Stored::Cached(..) => false,
+			},
+			NodeHandle::Hash(_) => false,
+		};
+		if root_is_new || !self.death_row.is_empty() {
+			// savepoints are invalidated, their journals no longer needed.
+			self.savepoints.clear();
+			self.storage.clear_journal();
+			self.death_row_journal = None;
+		}
 
 		// always kill all the nodes on death row.
 		#[cfg(feature = "std")]
 		trace!(target: "trie", "{:?} nodes to remove from db", self.death_row.len());
 		for (hash, prefix) in self.death_row.drain() {
-			self.db.remove(&hash, (&prefix.0[..], prefix.1));
+			if !self.hashes_only {
+				self.db.remove(&hash, (&prefix.0[..], prefix.1));
+			}
 		}
 
 		let handle = match self.root_handle() {
-			NodeHandle::Hash(_) => return, // no changes necessary.
+			NodeHandle::Hash(_) => return Ok(()), // no changes necessary.
 			NodeHandle::InMemory(h) => h,
 		};
 
 		match self.storage.destroy(handle) {
 			Stored::New(node) => {
+				let retained = if self.retain_nodes { Some(node.duplicate()) } else { None };
 				let mut k = NibbleVec::new();
-				let encoded_root = node.into_encoded::<_, L::Codec, L::Hash>(
-					|child, o_slice, o_index| {
-						let mov = k.append_optional_slice_and_nibble(o_slice, o_index);
-						let cr = self.commit_child(child, &mut k);
-						k.drop_lasts(mov);
-						cr
-					}
-				);
+				let mut encoded_root = self.encode_buffers.pop().unwrap_or_default();
+				let parts_hash = if self.hashes_only {
+					Self::hash_leaf_parts(&node, &mut encoded_root)
+				} else {
+					Ok(None)
+				};
+				let encoded = match parts_hash {
+					Ok(None) => node.encode_into::<_, L::Codec, L::Hash>(
+						&mut encoded_root,
+						|child, o_slice, o_index| {
+							let mov = k.append_optional_slice_and_nibble(o_slice, o_index);
+							let cr = self.commit_child(child, &mut k);
+							k.drop_lasts(mov);
+							cr
+						}
+					).map(|()| None),
+					parts_hash => parts_hash,
+				};
+				let parts_hash = match encoded {
+					Ok(parts_hash) => parts_hash,
+					Err(e) => {
+						self.root_handle = NodeHandle::Hash(*self.root);
+						return Err(Box::new(TrieError::EncoderError(e)));
+					},
+				};
 				#[cfg(feature = "std")]
 				trace!(target: "trie", "encoded root node: {:#x?}", &encoded_root[..]);
-				*self.root = self.db.insert(EMPTY_PREFIX, &encoded_root[..]);
+				*self.root = match parts_hash {
+					Some(hash) => hash,
+					None if self.hashes_only => L::Hash::hash(&encoded_root[..]),
+					None => self.db.insert(EMPTY_PREFIX, &encoded_root[..]),
+				};
+				encoded_root.clear();
+				self.encode_buffers.push(encoded_root);
 				self.hash_count += 1;
+				trie_event!(root = %Hex(self.root.as_ref()), hash_count = self.hash_count, "committed root");
 
-				self.root_handle = NodeHandle::Hash(*self.root);
+				self.root_handle = match retained {
+					Some(node) => NodeHandle::InMemory(
+						self.storage.alloc(Stored::Cached(node, *self.root)),
+					),
+					None => NodeHandle::Hash(*self.root),
+				};
 			}
 			Stored::Cached(node, hash) => {
 				// probably won't happen, but update the root and move on.
@@ -1444,53 +1991,209 @@ where
 				);
 			}
 		}
+		Ok(())
+	}
+
+	/// Hash a leaf with a value of at least `STREAM_HASH_THRESHOLD` bytes in parts, writing
+	/// its encoding up to the value to `buffer`, for commits with `hashes_only`.
+	/// Returns `None` with `buffer` left empty for other nodes, or if the codec cannot
+	/// encode the leaf up to its value.
+	fn hash_leaf_parts(
+		node: &Node<TrieHash<L>>,
+		buffer: &mut Vec<u8>,
+	) -> result::Result<Option<TrieHash<L>>, CError<L>> {
+		let (partial, value) = match node {
+			Node::Leaf(partial, value) if value.len() >= L::STREAM_HASH_THRESHOLD => (partial, value),
+			_ => return Ok(None),
+		};
+		let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
+		if !L::Codec::leaf_node_head_into(buffer, pr.right_iter(), pr.len(), value.len())? {
+			return Ok(None);
+		}
+		if buffer.len() + value.len() < L::Hash::LENGTH {
+			// inlined in its parent, the encoding is needed.
+			buffer.clear();
+			return Ok(None);
+		}
+		Ok(Some(hash_parts::<L::Hash>(&[&buffer[..], &value[..]])))
 	}
 
 	/// Commit a node by hashing it and writing it to the db. Returns a
 	/// `ChildReference` which in most cases carries a normal hash but for the
 	/// case where we can fit the actual data in the `Hasher`s output type, we
 	/// store the data inline. This function is used as the callback to the
-	/// `into_encoded` method of `Node`.
+	/// `encode_into` method of `Node`.
 	fn commit_child(
 		&mut self,
 		handle: NodeHandle<TrieHash<L>>,
 		prefix: &mut NibbleVec,
-	) -> ChildReference<TrieHash<L>> {
+	) -> result::Result<ChildReference<TrieHash<L>>, CError<L>> {
 		match handle {
-			NodeHandle::Hash(hash) => ChildReference::Hash(hash),
+			NodeHandle::Hash(hash) => Ok(ChildReference::Hash(hash)),
 			NodeHandle::InMemory(storage_handle) => {
-				match self.storage.destroy(storage_handle) {
-					Stored::Cached(_, hash) => ChildReference::Hash(hash),
+				let (child_ref, retained) = match self.storage.take(&storage_handle) {
+					Stored::Cached(node, hash) =>
+						(ChildReference::Hash(hash), Some(Stored::Cached(node, hash))),
 					Stored::New(node) => {
-						let encoded = {
-							let commit_child = |
-								node_handle,
-								o_slice: Option<&NibbleSlice>,
-								o_index: Option<u8>
-							| {
-								let mov = prefix.append_optional_slice_and_nibble(o_slice, o_index);
-								let cr = self.commit_child(node_handle, prefix);
-								prefix.drop_lasts(mov);
-								cr
-							};
-							node.into_encoded::<_, L::Codec, L::Hash>(commit_child)
+						let retained = if self.retain_nodes { Some(node.duplicate()) } else { None };
+						let mut encoded = self.encode_buffers.pop().unwrap_or_default();
+						let parts_hash = if self.hashes_only {
+							Self::hash_leaf_parts(&node, &mut encoded)?
+						} else {
+							None
 						};
-						if encoded.len() >= L::Hash::LENGTH {
-							let hash = self.db.insert(prefix.as_prefix(), &encoded[..]);
-							self.hash_count +=1;
+						let child_ref = if let Some(hash) = parts_hash {
+							self.hash_count += 1;
+							trie_event!(
+								hash = %Hex(hash.as_ref()),
+								nibbles = prefix.len(),
+								"committed leaf in parts"
+							);
 							ChildReference::Hash(hash)
 						} else {
-							// it's a small value, so we cram it into a `TrieHash<L>`
-							// and tag with length
-							let mut h = <TrieHash<L>>::default();
-							let len = encoded.len();
-							h.as_mut()[..len].copy_from_slice(&encoded[..len]);
-							ChildReference::Inline(h, len)
-						}
+							{
+								let commit_child = |
+									node_handle,
+									o_slice: Option<&NibbleSlice>,
+									o_index: Option<u8>
+								| {
+									let mov = prefix.append_optional_slice_and_nibble(o_slice, o_index);
+									let cr = self.commit_child(node_handle, prefix);
+									prefix.drop_lasts(mov);
+									cr
+								};
+								node.encode_into::<_, L::Codec, L::Hash>(&mut encoded, commit_child)?;
+							}
+							if encoded.len() >= L::Hash::LENGTH {
+								#[cfg(feature = "tracing")]
+								let len = encoded.len();
+								let hash = if self.hashes_only {
+									L::Hash::hash(&encoded[..])
+								} else {
+									// copy the encoded node once into the db value, keeping
+									// the pooled buffer for the next node.
+									self.db.emplace_with(
+										prefix.as_prefix(),
+										&mut |value| *value = DBValue::from(&encoded[..]),
+									)
+								};
+								self.hash_count +=1;
+								trie_event!(
+									hash = %Hex(hash.as_ref()),
+									nibbles = prefix.len(),
+									len = len,
+									"committed node"
+								);
+								ChildReference::Hash(hash)
+							} else {
+								// it's a small value, so we cram it into a `TrieHash<L>`
+								// and tag with length
+								let mut h = <TrieHash<L>>::default();
+								let len = encoded.len();
+								h.as_mut()[..len].copy_from_slice(&encoded[..len]);
+								ChildReference::Inline(h, len)
+							}
+						};
+						encoded.clear();
+						self.encode_buffers.push(encoded);
+						// inline nodes have no hash and stay new, they are encoded again
+						// with their parent.
+						let retained = retained.map(|node| match child_ref {
+							ChildReference::Hash(hash) => Stored::Cached(node, hash),
+							ChildReference::Inline(..) => Stored::New(node),
+						});
+						(child_ref, retained)
+					}
+				};
+				match retained {
+					Some(stored) if self.retain_nodes => self.storage.restore(&storage_handle, stored),
+					_ => self.storage.free(storage_handle),
+				}
+				Ok(child_ref)
+			}
+		}
+	}
+
+	/// Check the structural invariants of the nodes held in memory: nodes still
+	/// in the database are not loaded. Mutations always leave the trie valid, so
+	/// an error here points to a bug in the mutation logic.
+	pub fn validate(&self) -> result::Result<(), InvariantError> {
+		match self.root_handle {
+			NodeHandle::Hash(_) => Ok(()),
+			NodeHandle::InMemory(ref handle) => self.validate_node(handle, &mut NibbleVec::new(), true),
+		}
+	}
+
+	fn validate_node(
+		&self,
+		handle: &StorageHandle,
+		path: &mut NibbleVec,
+		is_root: bool,
+	) -> result::Result<(), InvariantError> {
+		let check_partial = |key: &NodeKey, path: &NibbleVec| {
+			if key.0 >= nibble_ops::NIBBLE_PER_BYTE || (key.0 != 0 && key.1.is_empty()) {
+				return Err(InvariantError::InvalidPartial(path.clone()));
+			}
+			if key.0 != 0 && nibble_ops::pad_left(key.1[0]) != 0 {
+				return Err(InvariantError::BadPadding(path.clone()));
+			}
+			Ok(())
+		};
+		let (partial, children) = match self.storage[handle] {
+			Node::Empty if is_root => return Ok(()),
+			Node::Empty => return Err(InvariantError::EmptyNode(path.clone())),
+			Node::Leaf(ref key, _) => {
+				check_partial(key, path)?;
+				return Ok(());
+			},
+			Node::Extension(ref key, ref child) => {
+				if !L::USE_EXTENSION {
+					return Err(InvariantError::UnexpectedNode(path.clone()));
+				}
+				check_partial(key, path)?;
+				let partial = NibbleSlice::from_stored(key);
+				if partial.is_empty() {
+					return Err(InvariantError::EmptyExtension(path.clone()));
+				}
+				if let NodeHandle::InMemory(ref child) = *child {
+					match self.storage[child] {
+						Node::Branch(..) => (),
+						_ => return Err(InvariantError::ExtensionChild(path.clone())),
 					}
+					let mov = path.append_optional_slice_and_nibble(Some(&partial), None);
+					self.validate_node(child, path, false)?;
+					path.drop_lasts(mov);
 				}
+				return Ok(());
+			},
+			Node::Branch(ref children, ref value) => {
+				if !L::USE_EXTENSION {
+					return Err(InvariantError::UnexpectedNode(path.clone()));
+				}
+				if children.iter().filter(|child| child.is_some()).count() + (value.is_some() as usize) < 2 {
+					return Err(InvariantError::RedundantBranch(path.clone()));
+				}
+				(None, children)
+			},
+			Node::NibbledBranch(ref key, ref children, ref value) => {
+				if L::USE_EXTENSION {
+					return Err(InvariantError::UnexpectedNode(path.clone()));
+				}
+				check_partial(key, path)?;
+				if children.iter().filter(|child| child.is_some()).count() + (value.is_some() as usize) < 2 {
+					return Err(InvariantError::RedundantBranch(path.clone()));
+				}
+				(Some(NibbleSlice::from_stored(key)), children)
+			},
+		};
+		for (index, child) in children.iter().enumerate() {
+			if let Some(NodeHandle::InMemory(ref child)) = *child {
+				let mov = path.append_optional_slice_and_nibble(partial.as_ref(), Some(index as u8));
+				self.validate_node(child, path, false)?;
+				path.drop_lasts(mov);
 			}
 		}
+		Ok(())
 	}
 
 	// a hack to get the root node's handle
@@ -1525,7 +2228,22 @@ where
 	fn get<'x, 'key>(&'x self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
 		where 'x: 'key
 	{
-		self.lookup(NibbleSlice::new(key), &self.root_handle)
+		let key = layout_key::<L>(key);
+		self.lookup(NibbleSlice::new(&key), &self.root_handle)
+	}
+
+	/// Nodes are read from the database only once, the lookup loading them in memory
+	/// for the insertion.
+	fn get_or_insert_with<F>(&mut self, key: &[u8], f: F) -> Result<DBValue, TrieHash<L>, CError<L>>
+		where F: FnOnce() -> DBValue, Self: Sized
+	{
+		let lookup_key = layout_key::<L>(key);
+		if let Some(value) = self.lookup_cached(&lookup_key)? {
+			return Ok(value);
+		}
+		let value = f();
+		self.insert(key, &value)?;
+		Ok(value)
 	}
 
 	fn insert(
@@ -1535,49 +2253,21 @@ where
 	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
 		if value.is_empty() { return self.remove(key) }
 
-		let mut old_val = None;
-
 		#[cfg(feature = "std")]
 		trace!(target: "trie", "insert: key={:#x?}, value={:?}", key, ToHex(&value));
 
-		let root_handle = self.root_handle();
-		let (new_handle, _changed) = self.insert_at(
-			root_handle,
-			&mut NibbleSlice::new(key),
-			value.to_vec(),
-			&mut old_val,
-		)?;
-
-		#[cfg(feature = "std")]
-		trace!(target: "trie", "insert: altered trie={}", _changed);
-		self.root_handle = NodeHandle::InMemory(new_handle);
-
-		Ok(old_val)
+		let key = layout_key::<L>(key);
+		// Check the key can be encoded now, so encoding nodes on commit cannot fail.
+		self.check_encodable(&key)?;
+		self.insert_partial(NibbleSlice::new(&key), value)
 	}
 
 	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
 		#[cfg(feature = "std")]
 		trace!(target: "trie", "remove: key={:#x?}", key);
 
-		let root_handle = self.root_handle();
-		let mut key = NibbleSlice::new(key);
-		let mut old_val = None;
-
-		match self.remove_at(root_handle, &mut key, &mut old_val)? {
-			Some((handle, _changed)) => {
-				#[cfg(feature = "std")]
-				trace!(target: "trie", "remove: altered trie={}", _changed);
-				self.root_handle = NodeHandle::InMemory(handle);
-			}
-			None => {
-				#[cfg(feature = "std")]
-				trace!(target: "trie", "remove: obliterated trie");
-				self.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
-				*self.root = L::Codec::hashed_null_node();
-			}
-		}
-
-		Ok(old_val)
+		let key = layout_key::<L>(key);
+		self.remove_partial(NibbleSlice::new(&key))
 	}
 }
 
@@ -1606,12 +2296,26 @@ fn combine_key(start: &mut NodeKey, end: (usize, &[u8])) {
 	(st..end.1.len()).for_each(|i| start.1.push(end.1[i]));
 }
 
+/// Check that the layout codec can encode the branch and extension nodes the
+/// layout uses.
+fn check_layout<L: TrieLayout>() -> result::Result<(), CError<L>> {
+	let no_children = [None; nibble_ops::NIBBLE_LENGTH];
+	if L::USE_EXTENSION {
+		let child = ChildReference::Inline(Default::default(), 0);
+		L::Codec::extension_node(iter::empty(), 0, child)?;
+		L::Codec::branch_node(no_children.iter(), None)?;
+	} else {
+		L::Codec::branch_node_nibbled(iter::empty(), 0, no_children.iter(), None)?;
+	}
+	Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 	use env_logger;
 	use trie_standardmap::*;
 	use log::debug;
-	use crate::DBValue;
+	use reference_trie::DBValue;
 	use memory_db::{MemoryDB, PrefixedKey};
 	use hash_db::{Hasher, HashDB};
 	use keccak_hasher::KeccakHasher;
@@ -1775,6 +2479,128 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn rollback_to_savepoint() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		t.insert(&[0x01u8, 0x23], &[0x01u8; 40]).unwrap();
+		t.insert(&[0x01u8, 0x24], &[0x02u8; 40]).unwrap();
+		t.commit();
+
+		let savepoint = t.savepoint();
+		t.insert(&[0x01u8, 0x25], &[0x03u8; 40]).unwrap();
+		let nested = t.savepoint();
+		t.remove(&[0x01u8, 0x23]).unwrap();
+		t.insert(&[0x02u8], &[0x04u8]).unwrap();
+		assert!(t.rollback_to(nested));
+		assert_eq!(t.get(&[0x01u8, 0x23]).unwrap(), Some(vec![0x01u8; 40].into()));
+		assert_eq!(t.get(&[0x01u8, 0x25]).unwrap(), Some(vec![0x03u8; 40].into()));
+		assert_eq!(t.get(&[0x02u8]).unwrap(), None);
+		assert!(t.rollback_to(savepoint));
+		assert_eq!(t.get(&[0x01u8, 0x25]).unwrap(), None);
+		assert_eq!(
+			*t.root(),
+			reference_trie_root(vec![
+				(vec![0x01u8, 0x23], vec![0x01u8; 40]),
+				(vec![0x01u8, 0x24], vec![0x02u8; 40]),
+			]),
+		);
+
+		// committed changes cannot be rolled back
+		let savepoint = t.savepoint();
+		t.remove(&[0x01u8, 0x24]).unwrap();
+		let committed = *t.root();
+		assert!(!t.rollback_to(savepoint));
+		assert_eq!(*t.root(), committed);
+		assert_eq!(t.get(&[0x01u8, 0x24]).unwrap(), None);
+	}
+
+	#[test]
+	fn rollback_to_outer_invalidates_nested_savepoint() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		t.insert(&[0x01u8, 0x23], &[0x01u8; 40]).unwrap();
+		t.commit();
+		let committed = *t.root();
+
+		let outer = t.savepoint();
+		t.insert(&[0x01u8, 0x24], &[0x02u8; 40]).unwrap();
+		let nested = t.savepoint();
+		t.insert(&[0x01u8, 0x25], &[0x03u8; 40]).unwrap();
+		assert!(t.rollback_to(outer));
+		// changes made after the rollback grow the journals past the nested savepoint.
+		let later = t.savepoint();
+		for i in 0..8u8 {
+			t.insert(&[0x02u8, i], &[i; 40]).unwrap();
+		}
+		assert!(!t.rollback_to(nested));
+		assert_eq!(t.get(&[0x01u8, 0x24]).unwrap(), None);
+		assert_eq!(t.get(&[0x02u8, 0x07]).unwrap(), Some(vec![0x07u8; 40].into()));
+		assert!(t.rollback_to(later));
+		assert_eq!(*t.root(), committed);
+		assert_eq!(t.get(&[0x01u8, 0x23]).unwrap(), Some(vec![0x01u8; 40].into()));
+	}
+
+	#[test]
+	fn rollback_removal_of_all_keys() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(&[0x01u8, 0x23], &[0x01u8; 40]).unwrap();
+			t.insert(&[0x01u8, 0x24], &[0x02u8; 40]).unwrap();
+		}
+		let expected = root;
+
+		let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+		let savepoint = t.savepoint();
+		t.remove(&[0x01u8, 0x23]).unwrap();
+		t.remove(&[0x01u8, 0x24]).unwrap();
+		assert!(t.is_empty());
+		assert!(t.rollback_to(savepoint));
+		assert_eq!(*t.root(), expected);
+		assert_eq!(t.get(&[0x01u8, 0x23]).unwrap(), Some(vec![0x01u8; 40].into()));
+	}
+
+	#[test]
+	fn rollback_retained_nodes() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 5,
+			journal_key: 0,
+			value_mode: ValueMode::Random,
+			count: 200,
+		}.make_with(&mut seed);
+		let (committed, changes) = x.split_at(100);
+
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut db, &mut root).with_retained_nodes();
+		for (key, value) in committed {
+			t.insert(key, value).unwrap();
+		}
+		let expected = *t.root();
+
+		let savepoint = t.savepoint();
+		for ((key, value), (removed, _)) in changes.iter().zip(committed.iter().step_by(2)) {
+			t.insert(key, value).unwrap();
+			t.remove(removed).unwrap();
+		}
+		assert!(t.rollback_to(savepoint));
+		assert_eq!(*t.root(), expected);
+		for (key, value) in committed {
+			assert_eq!(t.get(key).unwrap().as_deref(), Some(&value[..]));
+		}
+		for (key, _) in changes {
+			if committed.iter().all(|(committed, _)| committed != key) {
+				assert_eq!(t.get(key).unwrap(), None);
+			}
+		}
+	}
+
 	#[test]
 	fn remove_to_empty() {
 		let big_value = b"00000000000000000000000000000000";
@@ -1866,6 +2692,24 @@ mod tests {
 		]));
 	}
 
+	#[test]
+	fn nibbled_branch_value_under_partial_key() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+		t.insert(&[0x33u8, 0x00], &[0x0]).unwrap();
+		t.insert(&[0x33u8], &[0x1]).unwrap();
+		// the root branch holds the value of `0x33`, under a non-empty partial key.
+		assert_eq!(t.get(&[]).unwrap(), None);
+		assert_eq!(t.get(&[0x33u8]).unwrap(), Some(DBValue::from(&[0x1u8][..])));
+		assert_eq!(t.remove(&[]).unwrap(), None);
+		assert_eq!(t.get(&[0x33u8]).unwrap(), Some(DBValue::from(&[0x1u8][..])));
+		assert_eq!(*t.root(), reference_trie_root_no_extension(vec![
+			(vec![0x33u8, 0x00], vec![0x0]),
+			(vec![0x33u8], vec![0x1]),
+		]));
+	}
+
 	#[test]
 	fn insert_split_leaf() {
 		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
@@ -1910,6 +2754,73 @@ mod tests {
 		]));
 	}
 
+	#[test]
+	fn insert_too_long_key_fails() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		t.insert(&[0x01u8; 63], b"value").unwrap();
+		match t.insert(&[0x01u8; 64], b"value").map_err(|e| *e) {
+			Err(reference_trie::TrieError::EncoderError(_)) => {},
+			result => panic!("expected EncoderError, got {:?}", result),
+		}
+		assert_eq!(*t.root(), reference_trie_root(vec![(vec![0x01u8; 63], b"value".to_vec())]));
+	}
+
+	#[test]
+	fn insert_with_misconfigured_layout_fails() {
+		use reference_trie::TrieDBMut;
+
+		struct MisconfiguredLayout;
+
+		impl reference_trie::TrieLayout for MisconfiguredLayout {
+			const USE_EXTENSION: bool = true;
+			type Hash = KeccakHasher;
+			type Codec = reference_trie::ReferenceNodeCodecNoExt<KeccakHasher>;
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<MisconfiguredLayout>::new(&mut memdb, &mut root);
+			match t.insert(b"key", b"value").map_err(|e| *e) {
+				Err(reference_trie::TrieError::EncoderError(_)) => {},
+				result => panic!("expected EncoderError, got {:?}", result),
+			}
+			assert!(t.remove(b"key").is_err());
+		}
+		let result = TrieDBMut::<MisconfiguredLayout>::from_existing(&mut memdb, &mut root);
+		match result.err().map(|e| *e) {
+			Some(reference_trie::TrieError::EncoderError(_)) => {},
+			e => panic!("expected EncoderError, got {:?}", e),
+		}
+	}
+
+	#[test]
+	fn versioned_layout_reads_older_nodes() {
+		use reference_trie::{NoExtensionLayoutV1, TrieDB, TrieDBMut, Trie};
+
+		let x = vec![
+			(b"alfa".to_vec(), vec![1; 40]),
+			(b"bravo".to_vec(), vec![2; 40]),
+			(b"do".to_vec(), vec![3; 40]),
+		];
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		populate_trie_no_extension(&mut memdb, &mut root, &x).commit();
+
+		{
+			let mut t = TrieDBMut::<NoExtensionLayoutV1>::from_existing(&mut memdb, &mut root)
+				.unwrap();
+			t.insert(b"dog", &[4; 40]).unwrap();
+		}
+
+		let t = TrieDB::<NoExtensionLayoutV1>::new(&memdb, &root).unwrap();
+		for (key, value) in x.iter().chain(Some(&(b"dog".to_vec(), vec![4; 40]))) {
+			assert_eq!(t.get(key).unwrap().as_deref(), Some(&value[..]));
+		}
+	}
+
 	#[test]
 	fn insert_duplicate_value() {
 		let big_value = b"00000000000000000000000000000000";
@@ -2060,14 +2971,326 @@ mod tests {
 		let mut t = RefTrieDBMut::new(&mut db, &mut root);
 		for &(ref key, ref value) in &x {
 			assert!(t.insert(key, value).unwrap().is_none());
-			assert_eq!(t.insert(key, value).unwrap(), Some(value.clone()));
+			assert_eq!(t.insert(key, value).unwrap(), Some(value.clone().into()));
 		}
 		for (key, value) in x {
-			assert_eq!(t.remove(&key).unwrap(), Some(value));
+			assert_eq!(t.remove(&key).unwrap(), Some(value.into()));
 			assert!(t.remove(&key).unwrap().is_none());
 		}
 	}
 
+	#[test]
+	fn committed_nodes_are_canonical() {
+		use reference_trie::ReferenceNodeCodecNoExt;
+
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+			min_key: 5,
+			journal_key: 0,
+			value_mode: ValueMode::Index,
+			count: 100,
+		}.make_with(&mut Default::default());
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		populate_trie(&mut memdb, &mut root, &x).commit();
+		for (_, (node, _)) in memdb.drain() {
+			assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::is_canonical(&node));
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		populate_trie_no_extension(&mut memdb, &mut root, &x).commit();
+		for (_, (node, _)) in memdb.drain() {
+			assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::is_canonical(&node));
+		}
+	}
+
+	#[test]
+	fn mutations_keep_invariants() {
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+			min_key: 2,
+			journal_key: 0,
+			value_mode: ValueMode::Index,
+			count: 200,
+		}.make_with(&mut Default::default());
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = populate_trie(&mut memdb, &mut root, &x);
+		assert_eq!(t.validate(), Ok(()));
+		for (key, _) in x.iter().step_by(2) {
+			t.remove(key).unwrap();
+			assert_eq!(t.validate(), Ok(()));
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = populate_trie_no_extension(&mut memdb, &mut root, &x);
+		assert_eq!(t.validate(), Ok(()));
+		for (key, _) in x.iter().step_by(2) {
+			t.remove(key).unwrap();
+			assert_eq!(t.validate(), Ok(()));
+		}
+	}
+
+	#[test]
+	fn validate_detects_bad_padding() {
+		use reference_trie::{InvariantError, NibbleVec};
+
+		// root leaf with the 3 nibbles partial key 1, 2, 3 and a non-zero padding nibble.
+		let mut encoded = <ReferenceNodeCodec<KeccakHasher> as NodeCodec>
+			::leaf_node(((1, 0x01), &[0x23][..]), &[7; 4]).unwrap();
+		encoded[1] |= 0xf0;
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = memdb.insert(hash_db::EMPTY_PREFIX, &encoded[..]);
+		let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+		let mut key = NibbleVec::new();
+		[1, 2, 3].iter().for_each(|nibble| key.push(*nibble));
+		assert_eq!(t.insert_nibbles(&key, &[8; 4]).unwrap(), Some(vec![7; 4].into()));
+		assert_eq!(t.validate(), Err(InvariantError::BadPadding(NibbleVec::new())));
+		// committing checks the invariants in debug builds.
+		t.remove_nibbles(&key).unwrap();
+		assert_eq!(t.validate(), Ok(()));
+	}
+
+	#[test]
+	fn nibble_keys() {
+		use reference_trie::{NibbleVec, RefTrieDB, RefTrieDBNoExt};
+
+		let keys: Vec<NibbleVec> = [&[1][..], &[1, 2], &[1, 2, 3], &[1, 2, 3, 4], &[1, 3], &[2], &[2, 0, 0]]
+			.iter()
+			.map(|nibbles| {
+				let mut key = NibbleVec::new();
+				nibbles.iter().for_each(|nibble| key.push(*nibble));
+				key
+			})
+			.collect();
+		let value = |i: usize| vec![i as u8; 1 + i * 8];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (i, key) in keys.iter().enumerate() {
+				assert_eq!(t.insert_nibbles(key, &value(i)).unwrap(), None);
+				assert_eq!(t.validate(), Ok(()));
+			}
+			assert_eq!(t.insert(&[0x12, 0x34], b"bytes").unwrap(), Some(value(3).into()));
+		}
+		{
+			let t = RefTrieDB::new(&memdb, &root).unwrap();
+			assert_eq!(t.get_nibbles(&keys[0]).unwrap(), Some(value(0).into()));
+			assert_eq!(t.get_nibbles(&keys[3]).unwrap(), Some(b"bytes".to_vec().into()));
+			assert_eq!(t.get_nibbles(&keys[6]).unwrap(), Some(value(6).into()));
+		}
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+			for (i, key) in keys.iter().enumerate().skip(4) {
+				assert_eq!(t.get_nibbles(key).unwrap(), Some(value(i).into()));
+				assert_eq!(t.remove_nibbles(key).unwrap(), Some(value(i).into()));
+				assert_eq!(t.validate(), Ok(()));
+			}
+			for key in keys.iter().take(4) {
+				t.remove_nibbles(key).unwrap();
+			}
+			assert!(t.is_empty());
+		}
+		memdb.purge();
+		assert!(memdb.keys().is_empty());
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+			for (i, key) in keys.iter().enumerate() {
+				t.insert_nibbles(key, &value(i)).unwrap();
+				assert_eq!(t.validate(), Ok(()));
+			}
+		}
+		{
+			let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+			for (i, key) in keys.iter().enumerate() {
+				assert_eq!(t.get_nibbles(key).unwrap(), Some(value(i).into()));
+			}
+		}
+		let mut t = RefTrieDBMutNoExt::from_existing(&mut memdb, &mut root).unwrap();
+		for key in keys.iter().rev() {
+			t.remove_nibbles(key).unwrap();
+			assert_eq!(t.validate(), Ok(()));
+		}
+		assert!(t.is_empty());
+	}
+
+	#[test]
+	fn retained_nodes_survive_commits() {
+		use reference_trie::CountingMeter;
+
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+			min_key: 5,
+			journal_key: 0,
+			value_mode: ValueMode::Index,
+			count: 100,
+		}.make_with(&mut Default::default());
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut retained_memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut retained_root = Default::default();
+		let meter = CountingMeter::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			let mut retained = RefTrieDBMut::new(&mut retained_memdb, &mut retained_root)
+				.with_retained_nodes()
+				.with_meter(&meter);
+			for chunk in x.chunks(10) {
+				for (key, value) in chunk {
+					t.insert(key, value).unwrap();
+					retained.insert(key, value).unwrap();
+				}
+				t.remove(&chunk[0].0).unwrap();
+				retained.remove(&chunk[0].0).unwrap();
+				assert_eq!(retained.root(), t.root());
+				assert_eq!(retained.validate(), Ok(()));
+			}
+			for chunk in x.chunks(10) {
+				for (key, value) in &chunk[1..] {
+					assert_eq!(retained.get(key).unwrap().as_deref(), Some(&value[..]));
+				}
+			}
+		}
+		// only the empty root was read from the database.
+		assert_eq!(meter.nodes_read(), 1);
+		assert_eq!(retained_root, root);
+		assert!(retained_memdb == memdb);
+	}
+
+	#[test]
+	fn hashes_only_leaves_db_untouched() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for i in 0..20u8 {
+				t.insert(&[i, i], &[i; 40]).unwrap();
+			}
+		}
+		let original = memdb.clone();
+
+		let mut expected_memdb = memdb.clone();
+		let mut expected_root = root;
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut expected_memdb, &mut expected_root).unwrap();
+			t.insert(&[1, 1], &[0xff; 40]).unwrap();
+			t.commit();
+			t.remove(&[2, 2]).unwrap();
+		}
+
+		let mut hashes_root = root;
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut hashes_root)
+				.unwrap()
+				.with_hashes_only();
+			t.insert(&[1, 1], &[0xff; 40]).unwrap();
+			t.commit();
+			t.remove(&[2, 2]).unwrap();
+			assert_eq!(t.get(&[1, 1]).unwrap(), Some(vec![0xff; 40].into()));
+		}
+		assert_eq!(hashes_root, expected_root);
+		assert!(memdb == original);
+	}
+
+	#[test]
+	fn hashes_only_large_values() {
+		// values from `STREAM_HASH_THRESHOLD` bytes are hashed in parts
+		let data = vec![
+			(vec![0x01u8], vec![0x01u8; 5000]),
+			(vec![0x01u8, 0x23], vec![0x02u8; 5000]),
+			(vec![0x02u8], vec![0x03u8; 8]),
+		];
+		for data in [&data[..1], &data[..]].iter() {
+			let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+			let mut root = Default::default();
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root).with_hashes_only();
+			for (key, value) in data.iter() {
+				t.insert(key, value).unwrap();
+			}
+			assert_eq!(*t.root(), reference_trie_root(data.to_vec()));
+		}
+	}
+
+	#[test]
+	fn get_or_insert_with_reads_nodes_once() {
+		use reference_trie::CountingMeter;
+
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 5,
+			journal_key: 0,
+			value_mode: ValueMode::Index,
+			count: 100,
+		}.make_with(&mut Default::default());
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		populate_trie(&mut memdb, &mut root, &x);
+
+		let mut expected_memdb = memdb.clone();
+		let mut expected_root = root;
+		let expected_meter = CountingMeter::default();
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut expected_memdb, &mut expected_root)
+				.unwrap()
+				.with_meter(&expected_meter);
+			assert_eq!(t.get(b"ffffff").unwrap(), None);
+			t.insert(b"ffffff", b"new").unwrap();
+		}
+
+		let meter = CountingMeter::default();
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root)
+				.unwrap()
+				.with_meter(&meter);
+			assert_eq!(t.get_or_insert_with(b"ffffff", || b"new".to_vec().into()).unwrap(), b"new".to_vec());
+			assert_eq!(t.get_or_insert_with(b"ffffff", || unreachable!()).unwrap(), b"new".to_vec());
+			assert_eq!(t.get_or_insert_with(&x[0].0, || unreachable!()).unwrap(), x[0].1);
+		}
+		assert_eq!(root, expected_root);
+		assert!(meter.nodes_read() < expected_meter.nodes_read());
+	}
+
+	#[test]
+	fn unchecked_root_is_resolved_lazily() {
+		use reference_trie::{RefTrieDB, Trie, TrieError};
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"A", &[1; 40]).unwrap();
+			t.insert(b"B", &[2; 40]).unwrap();
+		}
+
+		let mut lazy_memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut lazy_root = root;
+		assert!(RefTrieDBMut::from_existing(&mut lazy_memdb, &mut lazy_root).is_err());
+		{
+			let t = RefTrieDB::new_unchecked(&lazy_memdb, &lazy_root);
+			assert_eq!(t.get(b"A").map_err(|e| *e), Err(TrieError::InvalidStateRoot(root)));
+		}
+		{
+			let mut t = RefTrieDBMut::from_existing_unchecked(&mut lazy_memdb, &mut lazy_root);
+			assert_eq!(t.insert(b"C", &[3]).map_err(|e| *e), Err(TrieError::InvalidStateRoot(root)));
+		}
+
+		lazy_memdb.consolidate(memdb);
+		let mut t = RefTrieDBMut::from_existing_unchecked(&mut lazy_memdb, &mut lazy_root);
+		assert_eq!(t.get(b"A").unwrap(), Some(vec![1; 40].into()));
+		t.insert(b"C", &[3]).unwrap();
+	}
+
 	#[test]
 	fn combine_test() {
 		let a: BackingByteVec = [0x12, 0x34][..].into();
@@ -2086,7 +3309,7 @@ mod tests {
 	#[test]
 	fn nice_debug_for_node() {
 		use super::Node;
-		let e: Node<u32> = Node::Leaf((1, vec![1, 2, 3].into()), vec![4, 5, 6]);
+		let e: Node<u32> = Node::Leaf((1, vec![1, 2, 3].into()), vec![4, 5, 6].into());
 		assert_eq!(format!("{:?}", e), "Leaf((1, 010203), 040506)");
 	}
 
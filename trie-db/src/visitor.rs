@@ -0,0 +1,143 @@
+// Copyright 2017, 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pre-order traversal of a trie calling back a visitor on each node.
+
+use crate::nibble::NibbleVec;
+use crate::node::{Node, NodeHandle};
+use crate::rstd::boxed::Box;
+use super::{Result, TrieDB, TrieError, TrieLayout, TrieHash, CError};
+
+/// Callbacks of `TrieDB::visit`, called in pre-order. Nodes are given with their path
+/// from the root and their hash, which is `None` for inline nodes.
+pub trait TrieVisitor<L: TrieLayout> {
+	/// Called on a branch or extension node, before its children.
+	/// Returning `false` skips its children, `exit_branch` still being called.
+	fn enter_branch(&mut self, _path: &NibbleVec, _hash: Option<&TrieHash<L>>, _node: &Node) -> bool {
+		true
+	}
+
+	/// Called on a leaf, or on the empty root node of an empty trie.
+	fn leaf(&mut self, _path: &NibbleVec, _hash: Option<&TrieHash<L>>, _node: &Node) {}
+
+	/// Called on a branch or extension node, after its children.
+	fn exit_branch(&mut self, _path: &NibbleVec, _hash: Option<&TrieHash<L>>, _node: &Node) {}
+}
+
+/// Visit the node at `path` referenced by `handle` in its parent, and its descendants.
+/// `depth` is the number of nodes traversed before it.
+pub(crate) fn visit_node<L: TrieLayout, V: TrieVisitor<L>>(
+	trie: &TrieDB<L>,
+	parent_hash: TrieHash<L>,
+	handle: NodeHandle,
+	path: &mut NibbleVec,
+	depth: usize,
+	visitor: &mut V,
+) -> Result<(), TrieHash<L>, CError<L>> {
+	let (node, hash) = trie.get_raw_or_lookup(parent_hash, handle, path.as_prefix())?;
+	let node_hash = hash.unwrap_or(parent_hash);
+	if trie.max_depth().map_or(false, |max_depth| depth >= max_depth) {
+		return Err(Box::new(TrieError::MaxDepthExceeded(node_hash)));
+	}
+	let decoded = node.node();
+	match decoded {
+		Node::Empty | Node::Leaf(..) => {
+			visitor.leaf(path, hash.as_ref(), &decoded);
+			return Ok(());
+		},
+		Node::Extension(..) | Node::Branch(..) | Node::NibbledBranch(..) => (),
+	}
+	if visitor.enter_branch(path, hash.as_ref(), &decoded) {
+		let path_len = path.len();
+		match decoded {
+			Node::Extension(ref partial, _) | Node::NibbledBranch(ref partial, _, _) =>
+				path.append_partial(partial.right()),
+			_ => (),
+		}
+		let children_path_len = path.len();
+		for (index, child) in decoded.children() {
+			if let Some(index) = index {
+				path.push(index);
+			}
+			visit_node(trie, node_hash, child, path, depth + 1, visitor)?;
+			path.drop_lasts(path.len() - children_path_len);
+		}
+		path.drop_lasts(path.len() - path_len);
+	}
+	visitor.exit_branch(path, hash.as_ref(), &decoded);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::DBValue;
+	use reference_trie::node::Node;
+	use reference_trie::{ExtensionLayout, NibbleVec, RefTrieDB, RefTrieDBMut, Trie, TrieMut,
+		TrieVisitor};
+
+	#[derive(Default)]
+	struct Counter {
+		skip_children: bool,
+		entered: usize,
+		exited: usize,
+		leaves: usize,
+		stored_nodes: usize,
+		max_path_len: usize,
+	}
+
+	impl TrieVisitor<ExtensionLayout> for Counter {
+		fn enter_branch(&mut self, path: &NibbleVec, hash: Option<&[u8; 32]>, _node: &Node) -> bool {
+			self.entered += 1;
+			self.stored_nodes += hash.is_some() as usize;
+			self.max_path_len = self.max_path_len.max(path.len());
+			!self.skip_children
+		}
+
+		fn leaf(&mut self, path: &NibbleVec, hash: Option<&[u8; 32]>, _node: &Node) {
+			self.leaves += 1;
+			self.stored_nodes += hash.is_some() as usize;
+			self.max_path_len = self.max_path_len.max(path.len());
+		}
+
+		fn exit_branch(&mut self, _path: &NibbleVec, _hash: Option<&[u8; 32]>, _node: &Node) {
+			self.exited += 1;
+		}
+	}
+
+	#[test]
+	fn visit_counts_and_skips_nodes() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for i in 0..40u8 {
+				t.insert(&[i / 4, i], &[i; 40]).unwrap();
+			}
+		}
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+
+		let mut counter = Counter::default();
+		t.visit(&mut counter).unwrap();
+		assert_eq!(counter.leaves, t.len().unwrap());
+		assert_eq!(counter.entered, counter.exited);
+		assert_eq!(counter.stored_nodes, memdb.keys().len());
+		assert!(counter.max_path_len <= 4);
+
+		let mut counter = Counter { skip_children: true, ..Default::default() };
+		t.visit(&mut counter).unwrap();
+		assert_eq!((counter.entered, counter.exited, counter.leaves), (1, 1, 0));
+	}
+}
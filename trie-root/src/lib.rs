@@ -26,22 +26,28 @@ extern crate alloc;
 mod rstd {
 	pub use std::vec::Vec;
 	pub use std::cmp;
-	pub use std::collections::{BTreeMap, VecDeque};
+	pub use std::fmt;
 }
 
 #[cfg(not(feature = "std"))]
 mod rstd {
 	pub use core::cmp;
-	pub use alloc::collections::{BTreeMap, VecDeque};
+	pub use core::fmt;
 	pub use alloc::vec::Vec;
 }
 
 use self::rstd::*;
 
 pub use hash_db::Hasher;
+pub use rlp::{hex_prefix_decode, hex_prefix_encode, RlpTrieStream, RlpTrieStreamError};
+
+mod rlp;
 
 /// Byte-stream oriented trait for constructing closed-form tries.
 pub trait TrieStream {
+	/// Error returned when a node cannot be encoded, eg a partial key too long
+	/// for the encoding.
+	type Error: fmt::Debug;
 	/// Construct a new `TrieStream`
 	fn new() -> Self;
 	/// Append an Empty node
@@ -53,20 +59,26 @@ pub trait TrieStream {
 		maybe_key: Option<&[u8]>,
 		maybe_value: Option<&[u8]>,
 		has_children: impl Iterator<Item = bool>,
-	);
+	) -> Result<(), Self::Error>;
 	/// Append an empty child node. Optional.
 	fn append_empty_child(&mut self) {}
 	/// Wrap up a Branch node portion of a `TrieStream` and append the value
 	/// stored on the Branch (if any).
 	fn end_branch(&mut self, _value: Option<&[u8]>) {}
 	/// Append a Leaf node
-	fn append_leaf(&mut self, key: &[u8], value: &[u8]);
+	fn append_leaf(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error>;
 	/// Append an Extension node
-	fn append_extension(&mut self, key: &[u8]);
+	fn append_extension(&mut self, key: &[u8]) -> Result<(), Self::Error>;
 	/// Append a Branch of Extension substream
 	fn append_substream<H: Hasher>(&mut self, other: Self);
 	/// Return the finished `TrieStream` as a vector of bytes.
 	fn out(self) -> Vec<u8>;
+	/// Return the hash of the finished `TrieStream`. The default implementation hashes
+	/// `out`, streams holding their encoding in several buffers can hash them with
+	/// `Hasher::hash_parts` instead of concatenating them.
+	fn out_hash<H: Hasher>(self) -> H::Out where Self: Sized {
+		H::hash(&self.out())
+	}
 }
 
 fn shared_prefix_length<T: Eq>(first: &[T], second: &[T]) -> usize {
@@ -76,7 +88,18 @@ fn shared_prefix_length<T: Eq>(first: &[T], second: &[T]) -> usize {
 		.unwrap_or_else(|| cmp::min(first.len(), second.len()))
 }
 
-/// Generates a trie root hash for a vector of key-value tuples
+/// Error of building a trie from input expected to be sorted, eg `trie_root_sorted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortedInputError<E> {
+	/// The key of the item at this position in the input is not greater than
+	/// the previous key: the input is not sorted or has duplicate keys.
+	Unsorted(usize),
+	/// A node cannot be encoded by the stream.
+	Stream(E),
+}
+
+/// Generates a trie root hash for a vector of key-value tuples.
+/// Fails if a node cannot be encoded by the stream.
 ///
 /// ```rust
 /// use hex_literal::hex;
@@ -91,9 +114,9 @@ fn shared_prefix_length<T: Eq>(first: &[T], second: &[T]) -> usize {
 /// ];
 ///
 /// let root = hex!["0807d5393ae7f349481063ebb5dbaf6bda58db282a385ca97f37dccba717cb79"];
-/// assert_eq!(trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(v), root);
+/// assert_eq!(trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(v).unwrap(), root);
 /// ```
-pub fn trie_root<H, S, I, A, B>(input: I) -> H::Out where
+pub fn trie_root<H, S, I, A, B>(input: I) -> Result<H::Out, S::Error> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
@@ -103,43 +126,22 @@ pub fn trie_root<H, S, I, A, B>(input: I) -> H::Out where
 	trie_root_inner::<H, S, I, A, B>(input, false)
 }
 
-fn trie_root_inner<H, S, I, A, B>(input: I, no_extension: bool) -> H::Out where
+fn trie_root_inner<H, S, I, A, B>(input: I, no_extension: bool) -> Result<H::Out, S::Error> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
 	H: Hasher,
 	S: TrieStream,
 {
-	// first put elements into btree to sort them and to remove duplicates
-	let input = input
-		.into_iter()
-		.collect::<BTreeMap<_, _>>();
-
-	// convert to nibbles
-	let mut nibbles = Vec::with_capacity(input.keys().map(|k| k.as_ref().len()).sum::<usize>() * 2);
-	let mut lens = Vec::with_capacity(input.len() + 1);
-	lens.push(0);
-	for k in input.keys() {
-		for &b in k.as_ref() {
-			nibbles.push(b >> 4);
-			nibbles.push(b & 0x0F);
-		}
-		lens.push(nibbles.len());
-	}
-
-	// then move them to a vector
-	let input = input.into_iter().zip(lens.windows(2))
-		.map(|((_, v), w)| (&nibbles[w[0]..w[1]], v))
-		.collect::<Vec<_>>();
-
-	let mut stream = S::new();
-	build_trie::<H, S, _, _>(&input, 0, &mut stream, no_extension);
-	H::hash(&stream.out())
+	let input = sort_input(input);
+	let stream = build_trie_sorted::<H, S, _, _, _>(input, no_extension)
+		.map_err(stream_error)?;
+	Ok(stream.out_hash::<H>())
 }
 
 /// Variant of `trie_root` for patricia trie without extension node.
 /// See [`trie_root`].
-pub fn trie_root_no_extension<H, S, I, A, B>(input: I) -> H::Out where
+pub fn trie_root_no_extension<H, S, I, A, B>(input: I) -> Result<H::Out, S::Error> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
@@ -153,7 +155,7 @@ pub fn trie_root_no_extension<H, S, I, A, B>(input: I) -> H::Out where
 /// Method similar to `trie_root` but returning the root encoded
 /// node instead of its hash.
 /// Mainly use for testing or debugging.
-pub fn unhashed_trie<H, S, I, A, B>(input: I) -> Vec<u8> where
+pub fn unhashed_trie<H, S, I, A, B>(input: I) -> Result<Vec<u8>, S::Error> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
@@ -163,42 +165,48 @@ pub fn unhashed_trie<H, S, I, A, B>(input: I) -> Vec<u8> where
 	unhashed_trie_inner::<H, S, I, A, B>(input, false)
 }
 
-fn unhashed_trie_inner<H, S, I, A, B>(input: I, no_extension: bool) -> Vec<u8> where
+fn unhashed_trie_inner<H, S, I, A, B>(input: I, no_extension: bool) -> Result<Vec<u8>, S::Error> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
 	H: Hasher,
 	S: TrieStream,
 {
-	// first put elements into btree to sort them and to remove duplicates
-	let input = input
-		.into_iter()
-		.collect::<BTreeMap<_, _>>();
-
-	let mut nibbles = Vec::with_capacity(input.keys().map(|k| k.as_ref().len()).sum::<usize>() * 2);
-	let mut lens = Vec::with_capacity(input.len() + 1);
-	lens.push(0);
-	for k in input.keys() {
-		for &b in k.as_ref() {
-			nibbles.push(b >> 4);
-			nibbles.push(b & 0x0F);
-		}
-		lens.push(nibbles.len());
-	}
+	let input = sort_input(input);
+	Ok(build_trie_sorted::<H, S, _, _, _>(input, no_extension)
+		.map_err(stream_error)?
+		.out())
+}
 
-	// then move them to a vector
-	let input = input.into_iter().zip(lens.windows(2))
-		.map(|((_, v), w)| (&nibbles[w[0]..w[1]], v))
-		.collect::<Vec<_>>();
+/// Collect the input, sorting it by key and keeping the last value of duplicate
+/// keys unless it is already sorted without duplicates.
+fn sort_input<I, A, B>(input: I) -> Vec<(A, B)> where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+{
+	let mut input: Vec<_> = input.into_iter().collect();
+	if input.windows(2).all(|pair| pair[0].0.as_ref() < pair[1].0.as_ref()) {
+		return input;
+	}
+	// the sort is stable: once reversed, the last value of a key comes first
+	// and is the one kept.
+	input.reverse();
+	input.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+	input.dedup_by(|a, b| a.0.as_ref() == b.0.as_ref());
+	input
+}
 
-	let mut stream = S::new();
-	build_trie::<H, S, _, _>(&input, 0, &mut stream, no_extension);
-	stream.out()
+/// Stream error of building a trie from input sorted by `sort_input`.
+fn stream_error<E>(error: SortedInputError<E>) -> E {
+	match error {
+		SortedInputError::Stream(error) => error,
+		SortedInputError::Unsorted(_) => unreachable!("input sorted by `sort_input`; qed"),
+	}
 }
 
 /// Variant of `unhashed_trie` for patricia trie without extension node.
 /// See [`unhashed_trie`].
-pub fn unhashed_trie_no_extension<H, S, I, A, B>(input: I) -> Vec<u8> where
+pub fn unhashed_trie_no_extension<H, S, I, A, B>(input: I) -> Result<Vec<u8>, S::Error> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord,
 	B: AsRef<[u8]>,
@@ -208,6 +216,56 @@ pub fn unhashed_trie_no_extension<H, S, I, A, B>(input: I) -> Vec<u8> where
 	unhashed_trie_inner::<H, S, I, A, B>(input, true)
 }
 
+/// Variant of `trie_root` for input sorted by key and without duplicate keys.
+/// The input is consumed in a single pass, with memory use bounded by the
+/// key length rather than the number of items.
+/// Fails with `SortedInputError::Unsorted` if a key is not greater than the previous one.
+pub fn trie_root_sorted<H, S, I, A, B>(input: I) -> Result<H::Out, SortedInputError<S::Error>> where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	let stream = build_trie_sorted::<H, S, _, _, _>(input, false)?;
+	Ok(stream.out_hash::<H>())
+}
+
+/// Variant of `trie_root_sorted` for patricia trie without extension node.
+pub fn trie_root_sorted_no_extension<H, S, I, A, B>(input: I) -> Result<H::Out, SortedInputError<S::Error>> where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	let stream = build_trie_sorted::<H, S, _, _, _>(input, true)?;
+	Ok(stream.out_hash::<H>())
+}
+
+/// Variant of `unhashed_trie` for input sorted by key and without duplicate keys.
+/// See [`trie_root_sorted`].
+pub fn unhashed_trie_sorted<H, S, I, A, B>(input: I) -> Result<Vec<u8>, SortedInputError<S::Error>> where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	Ok(build_trie_sorted::<H, S, _, _, _>(input, false)?.out())
+}
+
+/// Variant of `unhashed_trie_sorted` for patricia trie without extension node.
+pub fn unhashed_trie_sorted_no_extension<H, S, I, A, B>(input: I) -> Result<Vec<u8>, SortedInputError<S::Error>> where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	Ok(build_trie_sorted::<H, S, _, _, _>(input, true)?.out())
+}
+
 /// Generates a key-hashed (secure) trie root hash for a vector of key-value tuples.
 ///
 /// ```rust
@@ -223,9 +281,9 @@ pub fn unhashed_trie_no_extension<H, S, I, A, B>(input: I) -> Vec<u8> where
 /// ];
 ///
 /// let root = hex!["d6e02b2bd48aa04fd2ad87cfac1144a29ca7f7dc60f4526c7b7040763abe3d43"];
-/// assert_eq!(sec_trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(v), root);
+/// assert_eq!(sec_trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(v).unwrap(), root);
 /// ```
-pub fn sec_trie_root<H, S, I, A, B>(input: I) -> H::Out where
+pub fn sec_trie_root<H, S, I, A, B>(input: I) -> Result<H::Out, S::Error> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]>,
 	B: AsRef<[u8]>,
@@ -236,110 +294,240 @@ pub fn sec_trie_root<H, S, I, A, B>(input: I) -> H::Out where
 	trie_root::<H, S, _, _, _>(input.into_iter().map(|(k, v)| (H::hash(k.as_ref()), v)))
 }
 
-/// Takes a slice of key/value tuples where the key is a slice of nibbles
-/// and encodes it into the provided `Stream`.
-fn build_trie<H, S, A, B>(input: &[(A, B)], cursor: usize, stream: &mut S, no_extension: bool) where
+/// Generates a trie root hash for a vector of values, each value being keyed
+/// by its index in the vector, SCALE compact encoded.
+///
+/// ```rust
+/// use trie_root::{ordered_trie_root, trie_root};
+/// use keccak_hasher::KeccakHasher;
+/// use reference_trie::ReferenceTrieStream;
+///
+/// let v = vec!["doe", "reindeer"];
+/// let keyed = vec![(vec![0x00], "doe"), (vec![0x04], "reindeer")];
+/// assert_eq!(
+/// 	ordered_trie_root::<KeccakHasher, ReferenceTrieStream, _, _>(v).unwrap(),
+/// 	trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(keyed).unwrap(),
+/// );
+/// ```
+pub fn ordered_trie_root<H, S, I, V>(input: I) -> Result<H::Out, S::Error> where
+	I: IntoIterator<Item = V>,
+	V: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	trie_root::<H, S, _, _, _>(input
+		.into_iter()
+		.enumerate()
+		.map(|(i, v)| (compact_encode_index(i as u32), v))
+	)
+}
+
+/// SCALE compact encoding of an index.
+fn compact_encode_index(index: u32) -> Vec<u8> {
+	if index < 1 << 6 {
+		[(index as u8) << 2].to_vec()
+	} else if index < 1 << 14 {
+		((index as u16) << 2 | 0b01).to_le_bytes().to_vec()
+	} else if index < 1 << 30 {
+		(index << 2 | 0b10).to_le_bytes().to_vec()
+	} else {
+		let mut output = Vec::with_capacity(5);
+		output.push(0b11);
+		output.extend_from_slice(&index.to_le_bytes());
+		output
+	}
+}
+
+/// Branch node being built by `build_trie_sorted`, waiting for all its children.
+struct PendingBranch<S, B> {
+	/// Nibbles of the path to the branch, their number being the branch depth.
+	prefix: Vec<u8>,
+	value: Option<B>,
+	/// Encoded children with their index, in order.
+	children: Vec<(u8, S)>,
+}
+
+impl<S, B> PendingBranch<S, B> {
+	fn new(prefix: &[u8]) -> Self {
+		PendingBranch {
+			prefix: prefix.to_vec(),
+			value: None,
+			children: Vec::new(),
+		}
+	}
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+	let mut nibbles = Vec::with_capacity(key.len() * 2);
+	for &b in key {
+		nibbles.push(b >> 4);
+		nibbles.push(b & 0x0F);
+	}
+	nibbles
+}
+
+/// Encodes the trie of sorted key/value tuples into a `Stream`, in a single pass.
+///
+/// Items are added to the deepest pending branch once the next key is known,
+/// and branches deeper than the prefix shared with the next key are complete:
+/// they are encoded and added as a child of their parent branch.
+fn build_trie_sorted<H, S, I, A, B>(
+	input: I,
+	no_extension: bool,
+) -> Result<S, SortedInputError<S::Error>> where
+	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]>,
 	B: AsRef<[u8]>,
 	H: Hasher,
 	S: TrieStream,
 {
-	match input.len() {
-		// No input, just append empty data.
-		0 => stream.append_empty_data(),
-		// Leaf node; append the remainder of the key and the value. Done.
-		1 => stream.append_leaf(&input[0].0.as_ref()[cursor..], &input[0].1.as_ref() ),
-		// We have multiple items in the input. Figure out if we should add an
-		// extension node or a branch node.
-		_ => {
-			let (key, value) = (&input[0].0.as_ref(), input[0].1.as_ref());
-			// Count the number of nibbles in the other elements that are
-			// shared with the first key.
-			// e.g. input = [ [1'7'3'10'12'13], [1'7'3'], [1'7'7'8'9'] ] => [1'7'] is common => 2
-			let shared_nibble_count = input.iter().skip(1).fold(key.len(), |acc, &(ref k, _)| {
-				cmp::min( shared_prefix_length(key, k.as_ref()), acc )
-			});
-			// Add an extension node if the number of shared nibbles is greater
-			// than what we saw on the last call (`cursor`): append the new part
-			// of the path then recursively append the remainder of all items
-			// who had this partial key.
-			let (cursor, o_branch_slice) = if no_extension {
-				if shared_nibble_count > cursor {
-					(shared_nibble_count, Some(&key[cursor..shared_nibble_count]))
-				} else {
-					(cursor, Some(&key[0..0]))
-				}
-			} else if shared_nibble_count > cursor {
-				stream.append_extension(&key[cursor..shared_nibble_count]);
-				build_trie_trampoline::<H, _, _, _>(
-					input,
-					shared_nibble_count,
-					stream,
-					no_extension,
-				);
-				return;
-			} else { (cursor, None) };
-
-			// We'll be adding a branch node because the path is as long as it gets.
-			// First we need to figure out what entries this branch node will have...
-
-			// We have a a value for exactly this key. Branch node will have a value
-			// attached to it.
-			let value = if cursor == key.len() { Some(value) } else { None };
-
-			// We need to know how many key nibbles each of the children account for.
-			let mut shared_nibble_counts = [0usize; 16];
-			{
-				// If the Branch node has a value then the first of the input keys
-				// is exactly the key for that value and we don't care about it
-				// when finding shared nibbles for our child nodes. (We know it's
-				// the first of the input keys, because the input is sorted)
-				let mut begin = match value { None => 0, _ => 1 };
-				for i in 0..16 {
-					shared_nibble_counts[i] = input[begin..].iter()
-						.take_while(|(k, _)| k.as_ref()[cursor] == i as u8)
-						.count();
-					begin += shared_nibble_counts[i];
-				}
+	let mut stack: Vec<PendingBranch<S, B>> = Vec::new();
+	let mut previous: Option<(Vec<u8>, B)> = None;
+	for (index, (key, value)) in input.into_iter().enumerate() {
+		let key = to_nibbles(key.as_ref());
+		if let Some((previous_key, previous_value)) = previous.take() {
+			if previous_key >= key {
+				return Err(SortedInputError::Unsorted(index));
 			}
-
-			// Put out the node header:
-			stream.begin_branch(o_branch_slice, value, shared_nibble_counts.iter().map(|&n| n > 0));
-
-			// Fill in each slot in the branch node. We don't need to bother with empty slots
-			// since they were registered in the header.
-			let mut begin = match value { None => 0, _ => 1 };
-			for &count in &shared_nibble_counts {
-				if count > 0 {
-					build_trie_trampoline::<H, S, _, _>(
-						&input[begin..(begin + count)],
-						cursor + 1,
-						stream,
-						no_extension,
-					);
-					begin += count;
-				} else {
-					stream.append_empty_child();
-				}
+			let shared = shared_prefix_length(&previous_key, &key);
+			if stack.last().map_or(true, |branch| branch.prefix.len() < shared) {
+				stack.push(PendingBranch::new(&key[..shared]));
 			}
+			let top = stack.last_mut().expect("pushed above if missing; qed");
+			add_to_branch(top, previous_key, previous_value).map_err(SortedInputError::Stream)?;
+			close_branches::<H, _, _>(&mut stack, shared, no_extension)
+				.map_err(SortedInputError::Stream)?;
+		}
+		previous = Some((key, value));
+	}
 
-			stream.end_branch(value);
+	match (previous, stack.last_mut()) {
+		(None, _) => {
+			let mut stream = S::new();
+			stream.append_empty_data();
+			return Ok(stream);
+		},
+		(Some((key, value)), None) => {
+			let mut stream = S::new();
+			stream.append_leaf(&key, value.as_ref()).map_err(SortedInputError::Stream)?;
+			return Ok(stream);
+		},
+		(Some((key, value)), Some(top)) => add_to_branch(top, key, value)
+			.map_err(SortedInputError::Stream)?,
+	}
+	// the root branch is the one at the prefix shared by all keys.
+	let root_depth = stack[0].prefix.len();
+	close_branches::<H, _, _>(&mut stack, root_depth, no_extension)
+		.map_err(SortedInputError::Stream)?;
+	let root = stack.pop().expect("closing stops at depth 0 with a branch; qed");
+	encode_branch::<H, _, _>(root, None, no_extension).map_err(SortedInputError::Stream)
+}
+
+/// Add an item to `branch`, as its value or as a leaf child.
+fn add_to_branch<S, B>(
+	branch: &mut PendingBranch<S, B>,
+	key: Vec<u8>,
+	value: B,
+) -> Result<(), S::Error> where
+	B: AsRef<[u8]>,
+	S: TrieStream,
+{
+	let depth = branch.prefix.len();
+	if key.len() == depth {
+		branch.value = Some(value);
+	} else {
+		let mut leaf = S::new();
+		leaf.append_leaf(&key[depth + 1..], value.as_ref())?;
+		branch.children.push((key[depth], leaf));
+	}
+	Ok(())
+}
+
+/// Encode the pending branches deeper than `depth` into their parent, adding
+/// a branch at `depth` if there is none.
+fn close_branches<H, S, B>(
+	stack: &mut Vec<PendingBranch<S, B>>,
+	depth: usize,
+	no_extension: bool,
+) -> Result<(), S::Error> where
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	while stack.last().map_or(false, |branch| branch.prefix.len() > depth) {
+		let branch = stack.pop().expect("checked above; qed");
+		if stack.last().map_or(true, |parent| parent.prefix.len() < depth) {
+			stack.push(PendingBranch::new(&branch.prefix[..depth]));
 		}
+		let parent = stack.last_mut().expect("pushed above if missing; qed");
+		let parent_depth = parent.prefix.len();
+		let index = branch.prefix[parent_depth];
+		let child = encode_branch::<H, _, _>(branch, Some(parent_depth), no_extension)?;
+		parent.children.push((index, child));
 	}
+	Ok(())
 }
 
-fn build_trie_trampoline<H, S, A, B>(
-	input: &[(A, B)],
-	cursor: usize,
-	stream: &mut S,
+/// Encode a complete branch, with the extension node leading to it if needed.
+fn encode_branch<H, S, B>(
+	branch: PendingBranch<S, B>,
+	parent_depth: Option<usize>,
 	no_extension: bool,
-) where
-	A: AsRef<[u8]>,
+) -> Result<S, S::Error> where
 	B: AsRef<[u8]>,
 	H: Hasher,
 	S: TrieStream,
 {
-	let mut substream = S::new();
-	build_trie::<H, _, _, _>(input, cursor, &mut substream, no_extension);
-	stream.append_substream::<H>(substream);
+	let PendingBranch { prefix, value, children } = branch;
+	let partial = &prefix[parent_depth.map_or(0, |depth| depth + 1)..];
+	let value = value.as_ref().map(|value| value.as_ref());
+	let mut has_children = [false; 16];
+	for (index, _) in &children {
+		has_children[*index as usize] = true;
+	}
+
+	let mut stream = S::new();
+	let branch_partial = if no_extension { Some(partial) } else { None };
+	stream.begin_branch(branch_partial, value, has_children.iter().cloned())?;
+	let mut children = children.into_iter().peekable();
+	for i in 0..16 {
+		match children.peek() {
+			Some((index, _)) if *index == i => {
+				let (_, child) = children.next().expect("peeked above; qed");
+				stream.append_substream::<H>(child);
+			},
+			_ => stream.append_empty_child(),
+		}
+	}
+	stream.end_branch(value);
+
+	if no_extension || partial.is_empty() {
+		return Ok(stream);
+	}
+	let mut extension = S::new();
+	extension.append_extension(partial)?;
+	extension.append_substream::<H>(stream);
+	Ok(extension)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn index_compact_encoding() {
+		let cases: &[(u32, &[u8])] = &[
+			(0, &[0x00]),
+			(1, &[0x04]),
+			(63, &[0xfc]),
+			(64, &[0x01, 0x01]),
+			(16383, &[0xfd, 0xff]),
+			(16384, &[0x02, 0x00, 0x01, 0x00]),
+			(1073741823, &[0xfe, 0xff, 0xff, 0xff]),
+			(1073741824, &[0x03, 0x00, 0x00, 0x00, 0x40]),
+		];
+		for (index, encoded) in cases {
+			assert_eq!(&compact_encode_index(*index)[..], *encoded);
+		}
+	}
 }
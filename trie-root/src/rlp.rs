@@ -0,0 +1,364 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ethereum trie encoding: hex-prefix encoded partial keys and RLP encoded nodes.
+
+use crate::rstd::Vec;
+use crate::{Hasher, TrieStream};
+
+/// Hex-prefix encode a slice of nibbles (one nibble per byte), flagging
+/// whether it is the partial key of a leaf or of an extension node.
+pub fn hex_prefix_encode(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+	let mut output = Vec::with_capacity(1 + nibbles.len() / 2);
+	let flag = if leaf { 0x20 } else { 0 };
+	let rest = if nibbles.len() % 2 == 1 {
+		output.push(flag | 0x10 | nibbles[0]);
+		&nibbles[1..]
+	} else {
+		output.push(flag);
+		nibbles
+	};
+	output.extend(rest.chunks(2).map(|ch| ch[0] << 4 | ch[1]));
+	output
+}
+
+/// Decode a hex-prefix encoded partial key, returning its nibbles (one nibble per byte)
+/// and whether it is the partial key of a leaf.
+/// Returns `None` if `data` is not a valid hex-prefix encoding.
+pub fn hex_prefix_decode(data: &[u8]) -> Option<(Vec<u8>, bool)> {
+	let first = *data.first()?;
+	let leaf = match first >> 5 {
+		0 => false,
+		1 => true,
+		_ => return None,
+	};
+	let odd = first & 0x10 != 0;
+	if !odd && first & 0x0f != 0 {
+		return None;
+	}
+	let mut nibbles = Vec::with_capacity(data.len() * 2);
+	if odd {
+		nibbles.push(first & 0x0f);
+	}
+	for b in &data[1..] {
+		nibbles.push(b >> 4);
+		nibbles.push(b & 0x0f);
+	}
+	Some((nibbles, leaf))
+}
+
+/// Append the RLP header of an item of `len` bytes.
+/// `offset` is `0x80` for strings and `0xc0` for lists.
+fn append_rlp_header(output: &mut Vec<u8>, len: usize, offset: u8) {
+	if len < 56 {
+		output.push(offset + len as u8);
+	} else {
+		let bytes = (len as u64).to_be_bytes();
+		let skip = bytes.iter().take_while(|b| **b == 0).count();
+		output.push(offset + 55 + (bytes.len() - skip) as u8);
+		output.extend_from_slice(&bytes[skip..]);
+	}
+}
+
+/// Length of the RLP header of an item of `len` bytes.
+fn rlp_header_len(len: usize) -> usize {
+	if len < 56 {
+		1
+	} else {
+		1 + 8 - (len as u64).leading_zeros() as usize / 8
+	}
+}
+
+/// Append `data` as an RLP string.
+fn append_rlp_string(output: &mut Vec<u8>, data: &[u8]) {
+	if data.len() == 1 && data[0] < 0x80 {
+		output.push(data[0]);
+	} else {
+		append_rlp_header(output, data.len(), 0x80);
+		output.extend_from_slice(data);
+	}
+}
+
+/// RLP encoding of the empty string.
+const RLP_EMPTY_STRING: u8 = 0x80;
+
+/// Error of `RlpTrieStream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RlpTrieStreamError {
+	/// Ethereum tries have no branch node with a partial key, tries
+	/// without extension nodes cannot be encoded.
+	PartialKeyInBranch,
+}
+
+/// `TrieStream` encoding nodes as in Ethereum tries, to be used with
+/// `trie_root` and `sec_trie_root` (with the keccak-256 hasher) to compute
+/// Ethereum transaction, receipt or state roots.
+#[derive(Default, Clone)]
+pub struct RlpTrieStream {
+	/// RLP items of the node, without the list header.
+	buffer: Vec<u8>,
+	empty: bool,
+}
+
+impl TrieStream for RlpTrieStream {
+	type Error = RlpTrieStreamError;
+
+	fn new() -> Self {
+		RlpTrieStream {
+			buffer: Vec::new(),
+			empty: false,
+		}
+	}
+
+	fn append_empty_data(&mut self) {
+		self.empty = true;
+	}
+
+	fn begin_branch(
+		&mut self,
+		maybe_key: Option<&[u8]>,
+		_maybe_value: Option<&[u8]>,
+		_has_children: impl Iterator<Item = bool>,
+	) -> Result<(), Self::Error> {
+		match maybe_key {
+			Some(_) => Err(RlpTrieStreamError::PartialKeyInBranch),
+			None => Ok(()),
+		}
+	}
+
+	fn append_empty_child(&mut self) {
+		self.buffer.push(RLP_EMPTY_STRING);
+	}
+
+	fn end_branch(&mut self, value: Option<&[u8]>) {
+		match value {
+			Some(value) => append_rlp_string(&mut self.buffer, value),
+			None => self.buffer.push(RLP_EMPTY_STRING),
+		}
+	}
+
+	fn append_leaf(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+		append_rlp_string(&mut self.buffer, &hex_prefix_encode(key, true));
+		append_rlp_string(&mut self.buffer, value);
+		Ok(())
+	}
+
+	fn append_extension(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+		append_rlp_string(&mut self.buffer, &hex_prefix_encode(key, false));
+		Ok(())
+	}
+
+	fn append_substream<H: Hasher>(&mut self, other: Self) {
+		// nodes shorter than a hash are inlined
+		if other.encoded_len() < 32 {
+			self.buffer.extend_from_slice(&other.out());
+		} else {
+			append_rlp_string(&mut self.buffer, other.out_hash::<H>().as_ref());
+		}
+	}
+
+	fn out(self) -> Vec<u8> {
+		if self.empty {
+			return [RLP_EMPTY_STRING].to_vec();
+		}
+		let mut output = Vec::with_capacity(self.buffer.len() + 9);
+		append_rlp_header(&mut output, self.buffer.len(), 0xc0);
+		output.extend_from_slice(&self.buffer);
+		output
+	}
+
+	fn out_hash<H: Hasher>(self) -> H::Out {
+		if self.empty {
+			return H::hash(&[RLP_EMPTY_STRING]);
+		}
+		let mut header = Vec::with_capacity(9);
+		append_rlp_header(&mut header, self.buffer.len(), 0xc0);
+		H::hash_parts(&[&header, &self.buffer])
+			.unwrap_or_else(|| H::hash(&[&header[..], &self.buffer[..]].concat()))
+	}
+}
+
+impl RlpTrieStream {
+	/// Length of the node encoded by `out`.
+	fn encoded_len(&self) -> usize {
+		if self.empty {
+			1
+		} else {
+			rlp_header_len(self.buffer.len()) + self.buffer.len()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{trie_root, trie_root_no_extension, unhashed_trie};
+	use hex_literal::hex;
+	use keccak_hasher::KeccakHasher;
+
+	#[test]
+	fn hex_prefix_encoding() {
+		let cases: &[(&[u8], bool, &[u8])] = &[
+			(&[1, 2, 3, 4, 5], false, &[0x11, 0x23, 0x45]),
+			(&[0, 1, 2, 3, 4, 5], false, &[0x00, 0x01, 0x23, 0x45]),
+			(&[0, 0xf, 1, 0xc, 0xb, 8], true, &[0x20, 0x0f, 0x1c, 0xb8]),
+			(&[0xf, 1, 0xc, 0xb, 8], true, &[0x3f, 0x1c, 0xb8]),
+			(&[], true, &[0x20]),
+		];
+		for (nibbles, leaf, encoded) in cases {
+			assert_eq!(&hex_prefix_encode(nibbles, *leaf)[..], *encoded);
+			assert_eq!(hex_prefix_decode(encoded), Some((nibbles.to_vec(), *leaf)));
+		}
+		assert_eq!(hex_prefix_decode(&[]), None);
+		assert_eq!(hex_prefix_decode(&[0x40]), None);
+		assert_eq!(hex_prefix_decode(&[0x21]), None);
+	}
+
+	#[test]
+	fn rlp_header_encoding() {
+		let mut output = Vec::new();
+		append_rlp_string(&mut output, b"dog");
+		assert_eq!(output, vec![0x83, b'd', b'o', b'g']);
+		output.clear();
+		append_rlp_string(&mut output, &[0x0f]);
+		assert_eq!(output, vec![0x0f]);
+		output.clear();
+		append_rlp_string(&mut output, &[0x7a; 56]);
+		assert_eq!(&output[..2], &[0xb8, 56]);
+		output.clear();
+		append_rlp_header(&mut output, 1024, 0xc0);
+		assert_eq!(output, vec![0xf9, 0x04, 0x00]);
+		for len in [0, 55, 56, 255, 256, 1024, 1 << 24].iter() {
+			output.clear();
+			append_rlp_header(&mut output, *len, 0xc0);
+			assert_eq!(rlp_header_len(*len), output.len());
+		}
+	}
+
+	#[test]
+	fn ethereum_roots() {
+		// transactions and receipts root of blocks without transactions, eg the genesis block
+		let empty: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+		assert_eq!(
+			trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(empty).unwrap(),
+			hex!["56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"],
+		);
+
+		let dogs = vec![
+			("doe", "reindeer"),
+			("dog", "puppy"),
+			("dogglesworth", "cat"),
+		];
+		assert_eq!(
+			trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(dogs).unwrap(),
+			hex!["8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"],
+		);
+
+		let puppy = vec![
+			("do", "verb"),
+			("horse", "stallion"),
+			("doge", "coin"),
+			("dog", "puppy"),
+		];
+		assert_eq!(
+			trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(puppy).unwrap(),
+			hex!["5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"],
+		);
+
+		// transactions root of mainnet block 46147, holding the first transaction
+		let tx = hex!["f86780862d79883d2000825208945df9b87991262f6ba471f09758cde1c0fc1de734827a69801ca088ff6cf0fefd94db46111149ae4bfc179e9b94721fffd821d38d16464b3f71d0a045e0aff800961cfce805daef7016b9b675c137a6a41a548f7b60a3484c06a33a"];
+		assert_eq!(
+			KeccakHasher::hash(&tx),
+			hex!["5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060"],
+		);
+		assert_eq!(
+			trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(vec![(vec![0x80], tx.to_vec())]).unwrap(),
+			hex!["4513310fcb9f6f616972a3b948dc5d547f280849a87ebb5af0191f98b87be598"],
+		);
+
+		// receipts root of mainnet blocks holding a single successful plain transfer since
+		// Byzantium: status 1, 21000 gas used, empty logs bloom and no logs
+		let mut fields = Vec::new();
+		append_rlp_string(&mut fields, &[1]);
+		append_rlp_string(&mut fields, &[0x52, 0x08]);
+		append_rlp_string(&mut fields, &[0; 256]);
+		fields.push(0xc0);
+		let mut receipt = Vec::new();
+		append_rlp_header(&mut receipt, fields.len(), 0xc0);
+		receipt.extend_from_slice(&fields);
+		assert_eq!(
+			trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(vec![(vec![0x80], receipt)]).unwrap(),
+			hex!["056b23fbba480696b65fe5a59b8f2148a1299103c4f57df839233af2cf4ca2d2"],
+		);
+	}
+
+	#[test]
+	fn ethereum_trie_tests() {
+		use trie_standardmap::ethereum::{load, TRIE_ANY_ORDER, TRIE_TEST};
+
+		let root = |entries: Vec<(Vec<u8>, Vec<u8>)>|
+			trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(entries).unwrap().to_vec();
+		for fixture in &[TRIE_ANY_ORDER, TRIE_TEST] {
+			let cases = load(fixture).unwrap();
+			assert!(!cases.is_empty());
+			for case in cases {
+				let entries = case.entries();
+				assert_eq!(root(entries.clone()), case.root, "{}", case.name);
+				let mut reversed = entries.clone();
+				reversed.reverse();
+				assert_eq!(root(reversed), case.root, "{}", case.name);
+				for i in 1..entries.len() {
+					let mut rotated = entries.clone();
+					rotated.rotate_left(i);
+					assert_eq!(root(rotated), case.root, "{}", case.name);
+				}
+			}
+		}
+	}
+
+	/// Keccak-256 hasher without `hash_parts`, hashing nodes as a single slice.
+	struct SliceKeccakHasher;
+
+	impl Hasher for SliceKeccakHasher {
+		type Out = [u8; 32];
+		type StdHasher = <KeccakHasher as Hasher>::StdHasher;
+		const LENGTH: usize = 32;
+
+		fn hash(x: &[u8]) -> Self::Out {
+			KeccakHasher::hash(x)
+		}
+	}
+
+	#[test]
+	fn large_nodes_hashed_in_parts() {
+		let entries = vec![
+			(b"doe".to_vec(), vec![1u8; 5000]),
+			(b"dog".to_vec(), vec![2u8; 100_000]),
+			(b"dogglesworth".to_vec(), b"cat".to_vec()),
+		];
+		let root = trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(entries.clone()).unwrap();
+		assert_eq!(root, trie_root::<SliceKeccakHasher, RlpTrieStream, _, _, _>(entries.clone()).unwrap());
+		let unhashed = unhashed_trie::<SliceKeccakHasher, RlpTrieStream, _, _, _>(entries).unwrap();
+		assert_eq!(root, KeccakHasher::hash(&unhashed));
+	}
+
+	#[test]
+	fn no_extension_trie_is_rejected() {
+		let v = vec![("doe", "reindeer"), ("dog", "puppy")];
+		assert_eq!(
+			trie_root_no_extension::<KeccakHasher, RlpTrieStream, _, _, _>(v),
+			Err(RlpTrieStreamError::PartialKeyInBranch),
+		);
+	}
+}